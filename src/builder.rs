@@ -0,0 +1,301 @@
+use crate::error::Error;
+use crate::formatters::Formatter;
+use crate::metadata::{DirOverrides, Metadata, MANIFEST_VERSION};
+use crate::output::Layout;
+use crate::placeholder::Placeholder;
+use crate::query::{fallback_output, Queries, Query};
+use crate::query_template::{QueryTemplate, QueryTemplates};
+use crate::tagging::{NameTagStyle, NameTagger};
+use crate::test_template::{default_output, TestKind, TestTemplate, TestTemplates};
+use crate::validation::Severity;
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+fn strset(xs: &[&str]) -> HashSet<String> {
+    xs.iter().map(|s| (*s).to_owned()).collect()
+}
+
+/// Builder for constructing `Metadata` programmatically i.e. without
+/// a manifest file
+///
+/// The defaults are the same as in the manifest created by the `init`
+/// command. Paths of the templates are relative to the respective
+/// dirs, which are resolved only when building, so the methods can
+/// be called in any order.
+pub struct MetadataBuilder {
+    placeholder: Placeholder,
+    query_templates_dir: PathBuf,
+    test_templates_dir: PathBuf,
+    queries_output_dir: PathBuf,
+    tests_output_dir: PathBuf,
+    formatter: Option<Formatter>,
+    name_tagger: Option<NameTagger>,
+    // (path, all_conds)
+    query_templates: Vec<(PathBuf, HashSet<String>)>,
+    // (id, template, conds)
+    queries: Vec<(String, PathBuf, HashSet<String>)>,
+    // (query id, path)
+    test_templates: Vec<(String, PathBuf)>,
+}
+
+impl MetadataBuilder {
+    pub fn new() -> Self {
+        Self {
+            placeholder: Placeholder::PosArgs,
+            query_templates_dir: PathBuf::from("templates/queries"),
+            test_templates_dir: PathBuf::from("templates/tests"),
+            queries_output_dir: PathBuf::from("output/queries"),
+            tests_output_dir: PathBuf::from("output/tests"),
+            formatter: None,
+            name_tagger: Some(NameTagger {
+                style: NameTagStyle::KebabCase,
+            }),
+            query_templates: vec![],
+            queries: vec![],
+            test_templates: vec![],
+        }
+    }
+
+    pub fn set_placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    pub fn set_query_templates_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.query_templates_dir = dir.into();
+        self
+    }
+
+    pub fn set_test_templates_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.test_templates_dir = dir.into();
+        self
+    }
+
+    /// Replaces the dirs with the `overrides` (if any) and nests the
+    /// output dirs under the `out_suffix` (if any), same as when
+    /// reading the manifest file
+    pub fn set_dir_overrides(mut self, overrides: &DirOverrides) -> Self {
+        let dirs = [
+            (
+                "query_templates_dir",
+                &mut self.query_templates_dir,
+                &overrides.query_templates_dir,
+            ),
+            (
+                "test_templates_dir",
+                &mut self.test_templates_dir,
+                &overrides.test_templates_dir,
+            ),
+            (
+                "queries_output_dir",
+                &mut self.queries_output_dir,
+                &overrides.queries_output_dir,
+            ),
+            (
+                "tests_output_dir",
+                &mut self.tests_output_dir,
+                &overrides.tests_output_dir,
+            ),
+        ];
+        for (key, dir, path) in dirs {
+            if let Some(p) = path {
+                info!("Overriding '{key}' with '{}'", p.display());
+                *dir = p.clone();
+            }
+        }
+        if let Some(suffix) = &overrides.out_suffix {
+            self.queries_output_dir.push(suffix);
+            self.tests_output_dir.push(suffix);
+        }
+        self
+    }
+
+    pub fn set_formatter(mut self, formatter: Option<Formatter>) -> Self {
+        self.formatter = formatter;
+        self
+    }
+
+    pub fn set_name_tagger(mut self, name_tagger: Option<NameTagger>) -> Self {
+        self.name_tagger = name_tagger;
+        self
+    }
+
+    pub fn add_query_template<P: Into<PathBuf>>(mut self, path: P, all_conds: &[&str]) -> Self {
+        self.query_templates.push((path.into(), strset(all_conds)));
+        self
+    }
+
+    /// Adds a query with the defaults for all the other keys e.g. the
+    /// output file name is derived from the `id`
+    pub fn add_query<P: Into<PathBuf>>(mut self, id: &str, template: P, conds: &[&str]) -> Self {
+        self.queries
+            .push((id.to_owned(), template.into(), strset(conds)));
+        self
+    }
+
+    /// Adds a test template of the query `query_id`, with the output
+    /// file name derived from the `path`
+    pub fn add_test_template<P: Into<PathBuf>>(mut self, query_id: &str, path: P) -> Self {
+        self.test_templates.push((query_id.to_owned(), path.into()));
+        self
+    }
+
+    /// Returns the `Metadata`, after validating it the same way as
+    /// the `validate --syntax-only` command
+    ///
+    /// # Error:
+    /// Returns `Error::InvalidMetadata` with the messages of all the
+    /// mistakes (excluding warnings) found in it
+    pub fn build(self) -> Result<Metadata, Error> {
+        let layout = Layout::OneFileOneQuery;
+        let mut query_templates = QueryTemplates::new();
+        for (path, all_conds) in self.query_templates {
+            let path = self.query_templates_dir.join(path);
+            if path.to_str().is_none() {
+                return Err(Error::InvalidMetadata(format!(
+                    "Path is not valid UTF-8: {}",
+                    path.display()
+                )));
+            }
+            query_templates.push(QueryTemplate { path, all_conds });
+        }
+        let mut queries = Queries::new();
+        for (id, template, conds) in self.queries {
+            let output = fallback_output(&id, &self.queries_output_dir, &layout)?;
+            let template = self.query_templates_dir.join(template);
+            queries.push(Query::new(&id, template, conds, output));
+        }
+        let mut test_templates = TestTemplates::new();
+        for (query, path) in self.test_templates {
+            let path = self.test_templates_dir.join(path);
+            let output = default_output(
+                &path,
+                &self.test_templates_dir,
+                &self.tests_output_dir,
+                false,
+            )?;
+            test_templates.push(TestTemplate {
+                query,
+                path,
+                output,
+                requires_conds: HashSet::new(),
+                placeholder: None,
+                kind: TestKind::Pgtap,
+            });
+        }
+        let metadata = Metadata {
+            version: Some(MANIFEST_VERSION),
+            placeholder: self.placeholder,
+            query_templates_dir: self.query_templates_dir,
+            test_templates_dir: self.test_templates_dir,
+            formatter: self.formatter,
+            queries_output_dir: self.queries_output_dir,
+            tests_output_dir: self.tests_output_dir,
+            query_output_layout: layout,
+            name_tagger: self.name_tagger,
+            header_template: None,
+            normalize: None,
+            query_templates,
+            queries,
+            test_templates,
+            profiles: HashMap::new(),
+            default_conds: HashSet::new(),
+            post_render: None,
+            id_pattern: None,
+            unknown_keys: vec![],
+        };
+        let errors = metadata
+            .validate_syntax()
+            .iter()
+            .filter(|m| m.severity() == Severity::Error)
+            .map(|m| m.err_msg())
+            .collect::<Vec<String>>();
+        if errors.is_empty() {
+            Ok(metadata)
+        } else {
+            Err(Error::InvalidMetadata(errors.join("\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::render::Engine;
+    use crate::util::test_util::TempDir;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_metadata_builder() {
+        let dir = TempDir::new("builder");
+        let templates_dir = dir.join("templates");
+        fs::create_dir_all(templates_dir.join("tests")).unwrap();
+        fs::write(
+            templates_dir.join("artists.sql.j2"),
+            "SELECT * FROM artists{% if cond__genre %} WHERE genre = {{ placeholder('genre') }}{% endif %};\n",
+        )
+        .unwrap();
+        fs::write(
+            templates_dir.join("tests/artists_test.sql.j2"),
+            "PREPARE q AS {{ prepared_statement }}\n",
+        )
+        .unwrap();
+
+        let metadata = MetadataBuilder::new()
+            .set_placeholder(Placeholder::PosArgs)
+            .set_query_templates_dir(&templates_dir)
+            .set_test_templates_dir(templates_dir.join("tests"))
+            .set_dir_overrides(&DirOverrides {
+                queries_output_dir: Some(PathBuf::from("out/queries")),
+                ..DirOverrides::default()
+            })
+            .set_formatter(Some(Formatter::builtin()))
+            .set_name_tagger(Some(NameTagger {
+                style: NameTagStyle::SnakeCase,
+            }))
+            .add_query_template("artists.sql.j2", &["genre"])
+            .add_query("artists", "artists.sql.j2", &[])
+            .add_query("artists@genre", "artists.sql.j2", &["genre"])
+            .add_test_template("artists@genre", "artists_test.sql.j2")
+            .build()
+            .unwrap();
+        assert!(metadata
+            .validate()
+            .iter()
+            .all(|m| m.severity() != Severity::Error));
+
+        assert!(metadata.formatter.is_some());
+
+        let map = Engine::from(&metadata).render_to_map(false).unwrap();
+        assert_eq!(3, map.len());
+        assert_eq!(
+            "-- name: artists\nSELECT\n    *\nFROM\n    artists;\n",
+            map[Path::new("out/queries/artists.sql")]
+        );
+        assert_eq!(
+            "-- name: artists_genre\nSELECT\n    *\nFROM\n    artists\nWHERE\n    genre = $1;\n",
+            map[Path::new("out/queries/artists-genre.sql")]
+        );
+        assert_eq!(
+            "PREPARE q AS\nSELECT\n    *\nFROM\n    artists\nWHERE\n    genre = $1\n",
+            map[Path::new("output/tests/artists_test.sql")]
+        );
+
+        // Mistakes are reported when building
+        let res = MetadataBuilder::new()
+            .add_query_template("artists.sql.j2", &[])
+            .add_query("artists", "artists.sql.j2", &[])
+            .add_query("artists", "albums.sql.j2", &[])
+            .build();
+        match res {
+            Err(Error::InvalidMetadata(msg)) => {
+                assert!(msg.contains("artists"));
+                assert!(msg.contains("albums.sql.j2"));
+            }
+            _ => panic!(),
+        }
+    }
+}
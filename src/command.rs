@@ -1,99 +1,1468 @@
+use crate::assertion::{self, Outcome, Report};
+use crate::editor;
+use crate::envfile::EnvFile;
 use crate::error::Error;
-use crate::metadata::Metadata;
-use crate::output::{self, QueryOutputReader};
+use crate::formatters::{self, Formatter};
+use crate::header::{self, strip_header};
+use crate::hooks::RenderedOutput;
+use crate::lockfile::{Lockfile, LOCK_FILE};
+use crate::metadata::{DirOverrides, Metadata, DEFAULT_ID_PATTERN};
+use crate::migration;
+use crate::output::{self, OutputFormat, QueryOutputReader};
 use crate::placeholder::Placeholder;
-use crate::render::Engine;
+use crate::presenter::{self, PathDisplay};
+use crate::query::{self, Query};
+use crate::render::{check_sql_syntax, count_placeholders, is_empty_sql, CondOverrides, Engine};
+use crate::resolved::{DumpFormat, ResolvedManifest};
 use crate::scaffolding;
+use crate::stats::{
+    ArtifactKind, ArtifactLine, CoverageBaseline, JsonLines, PhaseTimings, RenderStats,
+};
+use crate::test_template::{TestKind, TestTemplate};
 // use crate::tagging::{NameTagStyle, NameTagger};
-use crate::util::ls_files;
+use crate::util::{is_within, line_diff, ls_files};
+use crate::validation::{ManifestMistake, Severity};
+use crate::watch;
+use clap::Args;
+use clap_complete::Shell;
 use comfy_table::Table;
+use log::{info, warn};
+use regex::Regex;
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-pub fn validate() -> Result<i32, Error> {
-    let path = Path::new("tapestry.toml");
-    let metadata = Metadata::try_from(path)?;
-    let mistakes = metadata.validate();
-    if mistakes.is_empty() {
+/// Returns only the errors from the mistakes found in the manifest
+///
+/// Warnings don't invalidate the manifest, so they're just logged.
+fn manifest_errors(mistakes: Vec<ManifestMistake>) -> Vec<ManifestMistake> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = mistakes
+        .into_iter()
+        .partition(|m| m.severity() == Severity::Error);
+    for w in warnings {
+        warn!("{}", w.err_msg());
+    }
+    errors
+}
+
+pub fn validate(
+    max_errors: usize,
+    syntax_only: bool,
+    dump_context_schema: bool,
+    strict_ids: bool,
+    fix: bool,
+) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    // Keys with wrong casing are fixed before validating so that
+    // they are not reported as unknown
+    if fix {
+        let contents = Metadata::read_manifest(Path::new(MANIFEST_FILE))?;
+        let fixed = migration::fix_key_casing(&contents)?;
+        if fixed != contents {
+            fs::write(MANIFEST_FILE, fixed).map_err(Error::Io)?;
+            println!(
+                "Fixed the casing of keys in manifest file '{}'",
+                path.display()
+            );
+        }
+    }
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    if dump_context_schema {
+        let schemas = Engine::from(&metadata).context_schemas()?;
+        // @UNWRAP: Serializing the schemas to json can't fail
+        println!("{}", serde_json::to_string_pretty(&schemas).unwrap());
+        return Ok(0);
+    }
+    let mut mistakes = if syntax_only {
+        metadata.validate_syntax()
+    } else {
+        metadata.validate()
+    };
+    // The `id_pattern` in the manifest, if any, is already checked
+    if strict_ids && metadata.id_pattern.is_none() {
+        mistakes.append(&mut metadata.id_mistakes(DEFAULT_ID_PATTERN));
+    }
+    let is_valid = mistakes.iter().all(|m| m.severity() != Severity::Error);
+    if is_valid {
         println!("All Ok: Manifest file '{}' is valid", path.display());
-        Ok(0)
     } else {
         println!("Invalid manifest file: '{}'", path.display());
-        for mistake in mistakes {
-            println!("{}", mistake.err_msg())
+    }
+    if !mistakes.is_empty() {
+        let color = presenter::stdout_color_enabled();
+        println!("{}", presenter::mistakes(&mistakes, color, max_errors));
+    }
+    Ok(if is_valid { 0 } else { 1 })
+}
+
+/// Prints the manifest as resolved by tapestry i.e. how the
+/// commands see it, for debugging the configuration
+pub fn print_manifest(format: DumpFormat) -> Result<i32, Error> {
+    let (_, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let cwd = env::current_dir().map_err(Error::Io)?;
+    let resolved = ResolvedManifest::new(&metadata, &cwd);
+    println!("{}", resolved.to_string(format)?.trim_end());
+    Ok(0)
+}
+
+/// Options for locating the manifest file and the dir that the paths
+/// in it are relative to
+#[derive(Args, Default)]
+pub struct ManifestArgs {
+    #[arg(
+        long,
+        help = "Path to the manifest file, or '-' to read it from stdin (default: tapestry.toml in the current dir or the nearest parent dir having one)"
+    )]
+    manifest_path: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Dir that paths in the manifest are relative to (default: dir of the manifest file, or cwd for stdin)"
+    )]
+    base_dir: Option<PathBuf>,
+    #[arg(long, help = "Override 'query_templates_dir' in the manifest")]
+    query_templates_dir: Option<PathBuf>,
+    #[arg(long, help = "Override 'test_templates_dir' in the manifest")]
+    test_templates_dir: Option<PathBuf>,
+    #[arg(long, help = "Override 'queries_output_dir' in the manifest")]
+    queries_output_dir: Option<PathBuf>,
+    #[arg(long, help = "Override 'tests_output_dir' in the manifest")]
+    tests_output_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["manifest_path", "base_dir"],
+        help = "Don't read the manifest, instead treat every *.sql.j2 file in DIR as a query"
+    )]
+    discover: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Nest all outputs under this subdir of the output dirs, with '{branch}' replaced by the current git branch"
+    )]
+    out_suffix: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Load env vars (e.g. TAPESTRY_QUERIES_OUTPUT_DIR) from a dotenv file"
+    )]
+    env_file: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "env_file",
+        help = "Let the values in the env file take precedence over the environment"
+    )]
+    env_file_override: bool,
+}
+
+/// Name of the manifest file searched for when `--manifest-path` is
+/// not specified
+const MANIFEST_FILE: &str = "tapestry.toml";
+
+/// Default value of the `--max-errors` option i.e. the max. no. of
+/// manifest mistakes to print
+pub const MAX_ERRORS: usize = 50;
+
+/// Returns the path of the manifest file in `dir` or the nearest of
+/// its ancestors having one, relative to `dir` e.g.
+/// `../../tapestry.toml`
+fn find_manifest(dir: &Path) -> Option<PathBuf> {
+    let mut rel = PathBuf::new();
+    for d in dir.ancestors() {
+        if d.join(MANIFEST_FILE).is_file() {
+            return Some(rel.join(MANIFEST_FILE));
         }
-        Ok(1)
+        rel.push("..");
+    }
+    None
+}
+
+/// Locates the manifest file for the commands that don't take
+/// `ManifestArgs`, the same way as they do by default i.e. by
+/// searching the current dir and its parents, and changes the current
+/// dir to the dir of the manifest file
+///
+/// Returns the path of the manifest file as found (relative to the
+/// current dir before changing it) for referring to it in messages,
+/// along with the dir overrides from the env. Afterwards, the file
+/// is at `MANIFEST_FILE` relative to the current dir.
+fn enter_project() -> Result<(PathBuf, DirOverrides), Error> {
+    let args = ManifestArgs::default();
+    let path = args.manifest_path()?.into_owned();
+    let overrides = args.enter_base_dir(&path)?;
+    Ok((path, overrides))
+}
+
+/// Returns the current git branch, or the abbreviated hash of the
+/// commit if HEAD is detached (as is common in CI)
+fn git_branch() -> Result<String, String> {
+    let git = |args: &[&str]| -> Result<String, String> {
+        let output = std::process::Command::new("git")
+            .args(args)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch == "HEAD" {
+        git(&["rev-parse", "--short", "HEAD"])
+    } else {
+        Ok(branch)
+    }
+}
+
+/// Returns the `--out-suffix` as a path, with the `{branch}` token
+/// (if any) replaced by the branch returned by `branch`
+///
+/// The suffix must be a relative path that stays within the output
+/// dirs. It may have multiple components e.g. for a branch named
+/// `feature/x`.
+fn resolve_out_suffix<F>(suffix: &str, branch: F) -> Result<PathBuf, Error>
+where
+    F: FnOnce() -> Result<String, String>,
+{
+    let suffix = if suffix.contains("{branch}") {
+        let b = branch().map_err(|e| {
+            Error::Cli(format!(
+                "Could not determine the git branch for '--out-suffix': {e}"
+            ))
+        })?;
+        suffix.replace("{branch}", &b)
+    } else {
+        suffix.to_owned()
+    };
+    let path = PathBuf::from(&suffix);
+    let is_valid = path.components().next().is_some()
+        && path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+    if !is_valid {
+        return Err(Error::Cli(format!(
+            "Invalid '--out-suffix': '{suffix}' (must be a relative path without '..')"
+        )));
+    }
+    Ok(path)
+}
+
+impl ManifestArgs {
+    /// Loads the manifest from `manifest_path`, or from stdin if
+    /// it's `-`, and changes the current dir to the base dir so that
+    /// paths in the manifest are resolved relative to it.
+    ///
+    /// Without `manifest_path`, the manifest is searched for in the
+    /// current dir and then in its parents, like `git` does.
+    ///
+    /// The base dir defaults to the dir of the manifest file and to
+    /// the current dir if the manifest is read from stdin.
+    ///
+    /// If `discover` is specified, the manifest is not read at all
+    /// and the queries discovered in the dir are printed instead.
+    fn load(&self) -> Result<Metadata, Error> {
+        if let Some(dir) = &self.discover {
+            let overrides = self.dir_overrides()?;
+            let metadata = Metadata::discover(dir, &overrides)?;
+            for query in metadata.queries.iter() {
+                println!(
+                    "Discovered query: {} ({})",
+                    query.id,
+                    query.template.display()
+                );
+            }
+            for tt in metadata.test_templates.iter() {
+                println!("Discovered test: {} ({})", tt.query, tt.path.display());
+            }
+            return Ok(metadata);
+        }
+        let manifest_path = self.manifest_path()?;
+        // The manifest is parsed only after changing the current dir
+        // as parsing involves listing the test templates matching
+        // `queries[].tests_glob`
+        let contents = if manifest_path == Path::new("-") {
+            None
+        } else {
+            Some(Metadata::read_manifest(&manifest_path)?)
+        };
+        let overrides = self.enter_base_dir(&manifest_path)?;
+        match contents {
+            Some(contents) => Metadata::parse(&contents, &overrides),
+            None => Metadata::from_reader(io::stdin().lock(), &overrides),
+        }
+    }
+
+    /// Changes the current dir to the base dir (if it's different),
+    /// returning the dir overrides
+    ///
+    /// The overrides are relative to the current dir, so they are
+    /// made absolute if it's changed.
+    fn enter_base_dir(&self, manifest_path: &Path) -> Result<DirOverrides, Error> {
+        let overrides = self.dir_overrides()?;
+        match self.base_dir(manifest_path) {
+            Some(dir) => {
+                let overrides = overrides.absolute(&env::current_dir().map_err(Error::Io)?);
+                env::set_current_dir(dir).map_err(Error::Io)?;
+                Ok(overrides)
+            }
+            None => Ok(overrides),
+        }
+    }
+
+    /// Returns the dir that paths in the manifest are to be resolved
+    /// against, if it's different from the current dir
+    ///
+    /// `--base-dir` takes precedence over the dir of the manifest
+    /// file. When the manifest is read from stdin, it's the current
+    /// dir unless `--base-dir` is specified.
+    fn base_dir<'b>(&'b self, manifest_path: &'b Path) -> Option<&'b Path> {
+        self.base_dir
+            .as_deref()
+            .or_else(|| manifest_path.parent().filter(|p| !p.as_os_str().is_empty()))
+    }
+
+    /// Returns `--manifest-path` if specified, or else the path of
+    /// the manifest file found in the current dir or its parents
+    fn manifest_path(&self) -> Result<Cow<'_, Path>, Error> {
+        if let Some(p) = &self.manifest_path {
+            return Ok(Cow::Borrowed(p));
+        }
+        let cwd = env::current_dir().map_err(Error::Io)?;
+        find_manifest(&cwd).map(Cow::Owned).ok_or_else(|| {
+            Error::Cli(format!(
+                "Could not find '{MANIFEST_FILE}' in the current dir or any of its parents"
+            ))
+        })
+    }
+
+    /// Returns the dir overrides specified as CLI options, falling
+    /// back to the env vars, which are also looked up in the env file
+    /// (if any)
+    ///
+    /// Both take precedence over the dirs in the manifest.
+    fn dir_overrides(&self) -> Result<DirOverrides, Error> {
+        let cli_overrides = DirOverrides {
+            query_templates_dir: self.query_templates_dir.clone(),
+            test_templates_dir: self.test_templates_dir.clone(),
+            queries_output_dir: self.queries_output_dir.clone(),
+            tests_output_dir: self.tests_output_dir.clone(),
+            out_suffix: self
+                .out_suffix
+                .as_deref()
+                .map(|s| resolve_out_suffix(s, git_branch))
+                .transpose()?,
+        };
+        let env_overrides = match &self.env_file {
+            Some(path) => {
+                let env_file = EnvFile::read(path, self.env_file_override)?;
+                DirOverrides::from_vars(|name| env_file.var(name))
+            }
+            None => DirOverrides::from_env(),
+        };
+        Ok(cli_overrides.or(env_overrides))
+    }
+}
+
+/// Options for turning conds on or off for all queries at render
+/// time
+#[derive(Args, Default)]
+pub struct CondArgs {
+    #[arg(long, help = "Turn on the conds of the named profile in the manifest")]
+    profile: Option<String>,
+    #[arg(long, help = "Turn on a cond for all queries (may be repeated)")]
+    cond: Vec<String>,
+    #[arg(long, help = "Turn off a cond for all queries (may be repeated)")]
+    no_cond: Vec<String>,
+}
+
+impl CondArgs {
+    /// Resolves the profile (if any) using the manifest and combines
+    /// its conds with the ones specified explicitly
+    fn resolve(&self, metadata: &Metadata) -> Result<CondOverrides, Error> {
+        let mut on = match &self.profile {
+            Some(name) => metadata.profile_conds(name)?.clone(),
+            None => HashSet::new(),
+        };
+        on.extend(self.cond.iter().cloned());
+        let off = self.no_cond.iter().cloned().collect();
+        Ok(CondOverrides { on, off })
+    }
+}
+
+/// Env var from which the connection URL of the database is read if
+/// not specified using the options
+const DB_URL_VAR: &str = "TAPESTRY_DB_URL";
+
+/// Options for specifying the connection URL of the database, for
+/// the `assert` command
+#[derive(Args, Debug, Default)]
+pub struct DbUrlArgs {
+    #[arg(
+        long,
+        conflicts_with = "url_file",
+        help = "Connection URL of the database e.g. postgres://user@localhost/db"
+    )]
+    url: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read the connection URL of the database from a file"
+    )]
+    url_file: Option<PathBuf>,
+}
+
+impl DbUrlArgs {
+    /// Returns the connection URL, in the order of precedence:
+    /// `--url`, `--url-file` and the `TAPESTRY_DB_URL` env var
+    fn resolve(&self) -> Result<String, Error> {
+        self.resolve_with(|name| env::var(name).ok())
+    }
+
+    fn resolve_with<F: Fn(&str) -> Option<String>>(&self, get_var: F) -> Result<String, Error> {
+        if let Some(url) = &self.url {
+            return Ok(url.clone());
+        }
+        if let Some(path) = &self.url_file {
+            let url = fs::read_to_string(path).map_err(|e| {
+                Error::Cli(format!(
+                    "Could not read the database URL from '{}': {e}",
+                    path.display()
+                ))
+            })?;
+            return Ok(url.trim().to_owned());
+        }
+        get_var(DB_URL_VAR)
+            .filter(|v| !v.trim().is_empty())
+            .ok_or(Error::Cli(format!(
+                "Database URL not specified\nTip: Use '--url', '--url-file' or the '{DB_URL_VAR}' env var"
+            )))
+    }
+}
+
+/// Option for restricting a command to the queries having any of the
+/// specified tags
+#[derive(Args, Debug, Default)]
+pub struct TagArgs {
+    #[arg(
+        long = "tag",
+        value_name = "TAG",
+        help = "Only consider queries with this tag (may be repeated)"
+    )]
+    tags: Vec<String>,
+}
+
+impl TagArgs {
+    /// Returns the tags, warning about the ones that are not defined
+    /// for any query as they won't match anything
+    fn resolve(&self, metadata: &Metadata) -> &[String] {
+        for tag in metadata.queries.unknown_tags(&self.tags) {
+            warn!("No queries are tagged with '{tag}'");
+        }
+        &self.tags
+    }
+}
+
+/// Option for restricting a command to the queries defined under a
+/// group in the manifest
+#[derive(Args, Debug, Default)]
+pub struct GroupArgs {
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Only consider queries in this group"
+    )]
+    group: Option<String>,
+}
+
+impl GroupArgs {
+    /// Returns the group, warning if it's not defined in the manifest
+    /// as it won't match anything
+    fn resolve(&self, metadata: &Metadata) -> Option<&str> {
+        if let Some(group) = &self.group {
+            if !metadata.queries.iter().any(|q| q.in_group(Some(group))) {
+                warn!("No queries are defined under the group '{group}'");
+            }
+        }
+        self.group.as_deref()
+    }
+}
+
+/// Options for restricting a command to either the outputs of the
+/// queries or of their tests (both by default)
+#[derive(Args, Debug, Default)]
+pub struct OutputKindArgs {
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "only_queries",
+        help = "Only consider the outputs of tests, not of queries"
+    )]
+    only_tests: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Only consider the outputs of queries, not of tests"
+    )]
+    only_queries: bool,
+}
+
+impl OutputKindArgs {
+    fn queries(&self) -> bool {
+        !self.only_tests
+    }
+
+    fn tests(&self) -> bool {
+        !self.only_queries
+    }
+}
+
+/// Option for allowing the output files to be located outside of the
+/// output dirs, for the commands that write, check or delete them
+#[derive(Args, Debug, Default)]
+pub struct EscapeArgs {
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Allow output files to be located outside of the output dirs"
+    )]
+    allow_escape: bool,
+}
+
+impl EscapeArgs {
+    /// Returns the `mistakes` without the output files escaping the
+    /// output dirs, if that's allowed
+    fn filter<'a>(&self, mistakes: Vec<ManifestMistake<'a>>) -> Vec<ManifestMistake<'a>> {
+        mistakes
+            .into_iter()
+            .filter(|m| !(self.allow_escape && matches!(m, ManifestMistake::PathEscapesDir { .. })))
+            .collect()
+    }
+}
+
+/// Options for the on-disk cache of formatted outputs, which is
+/// shared across invocations
+#[derive(Args, Debug, Default)]
+pub struct CacheArgs {
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Cache formatted outputs in DIR for reuse across runs (default: $TAPESTRY_CACHE_DIR, if set)"
+    )]
+    cache_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "cache_dir",
+        help = "Don't use the on-disk cache, even if $TAPESTRY_CACHE_DIR is set"
+    )]
+    no_cache: bool,
+}
+
+impl CacheArgs {
+    /// Returns the cache dir as per the options and the env var,
+    /// made absolute so that it's not affected by changing the
+    /// current dir
+    fn resolve<F: Fn(&str) -> Option<OsString>>(&self, get_var: F, cwd: &Path) -> Option<PathBuf> {
+        if self.no_cache {
+            return None;
+        }
+        let dir = self
+            .cache_dir
+            .clone()
+            .or_else(|| get_var("TAPESTRY_CACHE_DIR").map(PathBuf::from))?;
+        Some(cwd.join(dir))
+    }
+
+    /// Enables the on-disk cache for the formatters (if specified)
+    fn apply(&self) -> Result<(), Error> {
+        let cwd = env::current_dir().map_err(Error::Io)?;
+        let dir = self.resolve(|n| env::var_os(n), &cwd);
+        formatters::set_cache_dir(dir.as_deref());
+        Ok(())
+    }
+}
+
+/// Returns the queries to be rendered by the `render` command
+fn queries_to_render<'a>(
+    metadata: &'a Metadata,
+    query_id: Option<&'a str>,
+    include_skipped: bool,
+    tags: &'a [String],
+    group: Option<&'a str>,
+) -> Vec<&'a Arc<Query>> {
+    metadata
+        .queries
+        .tagged(include_skipped, tags)
+        .filter(|q| q.in_group(group))
+        .filter(|q| query_id.map_or(true, |id| q.id == id))
+        .collect()
+}
+
+/// Prints the previews of the query `query_id` for the `--preview`
+/// option of the `render` command, formatted if applicable
+fn print_preview(engine: &Engine, metadata: &Metadata, query_id: &str) -> Result<(), Error> {
+    // @UNWRAP: The query is checked to exist by the caller
+    let query = metadata.queries.get(query_id).unwrap();
+    let formatter = query.formatter(metadata.formatter.as_ref());
+    for (i, (cond, sql)) in engine.preview_query(query_id)?.into_iter().enumerate() {
+        let sql = match formatter {
+            Some(f) => String::from_utf8_lossy(&f.format(&sql)?).into_owned(),
+            None => sql,
+        };
+        if i > 0 {
+            println!();
+        }
+        match cond {
+            Some(c) => println!("-- preview: '{c}' on"),
+            None => println!("-- preview: all conds off"),
+        }
+        println!("{}", sql.trim_end());
     }
+    Ok(())
+}
+
+/// Returns the template source snippet around the location of a
+/// template error, for the `--verbose-errors` option of the `render`
+/// command
+///
+/// The template file is looked up by the name in the error, first
+/// in the query templates dir and then in the test templates dir.
+/// `None` is returned if it's not a template error or the location
+/// isn't known (e.g. for errors in the intermediate output of a
+/// query template).
+fn template_error_snippet(metadata: &Metadata, err: &Error) -> Option<String> {
+    let Error::MiniJinja(e) = err else {
+        return None;
+    };
+    let (name, line) = (e.name()?, e.line()?);
+    let source = [&metadata.query_templates_dir, &metadata.test_templates_dir]
+        .iter()
+        .find_map(|dir| fs::read_to_string(dir.join(name)).ok())?;
+    Some(presenter::template_snippet(name, &source, line, e.range()))
+}
+
+/// Options of the `render` command, except the ones for locating the
+/// manifest (see `ManifestArgs`)
+#[derive(Args, Default)]
+pub struct RenderArgs {
+    #[command(flatten)]
+    conds: CondArgs,
+    #[command(flatten)]
+    tags: TagArgs,
+    #[command(flatten)]
+    group: GroupArgs,
+    #[command(flatten)]
+    kinds: OutputKindArgs,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Check that the formatter can be run before writing any files"
+    )]
+    check_formatter: bool,
+    #[arg(long, help = "Render only the query with this id (and its tests)")]
+    query: Option<String>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print the context used for rendering each query to stderr"
+    )]
+    explain: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Render the tests of each query in parallel"
+    )]
+    parallel_tests: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Fail if any query renders to only whitespace or comments"
+    )]
+    fail_empty: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Fail if any rendered query is not syntactically valid SQL"
+    )]
+    validate_sql: bool,
+    #[arg(
+        long,
+        short,
+        default_value_t = false,
+        help = "Don't show progress while rendering"
+    )]
+    quiet: bool,
+    #[command(flatten)]
+    escape: EscapeArgs,
+    #[arg(
+        long,
+        default_value_t = MAX_ERRORS,
+        help = "Max no. of manifest mistakes to print (0 to print all)"
+    )]
+    max_errors: usize,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Write output files concurrently with at most N writes at a time (requires the 'tokio' feature)"
+    )]
+    async_writes: Option<NonZeroUsize>,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Create nested output dirs upfront, even for queries that are not rendered"
+    )]
+    emit_empty_dirs: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print the template source around the location of a template error"
+    )]
+    verbose_errors: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["async_writes", "emit_empty_dirs", "discover"],
+        help = "Write the output files as a tar archive to PATH ('-' for stdout) instead of the output dirs"
+    )]
+    tar: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "tar",
+        help = "Write metrics of the run (counts, bytes written, durations) as JSON to PATH"
+    )]
+    stats_json: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["tar", "async_writes"],
+        help = "Print a line of JSON to stdout for every output file as soon as it's written"
+    )]
+    json_lines: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print a breakdown of the time spent in rendering templates, formatting and I/O"
+    )]
+    profile_formatter: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "tar",
+        help = "Open the output files in $EDITOR after rendering"
+    )]
+    open: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "query",
+        conflicts_with_all = ["tar", "stats_json", "json_lines", "open"],
+        help = "Print the query rendered with all conds off and then with each cond on, without writing files"
+    )]
+    preview: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["tar", "only_tests", "only_queries"],
+        help = "Skip queries whose rendered output matches the hash recorded in tapestry.lock"
+    )]
+    since_lock: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Render untrusted templates with only an allowlisted set of filters and functions"
+    )]
+    sandbox: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Create the templates dirs if they don't exist, with a warning"
+    )]
+    create_dirs: bool,
+    #[arg(
+        long,
+        default_value = "sql",
+        help = "Format of the output files: sql or gzip (written as *.sql.gz)",
+        value_parser = out_format_parser,
+    )]
+    out_format: OutputFormat,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Include queries marked with 'skip = true' in the manifest"
+    )]
+    include_skipped: bool,
+    /// Write only the output files whose contents change, ignoring
+    /// the timestamps in the header (for the `watch` command)
+    #[arg(skip)]
+    only_changed: bool,
 }
 
-pub fn render() -> Result<i32, Error> {
-    let path = Path::new("tapestry.toml");
-    let metadata = Metadata::try_from(path)?;
-    let mistakes = metadata.validate();
+pub fn render(manifest: &ManifestArgs, args: &RenderArgs) -> Result<i32, Error> {
+    args.cache.apply()?;
+    // The path as specified or found, as the current dir may be
+    // changed when loading the manifest
+    let manifest_path = manifest.manifest_path()?.into_owned();
+    let metadata = manifest.load()?;
+    let rendered = render_metadata(&metadata, args, &manifest_path, Path::new(LOCK_FILE))?;
+    Ok(if rendered.is_some() { 0 } else { 1 })
+}
+
+/// Renders the outputs of the already loaded `metadata`, with paths
+/// in it resolved against the current dir
+///
+/// Returns the no. of output files written, or `None` if the manifest
+/// is invalid, in which case the mistakes are printed. The
+/// `manifest_path` is only for reporting mistakes in it. The
+/// `lock_path` is where the lock file is read from and written to in
+/// case of `--since-lock`.
+fn render_metadata(
+    metadata: &Metadata,
+    args: &RenderArgs,
+    manifest_path: &Path,
+    lock_path: &Path,
+) -> Result<Option<usize>, Error> {
+    let query_id = args.query.as_deref();
+    let out_format = args.out_format;
+    if args.create_dirs {
+        for dir in metadata.create_template_dirs().map_err(Error::Io)? {
+            warn!("Created missing templates dir: '{}'", dir.display());
+        }
+    }
+    let mistakes = manifest_errors(args.escape.filter(metadata.validate()));
     if mistakes.is_empty() {
-        let engine = Engine::from(&metadata);
+        if args.async_writes.is_some() && !cfg!(feature = "tokio") {
+            return Err(Error::Cli(
+                "Option '--async-writes' is not supported by this build\nTip: Install tapestry with `--features tokio`".to_owned(),
+            ));
+        }
+        // The hook would run an arbitrary command, defeating the
+        // purpose of the sandbox
+        if args.sandbox && metadata.post_render.is_some() {
+            return Err(Error::Sandbox("'post_render' hook can't be run".to_owned()));
+        }
+        let mut engine = Engine::from(metadata).with_cond_overrides(args.conds.resolve(metadata)?);
+        if args.sandbox {
+            engine = engine.sandboxed();
+        }
         let formatter = &metadata.formatter;
-        output::ensure_output_dirs(&metadata.queries_output_dir, &metadata.tests_output_dir)?;
+        // Fail fast if the formatter can't be run, before any output
+        // dirs or files are created
+        if args.check_formatter {
+            if let Some(f) = formatter.as_ref() {
+                f.ensure_available()?;
+            }
+        }
+        if let Some(id) = query_id {
+            if metadata.queries.get(id).is_none() {
+                return Err(Error::UndefinedQuery(id.to_owned()));
+            }
+        }
+        // Print the previews without writing any output files
+        if let (true, Some(id)) = (args.preview, query_id) {
+            print_preview(&engine, metadata, id)?;
+            return Ok(Some(0));
+        }
+        let tags = args.tags.resolve(metadata);
+        let group = args.group.resolve(metadata);
+        // Rendering a subset of queries would overwrite the combined
+        // output file with only those queries
+        if let output::Layout::OneFileAllQueries(_) = metadata.query_output_layout {
+            let opt = match (query_id, tags.is_empty(), group) {
+                (Some(_), _, _) => Some("--query"),
+                (None, false, _) => Some("--tag"),
+                (None, true, Some(_)) => Some("--group"),
+                (None, true, None) if args.since_lock => Some("--since-lock"),
+                (None, true, None) => None,
+            };
+            if let Some(opt) = opt {
+                return Err(Error::Cli(format!(
+                    "Option '{opt}' is not supported when layout = one-file-all-queries"
+                )));
+            }
+        }
+        if args.tar.as_deref().is_none() {
+            output::ensure_output_dirs(metadata, args.emit_empty_dirs)?;
+        }
         let mut queries_to_write: Vec<output::SqlToWrite> =
             Vec::with_capacity(metadata.queries.len());
         let mut tests_to_write: Vec<output::SqlToWrite> = Vec::new();
-        for query in metadata.queries.iter() {
+        // Ids of the queries corresponding to the above outputs, to
+        // be passed to the post render hook
+        let mut query_ids: Vec<&str> = Vec::with_capacity(metadata.queries.len());
+        let mut test_query_ids: Vec<&str> = Vec::new();
+        // Output paths of the queries with `split_on` along with the
+        // no. of parts, for removing the parts left over from earlier
+        // renders
+        let mut split_outputs: Vec<(&Path, usize)> = Vec::new();
+        // With `--since-lock`, queries whose outputs are the same as
+        // when they were last rendered are not written again
+        let mut lock = if args.since_lock {
+            Some(Lockfile::read(lock_path)?)
+        } else {
+            None
+        };
+        let mut num_locked = 0;
+        let generated_at = header::timestamp(SystemTime::now());
+        let queries = queries_to_render(metadata, query_id, args.include_skipped, tags, group)
+            .into_iter()
+            .filter(|q| {
+                let enabled = engine.is_enabled(q);
+                if !enabled {
+                    info!("Query '{}' is disabled by its 'enabled_if'", q.id);
+                }
+                enabled
+            })
+            .collect::<Vec<_>>();
+        // The progress bar is hidden with `--json-lines` so that the
+        // lines can be consumed as they are printed
+        let progress = presenter::progress_bar(queries.len(), args.quiet || args.json_lines);
+        let mut json_lines = args.json_lines.then(JsonLines::stdout);
+        let started = Instant::now();
+        // Time taken to render each query along with its tests
+        let mut durations: Vec<(String, Duration)> = Vec::with_capacity(queries.len());
+        for (i, query) in queries.iter().enumerate() {
+            let query_started = Instant::now();
+            if args.explain {
+                let ctx = engine.explain_query(&query.id)?;
+                // Suspend the progress bar so that it doesn't get
+                // mixed up with the output
+                progress.suspend(|| eprintln!("Context for query '{}':\n{ctx}", query.id));
+            }
+
             // render query output and collect in a vec
-            let query_output = engine.render_query(&query.id, None)?;
+            let print_snippet = |e: Error| {
+                if args.verbose_errors {
+                    if let Some(snippet) = template_error_snippet(metadata, &e) {
+                        progress.suspend(|| eprintln!("{snippet}"));
+                    }
+                }
+                e
+            };
+            let query_output = engine
+                .render_query(&query.id, None)
+                .map_err(&print_snippet)
+                .and_then(|sql| {
+                    if args.fail_empty && is_empty_sql(&sql) {
+                        return Err(Error::EmptyOutput(query.id.clone()));
+                    }
+                    if args.validate_sql {
+                        check_sql_syntax(&sql, query.sql_dialect().as_ref()).map_err(|msg| {
+                            Error::InvalidSql {
+                                query_id: query.id.clone(),
+                                msg,
+                            }
+                        })?;
+                    }
+                    Ok(sql)
+                })
+                .map_err(|e| {
+                    let paths = [query.output.as_path()];
+                    let e = report_failed(
+                        &mut json_lines,
+                        query,
+                        ArtifactKind::Query,
+                        paths,
+                        out_format,
+                        e,
+                    );
+                    let unwritten = unwritten_outputs(
+                        [
+                            (&query_ids, &queries_to_write, ArtifactKind::Query),
+                            (&test_query_ids, &tests_to_write, ArtifactKind::Test),
+                        ],
+                        metadata,
+                        &queries[i + 1..],
+                        &args.kinds,
+                    );
+                    report_unwritten(&mut json_lines, unwritten, out_format);
+                    e
+                })?;
 
             // process and render tests output, then collect in a vec
             let prep_stmt = match metadata.placeholder {
                 Placeholder::PosArgs => Some(query_output.as_str()),
                 Placeholder::Variables => None,
             };
-            for tt in metadata.test_templates.find_by_query(&query.id) {
-                let test_output = engine.render_test(&tt.path, prep_stmt)?;
+            let (num_queries, num_tests) = (queries_to_write.len(), tests_to_write.len());
+            let header = metadata.render_header(&query.id, &generated_at)?;
+            // The query is rendered even with `--only-tests` as the
+            // tests may need it as the prepared statement
+            let tts = if args.kinds.tests() {
+                metadata.test_templates.find_active_by_query(query)
+            } else {
+                vec![]
+            };
+            let tt_paths = tts
+                .iter()
+                .map(|tt| tt.path.as_path())
+                .collect::<Vec<&Path>>();
+            let test_outputs = engine
+                .render_tests(&tt_paths, prep_stmt, args.parallel_tests)
+                .map_err(&print_snippet)
+                .map_err(|e| {
+                    let paths = tts.iter().map(|tt| tt.output.as_path());
+                    let e = report_failed(
+                        &mut json_lines,
+                        query,
+                        ArtifactKind::Test,
+                        paths,
+                        out_format,
+                        e,
+                    );
+                    // The query itself is rendered, but not written
+                    let mut unwritten = vec![];
+                    if args.kinds.queries() {
+                        unwritten.push((
+                            query.id.as_str(),
+                            ArtifactKind::Query,
+                            query.output.clone(),
+                        ));
+                    }
+                    unwritten.extend(unwritten_outputs(
+                        [
+                            (&query_ids, &queries_to_write, ArtifactKind::Query),
+                            (&test_query_ids, &tests_to_write, ArtifactKind::Test),
+                        ],
+                        metadata,
+                        &queries[i + 1..],
+                        &args.kinds,
+                    ));
+                    report_unwritten(&mut json_lines, unwritten, out_format);
+                    e
+                })?;
+            for (tt, test_output) in tts.iter().zip(test_outputs) {
                 let ttw = output::SqlToWrite {
-                    path: &tt.output,
+                    path: Cow::Borrowed(&tt.output),
                     sql: test_output,
                     name_tag: None,
+                    format: query.format,
+                    dialect: query.dialect.as_deref(),
+                    header: header.clone(),
                 };
                 tests_to_write.push(ttw);
+                test_query_ids.push(&query.id);
+            }
+
+            // In case of `split_on`, there are multiple output files
+            // for the query, one per part
+            if args.kinds.queries() {
+                let qtws = output::SqlToWrite::for_query(query, query_output);
+                if query.split_on.is_some() {
+                    split_outputs.push((&query.output, qtws.len()));
+                }
+                for mut qtw in qtws {
+                    qtw.header = header.clone();
+                    queries_to_write.push(qtw);
+                    query_ids.push(&query.id);
+                }
+            }
+            if let Some(lock) = lock.as_mut() {
+                let outputs = queries_to_write[num_queries..]
+                    .iter()
+                    .chain(tests_to_write[num_tests..].iter());
+                // The header is hashed with a fixed `generated_at` as
+                // it changes on every render
+                let stable_header = metadata.render_header(&query.id, header::FIXED_TIMESTAMP)?;
+                let hash = Lockfile::hash(
+                    outputs.clone(),
+                    stable_header.as_deref(),
+                    formatter.as_ref(),
+                    metadata.name_tagger.as_ref(),
+                    out_format,
+                );
+                let mut exists = true;
+                for w in outputs {
+                    exists = exists && out_format.exists(&w.path).map_err(Error::Io)?;
+                }
+                if exists && lock.is_locked(&query.id, &hash) {
+                    if let Some(jl) = json_lines.as_mut() {
+                        let skipped = queries_to_write[num_queries..]
+                            .iter()
+                            .map(|w| (ArtifactKind::Query, w))
+                            .chain(
+                                tests_to_write[num_tests..]
+                                    .iter()
+                                    .map(|w| (ArtifactKind::Test, w)),
+                            );
+                        for (kind, w) in skipped {
+                            let path = out_format.path(&w.path);
+                            jl.emit(&ArtifactLine::skipped(&query.id, kind, &path))?;
+                        }
+                    }
+                    queries_to_write.truncate(num_queries);
+                    query_ids.truncate(num_queries);
+                    tests_to_write.truncate(num_tests);
+                    test_query_ids.truncate(num_tests);
+                    num_locked += 1;
+                } else {
+                    lock.update(&query.id, hash);
+                }
+            }
+            durations.push((query.id.clone(), query_started.elapsed()));
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+
+        // With `only_changed`, the files that would be written with
+        // the same contents as the existing ones are left untouched
+        if args.only_changed {
+            let tagger = metadata.name_tagger.as_ref();
+            match metadata.query_output_layout {
+                output::Layout::OneFileOneQuery => {
+                    retain_changed(&mut queries_to_write, &mut query_ids, |w| {
+                        output::is_changed(w, formatter.as_ref(), tagger, out_format)
+                    })?
+                }
+                output::Layout::OneFileAllQueries(_) => {
+                    if !queries_to_write.is_empty()
+                        && !output::is_combined_changed(
+                            &queries_to_write,
+                            formatter.as_ref(),
+                            tagger,
+                            out_format,
+                        )?
+                    {
+                        queries_to_write.clear();
+                        query_ids.clear();
+                    }
+                }
+            }
+            retain_changed(&mut tests_to_write, &mut test_query_ids, |w| {
+                output::is_changed(w, formatter.as_ref(), None, out_format)
+            })?;
+        }
+
+        // The outputs are formatted while writing them, so the time
+        // spent in the formatter is separated from the I/O time when
+        // profiling
+        let rendering = started.elapsed();
+        let writing_started = Instant::now();
+        let formatting_before = formatters::formatting_duration();
+        let print_profile = || {
+            if args.profile_formatter {
+                let formatting = formatters::formatting_duration() - formatting_before;
+                let timings = PhaseTimings::new(rendering, writing_started.elapsed(), formatting);
+                eprintln!("{timings}");
             }
+        };
 
-            let qtw = output::SqlToWrite {
-                path: &query.output,
-                sql: query_output,
-                name_tag: Some(&query.name_tag),
+        // Stream the outputs as a tar archive instead of writing them
+        // to the file system. The post render hook is not run as
+        // there are no files for it to act upon.
+        if let Some(path) = args.tar.as_deref() {
+            let writer: Box<dyn io::Write> = if path == Path::new("-") {
+                Box::new(io::stdout().lock())
+            } else {
+                Box::new(fs::File::create(path).map_err(Error::Io)?)
             };
-            queries_to_write.push(qtw);
+            output::write_tar(
+                writer,
+                &queries_to_write,
+                &tests_to_write,
+                &metadata.query_output_layout,
+                formatter.as_ref(),
+                metadata.name_tagger.as_ref(),
+                out_format,
+            )?;
+            print_profile();
+            return Ok(Some(0));
         }
 
+        // With `--json-lines`, a line is printed for every file as
+        // soon as it's written (`--async-writes` is not allowed in
+        // that case)
+        let write_separately =
+            |files: &Vec<output::SqlToWrite>,
+             tagger,
+             ids: &[&str],
+             kind,
+             json_lines: Option<&mut JsonLines<io::Stdout>>| {
+                match (args.async_writes, json_lines) {
+                    #[cfg(feature = "tokio")]
+                    (Some(limit), _) => output::write_separately_async(
+                        files,
+                        formatter.as_ref(),
+                        tagger,
+                        out_format,
+                        limit,
+                    ),
+                    (_, Some(jl)) => {
+                        let mut num_done = 0;
+                        output::write_separately_with(
+                            files,
+                            formatter.as_ref(),
+                            tagger,
+                            out_format,
+                            |i, res| {
+                                num_done = i + 1;
+                                let path = out_format.path(&files[i].path);
+                                jl.emit(&ArtifactLine::new(ids[i], kind, &path, res))
+                            },
+                        )
+                        .map_err(|e| {
+                            // The files after the one that failed are
+                            // not written
+                            for (id, w) in ids.iter().zip(files.iter()).skip(num_done) {
+                                let path = out_format.path(&w.path);
+                                let _ = jl.emit(&ArtifactLine::unwritten(id, kind, &path));
+                            }
+                            e
+                        })
+                    }
+                    _ => output::write_separately(files, formatter.as_ref(), tagger, out_format),
+                }
+            };
+
         // Write all queries, in a single file or separate files based
         // on the layout
-        match metadata.query_output_layout {
-            output::Layout::OneFileOneQuery => {
-                output::write_separately(
-                    &queries_to_write,
-                    formatter.as_ref(),
-                    metadata.name_tagger.as_ref(),
-                )?;
-            }
+        let res = match metadata.query_output_layout {
+            output::Layout::OneFileOneQuery => write_separately(
+                &queries_to_write,
+                metadata.name_tagger.as_ref(),
+                &query_ids,
+                ArtifactKind::Query,
+                json_lines.as_mut(),
+            ),
             output::Layout::OneFileAllQueries(_) => {
-                output::write_combined(
+                let res = output::write_combined(
                     &queries_to_write,
                     formatter.as_ref(),
                     metadata.name_tagger.as_ref(),
-                )?;
+                    out_format,
+                );
+                // All the queries are reported with the same output
+                // file, irrespective of whether it was written
+                if let Some(jl) = json_lines.as_mut() {
+                    for (id, w) in query_ids.iter().zip(queries_to_write.iter()) {
+                        let path = out_format.path(&w.path);
+                        let line = ArtifactLine::new(
+                            id,
+                            ArtifactKind::Query,
+                            &path,
+                            res.as_ref().copied(),
+                        );
+                        if let Err(e) = jl.emit(&line) {
+                            res?;
+                            return Err(e);
+                        }
+                    }
+                }
+                res
             }
+        };
+        if let Err(e) = res {
+            let unwritten = unwritten_outputs(
+                [(&test_query_ids, &tests_to_write, ArtifactKind::Test)],
+                metadata,
+                &[],
+                &args.kinds,
+            );
+            report_unwritten(&mut json_lines, unwritten, out_format);
+            return Err(e);
         }
 
         // Write all tests
-        output::write_separately(&tests_to_write, formatter.as_ref(), None)?;
+        write_separately(
+            &tests_to_write,
+            None,
+            &test_query_ids,
+            ArtifactKind::Test,
+            json_lines.as_mut(),
+        )?;
+        print_profile();
 
-        Ok(0)
+        for (path, num_parts) in split_outputs {
+            for part in
+                output::remove_stale_parts(path, num_parts, out_format).map_err(Error::Io)?
+            {
+                if !args.quiet {
+                    eprintln!("Removed stale part: {}", part.display());
+                }
+            }
+        }
+
+        if let Some(lock) = lock {
+            lock.write(lock_path)?;
+            if !args.quiet {
+                eprintln!("Skipped {num_locked} queries unchanged since {LOCK_FILE}");
+            }
+        }
+
+        if let Some(path) = args.stats_json.as_deref() {
+            let paths = queries_to_write
+                .iter()
+                .chain(tests_to_write.iter())
+                .map(|w| out_format.path(&w.path).into_owned())
+                .collect::<Vec<_>>();
+            RenderStats::new(
+                durations,
+                tests_to_write.len(),
+                paths.iter().map(|p| p.as_ref()),
+                started.elapsed(),
+            )?
+            .write(path)?;
+        }
+
+        let num_written = tests_to_write.len()
+            + match metadata.query_output_layout {
+                output::Layout::OneFileOneQuery => queries_to_write.len(),
+                output::Layout::OneFileAllQueries(_) => usize::from(!queries_to_write.is_empty()),
+            };
+
+        // The hook is not run if there are no changes in case of
+        // `only_changed`, same as the `--run` command of `watch`
+        let post_render = metadata
+            .post_render
+            .as_ref()
+            .filter(|_| !(args.only_changed && num_written == 0));
+        if let Some(hook) = post_render {
+            let outputs = query_ids
+                .iter()
+                .zip(queries_to_write.iter())
+                .chain(test_query_ids.iter().zip(tests_to_write.iter()))
+                .map(|(id, w)| (*id, out_format.path(&w.path).into_owned()))
+                .collect::<Vec<_>>();
+            let outputs = outputs
+                .iter()
+                .map(|(id, path)| RenderedOutput { id, path })
+                .collect::<Vec<RenderedOutput>>();
+            hook.run(&outputs, args.json_lines)?;
+        }
+
+        if args.open {
+            let mut paths = queries_to_write
+                .iter()
+                .chain(tests_to_write.iter())
+                .map(|w| out_format.path(&w.path))
+                .collect::<Vec<_>>();
+            // All queries are written to the same file in case of
+            // the one-file-all-queries layout
+            paths.dedup();
+            let paths = paths.iter().map(|p| p.as_ref()).collect::<Vec<&Path>>();
+            editor::open(&paths)?;
+        }
+
+        Ok(Some(num_written))
     } else {
-        println!("Invalid manifest file: '{}'", path.display());
-        for mistake in mistakes {
-            println!("{}", mistake.err_msg())
+        // With `--json-lines`, stdout is reserved for the lines
+        let print = |s: &str| {
+            if args.json_lines {
+                eprintln!("{s}")
+            } else {
+                println!("{s}")
+            }
+        };
+        print(&format!(
+            "Invalid manifest file: '{}'",
+            manifest_path.display()
+        ));
+        let lines = mistakes.iter().map(|m| m.err_msg()).collect();
+        for line in presenter::truncate_lines(lines, args.max_errors) {
+            print(&line);
+        }
+        Ok(None)
+    }
+}
+
+// Retains only the `files` (and their `ids`) for which `is_changed`
+// returns true
+fn retain_changed<F>(
+    files: &mut Vec<output::SqlToWrite>,
+    ids: &mut Vec<&str>,
+    is_changed: F,
+) -> Result<(), Error>
+where
+    F: Fn(&output::SqlToWrite) -> Result<bool, Error>,
+{
+    let mut i = 0;
+    while i < files.len() {
+        if is_changed(&files[i])? {
+            i += 1;
+        } else {
+            files.remove(i);
+            ids.remove(i);
+        }
+    }
+    Ok(())
+}
+
+// In case of `--json-lines`, prints a `failed` line for each of the
+// output `paths` of the query that couldn't be rendered, before the
+// error `e` is returned. Failure to print is ignored in favour of `e`.
+fn report_failed<'a, I>(
+    json_lines: &mut Option<JsonLines<io::Stdout>>,
+    query: &Query,
+    kind: ArtifactKind,
+    paths: I,
+    out_format: OutputFormat,
+    e: Error,
+) -> Error
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    if let Some(jl) = json_lines.as_mut() {
+        for path in paths {
+            let path = out_format.path(path);
+            let _ = jl.emit(&ArtifactLine::new(&query.id, kind, &path, Err(&e)));
+        }
+    }
+    e
+}
+
+// Returns the output files that won't be written as rendering or
+// writing failed midway i.e. the `rendered` ones (as a triple of the
+// query ids, the files and their kind) and those of the `remaining`
+// queries which are yet to be rendered
+fn unwritten_outputs<'a, const N: usize>(
+    rendered: [(&[&'a str], &[output::SqlToWrite], ArtifactKind); N],
+    metadata: &'a Metadata,
+    remaining: &[&'a Arc<Query>],
+    kinds: &OutputKindArgs,
+) -> Vec<(&'a str, ArtifactKind, PathBuf)> {
+    let mut result = vec![];
+    for (ids, files, kind) in rendered {
+        for (id, w) in ids.iter().zip(files.iter()) {
+            result.push((*id, kind, w.path.to_path_buf()));
+        }
+    }
+    for query in remaining {
+        if kinds.queries() {
+            result.push((query.id.as_str(), ArtifactKind::Query, query.output.clone()));
+        }
+        if kinds.tests() {
+            for tt in metadata.test_templates.find_active_by_query(query) {
+                result.push((query.id.as_str(), ArtifactKind::Test, tt.output.clone()));
+            }
+        }
+    }
+    result
+}
+
+// In case of `--json-lines`, prints a line for each of the `unwritten`
+// output files. Failure to print is ignored as there's already an
+// error to be returned.
+fn report_unwritten(
+    json_lines: &mut Option<JsonLines<io::Stdout>>,
+    unwritten: Vec<(&str, ArtifactKind, PathBuf)>,
+    out_format: OutputFormat,
+) {
+    if let Some(jl) = json_lines.as_mut() {
+        for (id, kind, path) in unwritten {
+            let path = out_format.path(&path);
+            let _ = jl.emit(&ArtifactLine::unwritten(id, kind, &path));
         }
-        Ok(1)
     }
 }
 
@@ -104,26 +1473,36 @@ pub fn init(dir: &Path) -> Result<i32, Error> {
     })
 }
 
-pub fn summary(include_all: bool) -> Result<i32, Error> {
-    let path = Path::new("tapestry.toml");
-    let metadata = Metadata::try_from(path)?;
-    let mistakes = metadata.validate();
+pub fn summary(
+    include_all: bool,
+    include_skipped: bool,
+    absolute_paths: bool,
+    tags: &TagArgs,
+    group: &GroupArgs,
+) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
     if mistakes.is_empty() {
-        let header = vec!["Id", "Query", "Template", "Tests"];
+        // The manifest is read from the cwd, which is therefore the
+        // base dir
+        let base_dir = env::current_dir().map_err(Error::Io)?;
+        let paths = if absolute_paths {
+            PathDisplay::Absolute(base_dir)
+        } else {
+            PathDisplay::Relative(base_dir)
+        };
+        let header = vec!["Id", "Query", "Template", "Conds", "Tags", "Tests"];
         let mut rows: Vec<Vec<String>> = Vec::with_capacity(metadata.queries.len());
         let mut qt_used: HashSet<&Path> = HashSet::new();
-        for query in metadata.queries.iter() {
-            let id = query.id.clone();
-            let path = query.output.display().to_string();
-            let template_path = query.template.display().to_string();
-            let tests = metadata
-                .test_templates
-                .find_by_query(&id)
-                .iter()
-                .map(|t| t.output.display().to_string())
-                .collect::<Vec<String>>()
-                .join("\n");
-            rows.push(vec![id, path, template_path, tests]);
+        let group = group.resolve(&metadata);
+        for query in metadata
+            .queries
+            .tagged(include_skipped, tags.resolve(&metadata))
+            .filter(|q| q.in_group(group))
+        {
+            let tests = metadata.test_templates.find_by_query(&query.id);
+            rows.push(presenter::summary_row(query, &tests, &paths));
 
             qt_used.insert(query.template.as_ref());
         }
@@ -145,7 +1524,9 @@ pub fn summary(include_all: bool) -> Result<i32, Error> {
             for qp in query_paths_undefined {
                 rows.push(vec![
                     "-".to_owned(),
-                    format!("{}\n(not defined in manifest)", qp.display()),
+                    format!("{}\n(not defined in manifest)", paths.show(qp)),
+                    "-".to_owned(),
+                    "-".to_owned(),
                     "-".to_owned(),
                     "-".to_owned(),
                 ]);
@@ -172,7 +1553,9 @@ pub fn summary(include_all: bool) -> Result<i32, Error> {
                     "-".to_owned(),
                     "-".to_owned(),
                     "-".to_owned(),
-                    format!("{}\n(not defined in manifest)", tp.display()),
+                    "-".to_owned(),
+                    "-".to_owned(),
+                    format!("{}\n(not defined in manifest)", paths.show(tp)),
                 ]);
             }
         }
@@ -190,60 +1573,363 @@ pub fn summary(include_all: bool) -> Result<i32, Error> {
     }
 }
 
-pub fn status(assert_no_changes: bool) -> Result<i32, Error> {
-    let path = Path::new("tapestry.toml");
-    let metadata = Metadata::try_from(path)?;
-    let mistakes = metadata.validate();
-    if mistakes.is_empty() {
-        let engine = Engine::from(&metadata);
-        let formatter = &metadata.formatter;
-        let name_tagger = &metadata.name_tagger;
-        let mut stats: HashMap<&Path, output::Status> = HashMap::new();
-        let query_reader = QueryOutputReader::new(&metadata)?;
-        for query in metadata.queries.iter() {
-            // query output sql (not tagged)
-            let q_output_sql = engine.render_query(&query.id, None)?;
+/// Status of an output file to be printed by the `status` command
+struct StatusLine<'a> {
+    path: Cow<'a, Path>,
+    status: output::Status,
+    /// Id of the query if the output file is of a query, `None` if
+    /// it's of a test
+    query_id: Option<&'a str>,
+}
+
+/// Result of the `status` command
+#[derive(Default)]
+struct StatusReport<'a> {
+    lines: Vec<StatusLine<'a>>,
+    /// Errors encountered while rendering (or checking the status
+    /// of) the outputs of queries, along with the query ids
+    errors: Vec<(&'a str, Error)>,
+}
 
+/// What's shared by the status checks of the outputs of all queries
+struct StatusContext<'a, 'b> {
+    metadata: &'a Metadata,
+    engine: &'b Engine<'a>,
+    query_reader: &'b QueryOutputReader<'a>,
+    manifest_path: &'b Path,
+    out_format: OutputFormat,
+    assume_fresh: bool,
+}
+
+/// Returns the status lines for the output files of `query` and its
+/// tests `tts` (restricted to the targeted ones)
+fn query_status_lines<'a>(
+    ctx: &StatusContext<'a, '_>,
+    query: &'a Query,
+    query_targeted: bool,
+    tts: Vec<&'a Arc<TestTemplate>>,
+) -> Result<Vec<StatusLine<'a>>, Error> {
+    let StatusContext {
+        metadata,
+        engine,
+        query_reader,
+        manifest_path,
+        out_format,
+        assume_fresh,
+    } = *ctx;
+    // Outputs of queries with `format = false` and their tests are
+    // written verbatim
+    let formatter = query.formatter(metadata.formatter.as_ref());
+    let mut lines = Vec::with_capacity(tts.len() + 1);
+
+    // With `--assume-fresh`, outputs that were modified after the
+    // files they are rendered from are considered unchanged without
+    // rendering them. This doesn't apply to queries with `split_on`
+    // as the number of parts is known only after rendering.
+    let query_template = query.template.as_path();
+    let q_fresh = assume_fresh
+        && query_targeted
+        && query.split_on.is_none()
+        && out_format
+            .is_fresh(&query.output, &[query_template, manifest_path])
+            .map_err(Error::Io)?;
+    let mut tts_fresh = Vec::with_capacity(tts.len());
+    for tt in tts {
+        let fresh = assume_fresh
+            && out_format
+                .is_fresh(&tt.output, &[&tt.path, query_template, manifest_path])
+                .map_err(Error::Io)?;
+        tts_fresh.push((tt, fresh));
+    }
+    let needs_render = (query_targeted && !q_fresh) || tts_fresh.iter().any(|(_, fresh)| !fresh);
+    // The header of the outputs is compared ignoring `generated_at`,
+    // so it's rendered with a fixed one
+    let header = metadata.render_header(&query.id, header::FIXED_TIMESTAMP)?;
+
+    // query output sql (not tagged)
+    let q_output_sql = if needs_render {
+        engine.render_query(&query.id, None)?
+    } else {
+        String::new()
+    };
+
+    if query_targeted && query.split_on.is_some() {
+        // Each part of the split output is compared separately
+        let files = output::SqlToWrite::for_query(query, q_output_sql.clone());
+        for mut file in files {
+            file.header = header.clone();
+            let status = file.status(formatter, metadata.name_tagger.as_ref(), out_format)?;
+            lines.push(StatusLine {
+                path: file.path,
+                status,
+                query_id: Some(&query.id),
+            });
+        }
+    } else if query_targeted {
+        let q_stat = if q_fresh {
+            output::Status::Unchanged
+        } else {
             // query output (tagged if name_tagger is configured)
-            let q_output = match name_tagger {
+            let q_output = match &metadata.name_tagger {
                 Some(t) => t.ensure_name_tag(&q_output_sql, &query.name_tag),
                 None => Cow::from(&q_output_sql),
             };
+            let formatter = query_reader.formatter(query);
+            output::query_status(query, query_reader, formatter, header.as_deref(), &q_output)?
+        };
+        lines.push(StatusLine {
+            path: Cow::Borrowed(&query.output),
+            status: q_stat,
+            query_id: Some(&query.id),
+        });
+    }
 
-            let q_stat = output::query_status(query, &query_reader, formatter.as_ref(), &q_output)?;
-            println!(
-                "Query: {}: {} ({})",
-                &q_stat.label(),
-                query.id,
-                query.output.display()
-            );
-            stats.insert(&query.output, q_stat);
-
-            // render and process tests
-            let prep_stmt = match metadata.placeholder {
-                Placeholder::PosArgs => Some(q_output_sql.as_str()),
-                Placeholder::Variables => None,
-            };
-            for tt in metadata.test_templates.find_by_query(&query.id) {
-                let t_output = engine.render_test(&tt.path, prep_stmt)?;
-                let t_stat = output::testfile_status(&tt.output, formatter.as_ref(), &t_output)?;
-                println!("  Test: {}: {}", &t_stat.label(), &tt.output.display());
-                stats.insert(&tt.output, t_stat);
-            }
-        }
-        let exit_code = if assert_no_changes {
-            let no_changes = stats
-                .values()
-                .all(|status| *status == output::Status::Unchanged);
-            if no_changes {
-                0
-            } else {
-                1
-            }
+    // render and process tests
+    let prep_stmt = match metadata.placeholder {
+        Placeholder::PosArgs => Some(q_output_sql.as_str()),
+        Placeholder::Variables => None,
+    };
+    for (tt, fresh) in tts_fresh {
+        let t_stat = if fresh {
+            output::Status::Unchanged
         } else {
-            0
+            let t_output = engine.render_test(&tt.path, prep_stmt)?;
+            output::testfile_status(
+                &tt.output,
+                formatter,
+                header.as_deref(),
+                out_format,
+                &t_output,
+            )?
         };
+        lines.push(StatusLine {
+            path: Cow::Borrowed(&tt.output),
+            status: t_stat,
+            query_id: None,
+        });
+    }
+    Ok(lines)
+}
+
+/// Checks the status of the output files of all queries and tests
+///
+/// Without `--keep-going`, the first error is returned. Otherwise,
+/// errors are collected in the report and the remaining queries are
+/// checked.
+fn check_status<'a>(
+    metadata: &'a Metadata,
+    manifest_path: &Path,
+    args: &StatusArgs,
+    cwd: &Path,
+) -> Result<StatusReport<'a>, Error> {
+    let engine = Engine::from(metadata);
+    // If paths are specified, the check is restricted to only those
+    // output files. They're relative to `cwd` i.e. the dir that the
+    // command was run from, not the base dir that's now current.
+    let targets = if args.paths.is_empty() {
+        None
+    } else {
+        let base_dir = env::current_dir().map_err(Error::Io)?;
+        Some(metadata.find_outputs(&args.paths, cwd, &base_dir)?)
+    };
+    let is_target = |p: &Path| targets.as_ref().map_or(true, |t| t.contains(p));
+    // In case of the one-file-all-queries layout, the combined file
+    // has the outputs of all the queries that `render` would write
+    let rendered = metadata
+        .queries
+        .active(args.include_skipped)
+        .filter(|q| engine.is_enabled(q))
+        .map(|q| q.as_ref());
+    let query_reader = QueryOutputReader::new(metadata, args.out_format, rendered)?;
+    let ctx = StatusContext {
+        metadata,
+        engine: &engine,
+        query_reader: &query_reader,
+        manifest_path,
+        out_format: args.out_format,
+        assume_fresh: args.assume_fresh,
+    };
+    let kinds = &args.kinds;
+    let mut report = StatusReport::default();
+    for query in metadata
+        .queries
+        .tagged(args.include_skipped, args.tags.resolve(metadata))
+        .filter(|q| engine.is_enabled(q))
+    {
+        let query_targeted = kinds.queries() && is_target(&query.output);
+        // Tests are sorted by output path so that the lines of every
+        // query are in a deterministic order, irrespective of the
+        // order in which the tests are defined
+        let mut tts = metadata
+            .test_templates
+            .find_active_by_query(query)
+            .into_iter()
+            .filter(|tt| kinds.tests() && is_target(&tt.output))
+            .collect::<Vec<_>>();
+        tts.sort_by(|a, b| a.output.cmp(&b.output));
+        if !query_targeted && tts.is_empty() {
+            continue;
+        }
+        match query_status_lines(&ctx, query, query_targeted, tts) {
+            Ok(lines) => report.lines.extend(lines),
+            Err(e) if args.keep_going => report.errors.push((&query.id, e)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+/// Writes the status lines in the `report` to `out` (unless
+/// `porcelain` is true) and returns the exit code
+///
+/// Errors in the report are always printed to stderr. In `porcelain`
+/// mode, the exit code is the same as with `assert_no_changes`.
+fn report_status<W: io::Write>(
+    out: &mut W,
+    report: &StatusReport,
+    out_format: OutputFormat,
+    assert_no_changes: bool,
+    porcelain: bool,
+) -> Result<i32, Error> {
+    if !porcelain {
+        for line in &report.lines {
+            match line.query_id {
+                Some(id) => writeln!(
+                    out,
+                    "Query: {}: {} ({})",
+                    line.status.label(),
+                    id,
+                    out_format.path(&line.path).display()
+                ),
+                None => writeln!(
+                    out,
+                    "  Test: {}: {}",
+                    line.status.label(),
+                    out_format.path(&line.path).display()
+                ),
+            }
+            .map_err(Error::Io)?;
+        }
+    }
+    for (id, e) in &report.errors {
+        eprintln!("Query: error: {id}\n{e}");
+    }
+    let exit_code = if !report.errors.is_empty() {
+        1
+    } else if assert_no_changes || porcelain {
+        let no_changes = report
+            .lines
+            .iter()
+            .all(|l| l.status == output::Status::Unchanged);
+        if no_changes {
+            0
+        } else {
+            1
+        }
+    } else {
+        0
+    };
+    Ok(exit_code)
+}
+
+/// Options of the `status` command
+#[derive(Args, Default)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    tags: TagArgs,
+    #[command(flatten)]
+    kinds: OutputKindArgs,
+    #[command(flatten)]
+    cache: CacheArgs,
+    #[command(flatten)]
+    escape: EscapeArgs,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Exit with non-zero code if any templates have unrendered changes"
+    )]
+    assert_no_changes: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "summary_line",
+        help = "Print nothing to stdout and exit with non-zero code if any templates have unrendered changes"
+    )]
+    porcelain: bool,
+    #[arg(help = "Restrict the check to these output files (defaults to all)")]
+    paths: Vec<PathBuf>,
+    #[arg(
+        long,
+        default_value = "sql",
+        help = "Format of the output files: sql or gzip (written as *.sql.gz)",
+        value_parser = out_format_parser,
+    )]
+    out_format: OutputFormat,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Include queries marked with 'skip = true' in the manifest"
+    )]
+    include_skipped: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Consider outputs modified after their templates as unchanged without rendering"
+    )]
+    assume_fresh: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print a line with the counts of changed, new and unchanged files to stderr"
+    )]
+    summary_line: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with = "keep_going",
+        help = "Stop at the first error while rendering (default)"
+    )]
+    fail_fast: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Report errors while rendering but continue checking the remaining queries"
+    )]
+    keep_going: bool,
+}
+
+pub fn status(args: &StatusArgs) -> Result<i32, Error> {
+    args.cache.apply()?;
+    let cwd = env::current_dir().map_err(Error::Io)?;
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(args.escape.filter(metadata.validate()));
+    if mistakes.is_empty() {
+        let report = check_status(&metadata, Path::new(MANIFEST_FILE), args, &cwd)?;
+        let exit_code = report_status(
+            &mut io::stdout().lock(),
+            &report,
+            args.out_format,
+            args.assert_no_changes,
+            args.porcelain,
+        )?;
+        if args.summary_line {
+            let stats = report
+                .lines
+                .iter()
+                .map(|l| (l.path.as_ref(), &l.status))
+                .collect::<HashMap<&Path, &output::Status>>();
+            eprintln!("{}", presenter::status_summary_line(stats.into_values()));
+        }
         Ok(exit_code)
+    } else if args.porcelain {
+        // Nothing is printed to stdout in porcelain mode, not even
+        // the manifest mistakes
+        eprintln!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            eprintln!("{}", mistake.err_msg())
+        }
+        Ok(1)
     } else {
         println!("Invalid manifest file: '{}'", path.display());
         for mistake in mistakes {
@@ -253,26 +1939,408 @@ pub fn status(assert_no_changes: bool) -> Result<i32, Error> {
     }
 }
 
-pub fn cov_threshold_parser(value: &str) -> Result<u8, String> {
-    let threshold: usize = value.parse().map_err(|_| "threshold is not a number")?;
-    if threshold > 100 {
-        Err("threshold not in range 0..100".to_string())
+pub fn assert(db_url: &DbUrlArgs, include_skipped: bool, tags: &TagArgs) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
+    if mistakes.is_empty() {
+        let engine = Engine::from(&metadata);
+        let mut rendered = engine.render_to_map(include_skipped)?;
+        let mut tests: Vec<(&Path, String)> = Vec::new();
+        for query in metadata
+            .queries
+            .tagged(include_skipped, tags.resolve(&metadata))
+            .filter(|q| engine.is_enabled(q))
+        {
+            // Golden tests are checked by the `test` command instead
+            for tt in metadata
+                .test_templates
+                .find_active_by_query(query)
+                .into_iter()
+                .filter(|tt| tt.kind == TestKind::Pgtap)
+            {
+                // @UNWRAP: All active tests are rendered in the map
+                let sql = rendered.remove(&tt.output).unwrap();
+                tests.push((&tt.output, sql));
+            }
+        }
+        let mut executor = assertion::connect(&db_url.resolve()?)?;
+        let reports = assertion::run(executor.as_mut(), &tests);
+        Ok(print_reports(&reports))
     } else {
-        Ok(threshold as u8)
+        println!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            println!("{}", mistake.err_msg())
+        }
+        Ok(1)
     }
 }
 
-pub fn coverage(fail_under: Option<u8>) -> Result<i32, Error> {
-    let path = Path::new("tapestry.toml");
-    let metadata = Metadata::try_from(path)?;
-    let mistakes = metadata.validate();
+/// Prints the outcome of every test in `reports` followed by the
+/// counts of passed and failed tests, returning the exit code
+fn print_reports(reports: &[Report]) -> i32 {
+    let mut num_failed = 0;
+    for report in reports {
+        match &report.outcome {
+            Outcome::Pass => println!("ok: {}", report.path.display()),
+            Outcome::Fail(reason) => {
+                num_failed += 1;
+                println!("FAILED: {}", report.path.display());
+                for line in reason.lines() {
+                    println!("  {line}");
+                }
+            }
+        }
+    }
+    println!(
+        "\n{} passed, {} failed",
+        reports.len() - num_failed,
+        num_failed
+    );
+    if num_failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Renders the golden tests and compares them with their expected
+/// files, printing a diff for every mismatch
+pub fn test(include_skipped: bool, tags: &TagArgs) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
     if mistakes.is_empty() {
-        let num_queries = metadata.queries.len();
+        let engine = Engine::from(&metadata);
+        let rendered = engine.render_to_map(include_skipped)?;
+        let mut reports = Vec::new();
+        for query in metadata
+            .queries
+            .tagged(include_skipped, tags.resolve(&metadata))
+            .filter(|q| engine.is_enabled(q))
+        {
+            // The header is excluded from the comparison as it may
+            // include the time of rendering, so it's rendered with a
+            // fixed one for stripping it
+            let header = metadata.render_header(&query.id, header::FIXED_TIMESTAMP)?;
+            for tt in metadata.test_templates.find_active_by_query(query) {
+                if let TestKind::Golden { expected } = &tt.kind {
+                    // @UNWRAP: All active tests are rendered in the map
+                    let sql = rendered.get(&tt.output).unwrap();
+                    let sql =
+                        strip_header(header.as_deref(), sql.as_bytes()).unwrap_or(sql.as_bytes());
+                    let sql = String::from_utf8_lossy(sql);
+                    reports.push(Report {
+                        path: &tt.output,
+                        outcome: assertion::check_golden(expected, &sql),
+                    });
+                }
+            }
+        }
+        if reports.is_empty() {
+            println!("No golden tests found");
+            return Ok(0);
+        }
+        Ok(print_reports(&reports))
+    } else {
+        println!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            println!("{}", mistake.err_msg())
+        }
+        Ok(1)
+    }
+}
+
+/// What the `list` command prints
+#[derive(Clone, Copy)]
+pub enum ListTarget {
+    Queries,
+    Tests,
+}
+
+pub fn list_target_parser(value: &str) -> Result<ListTarget, String> {
+    match value {
+        "queries" => Ok(ListTarget::Queries),
+        "tests" => Ok(ListTarget::Tests),
+        _ => Err("must be one of 'queries', 'tests'".to_string()),
+    }
+}
+
+pub fn list(target: ListTarget, query_pattern: Option<&str>, tags: &TagArgs) -> Result<i32, Error> {
+    let (_, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let tags = tags.resolve(&metadata);
+    let queries = metadata
+        .queries
+        .matching(query_pattern)
+        .filter(|q| q.has_any_tag(tags));
+    match target {
+        ListTarget::Queries => {
+            for query in queries {
+                println!("{}", query.id);
+            }
+        }
+        ListTarget::Tests => {
+            for query in queries {
+                for tt in metadata.test_templates.find_active_by_query(query) {
+                    println!("{}", tt.path.display());
+                }
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Writes the completion script for `shell` to `buf`
+pub fn completions<W: io::Write>(
+    shell: Shell,
+    cmd: &mut clap::Command,
+    buf: &mut W,
+) -> Result<i32, Error> {
+    let bin_name = cmd.get_name().to_string();
+    clap_complete::generate(shell, cmd, bin_name, buf);
+    Ok(0)
+}
+
+pub fn out_format_parser(value: &str) -> Result<OutputFormat, String> {
+    match value {
+        "sql" => Ok(OutputFormat::Sql),
+        "gzip" => Ok(OutputFormat::Gzip),
+        _ => Err("output format must be one of 'sql', 'gzip'".to_string()),
+    }
+}
+
+/// Minimum coverage, either as percentage or as the no. of queries
+/// having at least one test
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CovMinimum {
+    Pcent(f32),
+    Count(usize),
+}
+
+impl CovMinimum {
+    fn is_met(&self, cov: &Coverage) -> bool {
+        match self {
+            Self::Pcent(pcent) => cov.pcent() >= *pcent,
+            Self::Count(count) => cov.tested >= *count,
+        }
+    }
+}
+
+impl fmt::Display for CovMinimum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pcent(pcent) => write!(f, "{pcent}%"),
+            Self::Count(count) => write!(f, "{count} tested queries"),
+        }
+    }
+}
+
+/// Parses coverage threshold which may be specified as,
+///
+///   - percentage: an integer or float, optionally suffixed with `%`
+///     e.g. `85`, `85%`, `85.5`
+///   - ratio: a float between 0 and 1 e.g. `0.85`
+///   - count: min. no. of tested queries prefixed with `count:` e.g.
+///     `count:40`
+///
+/// A number without `%` is considered a ratio only if it has a
+/// decimal point and is not greater than 1, so `1` is 1% whereas
+/// `1.0` is 100%.
+pub fn cov_threshold_parser(value: &str) -> Result<CovMinimum, String> {
+    if let Some(count) = value.strip_prefix("count:") {
+        return count
+            .parse()
+            .map(CovMinimum::Count)
+            .map_err(|_| "count is not a non-negative integer".to_string());
+    }
+    let (num, is_pcent) = match value.strip_suffix('%') {
+        Some(num) => (num, true),
+        None => (value, false),
+    };
+    let threshold: f32 = num.parse().map_err(|_| "threshold is not a number")?;
+    if !is_pcent && num.contains('.') && (0.0..=1.0).contains(&threshold) {
+        Ok(CovMinimum::Pcent(threshold * 100.0))
+    } else if (0.0..=100.0).contains(&threshold) {
+        Ok(CovMinimum::Pcent(threshold))
+    } else {
+        Err("threshold not in range 0..=100".to_string())
+    }
+}
+
+/// Minimum coverage required by the `--fail-under` option of the
+/// `coverage` command, either overall or for the queries with a tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct CovThreshold {
+    tag: Option<String>,
+    min: CovMinimum,
+}
+
+/// Parses the value of `--fail-under` which is either a threshold for
+/// the overall coverage (see `cov_threshold_parser`) or a
+/// `tag=threshold` pair e.g. `critical=95%`
+pub fn fail_under_parser(value: &str) -> Result<CovThreshold, String> {
+    match value.split_once('=') {
+        Some((tag, threshold)) if !tag.trim().is_empty() => Ok(CovThreshold {
+            tag: Some(tag.trim().to_owned()),
+            min: cov_threshold_parser(threshold)?,
+        }),
+        Some(_) => Err("tag must not be empty".to_string()),
+        None => Ok(CovThreshold {
+            tag: None,
+            min: cov_threshold_parser(value)?,
+        }),
+    }
+}
+
+/// No. of queries having at least one test out of the total
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Coverage {
+    tested: usize,
+    total: usize,
+}
+
+impl Coverage {
+    fn add(&mut self, tested: bool) {
+        self.total += 1;
+        if tested {
+            self.tested += 1;
+        }
+    }
+
+    // Coverage is 100% when there are no queries as none of them
+    // are untested
+    fn pcent(&self) -> f32 {
+        if self.total == 0 {
+            return 100_f32;
+        }
+        (self.tested as f32 / self.total as f32) * 100_f32
+    }
+}
+
+/// Label under which the coverage of queries without tags is
+/// reported by `coverage --by-tag`
+const UNTAGGED: &str = "(untagged)";
+
+/// Returns the coverage of `queries` per tag. A query with multiple
+/// tags is counted for each of them.
+fn coverage_by_tag<'a>(
+    metadata: &Metadata,
+    queries: &[&'a Arc<Query>],
+) -> BTreeMap<&'a str, Coverage> {
+    let mut result: BTreeMap<&str, Coverage> = BTreeMap::new();
+    for query in queries {
+        let tested = !metadata
+            .test_templates
+            .find_active_by_query(query)
+            .is_empty();
+        if query.tags.is_empty() {
+            result.entry(UNTAGGED).or_default().add(tested);
+        }
+        for tag in &query.tags {
+            result.entry(tag.as_str()).or_default().add(tested);
+        }
+    }
+    result
+}
+
+/// Prints the no. of bind parameters in the rendered SQL of each query
+pub fn params(include_skipped: bool, tags: &TagArgs) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
+    if mistakes.is_empty() {
+        let engine = Engine::from(&metadata);
+        let header = vec!["Query", "Params"];
+        let mut rows: Vec<Vec<String>> = Vec::with_capacity(metadata.queries.len());
+        for query in metadata
+            .queries
+            .tagged(include_skipped, tags.resolve(&metadata))
+            .filter(|q| engine.is_enabled(q))
+        {
+            let sql = engine.render_query(&query.id, None)?;
+            let count = count_placeholders(&sql, &metadata.placeholder);
+            rows.push(vec![query.id.clone(), count.to_string()]);
+        }
+        let mut table = Table::new();
+        table.set_header(header).add_rows(rows);
+        println!("{table}");
+        Ok(0)
+    } else {
+        println!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            println!("{}", mistake.err_msg())
+        }
+        Ok(1)
+    }
+}
+
+/// Prints everything that's known about the query with `query_id`
+pub fn explain_query(query_id: &str, conds: &CondArgs) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
+    if mistakes.is_empty() {
+        let query = metadata
+            .queries
+            .get(query_id)
+            .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
+        let query_template =
+            metadata
+                .query_templates
+                .get(&query.template)
+                .ok_or(Error::UndefinedQueryTemplate(
+                    query.template_file_name().to_owned(),
+                ))?;
+        let tests = metadata.test_templates.find_active_by_query(query);
+        let output = presenter::explain_query(
+            query,
+            &query_template.all_conds,
+            &conds.resolve(&metadata)?,
+            metadata
+                .formatter
+                .as_ref()
+                .and_then(|f| f.for_dialect(query.dialect.as_deref())),
+            &tests,
+        );
+        println!("{output}");
+        Ok(0)
+    } else {
+        println!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            println!("{}", mistake.err_msg())
+        }
+        Ok(1)
+    }
+}
+
+pub fn coverage(
+    fail_under: &[CovThreshold],
+    include_skipped: bool,
+    tags: &TagArgs,
+    by_tag: bool,
+    baseline: Option<&Path>,
+    save: Option<&Path>,
+) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = manifest_errors(metadata.validate());
+    if mistakes.is_empty() {
+        // Skipped queries are excluded from coverage calculation
+        // altogether instead of being counted as untested
+        let tags = tags.resolve(&metadata);
+        let queries = metadata
+            .queries
+            .tagged(include_skipped, tags)
+            .collect::<Vec<&Arc<Query>>>();
+        let num_queries = queries.len();
+        let num_skipped = metadata.queries.tagged(true, tags).count() - num_queries;
         let mut untested: Vec<&str> = Vec::new();
-        let header = vec!["Query", "Has tests?"];
         let mut rows: Vec<Vec<String>> = Vec::with_capacity(num_queries + 1);
-        for query in metadata.queries.iter() {
-            let tts = metadata.test_templates.find_by_query(&query.id);
+        let mut current = CoverageBaseline::default();
+        for query in &queries {
+            let tts = metadata.test_templates.find_active_by_query(query);
+            current.queries.insert(query.id.clone(), tts.len());
             if tts.is_empty() {
                 untested.push(&query.id);
             }
@@ -283,31 +2351,88 @@ pub fn coverage(fail_under: Option<u8>) -> Result<i32, Error> {
             };
             rows.push(vec![query.id.clone(), has_tests.to_owned()]);
         }
+        let by_tag_cov = coverage_by_tag(&metadata, &queries);
 
         // Calculate coverage summary
         let num_untested = untested.len();
         let num_tested = num_queries - num_untested;
-        let pcent_cov = (num_tested as f32 / num_queries as f32) * 100_f32;
-        rows.push(vec![
-            "Total".to_owned(),
-            format!("{pcent_cov:.02}%\n({num_tested}/{num_queries} queries have at least 1 test)"),
-        ]);
+        let overall_cov = Coverage {
+            tested: num_tested,
+            total: num_queries,
+        };
+        let pcent_cov = overall_cov.pcent();
+        let mut total =
+            format!("{pcent_cov:.02}%\n({num_tested}/{num_queries} queries have at least 1 test)");
+        if num_skipped > 0 {
+            total.push_str(&format!("\n({num_skipped} skipped queries excluded)"));
+        }
+
+        // With `--by-tag`, the coverage of every tag is printed
+        // instead of that of the individual queries
+        let header = if by_tag {
+            rows = by_tag_cov
+                .iter()
+                .map(|(tag, cov)| {
+                    let summary = format!("{:.02}% ({}/{})", cov.pcent(), cov.tested, cov.total);
+                    vec![tag.to_string(), summary]
+                })
+                .collect();
+            vec!["Tag", "Coverage"]
+        } else {
+            vec!["Query", "Has tests?"]
+        };
+        rows.push(vec!["Total".to_owned(), total]);
 
         // Print table
         let mut table = Table::new();
         table.set_header(header).add_rows(rows);
         println!("{table}");
 
-        let exit_code = match fail_under {
-            Some(threshold) => {
-                if pcent_cov < (threshold as f32) {
-                    1
-                } else {
-                    0
+        let mut exit_code = 0;
+        for threshold in fail_under {
+            let cov = match &threshold.tag {
+                Some(tag) => match by_tag_cov.get(tag.as_str()) {
+                    Some(cov) => cov,
+                    None => {
+                        return Err(Error::Cli(format!(
+                            "No queries are tagged with '{tag}' (see '--fail-under')"
+                        )))
+                    }
+                },
+                None => &overall_cov,
+            };
+            if !threshold.min.is_met(cov) {
+                let actual = match threshold.min {
+                    CovMinimum::Pcent(_) => format!("{:.02}%", cov.pcent()),
+                    CovMinimum::Count(_) => format!("{} tested queries", cov.tested),
+                };
+                match &threshold.tag {
+                    Some(tag) => eprintln!(
+                        "Coverage of queries tagged with '{tag}' ({actual}) is under {}",
+                        threshold.min
+                    ),
+                    None => eprintln!("Coverage ({actual}) is under {}", threshold.min),
                 }
+                exit_code = 1;
             }
-            None => 0,
-        };
+        }
+
+        // Previously tested queries must not become untested,
+        // irrespective of the overall coverage
+        if let Some(path) = baseline {
+            let baseline = CoverageBaseline::read(path)?;
+            let regressions = baseline.regressions(&current);
+            if !regressions.is_empty() {
+                eprintln!("Queries with tests in the baseline that no longer have any tests:");
+                for id in regressions {
+                    eprintln!("  {id}");
+                }
+                exit_code = 1;
+            }
+        }
+        if let Some(path) = save {
+            current.write(path)?;
+        }
         Ok(exit_code)
     } else {
         println!("Invalid manifest file: '{}'", path.display());
@@ -317,3 +2442,1804 @@ pub fn coverage(fail_under: Option<u8>) -> Result<i32, Error> {
         Ok(1)
     }
 }
+
+/// Returns the output files (as `(from, to)` pairs) that need to be
+/// renamed as a result of renaming the query `old_id` to `new_id`
+///
+/// `before` and `after` are the metadata before and after renaming
+/// the query. Only the outputs whose paths are derived from the query
+/// id change, so the rest (e.g. explicitly specified outputs or the
+/// combined output file) are excluded.
+fn output_renames<'a>(
+    before: &'a Metadata,
+    after: &'a Metadata,
+    old_id: &str,
+    new_id: &str,
+) -> Vec<(&'a Path, &'a Path)> {
+    let mut result = vec![];
+    if let (Some(q1), Some(q2)) = (before.queries.get(old_id), after.queries.get(new_id)) {
+        result.push((q1.output.as_path(), q2.output.as_path()));
+    }
+    let tests_before = before.test_templates.find_by_query(old_id);
+    let tests_after = after.test_templates.find_by_query(new_id);
+    for (t1, t2) in tests_before.iter().zip(tests_after.iter()) {
+        result.push((t1.output.as_path(), t2.output.as_path()));
+    }
+    result.retain(|(from, to)| from != to);
+    result
+}
+
+/// Returns an error if `new_id` can't be used for renaming a query
+/// defined in `metadata` i.e. if it's not a valid id, doesn't match
+/// the `id_pattern` or is already the id of another query
+fn check_new_id(metadata: &Metadata, new_id: &str) -> Result<(), Error> {
+    if !query::is_valid_id(new_id) {
+        return Err(Error::Cli(format!(
+            "Invalid query id: {new_id:?} (must not be empty or contain path separators)"
+        )));
+    }
+    if let Some(pattern) = &metadata.id_pattern {
+        // @UNWRAP: The `id_pattern` is checked when parsing the
+        // manifest
+        if !Regex::new(pattern).unwrap().is_match(new_id) {
+            return Err(Error::Cli(format!(
+                "Query id '{new_id}' does not match the pattern '{pattern}'"
+            )));
+        }
+    }
+    if metadata.queries.get(new_id).is_some() {
+        return Err(Error::Cli(format!("Query already exists: id={new_id}")));
+    }
+    Ok(())
+}
+
+/// Moves the existing output files in all output formats as per
+/// `renames`, returning the ones that were actually moved
+///
+/// Existing files are not overwritten. In case of failure, the files
+/// that were already moved are moved back.
+fn move_outputs<'a>(renames: &[(&'a Path, &'a Path)]) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut pending = vec![];
+    for (from, to) in renames {
+        for out_format in [OutputFormat::Sql, OutputFormat::Gzip] {
+            let (from, to) = (out_format.path(from), out_format.path(to));
+            if from.try_exists()? {
+                if to.try_exists()? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("Output file already exists: {}", to.display()),
+                    ));
+                }
+                pending.push((from.into_owned(), to.into_owned()));
+            }
+        }
+    }
+    let mut moved = Vec::with_capacity(pending.len());
+    for (from, to) in pending {
+        let res = match to.parent() {
+            Some(dir) => fs::create_dir_all(dir).and_then(|_| fs::rename(&from, &to)),
+            None => fs::rename(&from, &to),
+        };
+        if let Err(e) = res {
+            undo_moves(&moved);
+            return Err(e);
+        }
+        moved.push((from, to));
+    }
+    Ok(moved)
+}
+
+/// Moves the files `moved` by `move_outputs` back, logging the ones
+/// that couldn't be
+fn undo_moves(moved: &[(PathBuf, PathBuf)]) {
+    for (from, to) in moved.iter().rev() {
+        if let Err(e) = fs::rename(to, from) {
+            warn!(
+                "Failed to move '{}' back to '{}': {e}",
+                to.display(),
+                from.display()
+            );
+        }
+    }
+}
+
+pub fn rename(old_id: &str, new_id: &str, dry_run: bool) -> Result<i32, Error> {
+    let (_, overrides) = enter_project()?;
+    let contents = Metadata::read_manifest(Path::new(MANIFEST_FILE))?;
+    let before = Metadata::parse(&contents, &overrides)?;
+    check_new_id(&before, new_id)?;
+    let renamed = migration::rename_query(&contents, old_id, new_id)?;
+    let after = Metadata::parse(&renamed, &overrides)?;
+    let renames = output_renames(&before, &after, old_id, new_id);
+    if dry_run {
+        println!("{}", line_diff(&contents, &renamed));
+        for (from, to) in renames {
+            println!("Rename: {} -> {}", from.display(), to.display());
+        }
+    } else {
+        // The outputs are moved first, so that the manifest is
+        // written only if they could all be moved
+        let moved = move_outputs(&renames).map_err(Error::Io)?;
+        if let Err(e) = fs::write(MANIFEST_FILE, renamed) {
+            undo_moves(&moved);
+            return Err(Error::Io(e));
+        }
+        for (from, to) in moved {
+            println!("Renamed: {} -> {}", from.display(), to.display());
+        }
+        println!("Query '{old_id}' renamed to '{new_id}'");
+        if before.name_tagger.is_some() {
+            println!("Tip: Run `tapestry render` to update the name tags in the output files");
+        }
+    }
+    Ok(0)
+}
+
+pub fn migrate_manifest(dry_run: bool, fix: bool) -> Result<i32, Error> {
+    let (path, _) = enter_project()?;
+    let contents = Metadata::read_manifest(Path::new(MANIFEST_FILE))?;
+    // The casing is fixed first so that deprecated keys with wrong
+    // casing are also migrated
+    let migrated = if fix {
+        migration::migrate(&migration::fix_key_casing(&contents)?)?
+    } else {
+        migration::migrate(&contents)?
+    };
+    if migrated == contents {
+        println!("Manifest file '{}' is already up to date", path.display());
+    } else if dry_run {
+        println!("{}", line_diff(&contents, &migrated));
+    } else {
+        fs::write(MANIFEST_FILE, migrated).map_err(Error::Io)?;
+        println!("Manifest file '{}' migrated", path.display());
+    }
+    Ok(0)
+}
+
+/// Returns the formatter for a template used by the `queries` (or
+/// by their tests) i.e. the one selected for formatting their
+/// outputs, or the default one if it's not used by any query
+///
+/// None is returned if no formatter applies, or if the queries have
+/// different formatters (e.g. being of different dialects) in which
+/// case the template is not formatted.
+fn template_formatter<'a>(
+    metadata: &'a Metadata,
+    path: &Path,
+    queries: &[&Query],
+) -> Option<&'a Formatter> {
+    let default = metadata.formatter.as_ref();
+    let mut selected = queries.iter().map(|q| q.formatter(default));
+    let Some(first) = selected.next() else {
+        return default.and_then(|f| f.for_dialect(None));
+    };
+    let same = |f: Option<&Formatter>| match (f, first) {
+        (Some(a), Some(b)) => std::ptr::eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    };
+    if selected.all(same) {
+        first
+    } else {
+        warn!(
+            "Skipping template '{}' as the queries using it have different formatters",
+            path.display()
+        );
+        None
+    }
+}
+
+/// Returns the query and test templates that are not formatted
+/// i.e. whose contents change upon formatting them with the
+/// formatter of the queries that use them, along with the formatted
+/// contents
+fn unformatted_templates(metadata: &Metadata) -> Result<Vec<(PathBuf, String)>, Error> {
+    let mut paths: BTreeMap<&Path, Vec<&Query>> = metadata
+        .query_templates
+        .iter()
+        .map(|qt| qt.path.as_path())
+        .chain(metadata.test_templates.iter().map(|tt| tt.path.as_path()))
+        .map(|p| (p, vec![]))
+        .collect();
+    for query in metadata.queries.iter() {
+        if let Some(queries) = paths.get_mut(query.template.as_path()) {
+            queries.push(query);
+        }
+    }
+    for tt in metadata.test_templates.iter() {
+        if let (Some(queries), Some(query)) = (
+            paths.get_mut(tt.path.as_path()),
+            metadata.queries.get(&tt.query),
+        ) {
+            queries.push(query);
+        }
+    }
+    let mut result = vec![];
+    for (path, queries) in paths {
+        let Some(formatter) = template_formatter(metadata, path, &queries) else {
+            continue;
+        };
+        let source = fs::read_to_string(path).map_err(Error::Io)?;
+        let formatted = formatters::format_template(formatter, &source).map_err(|e| match e {
+            Error::FormatterFailed(msg) => {
+                Error::FormatterFailed(format!("{}: {msg}", path.display()))
+            }
+            e => e,
+        })?;
+        if formatted != source {
+            result.push((path.to_path_buf(), formatted));
+        }
+    }
+    Ok(result)
+}
+
+pub fn fmt(check: bool) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let Some(formatter) = metadata.formatter.as_ref() else {
+        return Err(Error::Cli(format!(
+            "No formatter is configured in the manifest file: '{}'",
+            path.display()
+        )));
+    };
+    formatter.ensure_available()?;
+    let unformatted = unformatted_templates(&metadata)?;
+    if check {
+        for (p, _) in &unformatted {
+            println!("Unformatted: {}", p.display());
+        }
+        return Ok(if unformatted.is_empty() { 0 } else { 1 });
+    }
+    for (p, contents) in &unformatted {
+        fs::write(p, contents).map_err(Error::Io)?;
+        println!("Formatted: {}", p.display());
+    }
+    Ok(0)
+}
+
+/// Output file to be deleted by the `clean` command
+struct CleanTarget {
+    path: PathBuf,
+    is_test: bool,
+    size: u64,
+}
+
+/// Returns the existing output files of all queries (including the
+/// skipped ones) and tests defined in the manifest
+///
+/// Both the `sql` and `gzip` forms of the output files are
+/// considered, as are the numbered parts of queries with
+/// `split_on`. Files outside the output dirs are included only if
+/// `allow_escape` is true.
+fn clean_targets(metadata: &Metadata, allow_escape: bool) -> Result<Vec<CleanTarget>, Error> {
+    let mut targets: Vec<CleanTarget> = vec![];
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    // Adds the output file at `path` (in either format) if it
+    // exists, returning whether it does
+    let mut add = |path: &Path, dir: &Path, is_test: bool| -> Result<bool, Error> {
+        let mut found = false;
+        for format in [OutputFormat::Sql, OutputFormat::Gzip] {
+            let p = format.path(path);
+            match fs::metadata(&p) {
+                Ok(m) if m.is_file() => {
+                    found = true;
+                    if (allow_escape || is_within(&p, dir)) && seen.insert(p.to_path_buf()) {
+                        targets.push(CleanTarget {
+                            path: p.into_owned(),
+                            is_test,
+                            size: m.len(),
+                        });
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+        Ok(found)
+    };
+    for query in metadata.queries.iter() {
+        let dir = &metadata.queries_output_dir;
+        if query.split_on.is_some() {
+            for n in 1.. {
+                if !add(&output::part_path(&query.output, n), dir, false)? {
+                    break;
+                }
+            }
+        } else {
+            add(&query.output, dir, false)?;
+        }
+    }
+    for tt in metadata.test_templates.iter() {
+        add(&tt.output, &metadata.tests_output_dir, true)?;
+    }
+    Ok(targets)
+}
+
+/// Deletes the `targets` (unless `dry_run` is true), writing a line
+/// for each of them followed by the summary line to `out`
+fn clean_outputs<W: io::Write>(
+    out: &mut W,
+    targets: &[CleanTarget],
+    dry_run: bool,
+) -> Result<(), Error> {
+    for target in targets {
+        if dry_run {
+            writeln!(out, "Would delete: {}", target.path.display()).map_err(Error::Io)?;
+        } else {
+            fs::remove_file(&target.path).map_err(Error::Io)?;
+            writeln!(out, "Deleted: {}", target.path.display()).map_err(Error::Io)?;
+        }
+    }
+    let num_tests = targets.iter().filter(|t| t.is_test).count();
+    let bytes = targets.iter().map(|t| t.size).sum();
+    let summary = presenter::clean_summary(targets.len() - num_tests, num_tests, bytes, dry_run);
+    writeln!(out, "{summary}").map_err(Error::Io)
+}
+
+/// Deletes the output files of the queries and tests defined in the
+/// manifest
+pub fn clean(dry_run: bool, escape: &EscapeArgs) -> Result<i32, Error> {
+    let (path, overrides) = enter_project()?;
+    let metadata = Metadata::from_path(Path::new(MANIFEST_FILE), &overrides)?;
+    let mistakes = escape.filter(metadata.escape_mistakes());
+    if mistakes.is_empty() {
+        let targets = clean_targets(&metadata, escape.allow_escape)?;
+        clean_outputs(&mut io::stdout().lock(), &targets, dry_run)?;
+        Ok(0)
+    } else {
+        println!("Invalid manifest file: '{}'", path.display());
+        for mistake in mistakes {
+            println!("{}", mistake.err_msg())
+        }
+        Ok(1)
+    }
+}
+
+/// Re-renders the output files whenever the manifest or any of the
+/// templates change, running `run` (if any) after every render that
+/// changed the output files
+///
+/// The outputs are rendered the same way as the `render` command
+/// does, except that only the files whose contents change are
+/// written.
+pub fn watch(
+    manifest: &ManifestArgs,
+    escape: &EscapeArgs,
+    run: Option<&str>,
+    interval: Duration,
+) -> Result<i32, Error> {
+    let found = manifest.manifest_path()?.into_owned();
+    if manifest.discover.is_some() || found == Path::new("-") {
+        return Err(Error::Cli(
+            "The manifest must be read from a file so that it can be watched".to_owned(),
+        ));
+    }
+    // The manifest is read again on every change, after the current
+    // dir is changed to the base dir
+    let manifest_path = env::current_dir().map_err(Error::Io)?.join(&found);
+    let overrides = manifest.enter_base_dir(&found)?;
+    let metadata = Metadata::from_path(&manifest_path, &overrides)?;
+    let paths = vec![
+        manifest_path.clone(),
+        metadata.query_templates_dir.clone(),
+        metadata.test_templates_dir.clone(),
+    ];
+    eprintln!("Watching for changes (press Ctrl-C to stop)");
+    let args = RenderArgs {
+        quiet: true,
+        only_changed: true,
+        escape: EscapeArgs {
+            allow_escape: escape.allow_escape,
+        },
+        max_errors: MAX_ERRORS,
+        ..RenderArgs::default()
+    };
+    let render = || -> Result<usize, Error> {
+        let metadata = Metadata::from_path(&manifest_path, &overrides)?;
+        render_metadata(&metadata, &args, &found, Path::new(LOCK_FILE))?.ok_or_else(|| {
+            Error::InvalidMetadata(format!("See the mistakes in '{}' above", found.display()))
+        })
+    };
+    watch::watch_loop(
+        watch::Poller::new(paths, interval),
+        render,
+        run.map(|cmd| move || watch::run_shell(cmd)),
+    )?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::test_util::{test_manifest, TempDir};
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        manifest: ManifestArgs,
+    }
+
+    fn manifest_args(args: &[&str]) -> ManifestArgs {
+        TestCli::parse_from(std::iter::once("tapestry").chain(args.iter().copied())).manifest
+    }
+
+    #[test]
+    fn test_manifest_args_base_dir() {
+        let base_dir = |args: &[&str], manifest_path: &str| {
+            manifest_args(args)
+                .base_dir(Path::new(manifest_path))
+                .map(Path::to_path_buf)
+        };
+        // Defaults to the current dir
+        assert_eq!(None, base_dir(&[], "tapestry.toml"));
+        assert_eq!(None, base_dir(&["--manifest-path", "-"], "-"));
+
+        // Dir of the manifest file
+        assert_eq!(
+            Some(PathBuf::from("projects/chinook")),
+            base_dir(
+                &["--manifest-path", "projects/chinook/tapestry.toml"],
+                "projects/chinook/tapestry.toml"
+            )
+        );
+
+        // `--base-dir` takes precedence over the dir of the manifest
+        // file as well as the current dir (for stdin)
+        assert_eq!(
+            Some(PathBuf::from("/srv/sql")),
+            base_dir(
+                &[
+                    "--manifest-path",
+                    "projects/chinook/tapestry.toml",
+                    "--base-dir",
+                    "/srv/sql"
+                ],
+                "projects/chinook/tapestry.toml"
+            )
+        );
+        assert_eq!(
+            Some(PathBuf::from("/srv/sql")),
+            base_dir(&["--manifest-path", "-", "--base-dir", "/srv/sql"], "-")
+        );
+    }
+
+    #[test]
+    fn test_cache_args_resolve() {
+        #[derive(Parser)]
+        struct CacheCli {
+            #[command(flatten)]
+            cache: CacheArgs,
+        }
+        let resolve = |args: &[&str], env_dir: Option<&str>| {
+            CacheCli::parse_from(std::iter::once("tapestry").chain(args.iter().copied()))
+                .cache
+                .resolve(
+                    |name| {
+                        assert_eq!("TAPESTRY_CACHE_DIR", name);
+                        env_dir.map(OsString::from)
+                    },
+                    Path::new("/srv/sql"),
+                )
+        };
+        // Disabled by default
+        assert_eq!(None, resolve(&[], None));
+        // Relative dirs are resolved against the current dir
+        assert_eq!(
+            Some(PathBuf::from("/srv/sql/.cache")),
+            resolve(&[], Some(".cache"))
+        );
+        assert_eq!(
+            Some(PathBuf::from("/tmp/cache")),
+            resolve(&["--cache-dir", "/tmp/cache"], Some(".cache"))
+        );
+        assert_eq!(None, resolve(&["--no-cache"], Some(".cache")));
+    }
+
+    #[test]
+    fn test_resolve_out_suffix() {
+        let branch = || Ok("feature/x".to_owned());
+        assert_eq!(
+            PathBuf::from("foo"),
+            resolve_out_suffix("foo", || panic!()).unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("feature/x"),
+            resolve_out_suffix("{branch}", branch).unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("pr-feature/x"),
+            resolve_out_suffix("pr-{branch}", branch).unwrap()
+        );
+        for invalid in ["", "..", "../foo", "/tmp/foo", "foo/../.."] {
+            assert!(resolve_out_suffix(invalid, branch).is_err());
+        }
+        assert!(resolve_out_suffix("{branch}", || Err("not a git repo".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_find_manifest() {
+        let dir = TempDir::new("find");
+        let nested = dir.join("templates/queries/reports");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.join("tapestry.toml"), "placeholder = 'posargs'\n").unwrap();
+
+        // Found in the current dir
+        assert_eq!(Some(PathBuf::from("tapestry.toml")), find_manifest(&dir));
+
+        // Found in the nearest parent dir, with the paths in it
+        // resolved relative to that dir
+        let path = find_manifest(&nested).unwrap();
+        assert_eq!(PathBuf::from("../../../tapestry.toml"), path);
+        let args = manifest_args(&[]);
+        let base_dir = nested.join(args.base_dir(&path).unwrap());
+        assert_eq!(
+            dir.canonicalize().unwrap(),
+            base_dir.canonicalize().unwrap()
+        );
+        assert_eq!(
+            dir.join("templates/queries").canonicalize().unwrap(),
+            base_dir.join("templates/queries").canonicalize().unwrap()
+        );
+
+        // An explicit path disables the search
+        let args = manifest_args(&["--manifest-path", "other.toml"]);
+        assert_eq!(Path::new("other.toml"), args.manifest_path().unwrap());
+    }
+
+    #[test]
+    fn test_rename_outputs() {
+        let dir = TempDir::new("rename");
+        let manifest = test_manifest(
+            "templates",
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "songs_formats"
+template = "artists_long_songs.sql.j2"
+output = "songs_formats_custom.sql"
+
+[[test_templates]]
+query = "artists_long_songs"
+path = "artists_long_songs_test.sql.j2"
+"#,
+        );
+        let renamed =
+            migration::rename_query(&manifest, "artists_long_songs", "long_songs").unwrap();
+        let before: Metadata = manifest.parse().unwrap();
+        let after: Metadata = renamed.parse().unwrap();
+
+        // The test output is derived from the test template and
+        // hence doesn't change
+        let renames = output_renames(&before, &after, "artists_long_songs", "long_songs");
+        let old_output = dir.join("out/queries/artists_long_songs.sql");
+        let new_output = dir.join("out/queries/long_songs.sql");
+        assert_eq!(vec![(old_output.as_path(), new_output.as_path())], renames);
+        assert_eq!(
+            "long_songs",
+            after.test_templates.iter().next().unwrap().query
+        );
+
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        fs::write(&old_output, "SELECT 1;\n").unwrap();
+        let moved = move_outputs(&renames).unwrap();
+        assert_eq!(vec![(old_output.clone(), new_output.clone())], moved);
+        assert!(!old_output.exists());
+        assert_eq!("SELECT 1;\n", fs::read_to_string(&new_output).unwrap());
+
+        // Explicitly specified outputs are not renamed
+        let renamed = migration::rename_query(&manifest, "songs_formats", "formats").unwrap();
+        let after: Metadata = renamed.parse().unwrap();
+        assert!(output_renames(&before, &after, "songs_formats", "formats").is_empty());
+
+        // The new id is checked before anything is changed
+        assert!(check_new_id(&before, "long_songs").is_ok());
+        for new_id in ["songs_formats", "", "songs/long"] {
+            assert!(matches!(check_new_id(&before, new_id), Err(Error::Cli(_))));
+        }
+        let strict: Metadata = format!("id_pattern = \"^[a-z_]+$\"\n{manifest}")
+            .parse()
+            .unwrap();
+        match check_new_id(&strict, "LongSongs") {
+            Err(Error::Cli(msg)) => assert_eq!(
+                "Query id 'LongSongs' does not match the pattern '^[a-z_]+$'",
+                msg
+            ),
+            res => panic!("{res:?}"),
+        }
+
+        // Existing files are not overwritten
+        fs::write(&old_output, "SELECT 2;\n").unwrap();
+        let e = move_outputs(&renames).unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, e.kind());
+        assert_eq!("SELECT 2;\n", fs::read_to_string(&old_output).unwrap());
+        assert_eq!("SELECT 1;\n", fs::read_to_string(&new_output).unwrap());
+
+        // In case of failure, the files moved so far are moved back
+        fs::remove_file(&new_output).unwrap();
+        let blocker = dir.join("out/blocker");
+        fs::write(&blocker, "").unwrap();
+        let other_output = dir.join("out/queries/songs_formats_custom.sql");
+        fs::write(&other_output, "SELECT 3;\n").unwrap();
+        let unmovable = blocker.join("songs_formats.sql");
+        let renames = vec![
+            (old_output.as_path(), new_output.as_path()),
+            (other_output.as_path(), unmovable.as_path()),
+        ];
+        assert!(move_outputs(&renames).is_err());
+        assert_eq!("SELECT 2;\n", fs::read_to_string(&old_output).unwrap());
+        assert!(!new_output.exists());
+        assert_eq!("SELECT 3;\n", fs::read_to_string(&other_output).unwrap());
+    }
+
+    #[test]
+    fn test_check_status_keep_going() {
+        let dir = TempDir::new("keep-going");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        fs::create_dir_all(dir.join("out/tests")).unwrap();
+        fs::write(dir.join("templates/ok.sql.j2"), "SELECT 1;\n").unwrap();
+        // Calls to undefined functions fail at render time
+        fs::write(
+            dir.join("templates/broken.sql.j2"),
+            "SELECT {{ oops() }};\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("templates/tests/ok_test.sql.j2"),
+            "{{ prepared_statement }};\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "ok.sql.j2"
+
+[[query_templates]]
+path = "broken.sql.j2"
+
+[[queries]]
+id = "broken_a"
+template = "broken.sql.j2"
+
+[[queries]]
+id = "ok"
+template = "ok.sql.j2"
+
+[[queries]]
+id = "broken_b"
+template = "broken.sql.j2"
+
+[[test_templates]]
+query = "ok"
+path = "ok_test.sql.j2"
+"#,
+        );
+        fs::write(dir.join("out/queries/ok.sql"), "SELECT 1;\n").unwrap();
+        let manifest_path = dir.join("tapestry.toml");
+        fs::write(&manifest_path, &manifest).unwrap();
+        let metadata: Metadata = manifest.parse().unwrap();
+
+        // By default, the first error is returned
+        let res = check_status(&metadata, &manifest_path, &StatusArgs::default(), &dir);
+        assert!(matches!(res, Err(Error::MiniJinja(_))));
+
+        let report = check_status(
+            &metadata,
+            &manifest_path,
+            &StatusArgs {
+                keep_going: true,
+                ..StatusArgs::default()
+            },
+            &dir,
+        )
+        .unwrap();
+        let failed = report
+            .errors
+            .iter()
+            .map(|(id, _)| *id)
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["broken_a", "broken_b"], failed);
+        let statuses = report
+            .lines
+            .iter()
+            .map(|l| (l.query_id, l.status.label()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(Some("ok"), "unchanged"), (None, "added")], statuses);
+    }
+
+    #[test]
+    fn test_check_status_paths_from_subdir() {
+        let dir = TempDir::new("status-subdir");
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "artists.sql.j2"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        fs::write(&manifest_path, &manifest).unwrap();
+        let metadata: Metadata = manifest.parse().unwrap();
+        let check = |path: &str| {
+            let args = StatusArgs {
+                paths: vec![PathBuf::from(path)],
+                ..StatusArgs::default()
+            };
+            check_status(&metadata, &manifest_path, &args, &dir.join("sub"))
+        };
+
+        // The path is relative to the dir the command is run from
+        let report = check("../out/queries/artists.sql").unwrap();
+        let ids = report
+            .lines
+            .iter()
+            .map(|l| l.query_id)
+            .collect::<Vec<Option<&str>>>();
+        assert_eq!(vec![Some("artists")], ids);
+        assert!(matches!(
+            check("out/queries/artists.sql"),
+            Err(Error::UndefinedOutput(_))
+        ));
+    }
+
+    #[test]
+    fn test_report_status_porcelain() {
+        let dir = TempDir::new("porcelain");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        fs::create_dir_all(dir.join("out/tests")).unwrap();
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        fs::write(&manifest_path, &manifest).unwrap();
+        let metadata: Metadata = manifest.parse().unwrap();
+        let report_with = |porcelain: bool| {
+            let report =
+                check_status(&metadata, &manifest_path, &StatusArgs::default(), &dir).unwrap();
+            let mut out = vec![];
+            let code =
+                report_status(&mut out, &report, OutputFormat::Sql, false, porcelain).unwrap();
+            (String::from_utf8(out).unwrap(), code)
+        };
+
+        // Drift i.e. the output file has stale contents
+        fs::write(dir.join("out/queries/artists.sql"), "SELECT 2;\n").unwrap();
+        let (out, code) = report_with(false);
+        assert!(out.starts_with("Query: modified: artists"));
+        assert_eq!(0, code);
+        assert_eq!((String::new(), 1), report_with(true));
+
+        // No drift
+        fs::write(dir.join("out/queries/artists.sql"), "SELECT 1;\n").unwrap();
+        assert_eq!((String::new(), 0), report_with(true));
+    }
+
+    #[test]
+    fn test_status_grouped_by_query() {
+        let dir = TempDir::new("status-order");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        fs::create_dir_all(dir.join("out/tests")).unwrap();
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        fs::write(dir.join("templates/albums.sql.j2"), "SELECT 2;\n").unwrap();
+        for name in ["z_test", "a_test", "m_test", "b_test"] {
+            fs::write(
+                dir.join(format!("templates/tests/{name}.sql.j2")),
+                "{{ prepared_statement }};\n",
+            )
+            .unwrap();
+        }
+        // Tests are intentionally defined out of order and before the
+        // queries they belong to
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[query_templates]]
+path = "albums.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "albums.sql.j2"
+
+[[test_templates]]
+query = "albums"
+path = "m_test.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "z_test.sql.j2"
+
+[[test_templates]]
+query = "albums"
+path = "b_test.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "a_test.sql.j2"
+"#,
+        );
+        fs::write(dir.join("out/queries/artists.sql"), "SELECT 1;\n").unwrap();
+        let manifest_path = dir.join("tapestry.toml");
+        fs::write(&manifest_path, &manifest).unwrap();
+        let metadata: Metadata = manifest.parse().unwrap();
+        let report = check_status(&metadata, &manifest_path, &StatusArgs::default(), &dir).unwrap();
+        let mut out = vec![];
+        report_status(&mut out, &report, OutputFormat::Sql, false, false).unwrap();
+        let expected = format!(
+            "Query: unchanged: artists ({0}/out/queries/artists.sql)
+  Test: added: {0}/out/tests/a_test.sql
+  Test: added: {0}/out/tests/z_test.sql
+Query: added: albums ({0}/out/queries/albums.sql)
+  Test: added: {0}/out/tests/b_test.sql
+  Test: added: {0}/out/tests/m_test.sql
+",
+            dir.display()
+        );
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_clean_outputs() {
+        let dir = TempDir::new("clean");
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        fs::create_dir_all(dir.join("out/tests")).unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "schema"
+template = "artists.sql.j2"
+split_on = ";"
+
+[[queries]]
+id = "songs"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        // The output of `songs` doesn't exist and `README.md` is not
+        // an output file
+        let files = [
+            ("out/queries/artists.sql", 1000),
+            ("out/queries/albums.sql.gz", 24),
+            ("out/queries/schema.001.sql", 2048),
+            ("out/queries/schema.002.sql", 2048),
+            ("out/tests/artists_test.sql", 100),
+            ("out/queries/README.md", 10),
+        ];
+        for (p, size) in files {
+            fs::write(dir.join(p), "x".repeat(size)).unwrap();
+        }
+
+        let targets = clean_targets(&metadata, false).unwrap();
+        let mut out = vec![];
+        clean_outputs(&mut out, &targets, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert_eq!(6, lines.len());
+        assert!(lines[..5].iter().all(|l| l.starts_with("Would delete: ")));
+        assert_eq!(
+            "Would delete 5 files (1 test, 4 queries), freeing ~5 KB",
+            lines[5]
+        );
+        // Nothing is deleted in case of dry run
+        assert!(files.iter().all(|(p, _)| dir.join(p).exists()));
+
+        let mut out = vec![];
+        clean_outputs(&mut out, &targets, false).unwrap();
+        let remaining = files
+            .iter()
+            .filter(|(p, _)| dir.join(p).exists())
+            .map(|(p, _)| *p)
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["out/queries/README.md"], remaining);
+    }
+
+    #[test]
+    fn test_clean_targets_escape() {
+        let dir = TempDir::new("clean-escape");
+        fs::create_dir_all(dir.join("out/queries")).unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+output = "../evil.sql"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        fs::write(dir.join("out/evil.sql"), "x").unwrap();
+        assert_eq!(1, metadata.escape_mistakes().len());
+        assert!(clean_targets(&metadata, false).unwrap().is_empty());
+        let targets = clean_targets(&metadata, true).unwrap();
+        assert_eq!(1, targets.len());
+        assert_eq!(dir.join("out/queries/../evil.sql"), targets[0].path);
+    }
+
+    #[test]
+    fn test_check_status_output_kinds() {
+        let dir = TempDir::new("only");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(dir.join("templates/ok.sql.j2"), "SELECT 1;\n").unwrap();
+        fs::write(
+            dir.join("templates/tests/ok_test.sql.j2"),
+            "{{ prepared_statement }};\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "ok.sql.j2"
+
+[[queries]]
+id = "ok"
+template = "ok.sql.j2"
+
+[[test_templates]]
+query = "ok"
+path = "ok_test.sql.j2"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        let metadata: Metadata = manifest.parse().unwrap();
+        let statuses = |kinds: OutputKindArgs| {
+            check_status(
+                &metadata,
+                &manifest_path,
+                &StatusArgs {
+                    kinds,
+                    ..StatusArgs::default()
+                },
+                &dir,
+            )
+            .unwrap()
+            .lines
+            .into_iter()
+            .map(|l| l.path.into_owned())
+            .collect::<Vec<PathBuf>>()
+        };
+        let query_output = dir.join("out/queries/ok.sql");
+        let test_output = dir.join("out/tests/ok_test.sql");
+        assert_eq!(
+            vec![query_output.clone(), test_output.clone()],
+            statuses(OutputKindArgs::default())
+        );
+        let only_queries = OutputKindArgs {
+            only_queries: true,
+            ..OutputKindArgs::default()
+        };
+        assert_eq!(vec![query_output], statuses(only_queries));
+        let only_tests = OutputKindArgs {
+            only_tests: true,
+            ..OutputKindArgs::default()
+        };
+        assert_eq!(vec![test_output], statuses(only_tests));
+    }
+
+    #[test]
+    fn test_render_only_tests_combined_layout() {
+        let dir = TempDir::new("render-only-tests");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(dir.join("templates/ok.sql.j2"), "SELECT 1;\n").unwrap();
+        fs::write(
+            dir.join("templates/tests/ok_test.sql.j2"),
+            "{{ prepared_statement }};\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+query_output_layout = "one-file-all-queries"
+query_output_file = "queries.sql"
+
+[name_tagger]
+style = "kebab-case"
+
+[[query_templates]]
+path = "ok.sql.j2"
+
+[[queries]]
+id = "ok"
+template = "ok.sql.j2"
+
+[[test_templates]]
+query = "ok"
+path = "ok_test.sql.j2"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        let metadata: Metadata = manifest.parse().unwrap();
+        let args = RenderArgs {
+            kinds: OutputKindArgs {
+                only_tests: true,
+                ..OutputKindArgs::default()
+            },
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        assert_eq!(
+            Some(1),
+            render_metadata(&metadata, &args, &manifest_path, &dir.join(LOCK_FILE)).unwrap()
+        );
+        // Only the test is written, the combined output file is
+        // left untouched
+        assert!(dir.join("out/tests/ok_test.sql").exists());
+        assert!(!dir.join("out/queries/queries.sql").exists());
+    }
+
+    #[test]
+    fn test_status_combined_layout_formatting() {
+        let dir = TempDir::new("status-combined-fmt");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(
+            dir.join("templates/artists.sql.j2"),
+            "select id,name from artist;\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+query_output_layout = "one-file-all-queries"
+query_output_file = "queries.sql"
+
+[formatter]
+sqlformat-rs = {}
+
+[name_tagger]
+style = "kebab-case"
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "artists_raw"
+template = "artists.sql.j2"
+format = false
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        let metadata: Metadata = manifest.parse().unwrap();
+        let args = RenderArgs {
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        assert_eq!(
+            Some(1),
+            render_metadata(&metadata, &args, &manifest_path, &dir.join(LOCK_FILE)).unwrap()
+        );
+        // As one of the queries opts out of formatting, the combined
+        // file is not formatted, which status must agree with
+        let report = check_status(&metadata, &manifest_path, &StatusArgs::default(), &dir).unwrap();
+        assert_eq!(2, report.lines.len());
+        assert!(report
+            .lines
+            .iter()
+            .all(|l| l.status == output::Status::Unchanged));
+    }
+
+    // The output dir is a symlink to the templates dir, so the output
+    // path is the template path only after resolving it
+    #[cfg(unix)]
+    #[test]
+    fn test_render_output_overwrites_template() {
+        let dir = TempDir::new("render-overwrite");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        let template = dir.join("templates/artists.sql.j2");
+        fs::write(&template, "SELECT 1;\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("templates"), dir.join("queries")).unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            &dir,
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+output = "artists.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        assert!(metadata
+            .validate()
+            .iter()
+            .any(|m| matches!(m, ManifestMistake::OutputOverwritesTemplate { .. })));
+        let args = RenderArgs {
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        let res = render_metadata(
+            &metadata,
+            &args,
+            &dir.join("tapestry.toml"),
+            &dir.join(LOCK_FILE),
+        );
+        assert_eq!(None, res.unwrap());
+        assert_eq!("SELECT 1;\n", fs::read_to_string(&template).unwrap());
+    }
+
+    #[test]
+    fn test_render_check_formatter() {
+        let dir = TempDir::new("render-check-fmt");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            &format!(
+                r#"
+[formatter.pgFormatter]
+exec_path = "{0}/nonexistent/pg_format"
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+"#,
+                dir.display()
+            ),
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let args = RenderArgs {
+            check_formatter: true,
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        match render_metadata(
+            &metadata,
+            &args,
+            &dir.join("tapestry.toml"),
+            &dir.join(LOCK_FILE),
+        ) {
+            Err(Error::FormatterUnavailable(exec)) => {
+                assert!(exec.ends_with("nonexistent/pg_format"));
+            }
+            res => panic!("{res:?}"),
+        }
+        // Nothing is written, not even the output dirs
+        assert!(!dir.join("out").exists());
+    }
+
+    #[test]
+    fn test_render_since_lock_formatter_change() {
+        let dir = TempDir::new("render-lock-fmt");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(
+            dir.join("templates/artists.sql.j2"),
+            "select id,name from artist;\n",
+        )
+        .unwrap();
+        let manifest = |formatter: &str| {
+            test_manifest(
+                dir.join("templates"),
+                dir.join("out"),
+                &format!(
+                    r#"
+{formatter}
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+"#
+                ),
+            )
+        };
+        let manifest_path = dir.join("tapestry.toml");
+        let lock_path = dir.join(LOCK_FILE);
+        let output = dir.join("out/queries/artists.sql");
+        let args = RenderArgs {
+            since_lock: true,
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        let render = |manifest: String| {
+            let metadata: Metadata = manifest.parse().unwrap();
+            render_metadata(&metadata, &args, &manifest_path, &lock_path).unwrap();
+            fs::read_to_string(&output).unwrap()
+        };
+
+        let unformatted = render(manifest(""));
+        assert_eq!("select id,name from artist;", unformatted);
+        // Only the formatter is changed, which must not be skipped as
+        // unchanged since the last render
+        let formatted = render(manifest("[formatter]\nsqlformat-rs = {}"));
+        assert_ne!(unformatted, formatted);
+        // Same for the options of the formatter
+        let lowercased = render(manifest(
+            "[formatter]\nsqlformat-rs = { uppercase = false }",
+        ));
+        assert_ne!(formatted, lowercased);
+    }
+
+    #[test]
+    fn test_render_only_changed() {
+        let dir = TempDir::new("render-only-changed");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        fs::write(
+            dir.join("templates/tests/artists_test.sql.j2"),
+            "SELECT 2;\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"header_template = "-- generated at {{ generated_at }}\n"
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        let metadata: Metadata = manifest.parse().unwrap();
+        let args = RenderArgs {
+            only_changed: true,
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        let render = || render_metadata(&metadata, &args, &manifest_path, &dir.join(LOCK_FILE));
+        assert_eq!(Some(2), render().unwrap());
+
+        // The files are not written again if only `generated_at`
+        // would be different
+        let output = dir.join("out/queries/artists.sql");
+        let contents = fs::read_to_string(&output).unwrap();
+        let older = format!(
+            "-- generated at 2024-05-01T09:30:00Z\n{}",
+            contents.split_once('\n').unwrap().1
+        );
+        assert_ne!(older, contents);
+        fs::write(&output, &older).unwrap();
+        assert_eq!(Some(0), render().unwrap());
+        assert_eq!(older, fs::read_to_string(&output).unwrap());
+
+        // ..but they are if anything else changes
+        fs::write(dir.join("templates/artists.sql.j2"), "SELECT 3;\n").unwrap();
+        assert_eq!(Some(1), render().unwrap());
+        assert!(fs::read_to_string(&output).unwrap().ends_with("SELECT 3;"));
+    }
+
+    #[test]
+    fn test_render_disabled_queries() {
+        let dir = TempDir::new("render-disabled");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(dir.join("templates/ok.sql.j2"), "SELECT 1;\n").unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+query_output_layout = "one-file-all-queries"
+query_output_file = "queries.sql"
+
+[name_tagger]
+style = "kebab-case"
+
+[[query_templates]]
+path = "ok.sql.j2"
+all_conds = [ "eu" ]
+
+[[queries]]
+id = "ok_eu"
+template = "ok.sql.j2"
+enabled_if = "eu"
+"#,
+        );
+        let manifest_path = dir.join("tapestry.toml");
+        let metadata: Metadata = manifest.parse().unwrap();
+        let combined_output = dir.join("out/queries/queries.sql");
+
+        // The only query is disabled, so there's nothing to write
+        let args = RenderArgs {
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        assert_eq!(
+            Some(0),
+            render_metadata(&metadata, &args, &manifest_path, &dir.join(LOCK_FILE)).unwrap()
+        );
+        assert!(!combined_output.exists());
+
+        // It's enabled when the cond is turned on
+        let args = RenderArgs {
+            conds: CondArgs {
+                cond: vec!["eu".to_owned()],
+                ..CondArgs::default()
+            },
+            quiet: true,
+            ..RenderArgs::default()
+        };
+        assert_eq!(
+            Some(1),
+            render_metadata(&metadata, &args, &manifest_path, &dir.join(LOCK_FILE)).unwrap()
+        );
+        assert!(combined_output.exists());
+    }
+
+    #[test]
+    fn test_queries_to_render_group() {
+        let manifest = test_manifest(
+            "templates",
+            "output",
+            r#"
+[[query_templates]]
+path = "invoices.sql.j2"
+
+[[queries]]
+id = "invoices_all"
+template = "invoices.sql.j2"
+
+[[groups]]
+name = "billing"
+
+[[groups.queries]]
+id = "invoices_due"
+template = "invoices.sql.j2"
+
+[[groups.queries]]
+id = "invoices_paid"
+template = "invoices.sql.j2"
+tags = [ "nightly" ]
+
+[[groups]]
+name = "reporting"
+
+[[groups.queries]]
+id = "invoices_monthly"
+template = "invoices.sql.j2"
+tags = [ "nightly" ]
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let ids = |group, tags: &[&str]| {
+            let tags = tags.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+            queries_to_render(&metadata, None, false, &tags, group)
+                .iter()
+                .map(|q| q.id.clone())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            vec![
+                "invoices_all",
+                "invoices_due",
+                "invoices_paid",
+                "invoices_monthly"
+            ],
+            ids(None, &[])
+        );
+        assert_eq!(
+            vec!["invoices_due", "invoices_paid"],
+            ids(Some("billing"), &[])
+        );
+        assert_eq!(vec!["invoices_paid"], ids(Some("billing"), &["nightly"]));
+        assert!(ids(Some("archive"), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_queries_to_render_tagged() {
+        let manifest = test_manifest(
+            "templates",
+            "output",
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists_daily"
+template = "artists.sql.j2"
+tags = [ "reporting", "nightly" ]
+
+[[queries]]
+id = "artists_adhoc"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "artists_weekly"
+template = "artists.sql.j2"
+tags = [ "nightly" ]
+skip = true
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let ids = |query_id, include_skipped, tags: &[&str]| {
+            let tags = tags.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+            queries_to_render(&metadata, query_id, include_skipped, &tags, None)
+                .iter()
+                .map(|q| q.id.clone())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            vec!["artists_daily", "artists_adhoc"],
+            ids(None, false, &[])
+        );
+        assert_eq!(vec!["artists_daily"], ids(None, false, &["nightly"]));
+        assert_eq!(
+            vec!["artists_daily", "artists_weekly"],
+            ids(None, true, &["nightly"])
+        );
+        assert!(ids(Some("artists_adhoc"), false, &["reporting"]).is_empty());
+
+        // Unknown tags match nothing
+        assert!(ids(None, true, &["monthly"]).is_empty());
+        assert_eq!(
+            vec!["monthly"],
+            metadata.queries.unknown_tags(&["monthly".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_cov_threshold_parser() {
+        // Percentage
+        assert_eq!(Ok(CovMinimum::Pcent(90.0)), cov_threshold_parser("90"));
+        assert_eq!(Ok(CovMinimum::Pcent(90.0)), cov_threshold_parser("90%"));
+        assert_eq!(Ok(CovMinimum::Pcent(90.5)), cov_threshold_parser("90.5"));
+        assert_eq!(Ok(CovMinimum::Pcent(0.0)), cov_threshold_parser("0"));
+        assert_eq!(Ok(CovMinimum::Pcent(1.0)), cov_threshold_parser("1"));
+        assert_eq!(Ok(CovMinimum::Pcent(100.0)), cov_threshold_parser("100%"));
+        assert_eq!(Ok(CovMinimum::Pcent(0.5)), cov_threshold_parser("0.5%"));
+        assert_eq!(
+            Err("threshold not in range 0..=100".to_owned()),
+            cov_threshold_parser("101")
+        );
+        assert_eq!(
+            Err("threshold is not a number".to_owned()),
+            cov_threshold_parser("abc")
+        );
+        assert!(cov_threshold_parser("-1").is_err());
+        assert!(cov_threshold_parser("NaN").is_err());
+
+        // Ratio
+        assert_eq!(Ok(CovMinimum::Pcent(80.0)), cov_threshold_parser("0.8"));
+        assert_eq!(Ok(CovMinimum::Pcent(100.0)), cov_threshold_parser("1.0"));
+        assert_eq!(Ok(CovMinimum::Pcent(0.0)), cov_threshold_parser("0.0"));
+
+        // Count
+        assert_eq!(Ok(CovMinimum::Count(40)), cov_threshold_parser("count:40"));
+        assert_eq!(Ok(CovMinimum::Count(0)), cov_threshold_parser("count:0"));
+        assert_eq!(
+            Err("count is not a non-negative integer".to_owned()),
+            cov_threshold_parser("count:4.5")
+        );
+        assert!(cov_threshold_parser("count:-1").is_err());
+        assert!(cov_threshold_parser("count:").is_err());
+    }
+
+    #[test]
+    fn test_fail_under_parser() {
+        assert_eq!(
+            Ok(CovThreshold {
+                tag: None,
+                min: CovMinimum::Pcent(80.0)
+            }),
+            fail_under_parser("80%")
+        );
+        assert_eq!(
+            Ok(CovThreshold {
+                tag: Some("critical".to_owned()),
+                min: CovMinimum::Pcent(95.0)
+            }),
+            fail_under_parser("critical=95")
+        );
+        assert_eq!(
+            Ok(CovThreshold {
+                tag: Some("critical".to_owned()),
+                min: CovMinimum::Count(3)
+            }),
+            fail_under_parser("critical=count:3")
+        );
+        assert_eq!(
+            Err("threshold not in range 0..=100".to_owned()),
+            fail_under_parser("critical=101")
+        );
+        assert!(fail_under_parser("=90").is_err());
+    }
+
+    #[test]
+    fn test_cov_minimum_is_met() {
+        let cov = Coverage {
+            tested: 3,
+            total: 4,
+        };
+        assert!(CovMinimum::Pcent(75.0).is_met(&cov));
+        assert!(!CovMinimum::Pcent(75.5).is_met(&cov));
+        assert!(cov_threshold_parser("0.75").unwrap().is_met(&cov));
+        assert!(!cov_threshold_parser("0.8").unwrap().is_met(&cov));
+        assert!(CovMinimum::Count(3).is_met(&cov));
+        // Fails when fewer queries than required are tested
+        assert!(!CovMinimum::Count(4).is_met(&cov));
+        assert!(!cov_threshold_parser("count:40").unwrap().is_met(&cov));
+
+        // Any percentage is met when there are no queries
+        let cov = Coverage::default();
+        assert_eq!(100.0, cov.pcent());
+        assert!(CovMinimum::Pcent(100.0).is_met(&cov));
+        assert!(!CovMinimum::Count(1).is_met(&cov));
+    }
+
+    #[test]
+    fn test_coverage_by_tag() {
+        let manifest = test_manifest(
+            "templates",
+            "output",
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists_daily"
+template = "artists.sql.j2"
+tags = [ "critical", "nightly" ]
+
+[[queries]]
+id = "artists_monthly"
+template = "artists.sql.j2"
+tags = [ "critical" ]
+
+[[queries]]
+id = "artists_adhoc"
+template = "artists.sql.j2"
+tags = [ "experimental" ]
+
+[[queries]]
+id = "artists_all"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists_daily"
+path = "artists_daily_test.sql.j2"
+
+[[test_templates]]
+query = "artists_all"
+path = "artists_all_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let queries = metadata.queries.active(false).collect::<Vec<&Arc<Query>>>();
+        let cov = coverage_by_tag(&metadata, &queries);
+        let cov = |tag| cov.get(tag).map(|c| (c.tested, c.total, c.pcent()));
+        assert_eq!(Some((1, 2, 50.0)), cov("critical"));
+        assert_eq!(Some((1, 1, 100.0)), cov("nightly"));
+        assert_eq!(Some((0, 1, 0.0)), cov("experimental"));
+        assert_eq!(Some((1, 1, 100.0)), cov(UNTAGGED));
+        assert_eq!(None, cov("monthly"));
+    }
+
+    #[test]
+    fn test_db_url_precedence() {
+        let dir = TempDir::new("db-url");
+        let url_file = dir.join("db_url");
+        fs::write(&url_file, "postgres://file@localhost/chinook\n").unwrap();
+        let env = |name: &str| match name {
+            "TAPESTRY_DB_URL" => Some("postgres://env@localhost/chinook".to_owned()),
+            _ => None,
+        };
+        let no_env = |_: &str| None;
+
+        let args = DbUrlArgs::default();
+        assert_eq!(
+            "postgres://env@localhost/chinook",
+            args.resolve_with(env).unwrap()
+        );
+        match args.resolve_with(no_env) {
+            Err(Error::Cli(msg)) => assert!(msg.contains("TAPESTRY_DB_URL")),
+            _ => panic!(),
+        }
+
+        // The file takes precedence over the env var
+        let args = DbUrlArgs {
+            url: None,
+            url_file: Some(url_file.clone()),
+        };
+        assert_eq!(
+            "postgres://file@localhost/chinook",
+            args.resolve_with(env).unwrap()
+        );
+
+        // `--url` takes precedence over both
+        let args = DbUrlArgs {
+            url: Some("postgres://cli@localhost/chinook".to_owned()),
+            url_file: Some(url_file),
+        };
+        assert_eq!(
+            "postgres://cli@localhost/chinook",
+            args.resolve_with(env).unwrap()
+        );
+
+        let args = DbUrlArgs {
+            url: None,
+            url_file: Some(dir.join("missing")),
+        };
+        assert!(args.resolve_with(env).is_err());
+    }
+
+    #[test]
+    fn test_unformatted_templates() {
+        let dir = TempDir::new("fmt");
+        fs::create_dir_all(dir.join("templates/tests")).unwrap();
+        fs::write(
+            dir.join("templates/artists.sql.j2"),
+            "select * from artists {% if cond__genre %}where genre = {{ placeholder('genre') }}{% endif %};\n",
+        )
+        .unwrap();
+        let formatted_test = "SELECT\n    plan(1);\n";
+        fs::write(
+            dir.join("templates/tests/artists_test.sql.j2"),
+            formatted_test,
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[formatter.builtin]
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = ["genre"]
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        // Only the query template needs formatting
+        let unformatted = unformatted_templates(&metadata).unwrap();
+        assert_eq!(1, unformatted.len());
+        let (path, contents) = &unformatted[0];
+        assert_eq!(&dir.join("templates/artists.sql.j2"), path);
+        assert!(contents.contains("{% if cond__genre %}"));
+        assert!(contents.contains("{{ placeholder('genre') }}{% endif %};"));
+
+        // Once formatted, there's nothing to report
+        fs::write(path, contents).unwrap();
+        assert!(unformatted_templates(&metadata).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unformatted_templates_by_dialect() {
+        let dir = TempDir::new("fmt-dialect");
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        for name in ["artists", "albums", "songs"] {
+            fs::write(
+                dir.join(format!("templates/{name}.sql.j2")),
+                format!("select * from {name};\n"),
+            )
+            .unwrap();
+        }
+        let manifest = test_manifest(
+            dir.join("templates"),
+            dir.join("out"),
+            r#"
+[formatter.by_dialect.postgres]
+sqlformat-rs = {}
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[query_templates]]
+path = "albums.sql.j2"
+
+[[query_templates]]
+path = "songs.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+dialect = "postgres"
+
+[[queries]]
+id = "albums"
+template = "albums.sql.j2"
+dialect = "mysql"
+
+[[queries]]
+id = "songs"
+template = "songs.sql.j2"
+dialect = "postgres"
+
+[[queries]]
+id = "songs_mysql"
+template = "songs.sql.j2"
+dialect = "mysql"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        // Only the template of the postgres query is formatted, as
+        // there's no formatter for mysql and the queries using
+        // `songs.sql.j2` have different formatters
+        let unformatted = unformatted_templates(&metadata).unwrap();
+        assert_eq!(1, unformatted.len());
+        let (path, contents) = &unformatted[0];
+        assert_eq!(&dir.join("templates/artists.sql.j2"), path);
+        assert_eq!("SELECT\n    *\nFROM\n    artists;\n", contents);
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fmt;
+
+/// Boolean expression over the conds, for enabling a query only when
+/// a combination of conds holds e.g. `eu and not (legacy or beta)`
+///
+/// `not` binds tighter than `and`, which binds tighter than `or`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CondExpr {
+    Cond(String),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Ident(&'a str),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = vec![];
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' {
+                Token::LParen
+            } else {
+                Token::RParen
+            });
+            rest = &rest[1..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let token = match &rest[..end] {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                ident => Token::Ident(ident),
+            };
+            tokens.push(token);
+            rest = &rest[end..];
+        } else {
+            return Err(format!("unexpected character '{c}'"));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, one method per level of precedence
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token<'a>> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn or_expr(&mut self) -> Result<CondExpr, String> {
+        let mut expr = self.and_expr()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = CondExpr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<CondExpr, String> {
+        let mut expr = self.not_expr()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = CondExpr::And(Box::new(expr), Box::new(self.not_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn not_expr(&mut self) -> Result<CondExpr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(CondExpr::Not(Box::new(self.not_expr()?))),
+            Some(Token::Ident(name)) => Ok(CondExpr::Cond((*name).to_owned())),
+            Some(Token::LParen) => {
+                let expr = self.or_expr()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("expected ')'".to_owned()),
+                }
+            }
+            Some(t) => Err(format!("unexpected {}", describe(t))),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(name) => format!("'{name}'"),
+        Token::And => "'and'".to_owned(),
+        Token::Or => "'or'".to_owned(),
+        Token::Not => "'not'".to_owned(),
+        Token::LParen => "'('".to_owned(),
+        Token::RParen => "')'".to_owned(),
+    }
+}
+
+impl CondExpr {
+    /// Parses the expression `s`, returning the reason in case it's
+    /// not valid
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or_expr()?;
+        match parser.peek() {
+            Some(t) => Err(format!("unexpected {}", describe(t))),
+            None => Ok(expr),
+        }
+    }
+
+    /// Returns whether the expression holds, given the conds that
+    /// are on
+    pub fn eval(&self, conds: &HashSet<String>) -> bool {
+        match self {
+            Self::Cond(name) => conds.contains(name),
+            Self::Not(e) => !e.eval(conds),
+            Self::And(a, b) => a.eval(conds) && b.eval(conds),
+            Self::Or(a, b) => a.eval(conds) || b.eval(conds),
+        }
+    }
+
+    /// Returns the names of the conds referenced in the expression,
+    /// each only once and in the order of their first occurrence
+    pub fn conds(&self) -> Vec<&str> {
+        let mut result = vec![];
+        self.collect_conds(&mut result);
+        result
+    }
+
+    fn collect_conds<'a>(&'a self, result: &mut Vec<&'a str>) {
+        match self {
+            Self::Cond(name) => {
+                if !result.contains(&name.as_str()) {
+                    result.push(name);
+                }
+            }
+            Self::Not(e) => e.collect_conds(result),
+            Self::And(a, b) | Self::Or(a, b) => {
+                a.collect_conds(result);
+                b.collect_conds(result);
+            }
+        }
+    }
+}
+
+/// Prints the expression with parentheses around every compound sub
+/// expression, so that it parses back to the same value
+impl fmt::Display for CondExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sub = |e: &CondExpr| match e {
+            Self::Cond(_) | Self::Not(_) => e.to_string(),
+            _ => format!("({e})"),
+        };
+        match self {
+            Self::Cond(name) => write!(f, "{name}"),
+            Self::Not(e) => write!(f, "not {}", sub(e)),
+            Self::And(a, b) => write!(f, "{} and {}", sub(a), sub(b)),
+            Self::Or(a, b) => write!(f, "{} or {}", sub(a), sub(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn conds(xs: &[&str]) -> HashSet<String> {
+        xs.iter().map(|s| (*s).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cond = |s: &str| Box::new(CondExpr::Cond(s.to_owned()));
+        assert_eq!(
+            CondExpr::And(cond("eu"), Box::new(CondExpr::Not(cond("legacy")))),
+            CondExpr::parse("eu and not legacy").unwrap()
+        );
+        // `and` binds tighter than `or`
+        assert_eq!(
+            CondExpr::Or(cond("a"), Box::new(CondExpr::And(cond("b"), cond("c")))),
+            CondExpr::parse("a or b and c").unwrap()
+        );
+        assert_eq!(
+            CondExpr::And(Box::new(CondExpr::Or(cond("a"), cond("b"))), cond("c")),
+            CondExpr::parse(" (a or b)and c ").unwrap()
+        );
+        for expr in [
+            "eu and not legacy",
+            "not (a or b) and c",
+            "a or b and not c",
+        ] {
+            let parsed = CondExpr::parse(expr).unwrap();
+            assert_eq!(parsed, CondExpr::parse(&parsed.to_string()).unwrap());
+        }
+
+        assert_eq!(
+            Err("unexpected end of expression".to_owned()),
+            CondExpr::parse("")
+        );
+        assert_eq!(
+            Err("unexpected end of expression".to_owned()),
+            CondExpr::parse("eu and")
+        );
+        assert_eq!(Err("expected ')'".to_owned()), CondExpr::parse("(eu"));
+        assert_eq!(
+            Err("unexpected 'legacy'".to_owned()),
+            CondExpr::parse("eu legacy")
+        );
+        assert_eq!(
+            Err("unexpected character '&'".to_owned()),
+            CondExpr::parse("eu && legacy")
+        );
+    }
+
+    #[test]
+    fn test_eval() {
+        let expr = CondExpr::parse("eu and not legacy").unwrap();
+        assert!(expr.eval(&conds(&["eu"])));
+        assert!(expr.eval(&conds(&["eu", "beta"])));
+        assert!(!expr.eval(&conds(&["eu", "legacy"])));
+        assert!(!expr.eval(&conds(&[])));
+
+        let expr = CondExpr::parse("not (us or eu)").unwrap();
+        assert!(expr.eval(&conds(&["apac"])));
+        assert!(!expr.eval(&conds(&["eu"])));
+    }
+
+    #[test]
+    fn test_conds() {
+        let expr = CondExpr::parse("eu and not (legacy or eu) or beta").unwrap();
+        assert_eq!(vec!["eu", "legacy", "beta"], expr.conds());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::error::Error;
+use log::warn;
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Env var for the editor in which the output files are opened by
+/// `render --open`
+const EDITOR_VAR: &str = "EDITOR";
+
+/// Opens `paths` in the editor configured using the `EDITOR` env var
+///
+/// The value of the env var may include args e.g. `code --wait`. If
+/// it's not set, the files are not opened and a warning is logged
+/// instead. Returns whether the files were opened.
+pub fn open(paths: &[&Path]) -> Result<bool, Error> {
+    open_with(env::var(EDITOR_VAR).ok(), paths, |args, paths| {
+        let status = Command::new(args[0])
+            .args(&args[1..])
+            .args(paths)
+            .status()
+            .map_err(|e| e.to_string())?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("exited with {status}"))
+        }
+    })
+}
+
+fn open_with<F>(editor: Option<String>, paths: &[&Path], exec: F) -> Result<bool, Error>
+where
+    F: FnOnce(&[&str], &[&Path]) -> Result<(), String>,
+{
+    let editor = editor.unwrap_or_default();
+    let args = editor.split_whitespace().collect::<Vec<&str>>();
+    if args.is_empty() {
+        warn!("Not opening the output files as the '{EDITOR_VAR}' env var is not set");
+        return Ok(false);
+    }
+    if paths.is_empty() {
+        return Ok(false);
+    }
+    exec(&args, paths).map_err(|e| Error::Editor(format!("{editor}: {e}")))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_open_without_editor() {
+        let paths = [Path::new("output/queries/artists_long_songs.sql")];
+        let opened = open_with(None, &paths, |_, _| panic!("editor must not be run"));
+        assert!(!opened.unwrap());
+        let opened = open_with(Some(" ".to_owned()), &paths, |_, _| {
+            panic!("editor must not be run")
+        });
+        assert!(!opened.unwrap());
+    }
+
+    #[test]
+    fn test_open_with_editor() {
+        let paths = [
+            Path::new("output/queries/artists_long_songs.sql"),
+            Path::new("output/tests/all_artists_long_songs_count_test.sql"),
+        ];
+        let mut calls = vec![];
+        let opened = open_with(Some("code --wait".to_owned()), &paths, |args, paths| {
+            calls.push((args.join(" "), paths.len()));
+            Ok(())
+        });
+        assert!(opened.unwrap());
+        assert_eq!(vec![("code --wait".to_owned(), 2)], calls);
+
+        let res = open_with(Some("vim".to_owned()), &paths, |_, _| {
+            Err("exited with exit status: 1".to_owned())
+        });
+        match res {
+            Err(Error::Editor(msg)) => assert_eq!("vim: exited with exit status: 1", msg),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+/// Env vars loaded from a dotenv file (`--env-file`)
+///
+/// The vars are only used for the lookups that tapestry makes e.g.
+/// for the `TAPESTRY_*_DIR` overrides, and are never set in the
+/// environment of the process.
+#[derive(Debug)]
+pub struct EnvFile {
+    vars: HashMap<String, String>,
+    /// Whether the values in the file take precedence over the ones
+    /// in the real environment
+    override_env: bool,
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses the `KEY=VALUE` pairs in the contents of a dotenv file
+///
+/// Blank lines and lines starting with `#` are ignored, and so is an
+/// `export` prefix. Values may be enclosed in single or double quotes
+/// which are removed. Surrounding whitespace is removed from keys as
+/// well as unquoted values.
+fn parse(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut vars = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+        let key = key.trim();
+        if !is_valid_key(key) {
+            return Err(format!("line {}: invalid key '{key}'", i + 1));
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+            .unwrap_or(value);
+        vars.insert(key.to_owned(), value.to_owned());
+    }
+    Ok(vars)
+}
+
+impl EnvFile {
+    pub fn read(path: &Path, override_env: bool) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            Error::Cli(format!("Could not read env file '{}': {e}", path.display()))
+        })?;
+        let vars = parse(&contents)
+            .map_err(|e| Error::Cli(format!("Invalid env file '{}': {e}", path.display())))?;
+        Ok(Self { vars, override_env })
+    }
+
+    /// Returns the value of the env var `name` from the real
+    /// environment or the file, as per the precedence
+    pub fn var(&self, name: &str) -> Option<OsString> {
+        self.var_with(name, |n| env::var_os(n))
+    }
+
+    fn var_with<F: Fn(&str) -> Option<OsString>>(
+        &self,
+        name: &str,
+        get_var: F,
+    ) -> Option<OsString> {
+        let from_file = || self.vars.get(name).map(OsString::from);
+        if self.override_env {
+            from_file().or_else(|| get_var(name))
+        } else {
+            get_var(name).or_else(from_file)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = r#"
+# Dirs for the dev env
+TAPESTRY_QUERIES_OUTPUT_DIR=build/queries
+export TAPESTRY_TESTS_OUTPUT_DIR = "build/tests"
+GREETING='hello = world'
+EMPTY=
+"#;
+        let vars = parse(contents).unwrap();
+        assert_eq!(4, vars.len());
+        assert_eq!("build/queries", vars["TAPESTRY_QUERIES_OUTPUT_DIR"]);
+        assert_eq!("build/tests", vars["TAPESTRY_TESTS_OUTPUT_DIR"]);
+        assert_eq!("hello = world", vars["GREETING"]);
+        assert_eq!("", vars["EMPTY"]);
+
+        assert_eq!(
+            Err("line 2: expected KEY=VALUE".to_owned()),
+            parse("A=1\nB\n")
+        );
+        assert_eq!(Err("line 1: invalid key '1A'".to_owned()), parse("1A=1\n"));
+    }
+
+    #[test]
+    fn test_var_precedence() {
+        let vars = parse("TAPESTRY_QUERIES_OUTPUT_DIR=from_file\nONLY_IN_FILE=x\n").unwrap();
+        let get_var = |name: &str| {
+            (name == "TAPESTRY_QUERIES_OUTPUT_DIR").then(|| OsString::from("from_env"))
+        };
+
+        // The real env takes precedence by default
+        let file = EnvFile {
+            vars: vars.clone(),
+            override_env: false,
+        };
+        assert_eq!(
+            Some(OsString::from("from_env")),
+            file.var_with("TAPESTRY_QUERIES_OUTPUT_DIR", get_var)
+        );
+        assert_eq!(
+            Some(OsString::from("x")),
+            file.var_with("ONLY_IN_FILE", get_var)
+        );
+        assert_eq!(None, file.var_with("UNKNOWN", get_var));
+
+        // ..unless the file is set to override it
+        let file = EnvFile {
+            vars,
+            override_env: true,
+        };
+        assert_eq!(
+            Some(OsString::from("from_file")),
+            file.var_with("TAPESTRY_QUERIES_OUTPUT_DIR", get_var)
+        );
+    }
+}
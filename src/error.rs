@@ -1,19 +1,54 @@
 use std::fmt::{self, Display};
 use std::io;
+use std::path::PathBuf;
+
+/// Errors encountered when scaffolding a new project using the
+/// `init` command
+#[derive(Debug)]
+pub enum ScaffoldError {
+    /// The dir already contains a manifest file
+    AlreadyInitialized(PathBuf),
+    /// The dir already exists
+    DirExists(PathBuf),
+    Io(io::Error),
+}
+
+impl Display for ScaffoldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AlreadyInitialized(dir) => {
+                write!(f, "Dir is already a tapestry project: {}", dir.display())
+            }
+            Self::DirExists(dir) => write!(f, "Dir already exists: {}", dir.display()),
+            Self::Io(e) => write!(f, "I/O Error: {e:?}"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Toml(toml::de::Error),
     Parsing(String),
+    InvalidMetadata(String),
     UndefinedQuery(String),
     UndefinedQueryTemplate(String),
     UndefinedTestTemplate(String),
+    UndefinedOutput(String),
+    UndefinedProfile(String),
     MiniJinja(minijinja::Error),
-    Scaffolding(String),
+    Sandbox(String),
+    Scaffolding(ScaffoldError),
     ManifestNotFound,
     QueryOutputParsing(String),
     Layout(String),
+    FormatterUnavailable(String),
+    FormatterFailed(String),
+    EmptyOutput(String),
+    InvalidSql { query_id: String, msg: String },
+    Db(String),
+    Hook(String),
+    Editor(String),
     Cli(String),
 }
 
@@ -24,12 +59,13 @@ impl Display for Error {
                 write!(f, "Manifest file 'tapestry.toml' not found\nTip: Ensure you're inside the correct directory")
             }
             Self::Cli(msg) => write!(f, "Command error: {msg}"),
-            Self::Scaffolding(msg) => {
-                write!(f, "Error initializing new tapestry project\nReason: {msg}")
+            Self::Scaffolding(e) => {
+                write!(f, "Error initializing new tapestry project\nReason: {e}")
             }
             Self::Io(e) => write!(f, "I/O Error: {e:?}"),
             Self::Toml(e) => write!(f, "TOML Error: {e:?}"),
             Self::Parsing(msg) => write!(f, "Error parsing manifest file: {msg}"),
+            Self::InvalidMetadata(msg) => write!(f, "Invalid metadata:\n{msg}"),
             Self::UndefinedQuery(id) => write!(f, "Lookup for query failed: id={id}"),
             Self::UndefinedQueryTemplate(path) => {
                 write!(f, "Lookup for query template failed: path={path}")
@@ -37,11 +73,37 @@ impl Display for Error {
             Self::UndefinedTestTemplate(path) => {
                 write!(f, "Lookup for test template failed: path={path}")
             }
+            Self::UndefinedProfile(name) => {
+                write!(f, "Profile not defined in manifest: name={name}")
+            }
+            Self::UndefinedOutput(path) => {
+                write!(f, "Not a known query or test output file: path={path}")
+            }
             Self::MiniJinja(e) => write!(f, "MiniJinja Error: {e:?}"),
+            Self::Sandbox(msg) => write!(
+                f,
+                "Not allowed in sandbox mode: {msg}\nTip: Only the allowlisted filters and functions are available with `--sandbox`"
+            ),
             Self::QueryOutputParsing(msg) => {
                 write!(f, "Failed to parse the query output file: {msg}")
             }
             Self::Layout(msg) => write!(f, "Layout error: {msg}"),
+            Self::EmptyOutput(id) => {
+                write!(f, "Rendered query is empty: id={id}\nTip: Check the conds of the query and the template")
+            }
+            Self::InvalidSql { query_id, msg } => {
+                write!(
+                    f,
+                    "Rendered query is not valid SQL: id={query_id}\nReason: {msg}"
+                )
+            }
+            Self::Db(msg) => write!(f, "Database error: {msg}"),
+            Self::Hook(msg) => write!(f, "Post render hook failed:\n{msg}"),
+            Self::Editor(msg) => write!(f, "Failed to open the output files in the editor: {msg}"),
+            Self::FormatterFailed(msg) => write!(f, "Formatter failed: {msg}"),
+            Self::FormatterUnavailable(exec) => {
+                write!(f, "Formatter executable could not be run: {exec}\nTip: Ensure that the formatter is installed or update the 'formatter' section in the manifest")
+            }
         }
     }
 }
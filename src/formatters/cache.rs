@@ -0,0 +1,371 @@
+use crate::lockfile::Fnv;
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+/// Max no. of formatted outputs to be cached
+const CAPACITY: usize = 1024;
+
+/// Max no. of formatted outputs to be kept in the on-disk cache
+const DISK_CAPACITY: usize = 10_000;
+
+/// LRU cache of formatted outputs keyed by hash of the formatter
+/// config and the input
+///
+/// Formatting using an external formatter involves spawning a
+/// process, so when the same input is formatted more than once
+/// during an invocation (e.g. by `status` and `render` in watch
+/// mode), the cached output is returned instead. The input is stored
+/// along with the output, so that an entry is returned only if its
+/// input is the same and not merely its hash.
+///
+/// The cache is shared by the threads formatting in parallel, but
+/// it's locked only while looking up or updating the entries in
+/// memory, and not while formatting or accessing the disk. So two
+/// threads may format the same input at the same time on a miss,
+/// which is harmless as both get the same output.
+pub struct FormatCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    // Maps key to input and formatted output along with the "time"
+    // when the entry was last used
+    entries: HashMap<u64, (String, Vec<u8>, u64)>,
+    clock: u64,
+    /// Cache shared across invocations, looked up on a miss
+    disk: Option<DiskCache>,
+}
+
+impl State {
+    // Evicts the least recently used entry if the cache is full
+    // before inserting the entry
+    fn insert(&mut self, capacity: usize, key: u64, input: &str, output: &[u8]) {
+        if self.entries.len() >= capacity && !self.entries.contains_key(&key) {
+            let lru_key = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, last_used))| *last_used)
+                .map(|(k, _)| *k);
+            if let Some(k) = lru_key {
+                self.entries.remove(&k);
+            }
+        }
+        self.entries
+            .insert(key, (input.to_owned(), output.to_vec(), self.clock));
+    }
+}
+
+/// On-disk cache of formatted outputs, with one file per key
+///
+/// The entries are stored in a subdir named after the version of
+/// tapestry, as the formatted outputs may change across versions.
+/// Each file has the length of the input on the first line followed
+/// by the input and the output. Errors in reading or writing the
+/// entries are not fatal, they're treated as misses.
+#[derive(Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.join(concat!("v", env!("CARGO_PKG_VERSION"))),
+        }
+    }
+
+    fn entry_path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}"))
+    }
+
+    fn get(&self, key: u64, input: &str) -> Option<Vec<u8>> {
+        let mut contents = fs::read(self.entry_path(key)).ok()?;
+        let newline = contents.iter().position(|b| *b == b'\n')?;
+        let len = std::str::from_utf8(&contents[..newline])
+            .ok()?
+            .parse::<usize>()
+            .ok()?;
+        let start = newline + 1;
+        let stored_input = contents.get(start..start.checked_add(len)?)?;
+        if stored_input != input.as_bytes() {
+            return None;
+        }
+        Some(contents.split_off(start + len))
+    }
+
+    // The entry is written to a temp file which is then renamed, so
+    // that a concurrent invocation never reads a partial entry
+    fn put(&self, key: u64, input: &str, output: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.entry_path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let mut contents = format!("{}\n{input}", input.len()).into_bytes();
+        contents.extend_from_slice(output);
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// Removes the entries written the earliest so that at most
+    /// `capacity` entries remain
+    fn evict(&self, capacity: usize) -> io::Result<()> {
+        let mut entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|e| {
+                    let e = e.ok()?;
+                    let mtime = e.metadata().and_then(|m| m.modified()).ok()?;
+                    Some((mtime, e.path()))
+                })
+                .collect::<Vec<(SystemTime, PathBuf)>>(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if entries.len() > capacity {
+            entries.sort_unstable();
+            for (_, path) in &entries[..entries.len() - capacity] {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FormatCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                clock: 0,
+                disk: None,
+            }),
+        }
+    }
+
+    // A poisoned lock only means that another thread panicked while
+    // using the cache, which is still usable
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_disk(&self, disk: Option<DiskCache>) {
+        self.state().disk = disk;
+    }
+
+    /// Returns the key for formatting `input` with a formatter
+    /// having config `config`
+    ///
+    /// The key is stable across builds (so that it can be used for
+    /// the on-disk cache) and includes the version of tapestry.
+    pub fn key(config: &str, input: &str) -> u64 {
+        let mut hasher = Fnv::default();
+        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.update(config.as_bytes());
+        hasher.update(input.as_bytes());
+        hasher.finish()
+    }
+
+    /// Returns the cached output of `input` for `key` if present (in
+    /// memory or else on disk), otherwise computes it by calling
+    /// `format` and caches it, evicting the least recently used entry
+    /// if the cache is full
+    ///
+    /// If `format` fails, the error is returned and nothing is
+    /// cached.
+    pub fn get_or_insert_with<E, F>(&self, key: u64, input: &str, format: F) -> Result<Vec<u8>, E>
+    where
+        F: FnOnce() -> Result<Vec<u8>, E>,
+    {
+        let disk = {
+            let mut state = self.state();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some((cached_input, output, last_used)) = state.entries.get_mut(&key) {
+                if cached_input == input {
+                    *last_used = clock;
+                    return Ok(output.clone());
+                }
+            }
+            state.disk.clone()
+        };
+        let from_disk = disk.as_ref().and_then(|d| d.get(key, input));
+        let output = match from_disk {
+            Some(output) => output,
+            None => {
+                let output = format()?;
+                if let Some(d) = &disk {
+                    if let Err(e) = d.put(key, input, &output) {
+                        warn!("Could not write to the cache dir: {e}");
+                    }
+                }
+                output
+            }
+        };
+        self.state().insert(self.capacity, key, input, &output);
+        Ok(output)
+    }
+}
+
+/// Returns the cache shared across a single invocation
+pub fn shared() -> &'static FormatCache {
+    static CACHE: OnceLock<FormatCache> = OnceLock::new();
+    CACHE.get_or_init(|| FormatCache::new(CAPACITY))
+}
+
+/// Sets the dir of the on-disk cache that the shared cache falls
+/// back to (`None` to disable it)
+///
+/// The entries written the earliest are evicted if there are more
+/// than `DISK_CAPACITY` of them.
+pub fn set_cache_dir(dir: Option<&Path>) {
+    let disk = dir.map(DiskCache::new);
+    if let Some(d) = &disk {
+        if let Err(e) = d.evict(DISK_CAPACITY) {
+            warn!("Could not evict entries from the cache dir: {e}");
+        }
+    }
+    shared().set_disk(disk);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let calls = Cell::new(0);
+        let format = |input: &str| -> Result<Vec<u8>, String> {
+            calls.set(calls.get() + 1);
+            Ok(input.to_uppercase().into_bytes())
+        };
+        let cache = FormatCache::new(2);
+
+        let k1 = FormatCache::key("pg_format", "select 1");
+        assert_eq!(
+            Ok(b"SELECT 1".to_vec()),
+            cache.get_or_insert_with(k1, "select 1", || format("select 1"))
+        );
+        assert_eq!(
+            Ok(b"SELECT 1".to_vec()),
+            cache.get_or_insert_with(k1, "select 1", || format("select 1"))
+        );
+        // The formatter is invoked once for identical inputs
+        assert_eq!(1, calls.get());
+
+        // Same input but different config is a different key
+        let k2 = FormatCache::key("sqlfluff", "select 1");
+        assert_ne!(k1, k2);
+        cache
+            .get_or_insert_with(k2, "select 1", || format("select 1"))
+            .unwrap();
+        assert_eq!(2, calls.get());
+
+        // k1 was used before k2, so it gets evicted when the cache is
+        // full
+        let k3 = FormatCache::key("pg_format", "select 3");
+        cache
+            .get_or_insert_with(k3, "select 3", || format("select 3"))
+            .unwrap();
+        assert_eq!(3, calls.get());
+        cache
+            .get_or_insert_with(k2, "select 1", || format("select 1"))
+            .unwrap();
+        assert_eq!(3, calls.get());
+        cache
+            .get_or_insert_with(k1, "select 1", || format("select 1"))
+            .unwrap();
+        assert_eq!(4, calls.get());
+
+        // Failures are not cached
+        let k4 = FormatCache::key("pg_format", "select 4");
+        assert!(cache
+            .get_or_insert_with(k4, "select 4", || Err::<Vec<u8>, _>(
+                "exited with 1".to_owned()
+            ))
+            .is_err());
+        cache
+            .get_or_insert_with(k4, "select 4", || format("select 4"))
+            .unwrap();
+        assert_eq!(5, calls.get());
+    }
+
+    #[test]
+    fn test_get_or_insert_with_unlocked() {
+        // The cache isn't locked while formatting, so it can be used
+        // meanwhile (here, by the formatter itself) without blocking
+        let format =
+            |input: &str| -> Result<Vec<u8>, String> { Ok(input.to_uppercase().into_bytes()) };
+        let cache = FormatCache::new(2);
+        let k1 = FormatCache::key("pg_format", "select 1");
+        let k2 = FormatCache::key("pg_format", "select 2");
+        let output = cache.get_or_insert_with(k1, "select 1", || {
+            cache.get_or_insert_with(k2, "select 2", || format("select 2"))?;
+            format("select 1")
+        });
+        assert_eq!(Ok(b"SELECT 1".to_vec()), output);
+        assert_eq!(
+            Ok(b"SELECT 2".to_vec()),
+            cache.get_or_insert_with(k2, "select 2", || Err("not cached".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = TempDir::new("fmt-cache");
+        let calls = Cell::new(0);
+        let format = |input: &str| -> Result<Vec<u8>, String> {
+            calls.set(calls.get() + 1);
+            Ok(input.to_uppercase().into_bytes())
+        };
+        let key = FormatCache::key("pg_format", "select 1");
+
+        // First invocation with a cold cache
+        let cache = FormatCache::new(2);
+        cache.set_disk(Some(DiskCache::new(&dir)));
+        let first = cache.get_or_insert_with(key, "select 1", || format("select 1"));
+        assert_eq!(1, calls.get());
+
+        // Second invocation (i.e. with an empty in-memory cache)
+        // gets identical output from the disk without formatting
+        let cache = FormatCache::new(2);
+        cache.set_disk(Some(DiskCache::new(&dir)));
+        assert_eq!(
+            first,
+            cache.get_or_insert_with(key, "select 1", || format("select 1"))
+        );
+        assert_eq!(1, calls.get());
+
+        // ..unless the disk cache is disabled
+        let cache = FormatCache::new(2);
+        assert_eq!(
+            first,
+            cache.get_or_insert_with(key, "select 1", || format("select 1"))
+        );
+        assert_eq!(2, calls.get());
+    }
+
+    #[test]
+    fn test_disk_cache_entries() {
+        let dir = TempDir::new("fmt-cache-entries");
+        let disk = DiskCache::new(&dir);
+        disk.put(1, "select 1", b"SELECT 1").unwrap();
+        assert_eq!(Some(b"SELECT 1".to_vec()), disk.get(1, "select 1"));
+        // The entry of another input with the same key is a miss
+        assert_eq!(None, disk.get(1, "select 2"));
+        assert_eq!(None, disk.get(2, "select 1"));
+
+        for key in 2..6 {
+            disk.put(key, "select 1", b"SELECT 1").unwrap();
+        }
+        disk.evict(3).unwrap();
+        assert_eq!(3, fs::read_dir(&disk.dir).unwrap().count());
+    }
+}
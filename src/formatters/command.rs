@@ -0,0 +1,78 @@
+use super::config::Configurable;
+use super::external::ExternalFormatter;
+use crate::error::{parse_error, Error};
+use crate::toml::{decode_pathbuf, decode_string, SerializableTomlTable};
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Formatter that runs an arbitrary program, e.g. a custom script
+///
+/// The program is expected to read the SQL from stdin and write the
+/// formatted SQL to stdout.
+#[derive(Debug)]
+pub struct CommandFormatter {
+    exec_path: PathBuf,
+    args: Vec<String>,
+}
+
+impl TryFrom<&Value> for CommandFormatter {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value.as_table() {
+            Some(t) => {
+                let exec_path = t
+                    .get("exec_path")
+                    .ok_or(parse_error!("Missing 'exec_path' in 'formatter.command'"))
+                    .map(|v| decode_pathbuf(v, None, "formatter.command.exec_path"))??;
+                let args = match t.get("args") {
+                    Some(v) => v
+                        .as_array()
+                        .ok_or(parse_error!(
+                            "Value of 'formatter.command.args' must be an array of strings"
+                        ))?
+                        .iter()
+                        .map(|x| decode_string(x, "formatter.command.args[]"))
+                        .collect::<Result<Vec<String>, Error>>()?,
+                    None => vec![],
+                };
+                Ok(Self { exec_path, args })
+            }
+            None => Err(parse_error!(
+                "Value of 'formatter.command' must be a toml table"
+            )),
+        }
+    }
+}
+
+impl Configurable for CommandFormatter {
+    fn to_toml_table(&self) -> SerializableTomlTable {
+        let mut t = SerializableTomlTable::new("formatter.command");
+        t.push_comment("(required) Location of the executable");
+        let exec_path = self.exec_path.display().to_string();
+        t.push_entry_string("exec_path", &exec_path);
+        t.push_entry_strvec("args", &self.args);
+        t
+    }
+
+    fn config_file(&self) -> Option<(&Path, &'static str)> {
+        None
+    }
+}
+
+impl ExternalFormatter<'_> for CommandFormatter {
+    fn executable(&self) -> &Path {
+        self.exec_path.as_path()
+    }
+
+    fn format_args(&self) -> Vec<&str> {
+        self.args.iter().map(|a| a.as_str()).collect()
+    }
+
+    /// As it's not known whether the program supports any flag such
+    /// as `--version`, it's checked by running it with the same args
+    /// as for formatting (with empty stdin)
+    fn check_args(&self) -> Vec<&str> {
+        self.format_args()
+    }
+}
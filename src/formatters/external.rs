@@ -26,13 +26,16 @@ pub trait ExternalFormatter<'a>: TryFrom<&'a Value> + Configurable {
     /// The default implementation calls shell's out to the executable
     /// with by passing the input string through `stdin` along with
     /// the result of `self.format_args` as arguments.
-    fn format(&self, input: &str) -> Vec<u8> {
+    ///
+    /// Returns an error message if the executable couldn't be run or
+    /// if it exits with non-zero code.
+    fn format(&self, input: &str) -> Result<Vec<u8>, String> {
         let mut child = Command::new(self.executable())
             .args(self.format_args())
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
-            .expect("Failed to run formatter as a child process");
+            .map_err(|e| format!("failed to run as a child process: {e}"))?;
         let mut stdin = child.stdin.take().expect("Failed to open stdin");
         // @TODO: Check if it's possible to avoid allocating for an
         // owned String here
@@ -43,8 +46,14 @@ pub trait ExternalFormatter<'a>: TryFrom<&'a Value> + Configurable {
                 .expect("Failed to write to stdin");
         });
 
-        let output = child.wait_with_output().expect("Failed to read stdout");
-        output.stdout
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("failed to read stdout: {e}"))?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(format!("exited with {}", output.status))
+        }
     }
 
     /// Checks whether or not the executable exists (i.e. the
@@ -55,9 +64,10 @@ pub trait ExternalFormatter<'a>: TryFrom<&'a Value> + Configurable {
     fn check(&self) -> bool {
         Command::new(self.executable())
             .args(self.check_args())
+            .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .status()
-            .map_or(false, |s| s.success())
+            .is_ok_and(|s| s.success())
     }
 }
@@ -1,26 +1,82 @@
+use self::cache::FormatCache;
+use self::command::CommandFormatter;
 use self::config::Configurable;
 use self::external::ExternalFormatter;
+use self::retry::RetryPolicy;
 use self::sql_formatter::SqlFormatter;
 use self::sqlfluff::SqlFluff;
-use crate::{error::Error, toml::SerializableTomlTable};
+use crate::error::{parse_error, Error};
+use crate::toml::SerializableTomlTable;
+pub use cache::set_cache_dir;
 pub use pg_format::PgFormatter;
+use serde::Serialize;
 use sqlformat_rs::SqlFormat;
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::Path;
-use toml::Value;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+pub use template::format_template;
+use toml::{Table, Value};
 
+mod cache;
+mod command;
 mod config;
 mod external;
 mod pg_format;
+mod retry;
 mod sql_formatter;
 mod sqlfluff;
 mod sqlformat_rs;
+mod template;
 mod util;
 
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+static CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static FORMATTING_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// No. of times formatters were run during the invocation and the
+/// no. of times a cached output was returned instead. Each step of a
+/// chain is counted separately.
+#[derive(Debug, Serialize)]
+pub struct InvocationCounts {
+    pub runs: usize,
+    pub cache_hits: usize,
+}
+
+/// Returns the invocation counts so far
+pub fn invocation_counts() -> InvocationCounts {
+    InvocationCounts {
+        runs: RUNS.load(Ordering::Relaxed),
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns the total time spent in running formatters so far
+///
+/// Only the actual runs are timed i.e. not the cache lookups or the
+/// delays between retries.
+pub fn formatting_duration() -> Duration {
+    Duration::from_nanos(FORMATTING_NANOS.load(Ordering::Relaxed))
+}
+
+// Runs `f`, adding the time taken to the total time spent in
+// formatting
+fn timed<T, F: FnOnce() -> T>(f: F) -> T {
+    let started = Instant::now();
+    let res = f();
+    let nanos = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    FORMATTING_NANOS.fetch_add(nanos, Ordering::Relaxed);
+    res
+}
+
 /// Enum wrapping over abstractions for various sql formatting tools.
 ///
-/// This indirection is just a provision for plugging in sql
-/// formatting tools other than PgFormatter. But at present, only
-/// `PgFormatter` is supported.
+/// `Chain` is a sequence of formatters where each one formats the
+/// output of the previous one. `Retry` wraps a formatter that's
+/// retried on failure as per the policy. `ByDialect` selects the
+/// formatter based on the dialect of the query, falling back to the
+/// default one (if any).
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Formatter {
@@ -28,36 +84,256 @@ pub enum Formatter {
     SqlFormatRs(SqlFormat),
     SqlFormatter(SqlFormatter),
     SqlFluff(SqlFluff),
+    Command(CommandFormatter),
+    Chain(Vec<Formatter>),
+    Retry(Box<Formatter>, RetryPolicy),
+    ByDialect {
+        default: Option<Box<Formatter>>,
+        dialects: BTreeMap<String, Formatter>,
+    },
 }
 
 impl Formatter {
+    /// Returns the builtin formatter (sqlformat) with the default
+    /// config
+    pub fn builtin() -> Self {
+        Self::SqlFormatRs(SqlFormat::default())
+    }
+
     pub fn decode(value: &Value) -> Result<Option<Self>, Error> {
+        if let Some(xs) = value.as_array() {
+            let mut steps = Vec::with_capacity(xs.len());
+            for (i, x) in xs.iter().enumerate() {
+                // Chains can't be nested, nor can the steps be
+                // selected by dialect
+                let step = match x.as_table() {
+                    Some(t) if !t.contains_key("by_dialect") => Self::decode(x)?,
+                    _ => None,
+                };
+                match step {
+                    Some(f) => steps.push(f),
+                    None => return Err(parse_error!("Invalid formatter at 'formatter[{i}]'")),
+                }
+            }
+            if steps.is_empty() {
+                return Err(parse_error!(
+                    "Value of 'formatter' must not be an empty array"
+                ));
+            }
+            return Ok(Some(Self::Chain(steps)));
+        }
         match value.as_table() {
             Some(t) => {
-                if let Some(v) = t.get("pgFormatter") {
-                    return PgFormatter::try_from(v).map(|f| Some(Self::PgFormatter(f)));
+                let formatter = Self::decode_table(t)?;
+                let formatter = match (formatter, RetryPolicy::decode(t)?) {
+                    (Some(f), Some(policy)) => Some(Self::Retry(Box::new(f), policy)),
+                    (f, _) => f,
+                };
+                match t.get("by_dialect") {
+                    Some(v) => Self::decode_by_dialect(formatter, v).map(Some),
+                    None => Ok(formatter),
                 }
-                if let Some(v) = t.get("sql-formatter") {
-                    return SqlFormatter::try_from(v).map(|f| Some(Self::SqlFormatter(f)));
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes the `formatter.by_dialect` table that maps the
+    /// dialects to their formatters, each of which is specified the
+    /// same way as the top level formatter
+    fn decode_by_dialect(default: Option<Self>, value: &Value) -> Result<Self, Error> {
+        let table = value.as_table().ok_or(parse_error!(
+            "Value of 'formatter.by_dialect' must be a table"
+        ))?;
+        if table.is_empty() {
+            return Err(parse_error!(
+                "Value of 'formatter.by_dialect' must not be empty"
+            ));
+        }
+        let mut dialects = BTreeMap::new();
+        for (dialect, v) in table {
+            // Formatters can't be selected by dialect recursively
+            let f = match v.get("by_dialect") {
+                Some(_) => None,
+                None => Self::decode(v)?,
+            };
+            match f {
+                Some(f) => dialects.insert(dialect.clone(), f),
+                None => {
+                    return Err(parse_error!(
+                        "Invalid formatter at 'formatter.by_dialect.{dialect}'"
+                    ))
                 }
-                if let Some(v) = t.get("sqlfluff") {
-                    return SqlFluff::try_from(v).map(|f| Some(Self::SqlFluff(f)));
+            };
+        }
+        Ok(Self::ByDialect {
+            default: default.map(Box::new),
+            dialects,
+        })
+    }
+
+    /// Returns the formatter to be used for queries of the `dialect`
+    ///
+    /// Only in case of `ByDialect` the formatter configured for the
+    /// dialect is selected, falling back to the default one, which
+    /// may be none. Otherwise the formatter itself is returned.
+    pub fn for_dialect(&self, dialect: Option<&str>) -> Option<&Self> {
+        match self {
+            Self::ByDialect { default, dialects } => {
+                dialect.and_then(|d| dialects.get(d)).or(default.as_deref())
+            }
+            _ => Some(self),
+        }
+    }
+
+    // Returns all the formatters that may be selected by dialect
+    fn dialect_formatters(&self) -> impl Iterator<Item = &Self> {
+        let (default, dialects) = match self {
+            Self::ByDialect { default, dialects } => (default.as_deref(), Some(dialects)),
+            _ => (None, None),
+        };
+        default
+            .into_iter()
+            .chain(dialects.into_iter().flat_map(|d| d.values()))
+    }
+
+    fn decode_table(t: &Table) -> Result<Option<Self>, Error> {
+        if let Some(v) = t.get("pgFormatter") {
+            return PgFormatter::try_from(v).map(|f| Some(Self::PgFormatter(f)));
+        }
+        if let Some(v) = t.get("sql-formatter") {
+            return SqlFormatter::try_from(v).map(|f| Some(Self::SqlFormatter(f)));
+        }
+        if let Some(v) = t.get("sqlfluff") {
+            return SqlFluff::try_from(v).map(|f| Some(Self::SqlFluff(f)));
+        }
+        if let Some(v) = t.get("command") {
+            return CommandFormatter::try_from(v).map(|f| Some(Self::Command(f)));
+        }
+        if let Some(v) = t.get("sqlformat-rs") {
+            return SqlFormat::try_from(v).map(|f| Some(Self::SqlFormatRs(f)));
+        }
+        // `builtin` is an alias for `sqlformat-rs`
+        if let Some(v) = t.get("builtin") {
+            return SqlFormat::decode(v, "formatter.builtin").map(|f| Some(Self::SqlFormatRs(f)));
+        }
+        Ok(None)
+    }
+
+    /// Returns formatted `sql`
+    ///
+    /// Outputs of external formatters are cached for the duration
+    /// of the invocation so that the same input is not formatted
+    /// more than once, and also across invocations if a cache dir is
+    /// set (see `set_cache_dir`). In case of a chain, the steps are run in
+    /// order and the first failure aborts the chain.
+    ///
+    /// # Error:
+    /// Returns `Error::FormatterFailed` if an external formatter
+    /// couldn't be run or exits with non-zero code, or if called on
+    /// `ByDialect` as the formatter must first be selected using
+    /// `for_dialect`.
+    pub fn format(&self, sql: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::SqlFormatRs(f) => {
+                RUNS.fetch_add(1, Ordering::Relaxed);
+                Ok(timed(|| f.format(sql)))
+            }
+            Self::Retry(inner, policy) => policy.run(|| inner.format(sql)),
+            Self::ByDialect { .. } => Err(Error::FormatterFailed(
+                "formatter by dialect can't be applied without selecting it for the dialect"
+                    .to_owned(),
+            )),
+            Self::Chain(steps) => {
+                let mut output = sql.as_bytes().to_vec();
+                for (i, step) in steps.iter().enumerate() {
+                    let input = String::from_utf8_lossy(&output).into_owned();
+                    output = step.format(&input).map_err(|e| match e {
+                        Error::FormatterFailed(msg) => {
+                            Error::FormatterFailed(format!("step {}: {msg}", i + 1))
+                        }
+                        e => e,
+                    })?;
                 }
-                if let Some(v) = t.get("sqlformat-rs") {
-                    return SqlFormat::try_from(v).map(|f| Some(Self::SqlFormatRs(f)));
+                Ok(output)
+            }
+            _ => {
+                let key = FormatCache::key(&self.cache_config(), sql);
+                let mut hit = true;
+                let res = cache::shared().get_or_insert_with(key, sql, || {
+                    hit = false;
+                    self.format_uncached(sql)
+                });
+                if hit && res.is_ok() {
+                    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
                 }
-                Ok(None)
+                res
             }
-            None => Ok(None),
         }
     }
 
-    pub fn format(&self, sql: &str) -> Vec<u8> {
+    // Returns the config of an external formatter for the cache key,
+    // along with the contents of the config file that it reads (if
+    // any) as editing the file changes the output
+    fn cache_config(&self) -> String {
+        let config_file = match self {
+            Self::PgFormatter(p) => p.config_file(),
+            Self::SqlFormatter(f) => f.config_file(),
+            Self::SqlFluff(f) => f.config_file(),
+            _ => None,
+        };
+        let contents = config_file
+            .and_then(|(path, _)| fs::read(path).ok())
+            .unwrap_or_default();
+        format!(
+            "{}\n{}",
+            self.config_key(),
+            String::from_utf8_lossy(&contents)
+        )
+    }
+
+    fn format_uncached(&self, sql: &str) -> Result<Vec<u8>, Error> {
+        let res = match self {
+            Self::PgFormatter(p) => timed(|| p.format(sql)),
+            Self::SqlFormatter(f) => timed(|| f.format(sql)),
+            Self::SqlFluff(f) => timed(|| f.format(sql)),
+            Self::Command(f) => timed(|| f.format(sql)),
+            Self::SqlFormatRs(f) => Ok(timed(|| f.format(sql))),
+            Self::Chain(_) | Self::Retry(..) | Self::ByDialect { .. } => return self.format(sql),
+        };
+        RUNS.fetch_add(1, Ordering::Relaxed);
+        res.map_err(|msg| {
+            // @UNWRAP: External formatters always have an executable
+            let exec_path = self.executable().unwrap().display();
+            Error::FormatterFailed(format!("{exec_path}: {msg}"))
+        })
+    }
+
+    /// Returns a string identifying the config of the formatter,
+    /// including that of the steps of a chain and the formatters by
+    /// dialect, so that changing any setting that affects the output
+    /// results in a different key
+    pub fn config_key(&self) -> String {
         match self {
-            Self::PgFormatter(p) => p.format(sql),
-            Self::SqlFormatter(f) => f.format(sql),
-            Self::SqlFluff(f) => f.format(sql),
-            Self::SqlFormatRs(f) => f.format(sql),
+            Self::Chain(steps) => {
+                let steps = steps
+                    .iter()
+                    .map(|s| s.config_key())
+                    .collect::<Vec<String>>();
+                format!("[{}]", steps.join(","))
+            }
+            // The retry policy doesn't affect the output
+            Self::Retry(inner, _) => inner.config_key(),
+            Self::ByDialect { default, dialects } => {
+                let default = default.as_ref().map(|f| f.config_key());
+                let dialects = dialects
+                    .iter()
+                    .map(|(d, f)| format!("{d}={}", f.config_key()))
+                    .collect::<Vec<String>>();
+                format!("{default:?}/{}", dialects.join(","))
+            }
+            // @UNWRAP: Serializing the config to json can't fail
+            _ => serde_json::to_string(&self.config_toml_table()).unwrap(),
         }
     }
 
@@ -67,6 +343,9 @@ impl Formatter {
             Self::SqlFormatter(f) => Some(f.to_toml_table()),
             Self::SqlFluff(f) => Some(f.to_toml_table()),
             Self::SqlFormatRs(f) => Some(f.to_toml_table()),
+            Self::Command(f) => Some(f.to_toml_table()),
+            Self::Retry(inner, _) => inner.config_toml_table(),
+            Self::Chain(_) | Self::ByDialect { .. } => None,
         }
     }
 
@@ -75,7 +354,9 @@ impl Formatter {
             Self::PgFormatter(p) => Some(p.executable()),
             Self::SqlFormatter(f) => Some(f.executable()),
             Self::SqlFluff(f) => Some(f.executable()),
-            Self::SqlFormatRs(_) => None,
+            Self::Command(f) => Some(f.executable()),
+            Self::Retry(inner, _) => inner.executable(),
+            Self::SqlFormatRs(_) | Self::Chain(_) | Self::ByDialect { .. } => None,
         }
     }
 
@@ -85,16 +366,99 @@ impl Formatter {
             Self::SqlFormatter(f) => f.generate_config_file(dir),
             Self::SqlFormatRs(f) => f.generate_config_file(dir),
             Self::SqlFluff(f) => f.generate_config_file(dir),
+            Self::Command(f) => f.generate_config_file(dir),
+            Self::Retry(inner, _) => return inner.generate_config_file(dir),
+            Self::Chain(steps) => {
+                for step in steps {
+                    step.generate_config_file(dir)?;
+                }
+                Ok(())
+            }
+            Self::ByDialect { .. } => {
+                for f in self.dialect_formatters() {
+                    f.generate_config_file(dir)?;
+                }
+                Ok(())
+            }
         };
         res.map_err(Error::Io)
     }
 
-    pub fn is_available(&self) -> bool {
-        match self {
+    /// Returns a short human readable description of the formatter
+    /// i.e. its key in the manifest and the executable, if external
+    pub fn describe(&self) -> String {
+        let name = match self {
+            Self::PgFormatter(_) => "pgFormatter",
+            Self::SqlFormatRs(_) => "sqlformat-rs",
+            Self::SqlFormatter(_) => "sql-formatter",
+            Self::SqlFluff(_) => "sqlfluff",
+            Self::Command(_) => "command",
+            Self::Retry(inner, policy) => {
+                return format!("{} (retries: {})", inner.describe(), policy.retries)
+            }
+            Self::Chain(steps) => {
+                return steps
+                    .iter()
+                    .map(|s| s.describe())
+                    .collect::<Vec<String>>()
+                    .join(" -> ")
+            }
+            Self::ByDialect { default, dialects } => {
+                let default = default
+                    .as_ref()
+                    .map_or_else(|| "-".to_owned(), |f| f.describe());
+                let dialects = dialects
+                    .iter()
+                    .map(|(d, f)| format!("{d}: {}", f.describe()))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                return format!("{default} (by dialect: {dialects})");
+            }
+        };
+        match self.executable() {
+            Some(p) => format!("{name} ({})", p.display()),
+            None => name.to_owned(),
+        }
+    }
+
+    /// Returns the executables of all external formatters (i.e. the
+    /// steps, in case of a chain) that are not installed
+    pub fn unavailable_executables(&self) -> Vec<&Path> {
+        let available = match self {
             Self::PgFormatter(f) => f.check(),
             Self::SqlFormatRs(_) => true,
             Self::SqlFormatter(f) => f.check(),
             Self::SqlFluff(f) => f.check(),
+            Self::Command(f) => f.check(),
+            Self::Retry(inner, _) => return inner.unavailable_executables(),
+            Self::Chain(steps) => {
+                return steps
+                    .iter()
+                    .flat_map(|s| s.unavailable_executables())
+                    .collect()
+            }
+            Self::ByDialect { .. } => {
+                return self
+                    .dialect_formatters()
+                    .flat_map(|f| f.unavailable_executables())
+                    .collect()
+            }
+        };
+        match self.executable() {
+            Some(p) if !available => vec![p],
+            _ => vec![],
+        }
+    }
+
+    /// Probes the formatter once, returning
+    /// `Error::FormatterUnavailable` if it can't be run.
+    ///
+    /// Meant to be called before writing any output so that a missing
+    /// external formatter doesn't result in partially rendered files.
+    pub fn ensure_available(&self) -> Result<(), Error> {
+        match self.unavailable_executables().first() {
+            Some(exec_path) => Err(Error::FormatterUnavailable(exec_path.display().to_string())),
+            None => Ok(()),
         }
     }
 }
@@ -108,7 +472,7 @@ pub fn discover_available_formatters() -> Vec<Formatter> {
     // @NOTE: The following order needs to be maintained
 
     // 1. builtin formatter sqlformat
-    formatters.push(Formatter::SqlFormatRs(SqlFormat::default()));
+    formatters.push(Formatter::builtin());
 
     // 2. pgFormatter
     if let Some(pgf) = PgFormatter::discover() {
@@ -126,3 +490,211 @@ pub fn discover_available_formatters() -> Vec<Formatter> {
     }
     formatters
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_ensure_available() {
+        let f = Formatter::SqlFormatRs(SqlFormat::default());
+        assert!(f.ensure_available().is_ok());
+
+        let f = Formatter::PgFormatter(PgFormatter::new(
+            PathBuf::from("/nonexistent/pg_format"),
+            None,
+        ));
+        match f.ensure_available() {
+            Err(Error::FormatterUnavailable(exec)) => {
+                assert_eq!("/nonexistent/pg_format", exec);
+            }
+            _ => panic!(),
+        }
+    }
+
+    fn sed(script: &str) -> Formatter {
+        let value: Value = toml::from_str(&format!(
+            "exec_path = \"sed\"\nargs = [ \"-e\", \"{script}\" ]"
+        ))
+        .unwrap();
+        Formatter::Command(CommandFormatter::try_from(&value).unwrap())
+    }
+
+    #[test]
+    fn test_decode_chain() {
+        let value: Value = toml::from_str(
+            r#"
+[[formatter]]
+sqlformat-rs = {}
+
+[[formatter]]
+command = { exec_path = "strip-comments", args = [ "--keep-hints" ] }
+"#,
+        )
+        .unwrap();
+        match Formatter::decode(value.get("formatter").unwrap()) {
+            Ok(Some(Formatter::Chain(steps))) => {
+                assert_eq!(2, steps.len());
+                assert!(matches!(steps[0], Formatter::SqlFormatRs(_)));
+                assert_eq!(Some(Path::new("strip-comments")), steps[1].executable());
+            }
+            _ => panic!(),
+        }
+
+        let value: Value = toml::from_str("formatter = [ { unknown = {} } ]").unwrap();
+        match Formatter::decode(value.get("formatter").unwrap()) {
+            Err(Error::Parsing(msg)) => assert_eq!("Invalid formatter at 'formatter[0]'", msg),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decode_by_dialect() {
+        let value: Value = toml::from_str(
+            r#"
+[formatter]
+sqlformat-rs = {}
+
+[formatter.by_dialect.postgres]
+pgFormatter = { exec_path = "pg_format" }
+
+[formatter.by_dialect.mysql]
+sql-formatter = { exec_path = "sql-formatter" }
+"#,
+        )
+        .unwrap();
+        let f = Formatter::decode(value.get("formatter").unwrap())
+            .unwrap()
+            .unwrap();
+        let selected = |dialect| f.for_dialect(dialect).map(|f| f.describe());
+        assert_eq!(
+            Some("pgFormatter (pg_format)".to_owned()),
+            selected(Some("postgres"))
+        );
+        assert_eq!(
+            Some("sql-formatter (sql-formatter)".to_owned()),
+            selected(Some("mysql"))
+        );
+        // Falls back to the default formatter
+        assert_eq!(Some("sqlformat-rs".to_owned()), selected(Some("sqlite")));
+        assert_eq!(Some("sqlformat-rs".to_owned()), selected(None));
+        // Applying it without selecting one is a mistake
+        assert!(matches!(
+            f.format("SELECT 1;"),
+            Err(Error::FormatterFailed(_))
+        ));
+
+        // Without a default formatter, queries of other dialects are
+        // not formatted
+        let value: Value =
+            toml::from_str("[formatter.by_dialect.postgres]\nsqlformat-rs = {}").unwrap();
+        let f = Formatter::decode(value.get("formatter").unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(f.for_dialect(Some("postgres")).is_some());
+        assert!(f.for_dialect(Some("mysql")).is_none());
+        assert!(f.for_dialect(None).is_none());
+
+        let value: Value =
+            toml::from_str("[formatter.by_dialect.postgres.by_dialect.mysql]\nsqlformat-rs = {}")
+                .unwrap();
+        match Formatter::decode(value.get("formatter").unwrap()) {
+            Err(Error::Parsing(msg)) => {
+                assert_eq!("Invalid formatter at 'formatter.by_dialect.postgres'", msg)
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_cache_config() {
+        let dir = TempDir::new("cache-config");
+        let conf_path = dir.join("pg_format.conf");
+        let f = Formatter::PgFormatter(PgFormatter::new(
+            PathBuf::from("pg_format"),
+            Some(conf_path.clone()),
+        ));
+        let missing = f.cache_config();
+        fs::write(&conf_path, "keyword-case=2\n").unwrap();
+        let uppercase = f.cache_config();
+        fs::write(&conf_path, "keyword-case=1\n").unwrap();
+        // Editing the config file changes the key
+        assert_ne!(missing, uppercase);
+        assert_ne!(uppercase, f.cache_config());
+    }
+
+    #[test]
+    fn test_format_chain() {
+        // The second step sees the output of the first one, so the
+        // result depends on the order
+        let chain = Formatter::Chain(vec![sed("s/foo/bar/"), sed("s/bar/baz/")]);
+        assert_eq!(
+            b"SELECT baz;\n".to_vec(),
+            chain.format("SELECT foo;\n").unwrap()
+        );
+        let chain = Formatter::Chain(vec![sed("s/bar/baz/"), sed("s/foo/bar/")]);
+        assert_eq!(
+            b"SELECT bar;\n".to_vec(),
+            chain.format("SELECT foo;\n").unwrap()
+        );
+
+        // Failing step is identified in the error
+        let failing = Formatter::Command(
+            CommandFormatter::try_from(&toml::from_str::<Value>("exec_path = \"false\"").unwrap())
+                .unwrap(),
+        );
+        let chain = Formatter::Chain(vec![sed("s/foo/bar/"), failing]);
+        match chain.format("SELECT foo;\n") {
+            Err(Error::FormatterFailed(msg)) => {
+                assert!(msg.starts_with("step 2: false: exited with"))
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_format_retry() {
+        // Command that fails the first time it's run and succeeds
+        // after that
+        let dir = TempDir::new("retry");
+        let state = dir.join("state");
+        let script = format!(
+            "if [ -e {0} ]; then cat; else touch {0}; exit 1; fi",
+            state.display()
+        );
+        let value: Value = toml::from_str(&format!(
+            r#"
+[formatter]
+retries = 2
+retry_backoff_ms = 10
+command = {{ exec_path = "sh", args = [ "-c", "{script}" ] }}
+"#
+        ))
+        .unwrap();
+        let formatter = Formatter::decode(value.get("formatter").unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(matches!(formatter, Formatter::Retry(_, _)));
+        assert_eq!("command (sh) (retries: 2)", formatter.describe());
+        assert_eq!(
+            b"SELECT 1;\n".to_vec(),
+            formatter.format("SELECT 1;\n").unwrap()
+        );
+        std::fs::remove_file(&state).unwrap();
+
+        // Without retries, the first failure is returned
+        let value: Value = toml::from_str(&format!(
+            r#"command = {{ exec_path = "sh", args = [ "-c", "{script}" ] }}"#
+        ))
+        .unwrap();
+        let formatter = Formatter::decode(&value).unwrap().unwrap();
+        assert!(matches!(
+            formatter.format("SELECT 2;\n"),
+            Err(Error::FormatterFailed(_))
+        ));
+        std::fs::remove_file(&state).unwrap();
+    }
+}
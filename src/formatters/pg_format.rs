@@ -1,7 +1,7 @@
 use crate::error::{parse_error, Error};
 use crate::toml::{decode_pathbuf, SerializableTomlTable};
-use std::cell::OnceCell;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use toml::Value;
 
 use super::config::Configurable;
@@ -13,7 +13,7 @@ use super::external::ExternalFormatter;
 pub struct PgFormatter {
     pub exec_path: PathBuf,
     pub conf_path: Option<PathBuf>,
-    args: OnceCell<Vec<String>>,
+    args: OnceLock<Vec<String>>,
 }
 
 fn pg_format_args(conf_path: Option<&Path>) -> Vec<String> {
@@ -54,7 +54,7 @@ impl PgFormatter {
         Self {
             exec_path,
             conf_path,
-            args: OnceCell::new(),
+            args: OnceLock::new(),
         }
     }
 
@@ -128,7 +128,7 @@ mod tests {
         );
 
         let conf_path = Path::new("./.pg_format/config");
-        let args = pg_format_args(Some(&conf_path));
+        let args = pg_format_args(Some(conf_path));
         let expected = vec!["-c", "./.pg_format/config", "-"];
         assert_eq!(
             expected
@@ -0,0 +1,137 @@
+use crate::error::{parse_error, Error};
+use log::warn;
+use std::thread;
+use std::time::Duration;
+use toml::{Table, Value};
+
+/// Default delay before the first retry
+const DEFAULT_BACKOFF_MS: u64 = 200;
+
+/// Policy for retrying a formatter that fails e.g. due to a flaky
+/// binary or a networked service being unavailable
+///
+/// The delay is doubled after every retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+fn decode_u64(value: &Value, key: &str) -> Result<u64, Error> {
+    value
+        .as_integer()
+        .and_then(|i| u64::try_from(i).ok())
+        .ok_or(parse_error!(
+            "Value of '{}' must be a non-negative integer",
+            key
+        ))
+}
+
+impl RetryPolicy {
+    /// Decodes the `retries` and `retry_backoff_ms` keys of the
+    /// `formatter` table. Returns `None` if retries are not
+    /// configured.
+    pub fn decode(table: &Table) -> Result<Option<Self>, Error> {
+        let retries = match table.get("retries") {
+            Some(v) => decode_u64(v, "formatter.retries")?,
+            None => 0,
+        };
+        let backoff_ms = match table.get("retry_backoff_ms") {
+            Some(v) => decode_u64(v, "formatter.retry_backoff_ms")?,
+            None => DEFAULT_BACKOFF_MS,
+        };
+        if retries == 0 {
+            return Ok(None);
+        }
+        let retries = u32::try_from(retries)
+            .map_err(|_| parse_error!("Value of 'formatter.retries' is too large"))?;
+        Ok(Some(Self {
+            retries,
+            backoff: Duration::from_millis(backoff_ms),
+        }))
+    }
+
+    /// Calls `f` until it succeeds or the retries are exhausted, in
+    /// which case the last error is returned
+    ///
+    /// Only `Error::FormatterFailed` is considered transient.
+    pub fn run<T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(Error::FormatterFailed(msg)) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Formatter failed, retrying in {}ms ({attempt}/{}): {msg}",
+                        delay.as_millis(),
+                        self.retries
+                    );
+                    thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let table: Table = toml::from_str("retries = 2\nretry_backoff_ms = 50").unwrap();
+        assert_eq!(
+            Some(RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(50)
+            }),
+            RetryPolicy::decode(&table).unwrap()
+        );
+        let table: Table = toml::from_str("retries = 0").unwrap();
+        assert_eq!(None, RetryPolicy::decode(&table).unwrap());
+        let table: Table = toml::from_str("retries = -1").unwrap();
+        match RetryPolicy::decode(&table) {
+            Err(Error::Parsing(msg)) => assert_eq!(
+                "Value of 'formatter.retries' must be a non-negative integer",
+                msg
+            ),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let mut calls = 0;
+        let res = policy.run(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(Error::FormatterFailed(format!("attempt {calls}")))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(3, res.unwrap());
+
+        // The last error is returned once the retries are exhausted
+        let mut calls = 0;
+        let res: Result<(), Error> = policy.run(|| {
+            calls += 1;
+            Err(Error::FormatterFailed(format!("attempt {calls}")))
+        });
+        match res {
+            Err(Error::FormatterFailed(msg)) => assert_eq!("attempt 3", msg),
+            _ => panic!(),
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::error::{parse_error, Error};
 use crate::toml::{decode_pathbuf, SerializableTomlTable};
-use std::cell::OnceCell;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use toml::Value;
 
 use super::config::Configurable;
@@ -11,7 +11,7 @@ use super::external::ExternalFormatter;
 pub struct SqlFormatter {
     exec_path: PathBuf,
     conf_path: Option<PathBuf>,
-    args: OnceCell<Vec<String>>,
+    args: OnceLock<Vec<String>>,
 }
 
 impl TryFrom<&Value> for SqlFormatter {
@@ -48,7 +48,7 @@ impl SqlFormatter {
         Self {
             exec_path,
             conf_path,
-            args: OnceCell::new(),
+            args: OnceLock::new(),
         }
     }
 
@@ -2,7 +2,7 @@ use std::path::Path;
 
 use crate::{
     error::{parse_error, Error},
-    toml::SerializableTomlTable,
+    toml::{decode_bool, SerializableTomlTable},
 };
 use sqlformat::{FormatOptions, Indent, QueryParams};
 use toml::Value;
@@ -26,46 +26,63 @@ pub struct SqlFormat {
     options: FormatOptions,
 }
 
+/// Tries decoding a toml `Value` into `u8`, returning a parsing error
+/// if it's not an integer in the range 0..=255
+fn decode_u8(value: &Value, key: &str) -> Result<u8, Error> {
+    value
+        .as_integer()
+        .and_then(|i| u8::try_from(i).ok())
+        .ok_or(parse_error!(
+            "Value of '{}' must be an integer in the range 0..=255",
+            key
+        ))
+}
+
 impl TryFrom<&Value> for SqlFormat {
     type Error = crate::error::Error;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Self::decode(value, "formatter.sqlformat-rs")
+    }
+}
+
+impl Default for SqlFormat {
+    fn default() -> Self {
+        Self {
+            options: default_format_options(),
+        }
+    }
+}
+
+impl SqlFormat {
+    /// Decodes the formatter config from the toml table found under
+    /// `key_path` in the manifest.
+    ///
+    /// All options are optional. If specified, they're validated
+    /// here so that the same options are used when writing output
+    /// files as well as when comparing them in `status`.
+    pub fn decode(value: &Value, key_path: &str) -> Result<Self, Error> {
         match value.as_table() {
             Some(t) => {
                 let mut options = default_format_options();
                 if let Some(v) = t.get("indent") {
-                    if let Some(i) = v.as_integer() {
-                        options.indent = Indent::Spaces(i as u8);
-                    }
+                    let key = format!("{key_path}.indent");
+                    options.indent = Indent::Spaces(decode_u8(v, &key)?);
                 }
                 if let Some(v) = t.get("uppercase") {
-                    if let Some(b) = v.as_bool() {
-                        options.uppercase = b;
-                    }
+                    let key = format!("{key_path}.uppercase");
+                    options.uppercase = decode_bool(v, &key)?;
                 }
                 if let Some(v) = t.get("lines_between_queries") {
-                    if let Some(n) = v.as_integer() {
-                        options.lines_between_queries = n as u8;
-                    }
+                    let key = format!("{key_path}.lines_between_queries");
+                    options.lines_between_queries = decode_u8(v, &key)?;
                 }
                 Ok(Self { options })
             }
-            None => Err(parse_error!(
-                "Value of 'formatter.sqlformat-rs' must be a toml table"
-            )),
-        }
-    }
-}
-
-impl Default for SqlFormat {
-    fn default() -> Self {
-        Self {
-            options: default_format_options(),
+            None => Err(parse_error!("Value of '{}' must be a toml table", key_path)),
         }
     }
-}
 
-impl SqlFormat {
     pub fn format(&self, sql: &str) -> Vec<u8> {
         let mut formatted = sqlformat::format(sql, &QueryParams::None, self.options);
         formatted.push('\n');
@@ -76,12 +93,19 @@ impl SqlFormat {
 impl Configurable for SqlFormat {
     fn to_toml_table(&self) -> SerializableTomlTable {
         let mut t = SerializableTomlTable::new("formatter.sqlformat-rs");
+        let indent = match self.options.indent {
+            Indent::Spaces(n) => n,
+            Indent::Tabs => 4,
+        };
         t.push_comment("(optional) No. of spaces to indent by");
-        t.push_entry_i64("indent", 4);
+        t.push_entry_i64("indent", indent as i64);
         t.push_comment("(optional) Use ALL CAPS for reserved keywords");
-        t.push_entry_bool("uppercase", true);
+        t.push_entry_bool("uppercase", self.options.uppercase);
         t.push_comment("(optional) No. of line breaks after a query");
-        t.push_entry_i64("lines_between_queries", 1);
+        t.push_entry_i64(
+            "lines_between_queries",
+            self.options.lines_between_queries as i64,
+        );
         t
     }
 
@@ -89,3 +113,62 @@ impl Configurable for SqlFormat {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use toml::Table;
+
+    fn decode_str(s: &str) -> Result<SqlFormat, Error> {
+        let t = s.parse::<Table>().unwrap();
+        SqlFormat::decode(&t["builtin"], "formatter.builtin")
+    }
+
+    #[test]
+    fn test_decode() {
+        let f = decode_str("[builtin]").unwrap();
+        assert!(matches!(f.options.indent, Indent::Spaces(4)));
+        assert!(f.options.uppercase);
+        assert_eq!(1, f.options.lines_between_queries);
+
+        let f = decode_str("[builtin]\nindent = 2\nuppercase = false").unwrap();
+        assert!(matches!(f.options.indent, Indent::Spaces(2)));
+        assert!(!f.options.uppercase);
+
+        match decode_str("[builtin]\nindent = -1") {
+            Err(Error::Parsing(msg)) => assert_eq!(
+                "Value of 'formatter.builtin.indent' must be an integer in the range 0..=255",
+                msg
+            ),
+            _ => panic!(),
+        }
+
+        match decode_str("[builtin]\nuppercase = 'yes'") {
+            Err(Error::Parsing(msg)) => assert_eq!(
+                "Value of 'formatter.builtin.uppercase' must be a boolean",
+                msg
+            ),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_format_options() {
+        let sql = "select id from users where active = true";
+
+        let f = decode_str("[builtin]\nuppercase = true").unwrap();
+        let output = String::from_utf8(f.format(sql)).unwrap();
+        assert_eq!(
+            "SELECT\n    id\nFROM\n    users\nWHERE\n    active = TRUE\n",
+            output
+        );
+
+        let f = decode_str("[builtin]\nuppercase = false\nindent = 2").unwrap();
+        let output = String::from_utf8(f.format(sql)).unwrap();
+        assert_eq!(
+            "select\n  id\nfrom\n  users\nwhere\n  active = true\n",
+            output
+        );
+    }
+}
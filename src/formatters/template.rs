@@ -0,0 +1,182 @@
+use super::Formatter;
+use crate::error::Error;
+
+/// Prefix of the identifiers that the template tags are replaced with
+/// before formatting
+const MASK_PREFIX: &str = "__tapestry_tag_";
+
+fn mask(i: usize) -> String {
+    format!("{MASK_PREFIX}{i}__")
+}
+
+/// Template tag replaced with a mask in the source to be formatted
+#[derive(Debug)]
+struct Tag<'a> {
+    text: &'a str,
+    // Whether a space was added before/after the mask to separate it
+    // from the adjacent text
+    pad_before: bool,
+    pad_after: bool,
+}
+
+/// Replaces every template tag i.e. `{{ .. }}`, `{% .. %}` and
+/// `{# .. #}` in `source` with an identifier that the SQL formatter
+/// leaves untouched, returning the masked source along with the tags
+/// in the order of the masks
+///
+/// The masks of `{% .. %}` and `{# .. #}` tags are separated from the
+/// adjacent text by a space, so that the SQL around them is
+/// formatted e.g. the `where` in `{% if x %}where`. The masks of
+/// `{{ .. }}` tags are not, as an expression may be a part of an
+/// identifier e.g. `artists_{{ suffix }}`.
+fn mask_tags(source: &str) -> Result<(String, Vec<Tag<'_>>), String> {
+    let mut masked = String::with_capacity(source.len());
+    let mut tags = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find('{') {
+        let closing = match rest[start..].get(..2) {
+            Some("{{") => "}}",
+            Some("{%") => "%}",
+            Some("{#") => "#}",
+            _ => {
+                masked.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        let len = rest[start + 2..]
+            .find(closing)
+            .ok_or_else(|| format!("Unterminated tag: '{}'", &rest[start..start + 2]))?;
+        let end = start + 2 + len + 2;
+        masked.push_str(&rest[..start]);
+        let pad = closing != "}}";
+        let pad_before = pad
+            && masked
+                .chars()
+                .next_back()
+                .is_some_and(|c| !c.is_whitespace());
+        let pad_after = pad
+            && rest[end..]
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_whitespace());
+        if pad_before {
+            masked.push(' ');
+        }
+        masked.push_str(&mask(tags.len()));
+        if pad_after {
+            masked.push(' ');
+        }
+        tags.push(Tag {
+            text: &rest[start..end],
+            pad_before,
+            pad_after,
+        });
+        rest = &rest[end..];
+    }
+    masked.push_str(rest);
+    Ok((masked, tags))
+}
+
+/// Restores the `tags` in the `formatted` source, failing if any of
+/// the masks doesn't occur exactly once i.e. if the formatter has
+/// altered it
+///
+/// A space added around a mask is removed only if the formatter has
+/// kept it as a single space between the mask and the adjacent
+/// text, otherwise the whitespace is as formatted (e.g. a newline
+/// before a keyword).
+fn unmask_tags(formatted: &str, tags: &[Tag]) -> Result<String, String> {
+    let mut result = formatted.to_owned();
+    // Restored in reverse so that e.g. the mask of tag 1 doesn't
+    // match a prefix of the mask of tag 10
+    for (i, tag) in tags.iter().enumerate().rev() {
+        let m = mask(i);
+        if result.matches(&m).count() != 1 {
+            return Err(format!("Formatter altered the template tag '{}'", tag.text));
+        }
+        // @UNWRAP: The mask occurs exactly once as checked above
+        let mut start = result.find(&m).unwrap();
+        let mut end = start + m.len();
+        if tag.pad_before && is_single_space(result[..start].chars().rev()) {
+            start -= 1;
+        }
+        if tag.pad_after && is_single_space(result[end..].chars()) {
+            end += 1;
+        }
+        result.replace_range(start..end, tag.text);
+    }
+    Ok(result)
+}
+
+// Checks whether `chars` start with a space followed by some text
+// other than whitespace
+fn is_single_space<I: Iterator<Item = char>>(mut chars: I) -> bool {
+    chars.next() == Some(' ') && chars.next().is_some_and(|c| !c.is_whitespace())
+}
+
+/// Formats the template `source` using the `formatter`, preserving
+/// the template tags
+///
+/// The tags are masked during formatting and restored afterwards, see
+/// `mask_tags` for how the SQL around them is formatted. The trailing
+/// newline (if any) of the source is preserved.
+///
+/// # Error:
+/// Returns `Error::FormatterFailed` if the formatter fails or if
+/// the tags couldn't be restored.
+pub fn format_template(formatter: &Formatter, source: &str) -> Result<String, Error> {
+    let (masked, tags) = mask_tags(source).map_err(Error::FormatterFailed)?;
+    let output = formatter.format(&masked)?;
+    let formatted = String::from_utf8_lossy(&output);
+    let mut result = unmask_tags(formatted.trim_end(), &tags).map_err(Error::FormatterFailed)?;
+    if source.ends_with('\n') {
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use toml::Value;
+
+    #[test]
+    fn test_mask_tags() {
+        let source =
+            "SELECT * FROM {{ table }}{# comment #} WHERE x = '{'{% if y %} AND y{% endif %};";
+        let (masked, tags) = mask_tags(source).unwrap();
+        // Only the block and comment tags are padded
+        assert_eq!(
+            "SELECT * FROM __tapestry_tag_0__ __tapestry_tag_1__ WHERE x = '{' __tapestry_tag_2__ AND y __tapestry_tag_3__ ;",
+            masked
+        );
+        assert_eq!(
+            vec!["{{ table }}", "{# comment #}", "{% if y %}", "{% endif %}"],
+            tags.iter().map(|t| t.text).collect::<Vec<&str>>()
+        );
+        assert_eq!(source, unmask_tags(&masked, &tags).unwrap());
+
+        assert!(mask_tags("SELECT {{ x").is_err());
+        assert!(unmask_tags("SELECT 1", &tags).is_err());
+    }
+
+    #[test]
+    fn test_format_template() {
+        let value: Value = toml::from_str("[formatter.builtin]").unwrap();
+        let formatter = Formatter::decode(value.get("formatter").unwrap())
+            .unwrap()
+            .unwrap();
+        let source = "select * from artists_{{ suffix }} {% if cond__genre %}where genre = {{ placeholder('genre') }}{% endif %} limit {{ placeholder('limit') }};\n";
+        let formatted = format_template(&formatter, source).unwrap();
+        // The SQL inside the block is formatted too, while an
+        // expression that's part of an identifier remains so
+        assert_eq!(
+            "SELECT\n    *\nFROM\n    artists_{{ suffix }} {% if cond__genre %}\nWHERE\n    genre = {{ placeholder('genre') }}{% endif %}\nLIMIT\n    {{ placeholder('limit') }};\n",
+            formatted
+        );
+        // Formatting is idempotent
+        assert_eq!(formatted, format_template(&formatter, &formatted).unwrap());
+    }
+}
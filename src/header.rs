@@ -0,0 +1,165 @@
+use crate::error::{parse_error, Error};
+use crate::toml::decode_string;
+use minijinja::{context, Environment};
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml::Value;
+
+/// Value of `generated_at` for rendering the header that's compared
+/// with the header of an existing output file (see `strip_header`)
+pub const FIXED_TIMESTAMP: &str = "1970-01-01T00:00:00Z";
+
+/// Template of the header that's prepended to every output file,
+/// for embedding provenance info in it
+///
+/// It's rendered with the following variables in the context:
+/// `id` (of the query), `generated_at` (UTC timestamp of the render)
+/// and `tapestry_version`.
+#[derive(Debug)]
+pub struct HeaderTemplate {
+    source: String,
+}
+
+impl HeaderTemplate {
+    pub fn decode(value: &Value) -> Result<Self, Error> {
+        let source = decode_string(value, "header_template")?;
+        if source.trim().is_empty() {
+            return Err(parse_error!("Value of 'header_template' must not be empty"));
+        }
+        Environment::new()
+            .template_from_str(&source)
+            .map_err(|e| parse_error!("Invalid 'header_template': {e}"))?;
+        Ok(Self { source })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Renders the header for the outputs of query `id`, ensuring
+    /// that it ends with a newline
+    pub fn render(&self, id: &str, generated_at: &str) -> Result<String, Error> {
+        let ctx = context! {
+            id,
+            generated_at,
+            tapestry_version => env!("CARGO_PKG_VERSION"),
+        };
+        let mut header = Environment::new()
+            .render_str(&self.source, ctx)
+            .map_err(Error::MiniJinja)?;
+        if !header.ends_with('\n') {
+            header.push('\n');
+        }
+        Ok(header)
+    }
+}
+
+/// Returns `contents` without the `header`, or `None` if `contents`
+/// doesn't start with the `header`
+///
+/// The `header` is expected to be rendered with `FIXED_TIMESTAMP` as
+/// `generated_at`. As the header of an existing output file would
+/// differ in it, the timestamps in as many leading lines of
+/// `contents` as there are in the `header` are replaced with
+/// `FIXED_TIMESTAMP` before comparing.
+pub fn strip_header<'a>(header: Option<&str>, contents: &'a [u8]) -> Option<&'a [u8]> {
+    let header = match header {
+        Some(h) => h,
+        None => return Some(contents),
+    };
+    let mut rest = contents;
+    for _ in 0..header.lines().count() {
+        rest = match rest.iter().position(|b| *b == b'\n') {
+            Some(i) => &rest[i + 1..],
+            None => &[],
+        };
+    }
+    let leading = String::from_utf8_lossy(&contents[..contents.len() - rest.len()]);
+    (fix_timestamps(&leading) == header).then_some(rest)
+}
+
+/// Returns the rendered `header` with the timestamps in it replaced
+/// with `FIXED_TIMESTAMP`, for comparing it using `strip_header`
+pub fn fix_timestamps(header: &str) -> Cow<'_, str> {
+    static TIMESTAMP_RE: OnceLock<Regex> = OnceLock::new();
+    let re =
+        TIMESTAMP_RE.get_or_init(|| Regex::new(r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z").unwrap());
+    re.replace_all(header, FIXED_TIMESTAMP)
+}
+
+/// Returns `t` as a UTC timestamp in the RFC 3339 format
+/// e.g. `2024-05-01T09:30:00Z`
+pub fn timestamp(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Conversion of days since epoch to the civil date, based on
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_header_template() {
+        let value = Value::from("-- query: {{ id }}\n-- generated at {{ generated_at }} by tapestry v{{ tapestry_version }}");
+        let header = HeaderTemplate::decode(&value).unwrap();
+        let rendered = header.render("artists", "2024-05-01T09:30:00Z").unwrap();
+        assert_eq!(
+            format!(
+                "-- query: artists\n-- generated at 2024-05-01T09:30:00Z by tapestry v{}\n",
+                env!("CARGO_PKG_VERSION")
+            ),
+            rendered
+        );
+
+        // The header is stripped irrespective of `generated_at`
+        let contents = format!("{rendered}-- name: artists\nSELECT 1;\n");
+        let fixed = header.render("artists", FIXED_TIMESTAMP).unwrap();
+        assert_eq!(
+            Some(b"-- name: artists\nSELECT 1;\n".as_slice()),
+            strip_header(Some(&fixed), contents.as_bytes())
+        );
+        assert_eq!(
+            Some(contents.as_bytes()),
+            strip_header(None, contents.as_bytes())
+        );
+        // ..but not if any other part of it is different
+        let other = header.render("albums", FIXED_TIMESTAMP).unwrap();
+        assert_eq!(None, strip_header(Some(&other), contents.as_bytes()));
+        assert_eq!(None, strip_header(Some(&fixed), b"SELECT 1;"));
+        let older = contents.replace(env!("CARGO_PKG_VERSION"), "0.0.1");
+        assert_eq!(None, strip_header(Some(&fixed), older.as_bytes()));
+
+        assert!(HeaderTemplate::decode(&Value::from("-- {{ id ")).is_err());
+        assert!(HeaderTemplate::decode(&Value::from(" ")).is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!("1970-01-01T00:00:00Z", timestamp(UNIX_EPOCH));
+        let t = UNIX_EPOCH + Duration::from_secs(1_714_555_800);
+        assert_eq!("2024-05-01T09:30:00Z", timestamp(t));
+        let t = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!("2000-02-29T00:00:00Z", timestamp(t));
+    }
+}
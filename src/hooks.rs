@@ -0,0 +1,226 @@
+use crate::error::{parse_error, Error};
+use crate::toml::decode_string;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use toml::Value;
+
+/// A file written by the `render` command along with the id of the
+/// query it belongs to (for tests, the query being tested)
+pub struct RenderedOutput<'a> {
+    pub id: &'a str,
+    pub path: &'a Path,
+}
+
+/// Command to be executed after the output files are written
+///
+/// If any of the args contains the `{output}` or `{id}` placeholders,
+/// the command is executed once per output file with the placeholders
+/// substituted. Otherwise it's executed only once for all files.
+#[derive(Debug)]
+pub struct PostRenderHook {
+    args: Vec<String>,
+}
+
+impl PostRenderHook {
+    pub fn decode(value: &Value) -> Result<Self, Error> {
+        let args = value
+            .as_array()
+            .ok_or(parse_error!(
+                "Value of 'post_render' expected to be an array of strings"
+            ))?
+            .iter()
+            .map(|v| decode_string(v, "post_render[]"))
+            .collect::<Result<Vec<String>, Error>>()?;
+        if args.is_empty() {
+            return Err(parse_error!("Value of 'post_render' must not be empty"));
+        }
+        Ok(Self { args })
+    }
+
+    /// Returns the executable followed by its args
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    fn is_per_output(&self) -> bool {
+        self.args
+            .iter()
+            .any(|a| a.contains("{output}") || a.contains("{id}"))
+    }
+
+    /// Returns the args for every invocation of the command
+    ///
+    /// Identical invocations are included only once e.g. when all
+    /// queries are written to a single file and only `{output}` is
+    /// used.
+    fn invocations(&self, outputs: &[RenderedOutput]) -> Vec<Vec<String>> {
+        if !self.is_per_output() {
+            return vec![self.args.clone()];
+        }
+        let mut result: Vec<Vec<String>> = Vec::with_capacity(outputs.len());
+        for output in outputs {
+            // @UNWRAP: Output paths are derived from the manifest
+            // which is valid UTF-8
+            let path = output.path.to_str().unwrap();
+            let args = self
+                .args
+                .iter()
+                .map(|a| a.replace("{output}", path).replace("{id}", output.id))
+                .collect::<Vec<String>>();
+            if !result.contains(&args) {
+                result.push(args);
+            }
+        }
+        result
+    }
+
+    /// Executes the command for `outputs` using `exec`
+    ///
+    /// A failed invocation doesn't prevent the remaining ones from
+    /// being executed. All failures are reported together in the
+    /// returned error.
+    fn run_with<F>(&self, outputs: &[RenderedOutput], mut exec: F) -> Result<(), Error>
+    where
+        F: FnMut(&[String]) -> Result<(), String>,
+    {
+        let failures = self
+            .invocations(outputs)
+            .iter()
+            .filter_map(|args| exec(args).err().map(|e| format!("{}: {e}", args.join(" "))))
+            .collect::<Vec<String>>();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Hook(failures.join("\n")))
+        }
+    }
+
+    /// Executes the command for `outputs`
+    ///
+    /// If `stdout_to_stderr` is true, the output of the command is
+    /// printed to stderr instead e.g. when stdout is reserved for
+    /// the `--json-lines` output.
+    pub fn run(&self, outputs: &[RenderedOutput], stdout_to_stderr: bool) -> Result<(), Error> {
+        self.run_with(outputs, |args| {
+            let mut cmd = Command::new(&args[0]);
+            cmd.args(&args[1..]);
+            let status = if stdout_to_stderr {
+                let mut child = cmd
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| e.to_string())?;
+                // @UNWRAP: stdout of the child is piped above
+                let mut stdout = child.stdout.take().unwrap();
+                io::copy(&mut stdout, &mut io::stderr()).map_err(|e| e.to_string())?;
+                child.wait()
+            } else {
+                cmd.status()
+            }
+            .map_err(|e| e.to_string())?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(format!("exited with {status}"))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn hook(args: &[&str]) -> PostRenderHook {
+        let value = Value::Array(args.iter().map(|a| Value::from(*a)).collect());
+        PostRenderHook::decode(&value).unwrap()
+    }
+
+    fn outputs() -> Vec<RenderedOutput<'static>> {
+        vec![
+            RenderedOutput {
+                id: "artists_long_songs",
+                path: Path::new("output/queries/artists_long_songs.sql"),
+            },
+            RenderedOutput {
+                id: "artists_long_songs",
+                path: Path::new("output/tests/all_artists_long_songs_count_test.sql"),
+            },
+            RenderedOutput {
+                id: "songs_formats@artist",
+                path: Path::new("output/queries/songs_formats-artist.sql"),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_run_per_output() {
+        let h = hook(&["psql", "-f", "{output}", "-v", "id={id}"]);
+        let mut calls: Vec<Vec<String>> = vec![];
+        h.run_with(&outputs(), |args| {
+            calls.push(args.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(3, calls.len());
+        assert_eq!(
+            vec![
+                "psql",
+                "-f",
+                "output/queries/artists_long_songs.sql",
+                "-v",
+                "id=artists_long_songs"
+            ],
+            calls[0]
+        );
+        assert_eq!(
+            "output/tests/all_artists_long_songs_count_test.sql",
+            calls[1][2]
+        );
+        assert_eq!("output/queries/songs_formats-artist.sql", calls[2][2]);
+        assert_eq!("id=songs_formats@artist", calls[2][4]);
+    }
+
+    #[test]
+    fn test_run_once() {
+        let h = hook(&["make", "lint"]);
+        let mut calls: Vec<Vec<String>> = vec![];
+        h.run_with(&outputs(), |args| {
+            calls.push(args.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(vec![vec!["make", "lint"]], calls);
+    }
+
+    #[test]
+    fn test_run_aggregates_errors() {
+        let h = hook(&["psql", "-f", "{output}"]);
+        let mut count = 0;
+        let res = h.run_with(&outputs(), |args| {
+            count += 1;
+            if args[2].contains("/queries/") {
+                Err("exited with exit status: 3".to_owned())
+            } else {
+                Ok(())
+            }
+        });
+        // All invocations are executed even if some of them fail
+        assert_eq!(3, count);
+        match res {
+            Err(Error::Hook(msg)) => {
+                assert_eq!(2, msg.lines().count());
+                assert!(msg.contains("psql -f output/queries/artists_long_songs.sql"));
+                assert!(msg.contains("psql -f output/queries/songs_formats-artist.sql"));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(PostRenderHook::decode(&Value::from("psql")).is_err());
+        assert!(PostRenderHook::decode(&Value::Array(vec![])).is_err());
+    }
+}
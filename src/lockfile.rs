@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::formatters::Formatter;
+use crate::output::{OutputFormat, SqlToWrite};
+use crate::tagging::NameTagger;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the lockfile, which is read from and written to the base
+/// dir of the manifest
+pub const LOCK_FILE: &str = "tapestry.lock";
+
+/// Hashes of the rendered outputs of the queries (along with their
+/// tests), recorded by `render --since-lock` so that queries whose
+/// outputs haven't changed can be skipped in subsequent runs
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub queries: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Reads the lockfile at `path`, returning an empty one if it
+    /// doesn't exist yet
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        toml::from_str(&contents)
+            .map_err(|e| Error::Cli(format!("Invalid lockfile '{}': {e}", path.display())))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        // @UNWRAP: Serializing a map of strings to strings can't fail
+        let contents = toml::to_string_pretty(self).unwrap();
+        fs::write(path, contents).map_err(Error::Io)
+    }
+
+    /// Returns the hash of the `outputs` of a query i.e. the paths
+    /// and the (unformatted but tagged) SQL of the query and its
+    /// tests, along with the `header` (if any) prepended to them
+    ///
+    /// The config of the formatter applied to each output is hashed
+    /// too, and so is the `out_format` (by way of the path), so that
+    /// the outputs are written again if they'd be different even
+    /// though the SQL is the same.
+    pub fn hash<'a, 'b: 'a, I: IntoIterator<Item = &'a SqlToWrite<'b>>>(
+        outputs: I,
+        header: Option<&str>,
+        formatter: Option<&Formatter>,
+        tagger: Option<&NameTagger>,
+        out_format: OutputFormat,
+    ) -> String {
+        let mut hasher = Fnv::default();
+        if let Some(h) = header {
+            hasher.update(h.as_bytes());
+        }
+        for output in outputs {
+            hasher.update(out_format.path(&output.path).to_string_lossy().as_bytes());
+            hasher.update(output.tagged_sql(tagger).as_bytes());
+            let config = output.formatter(formatter).map(|f| f.config_key());
+            hasher.update(config.unwrap_or_default().as_bytes());
+        }
+        format!("{:016x}", hasher.finish())
+    }
+
+    pub fn is_locked(&self, query_id: &str, hash: &str) -> bool {
+        self.queries.get(query_id).is_some_and(|h| h == hash)
+    }
+
+    pub fn update(&mut self, query_id: &str, hash: String) {
+        self.queries.insert(query_id.to_owned(), hash);
+    }
+}
+
+/// 64-bit FNV-1a hasher, which is used as, unlike the hasher in std,
+/// it's guaranteed to be the same across builds
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv {
+    /// Updates the hash with `bytes` followed by a zero byte, so that
+    /// consecutive updates can't be confused with a single one
+    pub fn update(&mut self, bytes: &[u8]) {
+        for b in bytes.iter().chain(&[0]) {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::metadata::Metadata;
+    use crate::render::Engine;
+    use crate::util::test_util::{test_manifest, TempDir};
+
+    #[test]
+    fn test_lockfile() {
+        let dir = TempDir::new("lockfile");
+        fs::write(
+            dir.join("count_rows.sql.j2"),
+            "SELECT count(*) FROM {{ table }};\n",
+        )
+        .unwrap();
+        let manifest = |table: &str| {
+            test_manifest(
+                &dir,
+                "output",
+                &format!(
+                    r#"
+[[query_templates]]
+path = "count_rows.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "count_rows"
+template = "count_rows.sql.j2"
+args = {{ table = "{table}" }}
+"#
+                ),
+            )
+        };
+        let hash_of = |manifest: String| {
+            let metadata: Metadata = manifest.parse().unwrap();
+            let engine = Engine::from(&metadata);
+            let query = metadata.queries.get("count_rows").unwrap();
+            let sql = engine.render_query(&query.id, None).unwrap();
+            let outputs = SqlToWrite::for_query(query, sql);
+            Lockfile::hash(outputs.iter(), None, None, None, OutputFormat::Sql)
+        };
+
+        let lock_path = dir.join(LOCK_FILE);
+        // A missing lockfile is the same as an empty one
+        let mut lock = Lockfile::read(&lock_path).unwrap();
+        assert_eq!(Lockfile::default(), lock);
+        let hash = hash_of(manifest("orders"));
+        assert!(!lock.is_locked("count_rows", &hash));
+        lock.update("count_rows", hash);
+        lock.write(&lock_path).unwrap();
+
+        // Rendering again with the same args matches the lock, but
+        // changing an arg causes the query to be rendered again
+        let lock = Lockfile::read(&lock_path).unwrap();
+        assert!(lock.is_locked("count_rows", &hash_of(manifest("orders"))));
+        assert!(!lock.is_locked("count_rows", &hash_of(manifest("users"))));
+
+        fs::write(&lock_path, "queries = 1").unwrap();
+        assert!(Lockfile::read(&lock_path).is_err());
+    }
+}
@@ -1,7 +1,8 @@
+use crate::presenter::ColorChoice;
 use env_logger::WriteStyle;
 use log::LevelFilter;
 
-pub fn init(verbosity: u8) {
+pub fn init(verbosity: u8, color: ColorChoice) {
     let level = match verbosity {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
@@ -10,6 +11,10 @@ pub fn init(verbosity: u8) {
     };
     env_logger::Builder::new()
         .filter(None, level)
-        .write_style(WriteStyle::Always)
+        .write_style(match color {
+            ColorChoice::Auto => WriteStyle::Auto,
+            ColorChoice::Always => WriteStyle::Always,
+            ColorChoice::Never => WriteStyle::Never,
+        })
         .init();
 }
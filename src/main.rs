@@ -1,59 +1,286 @@
 use crate::error::Error;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use std::io;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
+mod assertion;
+mod builder;
 mod command;
+mod cond_expr;
+mod editor;
+mod envfile;
 mod error;
 mod formatters;
+mod header;
+mod hooks;
+mod lockfile;
 mod logging;
 mod metadata;
+mod migration;
+mod normalize;
 mod output;
 mod placeholder;
+mod presenter;
 mod query;
 mod query_template;
 mod render;
+mod resolved;
 mod scaffolding;
+mod stats;
 mod tagging;
 mod test_template;
 mod toml;
 mod util;
 mod validation;
+mod watch;
 
+// The command is parsed only once, so the size of the variants
+// doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "Initialize a new tapestry \"project\"")]
     Init { path: PathBuf },
     #[command(about = "Validate manifest and template files")]
-    Validate,
+    Validate {
+        #[arg(
+            long,
+            default_value_t = command::MAX_ERRORS,
+            help = "Max no. of manifest mistakes to print (0 to print all)"
+        )]
+        max_errors: usize,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Only check the manifest itself, skipping checks for existence of files"
+        )]
+        syntax_only: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "syntax_only",
+            help = "Print the variables and filters used in every template as JSON, instead of validating"
+        )]
+        dump_context_schema: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Reject query ids that are not lowercase snake case, unless 'id_pattern' is set in the manifest"
+        )]
+        strict_ids: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Fix the casing of keys in the manifest that match a supported key e.g. 'Template'"
+        )]
+        fix: bool,
+    },
+    #[command(about = "Render tests and run them against a database")]
+    Assert {
+        #[command(flatten)]
+        tags: command::TagArgs,
+        #[command(flatten)]
+        db_url: command::DbUrlArgs,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Include queries marked with 'skip = true' in the manifest"
+        )]
+        include_skipped: bool,
+    },
+    #[command(about = "Render golden tests and compare them with the expected files")]
+    Test {
+        #[command(flatten)]
+        tags: command::TagArgs,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Include queries marked with 'skip = true' in the manifest"
+        )]
+        include_skipped: bool,
+    },
+    #[command(about = "Print shell completion script")]
+    Completions { shell: Shell },
+    #[command(about = "Print query ids or test template paths, one per line")]
+    List {
+        #[command(flatten)]
+        tags: command::TagArgs,
+        #[arg(
+            default_value = "queries",
+            help = "What to list: queries or tests",
+            value_parser = command::list_target_parser,
+        )]
+        target: command::ListTarget,
+        #[arg(
+            long,
+            help = "Only include queries (or tests of queries) whose id matches this glob"
+        )]
+        query: Option<String>,
+    },
     #[command(about = "Render templates into SQL files")]
-    Render,
+    Render {
+        #[command(flatten)]
+        manifest: command::ManifestArgs,
+        #[command(flatten)]
+        args: command::RenderArgs,
+    },
     #[command(about = "Print tabular summary of queries and tests")]
     Summary {
+        #[command(flatten)]
+        tags: command::TagArgs,
+        #[command(flatten)]
+        group: command::GroupArgs,
         #[arg(
             long,
             default_value_t = false,
             help = "Include queries and tests not defined in manifest"
         )]
         all: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Include queries marked with 'skip = true' in the manifest"
+        )]
+        include_skipped: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "absolute_paths",
+            help = "Show paths relative to the dir containing the manifest (default)"
+        )]
+        relative_paths: bool,
+        #[arg(long, default_value_t = false, help = "Show absolute paths")]
+        absolute_paths: bool,
     },
     #[command(about = "Preview changes without rendering")]
     Status {
+        #[command(flatten)]
+        args: command::StatusArgs,
+    },
+    #[command(about = "Print the no. of bind parameters in each query")]
+    Params {
+        #[command(flatten)]
+        tags: command::TagArgs,
         #[arg(
             long,
             default_value_t = false,
-            help = "Exit with non-zero code if any templates have unrendered changes"
+            help = "Include queries marked with 'skip = true' in the manifest"
         )]
-        assert_no_changes: bool,
+        include_skipped: bool,
+    },
+    #[command(about = "Print resolved config of a query i.e. paths, vars, formatter and tests")]
+    ExplainQuery {
+        #[arg(help = "Id of the query")]
+        id: String,
+        #[command(flatten)]
+        conds: command::CondArgs,
     },
     #[command(about = "Print a summary of test coverage")]
     Coverage {
+        #[command(flatten)]
+        tags: command::TagArgs,
         #[arg(
             long,
-            help = "Exit with non-zero code if coverage is under specified percentage",
-            value_parser = command::cov_threshold_parser,
+            value_name = "[TAG=]THRESHOLD",
+            help = "Exit with non-zero code if coverage (overall or of queries with TAG) is under specified percentage (e.g. 80%), ratio (e.g. 0.8) or count of tested queries (e.g. count:40) (may be repeated)",
+            value_parser = command::fail_under_parser,
         )]
-        fail_under: Option<u8>,
+        fail_under: Vec<command::CovThreshold>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Include queries marked with 'skip = true' in the manifest"
+        )]
+        include_skipped: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print coverage per tag instead of per query"
+        )]
+        by_tag: bool,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Exit with non-zero code if any query with tests in the baseline (see '--save') has none now"
+        )]
+        baseline: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Save the no. of tests of every query as JSON to PATH, for use with '--baseline'"
+        )]
+        save: Option<PathBuf>,
+    },
+    #[command(about = "Rename a query along with its references and output files")]
+    Rename {
+        #[arg(help = "Current id of the query")]
+        old_id: String,
+        #[arg(help = "New id of the query")]
+        new_id: String,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print the changes without updating the manifest or output files"
+        )]
+        dry_run: bool,
+    },
+    #[command(about = "Format query and test templates in place using the configured formatter")]
+    Fmt {
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Exit with non-zero code if any templates are not formatted, without updating them"
+        )]
+        check: bool,
+    },
+    #[command(about = "Delete the output files of the queries and tests")]
+    Clean {
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print the files to be deleted along with their counts and size, without deleting them"
+        )]
+        dry_run: bool,
+        #[command(flatten)]
+        escape: command::EscapeArgs,
+    },
+    #[command(about = "Re-render the output files whenever the manifest or templates change")]
+    Watch {
+        #[command(flatten)]
+        manifest: command::ManifestArgs,
+        #[command(flatten)]
+        escape: command::EscapeArgs,
+        #[arg(
+            long,
+            value_name = "CMD",
+            help = "Shell command to run after every re-render that changes the output files"
+        )]
+        run: Option<String>,
+        #[arg(
+            long,
+            value_name = "MS",
+            default_value_t = 500,
+            help = "Interval in milliseconds for polling the files for changes"
+        )]
+        interval_ms: u64,
+    },
+    #[command(about = "Migrate manifest file to the latest conventions")]
+    MigrateManifest {
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print the changes without updating the manifest file"
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Also fix the casing of keys that match a supported key e.g. 'Template'"
+        )]
+        fix: bool,
     },
 }
 
@@ -62,6 +289,23 @@ enum Command {
 struct Cli {
     #[arg(short, global = true, action = clap::ArgAction::Count, help = "Verbosity level (can be specified multiple times)")]
     verbosity: u8,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = presenter::ColorChoice::Auto,
+        help = "When to color the output"
+    )]
+    color: presenter::ColorChoice,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "toml",
+        help = "Print the manifest as resolved by tapestry (absolute paths, defaults and overrides applied, globs expanded) and exit"
+    )]
+    print_manifest: Option<resolved::DumpFormat>,
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -69,14 +313,99 @@ struct Cli {
 impl Cli {
     fn execute(&self) -> Result<i32, Error> {
         // Initialize logging based on verbosity flag
-        logging::init(self.verbosity);
+        logging::init(self.verbosity, self.color);
+        presenter::set_color_choice(self.color);
+        if let Some(format) = self.print_manifest {
+            if self.command.is_some() {
+                return Err(Error::Cli(
+                    "'--print-manifest' can't be used with a command".to_owned(),
+                ));
+            }
+            return command::print_manifest(format);
+        }
         match &self.command {
             Some(Command::Init { path }) => command::init(path),
-            Some(Command::Validate) => command::validate(),
-            Some(Command::Render) => command::render(),
-            Some(Command::Summary { all }) => command::summary(*all),
-            Some(Command::Status { assert_no_changes }) => command::status(*assert_no_changes),
-            Some(Command::Coverage { fail_under }) => command::coverage(*fail_under),
+            Some(Command::Validate {
+                max_errors,
+                syntax_only,
+                dump_context_schema,
+                strict_ids,
+                fix,
+            }) => command::validate(
+                *max_errors,
+                *syntax_only,
+                *dump_context_schema,
+                *strict_ids,
+                *fix,
+            ),
+            Some(Command::Assert {
+                tags,
+                db_url,
+                include_skipped,
+            }) => command::assert(db_url, *include_skipped, tags),
+            Some(Command::Test {
+                tags,
+                include_skipped,
+            }) => command::test(*include_skipped, tags),
+            Some(Command::Completions { shell }) => {
+                command::completions(*shell, &mut Cli::command(), &mut io::stdout())
+            }
+            Some(Command::List {
+                tags,
+                target,
+                query,
+            }) => command::list(*target, query.as_deref(), tags),
+            Some(Command::Render { manifest, args }) => command::render(manifest, args),
+            Some(Command::Summary {
+                tags,
+                group,
+                all,
+                include_skipped,
+                relative_paths: _,
+                absolute_paths,
+            }) => command::summary(*all, *include_skipped, *absolute_paths, tags, group),
+            Some(Command::Status { args }) => command::status(args),
+            Some(Command::Params {
+                tags,
+                include_skipped,
+            }) => command::params(*include_skipped, tags),
+            Some(Command::ExplainQuery { id, conds }) => command::explain_query(id, conds),
+            Some(Command::Coverage {
+                tags,
+                fail_under,
+                include_skipped,
+                by_tag,
+                baseline,
+                save,
+            }) => command::coverage(
+                fail_under,
+                *include_skipped,
+                tags,
+                *by_tag,
+                baseline.as_deref(),
+                save.as_deref(),
+            ),
+            Some(Command::Rename {
+                old_id,
+                new_id,
+                dry_run,
+            }) => command::rename(old_id, new_id, *dry_run),
+            Some(Command::Fmt { check }) => command::fmt(*check),
+            Some(Command::Clean { dry_run, escape }) => command::clean(*dry_run, escape),
+            Some(Command::Watch {
+                manifest,
+                escape,
+                run,
+                interval_ms,
+            }) => command::watch(
+                manifest,
+                escape,
+                run.as_deref(),
+                Duration::from_millis(*interval_ms),
+            ),
+            Some(Command::MigrateManifest { dry_run, fix }) => {
+                command::migrate_manifest(*dry_run, *fix)
+            }
             None => Err(Error::Cli("Please specify the command".to_owned())),
         }
     }
@@ -93,3 +422,23 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_bash_completions() {
+        let mut buf = Vec::new();
+        let status = command::completions(Shell::Bash, &mut Cli::command(), &mut buf).unwrap();
+        assert_eq!(0, status);
+        let script = String::from_utf8(buf).unwrap();
+        assert!(!script.is_empty());
+        for subcommand in [
+            "init", "validate", "render", "status", "summary", "coverage",
+        ] {
+            assert!(script.contains(subcommand));
+        }
+    }
+}
@@ -1,22 +1,41 @@
+use crate::builder::MetadataBuilder;
 use crate::error::{parse_error, Error};
 use crate::formatters::Formatter;
+use crate::header::HeaderTemplate;
+use crate::hooks::PostRenderHook;
+use crate::normalize::Normalize;
 use crate::output::Layout;
 use crate::placeholder::Placeholder;
-use crate::query::Queries;
+use crate::query::{DefaultConds, Queries};
 use crate::query_template::QueryTemplates;
 use crate::tagging::{NameTagStyle, NameTagger};
-use crate::test_template::TestTemplates;
-use crate::toml::decode_pathbuf;
-use crate::util::ls_files;
+use crate::test_template::{TestKind, TestTemplates};
+use crate::toml::{decode_bool, decode_pathbuf, decode_string, decode_strset, unknown_keys};
+use crate::util::{is_same_path, is_within, ls_files, normalize_path, relative_to};
 use crate::validation::{validate_path, ManifestMistake};
 use log::{error, info, warn};
-use std::collections::HashSet;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use toml::Table;
+use std::str::FromStr;
+use toml::{Table, Value};
+
+/// Latest version of the manifest file format
+pub const MANIFEST_VERSION: i64 = 1;
+
+/// Pattern that query ids must match with `validate --strict-ids`
+/// when `id_pattern` is not specified in the manifest, i.e. lowercase
+/// snake case
+pub const DEFAULT_ID_PATTERN: &str = "^[a-z][a-z0-9_]*$";
 
 #[derive(Debug)]
 pub struct Metadata {
+    pub version: Option<i64>,
     pub placeholder: Placeholder,
     pub query_templates_dir: PathBuf,
     pub test_templates_dir: PathBuf,
@@ -25,9 +44,163 @@ pub struct Metadata {
     pub tests_output_dir: PathBuf,
     pub query_output_layout: Layout,
     pub name_tagger: Option<NameTagger>,
+    pub header_template: Option<HeaderTemplate>,
+    /// Normalization of whitespace in the rendered SQL
+    pub normalize: Option<Normalize>,
     pub query_templates: QueryTemplates,
     pub queries: Queries,
     pub test_templates: TestTemplates,
+    pub profiles: HashMap<String, HashSet<String>>,
+    /// Conds that are merged into the conds of every query whose
+    /// template supports them, unless it opts out
+    pub default_conds: HashSet<String>,
+    pub post_render: Option<PostRenderHook>,
+    /// Regex that all query ids must match
+    pub id_pattern: Option<String>,
+    pub unknown_keys: Vec<String>,
+}
+
+/// Top level keys supported in the manifest
+pub const KNOWN_KEYS: &[&str] = &[
+    "version",
+    "placeholder",
+    "query_templates_dir",
+    "test_templates_dir",
+    "queries_output_dir",
+    "tests_output_dir",
+    "formatter",
+    "query_output_layout",
+    "query_output_file",
+    "preserve_tree",
+    "name_tagger",
+    "header_template",
+    "normalize",
+    "query_templates",
+    "queries",
+    "test_templates",
+    "profiles",
+    "default_conds",
+    "id_pattern",
+    "groups",
+    "post_render",
+];
+
+pub const KNOWN_QUERY_TEMPLATE_KEYS: &[&str] = &["path", "all_conds"];
+
+pub const KNOWN_QUERY_KEYS: &[&str] = &[
+    "id",
+    "template",
+    "conds",
+    "inherit_conds",
+    "output",
+    "name_tag",
+    "skip",
+    "enabled_if",
+    "format",
+    "dialect",
+    "split_on",
+    "tests_glob",
+    "tags",
+    "args",
+    "variants",
+];
+
+pub const KNOWN_GROUP_KEYS: &[&str] = &["name", "queries"];
+
+pub const KNOWN_TEST_TEMPLATE_KEYS: &[&str] = &[
+    "query",
+    "path",
+    "output",
+    "requires_conds",
+    "placeholder",
+    "kind",
+    "expected",
+];
+
+/// Decodes the `profiles` table i.e. named sets of conds that can be
+/// selected using the `--profile` option
+fn decode_profiles(value: &Value) -> Result<HashMap<String, HashSet<String>>, Error> {
+    let table = value
+        .as_table()
+        .ok_or(parse_error!("Value of 'profiles' must be a table"))?;
+    let mut profiles = HashMap::with_capacity(table.len());
+    for (name, v) in table {
+        let conds = match v.get("conds") {
+            Some(c) => decode_strset(c, "profiles.*.conds")?,
+            None => HashSet::new(),
+        };
+        profiles.insert(name.to_owned(), conds);
+    }
+    Ok(profiles)
+}
+
+/// Returns the keys in the manifest that are not known to
+/// tapestry. Such keys are most likely typos.
+fn find_unknown_keys(table: &Table) -> Vec<String> {
+    let mut result = unknown_keys(table, KNOWN_KEYS, "");
+    let mut arrays = vec![
+        (
+            "query_templates[].",
+            table.get("query_templates"),
+            KNOWN_QUERY_TEMPLATE_KEYS,
+        ),
+        ("queries[].", table.get("queries"), KNOWN_QUERY_KEYS),
+        (
+            "test_templates[].",
+            table.get("test_templates"),
+            KNOWN_TEST_TEMPLATE_KEYS,
+        ),
+        ("groups[].", table.get("groups"), KNOWN_GROUP_KEYS),
+    ];
+    let groups = table.get("groups").and_then(|v| v.as_array());
+    for group in groups.into_iter().flatten() {
+        arrays.push((
+            "groups[].queries[].",
+            group.get("queries"),
+            KNOWN_QUERY_KEYS,
+        ));
+    }
+    for (prefix, value, known) in arrays {
+        if let Some(xs) = value.and_then(|v| v.as_array()) {
+            for t in xs.iter().filter_map(|x| x.as_table()) {
+                for k in unknown_keys(t, known, prefix) {
+                    if !result.contains(&k) {
+                        result.push(k);
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Decodes the `groups` array, returning the name and the `queries`
+/// of every group
+///
+/// Names must be unique and usable as dir names, as the outputs of
+/// the queries in a group are written to a sub dir by that name.
+fn decode_groups(value: &Value) -> Result<Vec<(String, &Value)>, Error> {
+    let xs = value
+        .as_array()
+        .ok_or(parse_error!("Value of 'groups' must be an array"))?;
+    let mut groups: Vec<(String, &Value)> = Vec::with_capacity(xs.len());
+    for x in xs {
+        let name = x
+            .get("name")
+            .ok_or(parse_error!("Missing 'name' in 'groups' entry"))
+            .map(|v| decode_string(v, "groups[].name"))??;
+        if name.trim().is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(parse_error!("Invalid 'groups[].name': {name:?}"));
+        }
+        if groups.iter().any(|(n, _)| *n == name) {
+            return Err(parse_error!("Duplicate group: '{name}'"));
+        }
+        let queries = x
+            .get("queries")
+            .ok_or(parse_error!("Missing 'queries' in 'groups' entry '{name}'"))?;
+        groups.push((name, queries));
+    }
+    Ok(groups)
 }
 
 /// `try_from` method for initializing `Metadata` from path to the
@@ -36,11 +209,191 @@ impl TryFrom<&Path> for Metadata {
     type Error = Error;
 
     fn try_from(p: &Path) -> Result<Self, Self::Error> {
-        let contents = std::fs::read_to_string(p).map_err(|e| {
-            error!("Unable to read manifest file {}: {}", p.display(), e);
-            Error::ManifestNotFound
-        })?;
+        Self::from_path(p, &DirOverrides::default())
+    }
+}
+
+/// Overrides for the dirs configured in the manifest
+///
+/// The overrides may be specified as CLI options or env vars and are
+/// applied on top of the manifest before it's decoded, so that the
+/// paths of query and test outputs are derived from the resolved
+/// dirs.
+#[derive(Debug, Default, PartialEq)]
+pub struct DirOverrides {
+    pub query_templates_dir: Option<PathBuf>,
+    pub test_templates_dir: Option<PathBuf>,
+    pub queries_output_dir: Option<PathBuf>,
+    pub tests_output_dir: Option<PathBuf>,
+    /// Subdir of both the output dirs (after the above overrides)
+    /// that all outputs are to be nested under
+    pub out_suffix: Option<PathBuf>,
+}
+
+impl DirOverrides {
+    /// Returns overrides set using the `TAPESTRY_*` env vars
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var_os(name))
+    }
+
+    pub fn from_vars<F: Fn(&str) -> Option<OsString>>(get_var: F) -> Self {
+        let get_path = |name| get_var(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+        Self {
+            query_templates_dir: get_path("TAPESTRY_QUERY_TEMPLATES_DIR"),
+            test_templates_dir: get_path("TAPESTRY_TEST_TEMPLATES_DIR"),
+            queries_output_dir: get_path("TAPESTRY_QUERIES_OUTPUT_DIR"),
+            tests_output_dir: get_path("TAPESTRY_TESTS_OUTPUT_DIR"),
+            out_suffix: None,
+        }
+    }
+
+    /// Returns overrides in which the values in `self` take
+    /// precedence over the ones in `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            query_templates_dir: self.query_templates_dir.or(other.query_templates_dir),
+            test_templates_dir: self.test_templates_dir.or(other.test_templates_dir),
+            queries_output_dir: self.queries_output_dir.or(other.queries_output_dir),
+            tests_output_dir: self.tests_output_dir.or(other.tests_output_dir),
+            out_suffix: self.out_suffix.or(other.out_suffix),
+        }
+    }
+
+    /// Returns overrides with all relative paths made absolute by
+    /// joining them with `base`
+    ///
+    /// The `out_suffix` is retained as is, as it's relative to the
+    /// output dirs.
+    pub fn absolute(self, base: &Path) -> Self {
+        let join = |p: Option<PathBuf>| p.map(|p| base.join(p));
+        Self {
+            query_templates_dir: join(self.query_templates_dir),
+            test_templates_dir: join(self.test_templates_dir),
+            queries_output_dir: join(self.queries_output_dir),
+            tests_output_dir: join(self.tests_output_dir),
+            out_suffix: self.out_suffix,
+        }
+    }
+
+    fn apply(&self, table: &mut Table) {
+        let overrides = [
+            ("query_templates_dir", &self.query_templates_dir),
+            ("test_templates_dir", &self.test_templates_dir),
+            ("queries_output_dir", &self.queries_output_dir),
+            ("tests_output_dir", &self.tests_output_dir),
+        ];
+        for (key, path) in overrides {
+            if let Some(p) = path {
+                info!("Overriding '{key}' with '{}'", p.display());
+                let value = Value::String(p.to_string_lossy().into_owned());
+                table.insert(key.to_owned(), value);
+            }
+        }
+        if let Some(suffix) = &self.out_suffix {
+            for key in ["queries_output_dir", "tests_output_dir"] {
+                if let Some(dir) = table.get(key).and_then(Value::as_str) {
+                    let nested = Path::new(dir).join(suffix);
+                    info!("Nesting '{key}' under '{}'", nested.display());
+                    let value = Value::String(nested.to_string_lossy().into_owned());
+                    table.insert(key.to_owned(), value);
+                }
+            }
+        }
+    }
+}
+
+/// `from_str` method for initializing `Metadata` from contents of
+/// the manifest file.
+impl FromStr for Metadata {
+    type Err = Error;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        Self::parse(contents, &DirOverrides::default())
+    }
+}
+
+impl Metadata {
+    /// Initializes `Metadata` from contents of the manifest file with
+    /// the dirs `overrides` applied
+    pub fn parse(contents: &str, overrides: &DirOverrides) -> Result<Self, Error> {
         let table: Table = contents.parse().map_err(Error::Toml)?;
+        Self::from_table(table, overrides)
+    }
+
+    /// Initializes `Metadata` without a manifest file by treating
+    /// every `*.sql.j2` file directly inside `dir` as a query template
+    ///
+    /// A query is defined for each template, with the id derived from
+    /// the file name (e.g. `artists.sql.j2` => `artists`) and no
+    /// conds. Every `<id>_test.sql.j2` file directly inside the
+    /// `tests` subdir is added as a test template of the query `id`.
+    /// The rest of the config takes the default values i.e. `posargs`
+    /// placeholder, the builtin formatter (the default choice of the
+    /// `init` command) and the output dirs `output/queries` and
+    /// `output/tests`.
+    pub fn discover(dir: &Path, overrides: &DirOverrides) -> Result<Self, Error> {
+        // Returns the (name, file name) of the `*.sql.j2` files in
+        // `dir` sorted by name
+        let templates = |dir: &Path| -> io::Result<Vec<(String, String)>> {
+            let mut files = ls_files(dir, false)?
+                .into_iter()
+                .filter_map(|p| {
+                    let file_name = p.file_name()?.to_str()?.to_owned();
+                    let name = file_name.strip_suffix(".sql.j2")?.to_owned();
+                    Some((name, file_name))
+                })
+                .collect::<Vec<(String, String)>>();
+            files.sort();
+            Ok(files)
+        };
+        let files = templates(dir).map_err(Error::Io)?;
+        let tests_dir = overrides
+            .test_templates_dir
+            .clone()
+            .unwrap_or_else(|| dir.join("tests"));
+        // The tests are optional
+        let test_files = match templates(&tests_dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let mut builder = MetadataBuilder::new()
+            .set_placeholder(Placeholder::PosArgs)
+            .set_query_templates_dir(dir)
+            .set_test_templates_dir(&tests_dir)
+            .set_formatter(Some(Formatter::builtin()))
+            .set_name_tagger(None)
+            .set_dir_overrides(overrides);
+        for (id, file_name) in &files {
+            builder = builder
+                .add_query_template(file_name, &[])
+                .add_query(id, file_name, &[]);
+        }
+        for (name, file_name) in test_files {
+            match name
+                .strip_suffix("_test")
+                .filter(|id| files.iter().any(|(x, _)| x == id))
+            {
+                Some(id) => builder = builder.add_test_template(id, &file_name),
+                None => warn!(
+                    "Skipping test template '{}' as it's not named '<query id>_test.sql.j2'",
+                    tests_dir.join(file_name).display()
+                ),
+            }
+        }
+        builder.build()
+    }
+
+    fn from_table(mut table: Table, overrides: &DirOverrides) -> Result<Self, Error> {
+        overrides.apply(&mut table);
+        let version = match table.get("version") {
+            Some(v) => Some(
+                v.as_integer()
+                    .ok_or(parse_error!("Value of 'version' must be an integer"))?,
+            ),
+            None => None,
+        };
+        let unknown_keys = find_unknown_keys(&table);
         let placeholder = table
             .get("placeholder")
             .ok_or(parse_error!("Key 'placeholder' is missing"))
@@ -75,11 +428,31 @@ impl TryFrom<&Path> for Metadata {
             }
         };
 
+        let preserve_tree = match table.get("preserve_tree") {
+            Some(v) => decode_bool(v, "preserve_tree")?,
+            None => false,
+        };
+
         let name_tagger = match table.get("name_tagger") {
             Some(v) => NameTagger::decode(v)?,
             None => None,
         };
 
+        let header_template = match table.get("header_template") {
+            Some(v) => Some(HeaderTemplate::decode(v)?),
+            None => None,
+        };
+        if let (Some(_), Layout::OneFileAllQueries(_)) = (&header_template, &query_output_layout) {
+            return Err(parse_error!(
+                "Key 'header_template' is not supported when layout = one-file-all-queries"
+            ));
+        }
+
+        let normalize = match table.get("normalize") {
+            Some(v) => Some(Normalize::decode(v)?),
+            None => None,
+        };
+
         let query_templates = match table.get("query_templates") {
             Some(v) => QueryTemplates::decode(&query_templates_dir, v)?,
             None => {
@@ -88,28 +461,99 @@ impl TryFrom<&Path> for Metadata {
             }
         };
 
-        let queries = match table.get("queries") {
+        let id_pattern = match table.get("id_pattern") {
+            Some(v) => {
+                let pattern = decode_string(v, "id_pattern")?;
+                Regex::new(&pattern)
+                    .map_err(|e| parse_error!("Invalid regex in 'id_pattern': {e}"))?;
+                Some(pattern)
+            }
+            None => None,
+        };
+
+        let default_conds = match table.get("default_conds") {
+            Some(v) => decode_strset(v, "default_conds")?,
+            None => HashSet::new(),
+        };
+        let inherited_conds = DefaultConds::new(&default_conds, &query_templates);
+
+        let groups = match table.get("groups") {
+            Some(v) => decode_groups(v)?,
+            None => vec![],
+        };
+
+        let mut queries = match table.get("queries") {
             Some(v) => Queries::decode(
                 &query_templates_dir,
                 &queries_output_dir,
                 &query_output_layout,
+                preserve_tree,
+                &inherited_conds,
                 v,
             )?,
+            None if !groups.is_empty() => Queries::new(),
             None => {
                 warn!("TOML key 'queries' not found in manifest");
                 Queries::new()
             }
         };
 
-        let test_templates = match table.get("test_templates") {
-            Some(v) => TestTemplates::decode(&test_templates_dir, &tests_output_dir, v)?,
+        // Groups are flattened into the queries. Their outputs are
+        // written to a sub dir by the name of the group, except in
+        // case of the one-file-all-queries layout.
+        for (name, v) in &groups {
+            let output_dir = match query_output_layout {
+                Layout::OneFileOneQuery => queries_output_dir.join(name),
+                Layout::OneFileAllQueries(_) => queries_output_dir.clone(),
+            };
+            queries.extend(Queries::decode_group(
+                Some(name),
+                &query_templates_dir,
+                &output_dir,
+                &query_output_layout,
+                preserve_tree,
+                &inherited_conds,
+                v,
+            )?);
+        }
+
+        let mut test_templates = match table.get("test_templates") {
+            Some(v) => {
+                TestTemplates::decode(&test_templates_dir, &tests_output_dir, preserve_tree, v)?
+            }
+            None if queries.iter().any(|q| q.tests_glob.is_some()) => TestTemplates::new(),
             None => {
                 warn!("TOML key 'test_templates' not found in manifest");
                 TestTemplates::new()
             }
         };
+        // Test templates matching the `tests_glob` of the queries are
+        // expanded here so that all commands see them as declared
+        for query in queries.iter() {
+            if let Some(pattern) = &query.tests_glob {
+                test_templates.expand_glob(
+                    &query.id,
+                    pattern,
+                    &test_templates_dir,
+                    &tests_output_dir,
+                    preserve_tree,
+                )?;
+            }
+        }
+        test_templates.expand_variants(&queries);
+
+        let profiles = match table.get("profiles") {
+            Some(v) => decode_profiles(v)?,
+            None => HashMap::new(),
+        };
+
+        let post_render = match table.get("post_render") {
+            Some(v) => Some(PostRenderHook::decode(v)?),
+            None => None,
+        };
 
         let m = Self {
+            version,
             placeholder,
             query_templates_dir,
             test_templates_dir,
@@ -118,18 +562,24 @@ impl TryFrom<&Path> for Metadata {
             formatter,
             query_output_layout,
             name_tagger,
+            header_template,
+            normalize,
             query_templates,
             queries,
             test_templates,
+            profiles,
+            default_conds,
+            post_render,
+            id_pattern,
+            unknown_keys,
         };
 
         Ok(m)
     }
-}
 
-impl Metadata {
     pub fn default() -> Self {
         Self {
+            version: Some(MANIFEST_VERSION),
             placeholder: Placeholder::PosArgs,
             query_templates_dir: PathBuf::from("templates/queries"),
             test_templates_dir: PathBuf::from("templates/tests"),
@@ -140,16 +590,48 @@ impl Metadata {
             name_tagger: Some(NameTagger {
                 style: NameTagStyle::KebabCase,
             }),
+            header_template: None,
+            normalize: None,
             query_templates: QueryTemplates::new(),
             queries: Queries::new(),
             test_templates: TestTemplates::new(),
+            profiles: HashMap::new(),
+            default_conds: HashSet::new(),
+            post_render: None,
+            id_pattern: None,
+            unknown_keys: vec![],
         }
     }
 
-    /// Logs warnings when certain conditions where we don't want to
-    /// invalidate the command, but simply let the user know that
-    /// something may not be as per expectation
-    fn warnings(&self) -> Result<(), Error> {
+    /// Initializes `Metadata` by reading the contents of the manifest
+    /// file from `reader` e.g. stdin
+    pub fn from_reader<R: Read>(mut reader: R, overrides: &DirOverrides) -> Result<Self, Error> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).map_err(Error::Io)?;
+        Self::parse(&contents, overrides)
+    }
+
+    /// Initializes `Metadata` from path to the manifest file with the
+    /// dirs `overrides` applied
+    pub fn from_path(p: &Path, overrides: &DirOverrides) -> Result<Self, Error> {
+        let contents = Self::read_manifest(p)?;
+        Self::parse(&contents, overrides)
+    }
+
+    /// Reads the contents of the manifest file at path `p`
+    pub fn read_manifest(p: &Path) -> Result<String, Error> {
+        std::fs::read_to_string(p).map_err(|e| {
+            error!("Unable to read manifest file {}: {}", p.display(), e);
+            Error::ManifestNotFound
+        })
+    }
+
+    /// Returns warnings i.e. mistakes that don't invalidate the
+    /// manifest, but simply let the user know that something may not
+    /// be as per expectation
+    fn warnings(&self) -> Result<Vec<ManifestMistake<'_>>, Error> {
+        let mut warnings = vec![];
+
         // Warn regarding unused query templates (i.e. when a query
         // template is defined in the manifest but there's no query
         // defined that uses it)
@@ -159,9 +641,8 @@ impl Metadata {
             .map(|qt| qt.path.as_ref())
             .collect();
         let qt_used: HashSet<&Path> = self.queries.iter().map(|q| q.template.as_ref()).collect();
-        let qt_unused = qt_defined.difference(&qt_used);
-        for qt in qt_unused {
-            warn!("Unused query template found in manifest: {}", qt.display());
+        for qt in qt_defined.difference(&qt_used) {
+            warnings.push(ManifestMistake::UnusedQueryTemplate(qt));
         }
 
         // Warn regarding undefined query template files i.e. the
@@ -171,66 +652,87 @@ impl Metadata {
         // forgets to specify it in the manifest
         let qt_files = ls_files(&self.query_templates_dir, false).map_err(Error::Io)?;
         let qt_actual: HashSet<&Path> = qt_files.iter().map(|p| p.as_ref()).collect();
-        let qt_undefined = qt_actual.difference(&qt_defined);
-        for qt in qt_undefined {
-            warn!(
-                "Did you miss defining query template in manifest? {}",
-                qt.display()
-            );
+        for qt in qt_actual.difference(&qt_defined) {
+            warnings.push(ManifestMistake::UndefinedQueryTemplateFile(
+                qt.to_path_buf(),
+            ));
         }
 
         // Warn regarding undefined test template files i.e. the test
         // template files that exist in the `test_templates_dir` but
         // not defined in the manifest. This will happen when the user
         // creates a test template file but forgets to specify it in
-        // the manifest.
+        // the manifest. The expected files of golden tests are
+        // typically stored alongside the test templates, hence
+        // considered as defined.
         let tt_defined: HashSet<&Path> = self
             .test_templates
             .iter()
-            .map(|tt| tt.path.as_ref())
+            .flat_map(|tt| match &tt.kind {
+                TestKind::Pgtap => vec![tt.path.as_ref()],
+                TestKind::Golden { expected } => vec![tt.path.as_ref(), expected.as_ref()],
+            })
             .collect();
         let tt_files = ls_files(&self.test_templates_dir, false).map_err(Error::Io)?;
         let tt_actual: HashSet<&Path> = tt_files.iter().map(|p| p.as_ref()).collect();
-        let tt_undefined = tt_actual.difference(&tt_defined);
-        for tt in tt_undefined {
-            warn!(
-                "Did you miss defining test template in manifest? {}",
-                tt.display()
-            );
+        for tt in tt_actual.difference(&tt_defined) {
+            warnings.push(ManifestMistake::UndefinedTestTemplateFile(tt.to_path_buf()));
         }
 
-        // Warn when `query_output_layout` is `one-file-all-queries`
-        // and `name_tagger` is not set.
-        if let Layout::OneFileAllQueries(_) = self.query_output_layout {
-            if self.name_tagger.is_none() {
-                warn!("Name tagging is recommended in case of 'one-file-all-queries' layout")
+        // Warn regarding conds that are referenced in the query
+        // templates but not declared, as they would silently render
+        // as false. Templates that can't be read or parsed are
+        // skipped here as they're reported elsewhere.
+        for qt in self.query_templates.iter() {
+            if let Ok(conds) = qt.undeclared_conds() {
+                for cond in conds {
+                    warnings.push(ManifestMistake::UndeclaredCond {
+                        template: &qt.path,
+                        cond,
+                    });
+                }
             }
         }
 
         // Warn if the provided formatter is not found or installed on
         // the system
         if let Some(formatter) = self.formatter.as_ref() {
-            if !formatter.is_available() {
-                // @SAFE use of unwrap because if executable() returns
-                // None, it means the formatter is internal and hence
-                // will always be available.
-                let exec_path = formatter.executable().unwrap().display();
-                warn!("Executable for external formatter not found: {exec_path}");
+            for exec_path in formatter.unavailable_executables() {
+                warnings.push(ManifestMistake::FormatterNotFound(exec_path));
             }
         }
 
-        Ok(())
+        Ok(warnings)
     }
 
-    pub fn validate(&self) -> Vec<ManifestMistake> {
+    pub fn validate(&self) -> Vec<ManifestMistake<'_>> {
         let mut mistakes = vec![];
+        if let Some(v) = self.version {
+            if !(1..=MANIFEST_VERSION).contains(&v) {
+                mistakes.push(ManifestMistake::UnsupportedVersion(v));
+            }
+        }
+        // Templates dirs that don't exist. The templates inside them
+        // are then not reported individually, so that a missing dir
+        // results in a single mistake.
+        let mut missing_dirs: Vec<&Path> = vec![];
         match validate_path(&self.query_templates_dir, "query_templates_dir") {
             Ok(()) => {}
-            Err(m) => mistakes.push(m),
+            Err(m) => {
+                mistakes.push(m);
+                missing_dirs.push(&self.query_templates_dir);
+            }
         }
-        match validate_path(&self.test_templates_dir, "test_templates_dir") {
-            Ok(()) => {}
-            Err(m) => mistakes.push(m),
+        // Nothing is read from the test templates dir if there are no
+        // test templates, so it's fine for it to not exist
+        if !self.test_templates.is_empty() {
+            match validate_path(&self.test_templates_dir, "test_templates_dir") {
+                Ok(()) => {}
+                Err(m) => {
+                    mistakes.push(m);
+                    missing_dirs.push(&self.test_templates_dir);
+                }
+            }
         }
 
         if self.queries_output_dir.parent().is_none() {
@@ -258,13 +760,104 @@ impl Metadata {
             mistakes.push(ManifestMistake::NonUniqueDirs);
         }
 
-        mistakes.append(&mut self.query_templates.validate());
+        let in_missing_dir = |m: &ManifestMistake| match m {
+            ManifestMistake::PathDoesnotExist { path, .. } => {
+                missing_dirs.iter().any(|d| path.starts_with(d))
+            }
+            _ => false,
+        };
+        let mut qt_mistakes = self.query_templates.validate();
+        qt_mistakes.retain(|m| !in_missing_dir(m));
+        mistakes.append(&mut qt_mistakes);
         mistakes.append(
             &mut self
                 .queries
                 .validate(&self.query_templates, &self.query_output_layout),
         );
-        mistakes.append(&mut self.test_templates.validate(&self.queries));
+        let mut tt_mistakes = self.test_templates.validate(&self.queries);
+        tt_mistakes.retain(|m| !in_missing_dir(m));
+        mistakes.append(&mut tt_mistakes);
+
+        // Output files must not be written outside of the output dirs
+        mistakes.append(&mut self.escape_mistakes());
+
+        // Rendering must never overwrite the templates
+        let templates = self
+            .query_templates
+            .iter()
+            .map(|qt| qt.path.as_path())
+            .chain(self.test_templates.iter().map(|tt| tt.path.as_path()))
+            .collect::<Vec<&Path>>();
+        let outputs = self
+            .queries
+            .iter()
+            .map(|q| (q.output.as_path(), "queries[].output"))
+            .chain(
+                self.test_templates
+                    .iter()
+                    .map(|tt| (tt.output.as_path(), "test_templates[].output")),
+            );
+        // The same output may be shared by multiple queries in case
+        // of the one-file-all-queries layout, but it's reported once
+        let mut reported = HashSet::new();
+        for (output, key) in outputs {
+            if templates.iter().any(|t| is_same_path(t, output)) && reported.insert(output) {
+                mistakes.push(ManifestMistake::OutputOverwritesTemplate { output, key });
+            }
+        }
+
+        // Tests that are rendered with named variables must only
+        // refer to the params of the linked query, except for the
+        // ones that the test sets itself. Templates that can't be
+        // read are skipped as they're reported above.
+        for tt in self
+            .test_templates
+            .iter()
+            .filter(|tt| tt.placeholder == Some(Placeholder::Variables))
+        {
+            let Some(qt) = self
+                .queries
+                .get(&tt.query)
+                .and_then(|q| self.query_templates.get(&q.template))
+            else {
+                continue;
+            };
+            let Ok(Some(declared)) = qt.param_names() else {
+                continue;
+            };
+            if let Ok(params) = tt.undeclared_params(&declared) {
+                for param in params {
+                    mistakes.push(ManifestMistake::UndeclaredTestParam {
+                        test_template: &tt.path,
+                        query_id: &tt.query,
+                        param,
+                    });
+                }
+            }
+        }
+
+        if let Some(pattern) = &self.id_pattern {
+            mistakes.append(&mut self.id_mistakes(pattern));
+        }
+
+        // Default conds are merged only into the queries whose
+        // templates support them, so the ones that no template
+        // supports are reported once here (they're likely typos)
+        let mut invalid_default_conds = self
+            .default_conds
+            .iter()
+            .filter(|c| {
+                !self
+                    .query_templates
+                    .iter()
+                    .any(|qt| qt.all_conds.contains(*c))
+            })
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        if !invalid_default_conds.is_empty() {
+            invalid_default_conds.sort();
+            mistakes.push(ManifestMistake::InvalidDefaultConds(invalid_default_conds));
+        }
 
         if let Layout::OneFileAllQueries(_) = self.query_output_layout {
             if self.name_tagger.is_none() {
@@ -274,12 +867,167 @@ impl Metadata {
             }
         }
 
-        // Log warnings if any
-        let _ = self.warnings();
+        // Warn regarding unknown keys as they're likely to be typos
+        for key in self.unknown_keys.iter() {
+            mistakes.push(ManifestMistake::UnknownKey(key));
+        }
+
+        // Include warnings if any. Failure to compute the warnings
+        // (e.g. due to a missing dir) is not considered a mistake in
+        // itself as it's already covered by the checks above.
+        if let Ok(mut warnings) = self.warnings() {
+            mistakes.append(&mut warnings);
+        }
 
         mistakes
     }
 
+    /// Returns a mistake for every query and test output file that's
+    /// located outside of the respective output dir e.g. due to `..`
+    /// in the path or a symlink
+    pub fn escape_mistakes(&self) -> Vec<ManifestMistake<'_>> {
+        let mut mistakes = vec![];
+        for query in self.queries.iter() {
+            if !is_within(&query.output, &self.queries_output_dir) {
+                mistakes.push(ManifestMistake::PathEscapesDir {
+                    path: &query.output,
+                    key: "queries[].output",
+                    dir: &self.queries_output_dir,
+                });
+            }
+        }
+        for tt in self.test_templates.iter() {
+            if !is_within(&tt.output, &self.tests_output_dir) {
+                mistakes.push(ManifestMistake::PathEscapesDir {
+                    path: &tt.output,
+                    key: "test_templates[].output",
+                    dir: &self.tests_output_dir,
+                });
+            }
+        }
+        mistakes
+    }
+
+    /// Returns a mistake for every query id that doesn't match the
+    /// regex `pattern`
+    ///
+    /// In case of a variant of a query whose own id matches it, the
+    /// mistake is reported against the suffix of the variant.
+    ///
+    /// # Panics
+    ///
+    /// If `pattern` is not a valid regex. The `id_pattern` in the
+    /// manifest is checked when parsing it.
+    pub fn id_mistakes<'a>(&'a self, pattern: &'a str) -> Vec<ManifestMistake<'a>> {
+        let re = Regex::new(pattern).unwrap();
+        self.queries
+            .iter()
+            .filter(|q| !re.is_match(&q.id))
+            .map(|q| match &q.variant {
+                Some((base, suffix)) if re.is_match(base) => {
+                    ManifestMistake::VariantSuffixMismatch {
+                        id: &q.id,
+                        suffix,
+                        pattern,
+                    }
+                }
+                _ => ManifestMistake::IdPatternMismatch { id: &q.id, pattern },
+            })
+            .collect()
+    }
+
+    /// Creates the templates dirs that don't exist, returning the
+    /// paths of the ones that were created
+    ///
+    /// Same as in case of validation, the test templates dir is
+    /// considered only if there are any test templates.
+    pub fn create_template_dirs(&self) -> io::Result<Vec<&Path>> {
+        let mut dirs = vec![self.query_templates_dir.as_path()];
+        if !self.test_templates.is_empty() {
+            dirs.push(self.test_templates_dir.as_path());
+        }
+        let mut created = vec![];
+        for dir in dirs {
+            if !dir.try_exists()? {
+                fs::create_dir_all(dir)?;
+                created.push(dir);
+            }
+        }
+        Ok(created)
+    }
+
+    /// Same as `validate` but excluding the mistakes found by
+    /// checking the file system e.g. missing template files, so that
+    /// the manifest can be validated in isolation
+    pub fn validate_syntax(&self) -> Vec<ManifestMistake<'_>> {
+        self.validate()
+            .into_iter()
+            .filter(|m| !m.is_fs_check())
+            .collect()
+    }
+
+    /// Resolves the given paths to output files of the queries and
+    /// tests defined in the manifest
+    ///
+    /// The `paths` are relative to `cwd` (e.g. the dir the command is
+    /// run from) whereas the output files in the manifest are
+    /// relative to `base_dir`. Both are compared after resolving them
+    /// against the respective dirs, so absolute paths match too.
+    ///
+    /// Returns `Error::UndefinedOutput` if any of the paths doesn't
+    /// correspond to a known output file.
+    pub fn find_outputs(
+        &self,
+        paths: &[PathBuf],
+        cwd: &Path,
+        base_dir: &Path,
+    ) -> Result<HashSet<&Path>, Error> {
+        let known: HashMap<PathBuf, &Path> = self
+            .queries
+            .output_files()
+            .chain(self.test_templates.iter().map(|tt| tt.output.as_path()))
+            .map(|p| (relative_to(base_dir.join(p), base_dir), p))
+            .collect();
+        let mut result = HashSet::with_capacity(paths.len());
+        for path in paths {
+            match known.get(&relative_to(cwd.join(path), base_dir)) {
+                Some(p) => {
+                    result.insert(*p);
+                }
+                None => {
+                    return Err(Error::UndefinedOutput(
+                        normalize_path(path).display().to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the conds of the profile `name`
+    ///
+    /// # Error:
+    /// Returns `Error::UndefinedProfile` if the profile is not
+    /// defined in the manifest
+    pub fn profile_conds(&self, name: &str) -> Result<&HashSet<String>, Error> {
+        self.profiles
+            .get(name)
+            .ok_or(Error::UndefinedProfile(name.to_owned()))
+    }
+
+    /// Returns the header to be prepended to the output files of the
+    /// query `query_id`, if `header_template` is configured
+    pub fn render_header(
+        &self,
+        query_id: &str,
+        generated_at: &str,
+    ) -> Result<Option<String>, Error> {
+        self.header_template
+            .as_ref()
+            .map(|h| h.render(query_id, generated_at))
+            .transpose()
+    }
+
     /// Returns the combined output file in case layout =
     /// `OneFileAllQueries`
     ///
@@ -313,3 +1061,919 @@ impl Metadata {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::query::test_util::setup_queries;
+    use crate::render::Engine;
+    use crate::test_template::test_util::setup_test_templates;
+    use crate::util::test_util::TempDir;
+    use crate::validation::Severity;
+
+    #[test]
+    fn test_from_reader() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "examples/chinook/templates/queries"
+test_templates_dir = "examples/chinook/templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists_long_songs.sql.j2"
+all_conds = [ "genre", "limit" ]
+
+[[queries]]
+id = "artists_long_songs@genre"
+template = "artists_long_songs.sql.j2"
+conds = [ "genre" ]
+"#;
+        let m = Metadata::from_reader(manifest.as_bytes(), &DirOverrides::default()).unwrap();
+        assert_eq!(1, m.queries.len());
+        let engine = Engine::from(&m);
+        let output = engine
+            .render_query("artists_long_songs@genre", None)
+            .unwrap();
+        assert!(output.contains("WHERE"));
+        assert!(output.contains("$1"));
+    }
+
+    #[test]
+    fn test_dir_overrides_precedence() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+"#;
+        let env = DirOverrides::from_vars(|name| match name {
+            "TAPESTRY_QUERIES_OUTPUT_DIR" => Some(OsString::from("env/queries")),
+            "TAPESTRY_TESTS_OUTPUT_DIR" => Some(OsString::from("env/tests")),
+            "TAPESTRY_TEST_TEMPLATES_DIR" => Some(OsString::from("")),
+            _ => None,
+        });
+        let cli = DirOverrides {
+            queries_output_dir: Some(PathBuf::from("cli/queries")),
+            ..DirOverrides::default()
+        };
+        let overrides = cli.or(env);
+        assert_eq!(
+            DirOverrides {
+                query_templates_dir: None,
+                test_templates_dir: None,
+                queries_output_dir: Some(PathBuf::from("cli/queries")),
+                tests_output_dir: Some(PathBuf::from("env/tests")),
+                out_suffix: None,
+            },
+            overrides
+        );
+
+        let m = Metadata::parse(manifest, &overrides).unwrap();
+        // CLI > env > manifest
+        assert_eq!(PathBuf::from("cli/queries"), m.queries_output_dir);
+        assert_eq!(PathBuf::from("env/tests"), m.tests_output_dir);
+        assert_eq!(PathBuf::from("templates/queries"), m.query_templates_dir);
+        assert_eq!(PathBuf::from("templates/tests"), m.test_templates_dir);
+        // Output paths of queries are derived from the resolved dir
+        let q = m.queries.get("artists_long_songs").unwrap();
+        assert_eq!(
+            PathBuf::from("cli/queries/artists_long_songs.sql"),
+            q.output
+        );
+    }
+
+    #[test]
+    fn test_dir_overrides_out_suffix() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "artists.sql.j2"
+output = "catalog/albums.sql"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+"#;
+        let overrides = DirOverrides {
+            tests_output_dir: Some(PathBuf::from("cli/tests")),
+            out_suffix: Some(PathBuf::from("foo")),
+            ..DirOverrides::default()
+        };
+        let m = Metadata::parse(manifest, &overrides).unwrap();
+        // Both output dirs (after overriding) are nested under the
+        // suffix, and so are all the outputs
+        assert_eq!(PathBuf::from("output/queries/foo"), m.queries_output_dir);
+        assert_eq!(PathBuf::from("cli/tests/foo"), m.tests_output_dir);
+        assert_eq!(
+            PathBuf::from("output/queries/foo/artists.sql"),
+            m.queries.get("artists").unwrap().output
+        );
+        assert_eq!(
+            PathBuf::from("output/queries/foo/catalog/albums.sql"),
+            m.queries.get("albums").unwrap().output
+        );
+        let tt = m.test_templates.iter().next().unwrap();
+        assert!(tt.output.starts_with("cli/tests/foo"));
+        // The template dirs are not affected
+        assert_eq!(PathBuf::from("templates/queries"), m.query_templates_dir);
+    }
+
+    #[test]
+    fn test_output_path_escaping_dir() {
+        let mut m = Metadata::default();
+        m.query_templates_dir = PathBuf::from("examples/chinook/templates/queries");
+        m.test_templates_dir = PathBuf::from("examples/chinook/templates/tests");
+        m.queries = setup_queries(vec![
+            (
+                "artists_long_songs",
+                "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+                vec![],
+                "output/queries/artists_long_songs.sql",
+                None,
+            ),
+            (
+                "evil",
+                "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+                vec![],
+                "output/queries/../evil.sql",
+                None,
+            ),
+        ]);
+        m.test_templates = setup_test_templates(vec![(
+            "artists_long_songs",
+            "examples/chinook/templates/tests/all_artists_long_songs_test.sql.j2",
+            "output/tests/../../evil_test.sql",
+        )]);
+        let escaping = m
+            .validate()
+            .into_iter()
+            .filter_map(|mistake| match mistake {
+                ManifestMistake::PathEscapesDir { path, key, .. } => Some((path, key)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Path::new("output/queries/../evil.sql"), "queries[].output"),
+                (
+                    Path::new("output/tests/../../evil_test.sql"),
+                    "test_templates[].output"
+                ),
+            ],
+            escaping
+        );
+    }
+
+    #[test]
+    fn test_validate_syntax() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "examples/chinook/templates/queries"
+test_templates_dir = "examples/chinook/templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "nonexistent.sql.j2"
+all_conds = [ "genre" ]
+
+[[queries]]
+id = "nonexistent@genre"
+template = "nonexistent.sql.j2"
+conds = [ "genre" ]
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        let mistakes = m.validate();
+        assert!(mistakes.iter().any(|m| matches!(
+            m,
+            ManifestMistake::PathDoesnotExist {
+                key: "query_templates[].path",
+                ..
+            }
+        )));
+        assert!(m.validate_syntax().is_empty());
+
+        // Mistakes in the manifest itself are still caught
+        let manifest = manifest.replace(r#"all_conds = [ "genre" ]"#, r#"all_conds = []"#);
+        let m = Metadata::parse(&manifest, &DirOverrides::default()).unwrap();
+        match m.validate_syntax().as_slice() {
+            [ManifestMistake::InvalidConds { query_id, .. }] => {
+                assert_eq!(&"nonexistent@genre", query_id)
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_validate_conds() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "examples/chinook/templates/queries"
+test_templates_dir = "examples/chinook/templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "sales.sql.j2"
+all_conds = [ "eu", "has space", "2024" ]
+
+[[queries]]
+id = "sales_eu"
+template = "sales.sql.j2"
+conds = [ "eu", "eu" ]
+
+[[queries]]
+id = "sales_other"
+template = "sales.sql.j2"
+conds = [ "has space", "2024" ]
+enabled_if = "eu and not legacy"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        // Duplicates are removed when decoding
+        assert_eq!(1, m.queries.get("sales_eu").unwrap().conds.len());
+        let msgs = m
+            .validate_syntax()
+            .iter()
+            .map(|m| m.err_msg())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            vec![
+                "Cond 'eu' is specified more than once in 'conds' of query 'sales_eu'",
+                "Cond 'legacy' in 'enabled_if' of query 'sales_other' is not in the 'all_conds' of its template",
+                "Invalid cond \"has space\" in 'conds' of query 'sales_other' (must consist of only letters, digits and underscores, so that 'cond__<name>' is a valid identifier)",
+            ],
+            msgs
+        );
+    }
+
+    #[test]
+    fn test_validate_dangling_test_template() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "examples/chinook/templates/queries"
+test_templates_dir = "examples/chinook/templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists_long_songs.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+
+[[test_templates]]
+query = "all_artists_long_songs"
+path = "all_artists_long_songs_test.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        // Other templates in the example dirs are reported as warnings
+        let mistakes = m
+            .validate()
+            .into_iter()
+            .filter(|m| m.severity() == Severity::Error)
+            .collect::<Vec<ManifestMistake>>();
+        assert_eq!(1, mistakes.len());
+        match &mistakes[0] {
+            ManifestMistake::QueryRefNotFound {
+                query_id,
+                test_template,
+            } => {
+                assert_eq!(&"all_artists_long_songs", query_id);
+                assert!(test_template.ends_with("all_artists_long_songs_test.sql.j2"));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_validate_missing_templates_dir() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "examples/chinook/templates/missing"
+test_templates_dir = "examples/chinook/templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = []
+
+[[query_templates]]
+path = "albums.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "albums.sql.j2"
+
+[[test_templates]]
+query = "albums"
+path = "albums_test.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        let mistakes = m
+            .validate()
+            .into_iter()
+            .filter(|m| m.severity() == Severity::Error)
+            .collect::<Vec<ManifestMistake>>();
+        // The missing query templates dir is reported once instead of
+        // every template in it, whereas the test template (in a dir
+        // that exists) is reported individually
+        let keys = mistakes
+            .iter()
+            .map(|m| match m {
+                ManifestMistake::PathDoesnotExist { key, .. } => *key,
+                _ => panic!(),
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            vec!["query_templates_dir", "test_templates[].template"],
+            keys
+        );
+    }
+
+    #[test]
+    fn test_validate_output_overwrites_template() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "templates"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+output = "queries/artists.sql.j2"
+
+[[queries]]
+id = "albums"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "albums"
+path = "albums_test.sql.j2"
+output = "../../templates/tests/./albums_test.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        // The test output is also reported as escaping the tests
+        // output dir
+        let mistakes = m
+            .validate_syntax()
+            .into_iter()
+            .filter(|m| matches!(m, ManifestMistake::OutputOverwritesTemplate { .. }))
+            .collect::<Vec<ManifestMistake>>();
+        let keys = mistakes
+            .iter()
+            .map(|m| match m {
+                ManifestMistake::OutputOverwritesTemplate { key, .. } => *key,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["queries[].output", "test_templates[].output"], keys);
+        // Errors cause the `render` command to refuse to run
+        assert!(mistakes.iter().all(|m| m.severity() == Severity::Error));
+    }
+
+    #[test]
+    fn test_validate_undeclared_test_params() {
+        let dir = TempDir::new("test-params");
+        for d in ["templates/queries", "templates/tests"] {
+            fs::create_dir_all(dir.join(d)).unwrap();
+        }
+        fs::write(
+            dir.join("templates/queries/artists.sql.j2"),
+            "SELECT * FROM artists WHERE genre = {{ placeholder('genre') }}::text;\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("templates/tests/artists_test.sql.j2"),
+            "\\set limit 10\nPREPARE q AS {{ prepared_statement }};\n-- :month is not checked\nSELECT * FROM q WHERE genre = :'genre' AND year = :year AND meta @> '{\"k\":\"v\"}'::jsonb LIMIT :limit;\n",
+        )
+        .unwrap();
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+placeholder = "variables"
+"#;
+        let overrides = DirOverrides {
+            query_templates_dir: Some(dir.join("templates/queries")),
+            test_templates_dir: Some(dir.join("templates/tests")),
+            ..DirOverrides::default()
+        };
+        let m = Metadata::parse(manifest, &overrides).unwrap();
+        // `genre` is a param of the query and `limit` is set in the
+        // test itself
+        let params = m
+            .validate()
+            .into_iter()
+            .filter_map(|m| match m {
+                ManifestMistake::UndeclaredTestParam { param, .. } => Some(param),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["year"], params);
+        // Not reported with the `posargs` placeholder
+        let m = Metadata::parse(
+            &manifest.replace("placeholder = \"variables\"", ""),
+            &overrides,
+        )
+        .unwrap();
+        assert!(m
+            .validate()
+            .iter()
+            .all(|m| !matches!(m, ManifestMistake::UndeclaredTestParam { .. })));
+        // Nor if the params of the query are not all known
+        fs::write(
+            dir.join("templates/queries/artists.sql.j2"),
+            "SELECT * FROM artists WHERE {% for col in ['genre'] %}{{ col }} = {{ placeholder(col) }}{% endfor %};\n",
+        )
+        .unwrap();
+        let m = Metadata::parse(manifest, &overrides).unwrap();
+        assert!(m
+            .validate()
+            .iter()
+            .all(|m| !matches!(m, ManifestMistake::UndeclaredTestParam { .. })));
+    }
+
+    #[test]
+    fn test_tests_glob() {
+        let dir = TempDir::new("tests-glob");
+        let orders_dir = dir.join("templates/tests/orders");
+        fs::create_dir_all(&orders_dir).unwrap();
+        for name in ["totals_test.sql.j2", "refunds_test.sql.j2", "README.md"] {
+            fs::write(orders_dir.join(name), "").unwrap();
+        }
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "orders.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "orders"
+template = "orders.sql.j2"
+tests_glob = "orders/*_test.sql.j2"
+"#;
+        let overrides = DirOverrides {
+            test_templates_dir: Some(dir.join("templates/tests")),
+            tests_output_dir: Some(dir.join("output/tests")),
+            ..DirOverrides::default()
+        };
+        let m = Metadata::parse(manifest, &overrides).unwrap();
+        let query = m.queries.get("orders").unwrap();
+        let tests = m
+            .test_templates
+            .find_active_by_query(query)
+            .into_iter()
+            .map(|tt| (tt.path.clone(), tt.output.clone()))
+            .collect::<Vec<(PathBuf, PathBuf)>>();
+        assert_eq!(
+            vec![
+                (
+                    orders_dir.join("refunds_test.sql.j2"),
+                    dir.join("output/tests/refunds_test.sql")
+                ),
+                (
+                    orders_dir.join("totals_test.sql.j2"),
+                    dir.join("output/tests/totals_test.sql")
+                ),
+            ],
+            tests
+        );
+
+        // Wildcards in the dir part are not supported
+        let manifest = manifest.replace("orders/*_test", "*/*_test");
+        assert!(Metadata::parse(&manifest, &overrides).is_err());
+    }
+
+    #[test]
+    fn test_id_pattern() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+id_pattern = "^[a-z][a-z0-9_]*$"
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "ArtistsLongSongs"
+template = "artists.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        let ids = m
+            .validate_syntax()
+            .into_iter()
+            .filter_map(|m| match m {
+                ManifestMistake::IdPatternMismatch { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["ArtistsLongSongs"], ids);
+
+        // Without `id_pattern`, ids are checked only when asked for
+        // e.g. using `validate --strict-ids`
+        let manifest = manifest.replace("id_pattern = \"^[a-z][a-z0-9_]*$\"", "");
+        let m = Metadata::parse(&manifest, &DirOverrides::default()).unwrap();
+        assert!(!m
+            .validate_syntax()
+            .iter()
+            .any(|m| matches!(m, ManifestMistake::IdPatternMismatch { .. })));
+        let mistakes = m.id_mistakes(DEFAULT_ID_PATTERN);
+        assert_eq!(1, mistakes.len());
+        assert_eq!(Severity::Error, mistakes[0].severity());
+        assert_eq!(
+            "Query id 'ArtistsLongSongs' does not match the pattern '^[a-z][a-z0-9_]*$'",
+            mistakes[0].err_msg()
+        );
+
+        let manifest = format!("id_pattern = \"[a-z\"\n{manifest}");
+        assert!(Metadata::parse(&manifest, &DirOverrides::default()).is_err());
+    }
+
+    #[test]
+    fn test_variants() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "orders.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "orders"
+template = "orders.sql.j2"
+variants = [ { suffix = "tenant_a" }, { suffix = "Tenant-B" } ]
+
+[[test_templates]]
+query = "orders"
+path = "orders_test.sql.j2"
+
+[[test_templates]]
+query = "orders_tenant_a"
+path = "orders_tenant_a_test.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        // The test linked to the original id is linked to every
+        // variant, while the one linked to a variant is kept as is
+        let outputs = |id: &str| {
+            m.test_templates
+                .find_by_query(id)
+                .iter()
+                .map(|tt| tt.output.clone())
+                .collect::<Vec<PathBuf>>()
+        };
+        assert_eq!(
+            vec![
+                PathBuf::from("output/tests/orders_test_tenant_a.sql"),
+                PathBuf::from("output/tests/orders_tenant_a_test.sql")
+            ],
+            outputs("orders_tenant_a")
+        );
+        assert_eq!(
+            vec![PathBuf::from("output/tests/orders_test_Tenant-B.sql")],
+            outputs("orders_Tenant-B")
+        );
+        assert!(outputs("orders").is_empty());
+        assert!(!m
+            .validate_syntax()
+            .iter()
+            .any(|m| matches!(m, ManifestMistake::QueryRefNotFound { .. })));
+
+        // The suffix is reported if the id of the variant doesn't
+        // match the pattern only because of it
+        let mistakes = m.id_mistakes(DEFAULT_ID_PATTERN);
+        assert_eq!(1, mistakes.len());
+        assert_eq!(
+            "Invalid 'queries[].variants[].suffix': 'Tenant-B' (id 'orders_Tenant-B' of the variant does not match the pattern '^[a-z][a-z0-9_]*$')",
+            mistakes[0].err_msg()
+        );
+        let m = Metadata::parse(
+            &manifest.replace("id = \"orders\"", "id = \"Orders\""),
+            &DirOverrides::default(),
+        )
+        .unwrap();
+        assert!(m
+            .id_mistakes(DEFAULT_ID_PATTERN)
+            .iter()
+            .all(|m| matches!(m, ManifestMistake::IdPatternMismatch { .. })));
+    }
+
+    #[test]
+    fn test_default_conds() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+default_conds = [ "soft_delete", "sof_delete" ]
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = [ "soft_delete" ]
+
+[[query_templates]]
+path = "genres.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[queries]]
+id = "genres"
+template = "genres.sql.j2"
+
+[[queries]]
+id = "genres_all"
+template = "genres.sql.j2"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        assert_eq!(
+            HashSet::from(["soft_delete".to_owned()]),
+            m.queries.get("artists").unwrap().conds
+        );
+        assert!(m.queries.get("genres").unwrap().conds.is_empty());
+        // The cond that no template supports is reported once,
+        // instead of against every query
+        match m.validate_syntax().as_slice() {
+            [m @ ManifestMistake::InvalidDefaultConds(conds)] => {
+                assert_eq!(&vec!["sof_delete"], conds);
+                assert_eq!(Severity::Error, m.severity());
+            }
+            ms => panic!("{ms:?}"),
+        }
+    }
+
+    #[test]
+    fn test_groups() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[[query_templates]]
+path = "invoices.sql.j2"
+all_conds = []
+
+[[queries]]
+id = "invoices"
+template = "invoices.sql.j2"
+
+[[groups]]
+name = "billing"
+
+[[groups.queries]]
+id = "invoices_due"
+template = "invoices.sql.j2"
+tempalte = "typo"
+
+[[groups]]
+name = "reporting"
+
+[[groups.queries]]
+id = "invoices_due"
+template = "invoices.sql.j2"
+output = "reporting_due.sql"
+"#;
+        let m = Metadata::parse(manifest, &DirOverrides::default()).unwrap();
+        let groups = m
+            .queries
+            .iter()
+            .map(|q| (q.id.as_str(), q.group.as_deref(), q.output.as_path()))
+            .collect::<Vec<(&str, Option<&str>, &Path)>>();
+        assert_eq!(
+            vec![
+                ("invoices", None, Path::new("output/queries/invoices.sql")),
+                (
+                    "invoices_due",
+                    Some("billing"),
+                    Path::new("output/queries/billing/invoices_due.sql")
+                ),
+                (
+                    "invoices_due",
+                    Some("reporting"),
+                    Path::new("output/queries/reporting/reporting_due.sql")
+                ),
+            ],
+            groups
+        );
+        assert_eq!(vec!["groups[].queries[].tempalte"], m.unknown_keys);
+
+        // Ids must be unique across groups
+        let mistakes = m.validate_syntax();
+        assert!(mistakes.iter().any(|m| matches!(
+            m,
+            ManifestMistake::Duplicates {
+                key: "queries[].id",
+                value: "invoices_due"
+            }
+        )));
+
+        let manifest = manifest.replace("name = \"reporting\"", "name = \"billing\"");
+        match Metadata::parse(&manifest, &DirOverrides::default()) {
+            Err(Error::Parsing(msg)) => assert_eq!("Duplicate group: 'billing'", msg),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_profiles() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+
+[profiles.eu_premium]
+conds = [ "eu", "premium" ]
+
+[profiles.none]
+"#;
+        let m: Metadata = manifest.parse().unwrap();
+        assert_eq!(
+            &HashSet::from(["eu".to_owned(), "premium".to_owned()]),
+            m.profile_conds("eu_premium").unwrap()
+        );
+        assert!(m.profile_conds("none").unwrap().is_empty());
+        match m.profile_conds("unknown") {
+            Err(Error::UndefinedProfile(name)) => assert_eq!("unknown", name),
+            _ => panic!(),
+        }
+        assert!(m.unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let manifest = r#"
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+query_output_fiel = "queries.sql"
+
+[[query_templates]]
+path = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+tempalte = "artists_long_songs.sql.j2"
+"#;
+        let metadata: Metadata = manifest.parse().unwrap();
+        assert_eq!(None, metadata.version);
+        let warnings = metadata
+            .validate()
+            .into_iter()
+            .filter_map(|m| match m {
+                ManifestMistake::UnknownKey(k) => Some(k),
+                _ => None,
+            })
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["query_output_fiel", "queries[].tempalte"], warnings);
+
+        // When version is not supported
+        let manifest = r#"
+version = 2
+placeholder = "posargs"
+query_templates_dir = "templates/queries"
+test_templates_dir = "templates/tests"
+queries_output_dir = "output/queries"
+tests_output_dir = "output/tests"
+"#;
+        let metadata: Metadata = manifest.parse().unwrap();
+        assert_eq!(Some(2), metadata.version);
+        assert!(metadata
+            .validate()
+            .iter()
+            .any(|m| matches!(m, ManifestMistake::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_find_outputs() {
+        let mut metadata = Metadata::default();
+        metadata.queries = setup_queries(vec![
+            (
+                "artists_long_songs",
+                "templates/queries/artists_long_songs.sql.j2",
+                vec![],
+                "output/queries/artists_long_songs.sql",
+                None,
+            ),
+            (
+                "songs_formats",
+                "templates/queries/songs_formats.sql.j2",
+                vec![],
+                "output/queries/songs_formats.sql",
+                None,
+            ),
+        ]);
+        metadata.test_templates = setup_test_templates(vec![(
+            "artists_long_songs",
+            "templates/tests/artists_long_songs_test.sql.j2",
+            "output/tests/artists_long_songs_test.sql",
+        )]);
+
+        let base_dir = Path::new("/project");
+        let paths = vec![PathBuf::from("./output/tests/artists_long_songs_test.sql")];
+        let outputs = metadata.find_outputs(&paths, base_dir, base_dir).unwrap();
+        assert_eq!(1, outputs.len());
+        assert!(outputs.contains(Path::new("output/tests/artists_long_songs_test.sql")));
+
+        // Paths relative to a subdir of the base dir, or absolute
+        let paths = vec![
+            PathBuf::from("../output/queries/songs_formats.sql"),
+            PathBuf::from("/project/output/queries/artists_long_songs.sql"),
+        ];
+        let outputs = metadata
+            .find_outputs(&paths, Path::new("/project/sub"), base_dir)
+            .unwrap();
+        assert_eq!(2, outputs.len());
+        assert!(outputs.contains(Path::new("output/queries/songs_formats.sql")));
+        assert!(outputs.contains(Path::new("output/queries/artists_long_songs.sql")));
+
+        // ..which doesn't match if resolved against the base dir
+        let paths = vec![PathBuf::from("../output/queries/songs_formats.sql")];
+        assert!(metadata.find_outputs(&paths, base_dir, base_dir).is_err());
+
+        let paths = vec![
+            PathBuf::from("output/queries/songs_formats.sql"),
+            PathBuf::from("output/queries/unknown.sql"),
+        ];
+        match metadata.find_outputs(&paths, base_dir, base_dir) {
+            Err(Error::UndefinedOutput(p)) => assert_eq!("output/queries/unknown.sql", p),
+            _ => panic!(),
+        }
+    }
+}
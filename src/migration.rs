@@ -0,0 +1,334 @@
+use crate::error::{parse_error, Error};
+use crate::metadata::{
+    KNOWN_GROUP_KEYS, KNOWN_KEYS, KNOWN_QUERY_KEYS, KNOWN_QUERY_TEMPLATE_KEYS,
+    KNOWN_TEST_TEMPLATE_KEYS, MANIFEST_VERSION,
+};
+use log::warn;
+use toml_edit::{value, DocumentMut, Item, Key, Table};
+
+/// Keys that have been renamed in newer versions of the manifest
+///
+/// Each entry is a tuple of (array of tables, old key, new key)
+const RENAMED_KEYS: &[(&str, &str, &str)] = &[("queries", "option", "output")];
+
+/// Renames `old` key to `new` in the table while preserving the
+/// position of the entry and the comments around it.
+///
+/// If the table already has the `new` key, it's left untouched.
+fn rename_key(table: &mut Table, old: &str, new: &str) -> bool {
+    if table.contains_key(new) {
+        return false;
+    }
+    let order = table
+        .iter()
+        .map(|(k, _)| {
+            if k == old {
+                new.to_owned()
+            } else {
+                k.to_owned()
+            }
+        })
+        .collect::<Vec<String>>();
+    match table.remove_entry(old) {
+        Some((old_key, item)) => {
+            let mut new_key = Key::new(new);
+            *new_key.leaf_decor_mut() = old_key.leaf_decor().clone();
+            table.insert_formatted(&new_key, item);
+            let pos = |k: &Key| order.iter().position(|x| x == k.get());
+            table.sort_values_by(|k1, _, k2, _| pos(k1).cmp(&pos(k2)));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Migrates contents of the manifest file to the latest conventions,
+/// returning the migrated contents.
+///
+/// Deprecated keys are renamed and the `version` key is added if
+/// missing. Comments and ordering of the entries are preserved.
+pub fn migrate(contents: &str) -> Result<String, Error> {
+    let mut doc = contents
+        .parse::<DocumentMut>()
+        .map_err(|e| parse_error!("{}", e))?;
+
+    for (array_key, old, new) in RENAMED_KEYS {
+        if let Some(Item::ArrayOfTables(tables)) = doc.get_mut(array_key) {
+            for table in tables.iter_mut() {
+                rename_key(table, old, new);
+            }
+        }
+    }
+
+    // The `version` key gets added after all other top level
+    // key-value pairs (and before the tables)
+    if !doc.contains_key("version") {
+        let mut key = Key::new("version");
+        key.leaf_decor_mut()
+            .set_prefix("\n## Version of the manifest file format\n");
+        doc.insert_formatted(&key, value(MANIFEST_VERSION));
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Renames the keys of `table` that match one of the `known` keys
+/// except for the case (e.g. `Template`) to the known key
+///
+/// A key is left untouched, with a warning, if the known key is
+/// already present in the table or if other keys in it map to the
+/// same known key.
+fn fix_table_key_casing(table: &mut Table, known: &[&str], prefix: &str) {
+    let keys = table
+        .iter()
+        .map(|(k, _)| k.to_owned())
+        .collect::<Vec<String>>();
+    for key in &keys {
+        if known.contains(&key.as_str()) {
+            continue;
+        }
+        let Some(new) = known.iter().find(|k| k.eq_ignore_ascii_case(key)) else {
+            continue;
+        };
+        let num_matching = keys.iter().filter(|k| k.eq_ignore_ascii_case(new)).count();
+        if num_matching > 1 {
+            warn!("Not fixing the casing of key '{prefix}{key}' as it's ambiguous with other keys in the same table");
+        } else {
+            rename_key(table, key, new);
+        }
+    }
+}
+
+/// Normalizes the casing of keys in the contents of the manifest
+/// file, returning the updated contents
+///
+/// Only the keys that match a supported key except for the case are
+/// renamed (see `fix_table_key_casing`). Comments and ordering of
+/// the entries are preserved.
+pub fn fix_key_casing(contents: &str) -> Result<String, Error> {
+    let mut doc = contents
+        .parse::<DocumentMut>()
+        .map_err(|e| parse_error!("{}", e))?;
+
+    // Top level keys are fixed first so that the arrays of tables
+    // are found by their normalized keys below
+    fix_table_key_casing(doc.as_table_mut(), KNOWN_KEYS, "");
+    let arrays = [
+        ("query_templates", KNOWN_QUERY_TEMPLATE_KEYS),
+        ("queries", KNOWN_QUERY_KEYS),
+        ("test_templates", KNOWN_TEST_TEMPLATE_KEYS),
+        ("groups", KNOWN_GROUP_KEYS),
+    ];
+    for (array_key, known) in arrays {
+        if let Some(Item::ArrayOfTables(tables)) = doc.get_mut(array_key) {
+            let prefix = format!("{array_key}[].");
+            for table in tables.iter_mut() {
+                fix_table_key_casing(table, known, &prefix);
+                if array_key != "groups" {
+                    continue;
+                }
+                if let Some(Item::ArrayOfTables(queries)) = table.get_mut("queries") {
+                    for query in queries.iter_mut() {
+                        fix_table_key_casing(query, KNOWN_QUERY_KEYS, "groups[].queries[].");
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Renames the query `old_id` to `new_id` in the contents of the
+/// manifest file, returning the updated contents
+///
+/// Along with `queries[].id`, the references to the query in
+/// `test_templates[].query` are also updated. Everything else,
+/// including the templates, comments and formatting, is preserved.
+pub fn rename_query(contents: &str, old_id: &str, new_id: &str) -> Result<String, Error> {
+    let mut doc = contents
+        .parse::<DocumentMut>()
+        .map_err(|e| parse_error!("{}", e))?;
+
+    let ids = match doc.get("queries") {
+        Some(Item::ArrayOfTables(tables)) => tables
+            .iter()
+            .filter_map(|t| t.get("id").and_then(|v| v.as_str()))
+            .collect::<Vec<&str>>(),
+        _ => vec![],
+    };
+    if !ids.contains(&old_id) {
+        return Err(Error::UndefinedQuery(old_id.to_owned()));
+    }
+    if ids.contains(&new_id) {
+        return Err(Error::Cli(format!("Query already exists: id={new_id}")));
+    }
+
+    for (array_key, key) in [("queries", "id"), ("test_templates", "query")] {
+        if let Some(Item::ArrayOfTables(tables)) = doc.get_mut(array_key) {
+            for table in tables.iter_mut() {
+                if let Some(v) = table.get_mut(key) {
+                    if v.as_str() == Some(old_id) {
+                        // Replace only the value so that the
+                        // decor (e.g. trailing comments) is retained
+                        let decor = v.as_value().map(|x| x.decor().clone());
+                        *v = value(new_id);
+                        if let (Some(d), Some(x)) = (decor, v.as_value_mut()) {
+                            *x.decor_mut() = d;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(doc.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let manifest = r#"placeholder = "posargs"
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+# Output file for the query
+option = "artists_long_songs.sql"
+conds = []
+
+[[queries]]
+id = "songs_formats"
+template = "songs_formats.sql.j2"
+"#;
+        let expected = r#"placeholder = "posargs"
+
+## Version of the manifest file format
+version = 1
+
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+# Output file for the query
+output = "artists_long_songs.sql"
+conds = []
+
+[[queries]]
+id = "songs_formats"
+template = "songs_formats.sql.j2"
+"#;
+        let migrated = migrate(manifest).unwrap();
+        assert_eq!(expected, migrated);
+
+        // Migrating again results in no changes
+        assert_eq!(expected, migrate(&migrated).unwrap());
+    }
+
+    #[test]
+    fn test_fix_key_casing() {
+        let manifest = r#"Placeholder = "posargs"
+
+[[queries]]
+ID = "artists_long_songs"
+# Template of the query
+Template = "artists_long_songs.sql.j2"
+Conds = []
+extra = 1
+
+[[queries]]
+id = "songs_formats"
+Id = "formats"
+template = "songs_formats.sql.j2"
+
+[[Groups]]
+name = "billing"
+
+[[Groups.queries]]
+id = "invoices"
+TEMPLATE = "invoices.sql.j2"
+"#;
+        let expected = r#"placeholder = "posargs"
+
+[[queries]]
+id = "artists_long_songs"
+# Template of the query
+template = "artists_long_songs.sql.j2"
+conds = []
+extra = 1
+
+[[queries]]
+id = "songs_formats"
+Id = "formats"
+template = "songs_formats.sql.j2"
+
+[[groups]]
+name = "billing"
+
+[[groups.queries]]
+id = "invoices"
+template = "invoices.sql.j2"
+"#;
+        let fixed = fix_key_casing(manifest).unwrap();
+        assert_eq!(expected, fixed);
+
+        // Fixing again results in no changes
+        assert_eq!(expected, fix_key_casing(&fixed).unwrap());
+    }
+
+    #[test]
+    fn test_rename_query() {
+        let manifest = r#"placeholder = "posargs"
+
+[[queries]]
+id = "artists_long_songs" # used by the dashboard
+template = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "songs_formats"
+template = "songs_formats.sql.j2"
+
+[[test_templates]]
+query = "artists_long_songs"
+path = "artists_long_songs_test.sql.j2"
+
+[[test_templates]]
+query = "songs_formats"
+path = "songs_formats_test.sql.j2"
+"#;
+        let expected = r#"placeholder = "posargs"
+
+[[queries]]
+id = "long_songs" # used by the dashboard
+template = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "songs_formats"
+template = "songs_formats.sql.j2"
+
+[[test_templates]]
+query = "long_songs"
+path = "artists_long_songs_test.sql.j2"
+
+[[test_templates]]
+query = "songs_formats"
+path = "songs_formats_test.sql.j2"
+"#;
+        assert_eq!(
+            expected,
+            rename_query(manifest, "artists_long_songs", "long_songs").unwrap()
+        );
+
+        match rename_query(manifest, "unknown", "long_songs") {
+            Err(Error::UndefinedQuery(id)) => assert_eq!("unknown", id),
+            _ => panic!(),
+        }
+        match rename_query(manifest, "artists_long_songs", "songs_formats") {
+            Err(Error::Cli(msg)) => assert_eq!("Query already exists: id=songs_formats", msg),
+            _ => panic!(),
+        }
+    }
+}
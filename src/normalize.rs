@@ -0,0 +1,159 @@
+use crate::error::{parse_error, Error};
+use crate::toml::decode_bool;
+use toml::Value;
+
+const KNOWN_NORMALIZE_KEYS: &[&str] =
+    &["trim_trailing_ws", "collapse_blank_lines", "final_newline"];
+
+/// Lightweight normalization of the whitespace in the rendered SQL,
+/// applied irrespective of whether a formatter is configured
+///
+/// It's applied right after rendering, so that the output files are
+/// written as well as compared (e.g. by the `status` command) after
+/// normalization.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Normalize {
+    /// Whether to remove whitespace at the end of every line
+    trim_trailing_ws: bool,
+    /// Max. no. of consecutive blank lines to retain, if set
+    collapse_blank_lines: Option<usize>,
+    /// Whether to ensure that the SQL ends with a newline
+    final_newline: bool,
+}
+
+impl Normalize {
+    pub fn decode(value: &Value) -> Result<Self, Error> {
+        let table = value
+            .as_table()
+            .ok_or(parse_error!("Value of 'normalize' must be a table"))?;
+        if let Some(k) = table
+            .keys()
+            .find(|k| !KNOWN_NORMALIZE_KEYS.contains(&k.as_str()))
+        {
+            return Err(parse_error!("Unknown key in 'normalize': '{k}'"));
+        }
+        let trim_trailing_ws = match table.get("trim_trailing_ws") {
+            Some(v) => decode_bool(v, "normalize.trim_trailing_ws")?,
+            None => true,
+        };
+        let collapse_blank_lines = match table.get("collapse_blank_lines") {
+            Some(v) => {
+                let n =
+                    v.as_integer()
+                        .and_then(|n| usize::try_from(n).ok())
+                        .ok_or(parse_error!(
+                        "Value of 'normalize.collapse_blank_lines' must be a non-negative integer"
+                    ))?;
+                Some(n)
+            }
+            None => None,
+        };
+        let final_newline = match table.get("final_newline") {
+            Some(v) => decode_bool(v, "normalize.final_newline")?,
+            None => true,
+        };
+        Ok(Self {
+            trim_trailing_ws,
+            collapse_blank_lines,
+            final_newline,
+        })
+    }
+
+    /// Returns the normalized `sql`
+    ///
+    /// Lines with only whitespace are considered blank when collapsing
+    /// blank lines, irrespective of `trim_trailing_ws`.
+    pub fn apply(&self, sql: &str) -> String {
+        let mut result = String::with_capacity(sql.len());
+        let mut num_blank = 0;
+        for line in sql.lines() {
+            if line.trim().is_empty() {
+                num_blank += 1;
+                if self.collapse_blank_lines.is_some_and(|max| num_blank > max) {
+                    continue;
+                }
+            } else {
+                num_blank = 0;
+            }
+            if self.trim_trailing_ws {
+                result.push_str(line.trim_end());
+            } else {
+                result.push_str(line);
+            }
+            result.push('\n');
+        }
+        // The newline after the last line is retained only if it was
+        // there in the original sql or if it's required
+        if !(sql.ends_with('\n') || self.final_newline) {
+            result.pop();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn normalize(toml_str: &str) -> Normalize {
+        let value: Value = toml::from_str(toml_str).unwrap();
+        Normalize::decode(value.get("normalize").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Normalize {
+                trim_trailing_ws: true,
+                collapse_blank_lines: None,
+                final_newline: true,
+            },
+            normalize("normalize = {}")
+        );
+        assert_eq!(
+            Normalize {
+                trim_trailing_ws: false,
+                collapse_blank_lines: Some(2),
+                final_newline: false,
+            },
+            normalize(
+                "normalize = { trim_trailing_ws = false, collapse_blank_lines = 2, final_newline = false }"
+            )
+        );
+        for invalid in [
+            "normalize = true",
+            "normalize = { collapse_blank_lines = -1 }",
+            "normalize = { final_newline = 'yes' }",
+            "normalize = { trim_ws = true }",
+        ] {
+            let value: Value = toml::from_str(invalid).unwrap();
+            assert!(Normalize::decode(value.get("normalize").unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_trim_trailing_ws() {
+        let sql = "SELECT *  \nFROM artists\t\nWHERE id = 1;";
+        let n = normalize("normalize = { final_newline = false }");
+        assert_eq!("SELECT *\nFROM artists\nWHERE id = 1;", n.apply(sql));
+        let n = normalize("normalize = {}");
+        assert_eq!("SELECT *\nFROM artists\nWHERE id = 1;\n", n.apply(sql));
+        let n = normalize("normalize = { trim_trailing_ws = false, final_newline = false }");
+        assert_eq!(sql, n.apply(sql));
+    }
+
+    #[test]
+    fn test_collapse_blank_lines() {
+        let sql = "SELECT 1;\n\n\n  \n\nSELECT 2;\n\nSELECT 3;\n";
+        let n = normalize("normalize = { collapse_blank_lines = 1 }");
+        assert_eq!("SELECT 1;\n\nSELECT 2;\n\nSELECT 3;\n", n.apply(sql));
+        let n = normalize("normalize = { collapse_blank_lines = 2 }");
+        assert_eq!("SELECT 1;\n\n\nSELECT 2;\n\nSELECT 3;\n", n.apply(sql));
+        let n = normalize("normalize = { collapse_blank_lines = 0 }");
+        assert_eq!("SELECT 1;\nSELECT 2;\nSELECT 3;\n", n.apply(sql));
+        // Not collapsed unless specified
+        let n = normalize("normalize = {}");
+        assert_eq!("SELECT 1;\n\n\n\n\nSELECT 2;\n\nSELECT 3;\n", n.apply(sql));
+    }
+}
@@ -3,22 +3,140 @@ use toml::Value;
 
 use crate::error::{parse_error, Error};
 use crate::formatters::Formatter;
+use crate::header::{fix_timestamps, strip_header};
 use crate::metadata::Metadata;
 use crate::query::{Queries, Query};
+use crate::render::{has_block_markers, strip_block_markers};
 use crate::tagging::{NameTag, NameTagger};
 use crate::toml::decode_pathbuf;
+use crate::util;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sqlparser::dialect::Dialect;
+use sqlparser::tokenizer::Tokenizer;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::fs::{self, File};
-use std::io::{self, BufRead};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn ensure_output_dirs<P: AsRef<Path>>(queries_dir: P, tests_dir: P) -> Result<(), Error> {
-    fs::create_dir_all(queries_dir.as_ref()).map_err(Error::Io)?;
-    fs::create_dir_all(tests_dir.as_ref()).map_err(Error::Io)?;
+/// Creates the output dirs
+///
+/// If `eager` is true, any nested dirs that the output files are to
+/// be written into (e.g. when `preserve_tree = true`) are also
+/// created upfront, including the ones for queries that may not get
+/// rendered. Otherwise, nested dirs are created only when a file is
+/// actually written into them (see `write`).
+pub fn ensure_output_dirs(metadata: &Metadata, eager: bool) -> Result<(), Error> {
+    fs::create_dir_all(&metadata.queries_output_dir).map_err(Error::Io)?;
+    fs::create_dir_all(&metadata.tests_output_dir).map_err(Error::Io)?;
+    if !eager {
+        return Ok(());
+    }
+    let outputs = metadata
+        .queries
+        .output_files()
+        .chain(metadata.test_templates.iter().map(|tt| tt.output.as_path()));
+    let dirs = outputs
+        .filter_map(|p| p.parent())
+        .filter(|d| !d.as_os_str().is_empty())
+        .collect::<HashSet<&Path>>();
+    for dir in dirs {
+        fs::create_dir_all(dir).map_err(Error::Io)?;
+    }
     Ok(())
 }
 
+/// Creates the parent dir of `path` if it doesn't exist
+fn ensure_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+/// Format in which output files are written to the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Sql,
+    Gzip,
+}
+
+impl OutputFormat {
+    /// Returns the path where the output file will actually be
+    /// written. In case of `Gzip`, the `.gz` extension is appended to
+    /// the path.
+    pub fn path<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match self {
+            Self::Sql => Cow::Borrowed(path),
+            Self::Gzip => {
+                let mut p = path.as_os_str().to_owned();
+                p.push(".gz");
+                Cow::Owned(PathBuf::from(p))
+            }
+        }
+    }
+
+    /// Returns the bytes to be written to the output file for
+    /// `content` i.e. compressed in case of `Gzip`
+    fn encode<'a>(&self, content: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match self {
+            Self::Sql => Ok(Cow::Borrowed(content)),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(content)?;
+                encoder.finish().map(Cow::Owned)
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        ensure_parent_dir(path)?;
+        fs::write(self.path(path), self.encode(content)?)
+    }
+
+    /// Reads the (decompressed if applicable) contents of the output
+    /// file for `path`
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Sql => fs::read(path),
+            Self::Gzip => {
+                let compressed = fs::read(self.path(path))?;
+                let mut content = Vec::new();
+                GzDecoder::new(compressed.as_slice()).read_to_end(&mut content)?;
+                Ok(content)
+            }
+        }
+    }
+
+    pub fn exists(&self, path: &Path) -> io::Result<bool> {
+        self.path(path).try_exists()
+    }
+
+    /// Checks whether the output file for `path` exists and was
+    /// modified after all the `sources` i.e. the files that it's
+    /// rendered from
+    ///
+    /// Only the modification times are compared, so changes to files
+    /// that are not included in `sources` (e.g. templates included
+    /// using `{% include %}`) are not detected.
+    pub fn is_fresh(&self, path: &Path, sources: &[&Path]) -> io::Result<bool> {
+        if !self.exists(path)? {
+            return Ok(false);
+        }
+        let output_mtime = fs::metadata(self.path(path))?.modified()?;
+        for source in sources {
+            if fs::metadata(source)?.modified()? > output_mtime {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Status {
     Added,
@@ -72,16 +190,23 @@ pub fn query_status(
     query: &Query,
     reader: &QueryOutputReader,
     formatter: Option<&Formatter>,
+    header: Option<&str>,
     rendered_output: &str,
 ) -> Result<Status, Error> {
     let exists = reader.exists(&query.id)?;
     if exists {
         let contents = reader.read(&query.id)?;
+        // A different header (other than in `generated_at`) is
+        // a modification in itself
+        let contents = match strip_header(header, &contents) {
+            Some(rest) => rest,
+            None => return Ok(Status::Modified),
+        };
         // @NOTE: The code duplication below is intentional. It
         // prevents unnecessary conversion of byte array into vec
         match formatter {
             Some(f) => {
-                if f.format(rendered_output) != contents {
+                if !is_same_output(&f.format(rendered_output)?, contents) {
                     Ok(Status::Modified)
                 } else {
                     Ok(Status::Unchanged)
@@ -89,7 +214,7 @@ pub fn query_status(
             }
             None => {
                 let output = ensure_trailing_newline(rendered_output);
-                if output.as_bytes() != contents {
+                if !is_same_output(output.as_bytes(), contents) {
                     Ok(Status::Modified)
                 } else {
                     Ok(Status::Unchanged)
@@ -101,6 +226,22 @@ pub fn query_status(
     }
 }
 
+// Compares the rendered output with the contents of the existing
+// output file, ignoring the markers of named blocks (see
+// `render::sql_block`) if either of them has any. Otherwise, the
+// bytes must be the same i.e. including any trailing whitespace.
+fn is_same_output(rendered: &[u8], contents: &[u8]) -> bool {
+    if rendered == contents {
+        return true;
+    }
+    let (rendered, contents) = (
+        String::from_utf8_lossy(rendered),
+        String::from_utf8_lossy(contents),
+    );
+    (has_block_markers(&rendered) || has_block_markers(&contents))
+        && strip_block_markers(&rendered) == strip_block_markers(&contents)
+}
+
 /// Returns status of a test output file without modifying it
 ///
 /// This function compares the `rendered_output` (after formatting if
@@ -126,23 +267,31 @@ pub fn query_status(
 pub fn testfile_status<P: AsRef<Path>>(
     path: P,
     formatter: Option<&Formatter>,
+    header: Option<&str>,
+    out_format: OutputFormat,
     rendered_output: &str,
 ) -> Result<Status, Error> {
-    let exists = path.as_ref().try_exists().map_err(Error::Io)?;
+    let exists = out_format.exists(path.as_ref()).map_err(Error::Io)?;
     if exists {
-        let contents = fs::read(path).map_err(Error::Io)?;
+        let contents = out_format.read(path.as_ref()).map_err(Error::Io)?;
+        // A different header (other than in `generated_at`) is
+        // a modification in itself
+        let contents = match strip_header(header, &contents) {
+            Some(rest) => rest,
+            None => return Ok(Status::Modified),
+        };
         // @NOTE: The code duplication below is intentional. It
         // prevents unnecessary conversion of byte array into vec
         match formatter {
             Some(f) => {
-                if f.format(rendered_output) != contents {
+                if !is_same_output(&f.format(rendered_output)?, contents) {
                     Ok(Status::Modified)
                 } else {
                     Ok(Status::Unchanged)
                 }
             }
             None => {
-                if rendered_output.as_bytes() != contents {
+                if !is_same_output(rendered_output.as_bytes(), contents) {
                     Ok(Status::Modified)
                 } else {
                     Ok(Status::Unchanged)
@@ -201,9 +350,18 @@ impl Layout {
 
 // Struct for representing output files that need to written
 pub struct SqlToWrite<'a> {
-    pub path: &'a Path,
+    pub path: Cow<'a, Path>,
     pub sql: String,
     pub name_tag: Option<&'a NameTag>,
+    /// Whether the formatter (if any) is to be applied i.e. false for
+    /// queries (and their tests) with `format = false`
+    pub format: bool,
+    /// Dialect of the query, for selecting the formatter when it's
+    /// configured per dialect
+    pub dialect: Option<&'a str>,
+    /// Rendered `header_template` (if configured), which is prepended
+    /// to the file after formatting
+    pub header: Option<String>,
 }
 
 impl<'a> SqlToWrite<'a> {
@@ -212,7 +370,7 @@ impl<'a> SqlToWrite<'a> {
     // Tagging is done only if both the conditions are satisfied:
     //   1. `tagger` is not None
     //   2. `self.name_tag` is not None
-    fn tagged_sql(&'a self, tagger: Option<&NameTagger>) -> Cow<'a, str> {
+    pub fn tagged_sql(&'a self, tagger: Option<&NameTagger>) -> Cow<'a, str> {
         match tagger {
             Some(t) => match self.name_tag {
                 Some(nt) => t.ensure_name_tag(&self.sql, nt),
@@ -221,23 +379,299 @@ impl<'a> SqlToWrite<'a> {
             None => Cow::from(&self.sql),
         }
     }
+
+    /// Returns the formatter to be applied to this file, if any
+    pub fn formatter<'f>(&self, formatter: Option<&'f Formatter>) -> Option<&'f Formatter> {
+        formatter
+            .filter(|_| self.format)
+            .and_then(|f| f.for_dialect(self.dialect))
+    }
+
+    /// Returns the status of the output file without modifying it
+    /// (see `testfile_status`)
+    pub fn status(
+        &self,
+        formatter: Option<&Formatter>,
+        tagger: Option<&NameTagger>,
+        out_format: OutputFormat,
+    ) -> Result<Status, Error> {
+        let sql = self.tagged_sql(tagger);
+        testfile_status(
+            &self.path,
+            self.formatter(formatter),
+            self.header.as_deref(),
+            out_format,
+            &sql,
+        )
+    }
+
+    /// Returns the output files for the rendered `sql` of `query`
+    /// i.e. one file per part if `split_on` is set for the query,
+    /// otherwise a single file
+    ///
+    /// Only the first part is name tagged.
+    pub fn for_query(query: &'a Query, sql: String) -> Vec<Self> {
+        match &query.split_on {
+            Some(delim) => split_sql(&sql, delim, query.sql_dialect().as_ref())
+                .into_iter()
+                .enumerate()
+                .map(|(i, part)| Self {
+                    path: Cow::Owned(part_path(&query.output, i + 1)),
+                    sql: part,
+                    name_tag: if i == 0 { Some(&query.name_tag) } else { None },
+                    format: query.format,
+                    dialect: query.dialect.as_deref(),
+                    header: None,
+                })
+                .collect(),
+            None => vec![Self {
+                path: Cow::Borrowed(&query.output),
+                sql,
+                name_tag: Some(&query.name_tag),
+                format: query.format,
+                dialect: query.dialect.as_deref(),
+                header: None,
+            }],
+        }
+    }
+}
+
+/// Splits the rendered `sql` into parts on the `delim`
+///
+/// The `sql` is tokenized as per the `dialect` so that the `delim` is
+/// matched only at the start of a token i.e. not inside string literals (including `$$`
+/// quoted bodies), quoted identifiers or comments, unless the comment
+/// itself is the `delim`. If it can't be tokenized, it's split on
+/// every occurrence of the `delim`.
+///
+/// If `delim` is `;`, it's retained at the end of every statement,
+/// otherwise it's considered to be a marker (e.g. a comment) and is
+/// dropped. Blank parts are ignored.
+pub fn split_sql(sql: &str, delim: &str, dialect: &dyn Dialect) -> Vec<String> {
+    let keep_delim = delim == ";";
+    let token_starts = token_offsets(sql, dialect);
+    let mut parts = vec![];
+    let mut start = 0;
+    for (i, _) in sql.match_indices(delim) {
+        let at_token = token_starts
+            .as_ref()
+            .map_or(true, |offsets| offsets.binary_search(&i).is_ok());
+        if i >= start && at_token {
+            parts.push(&sql[start..i]);
+            start = i + delim.len();
+        }
+    }
+    parts.push(&sql[start..]);
+    parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            if keep_delim {
+                format!("{part}{delim}\n")
+            } else {
+                format!("{part}\n")
+            }
+        })
+        .collect()
+}
+
+// Returns the byte offsets at which the tokens of `sql` start, or
+// `None` if it can't be tokenized
+fn token_offsets(sql: &str, dialect: &dyn Dialect) -> Option<Vec<usize>> {
+    let tokens = Tokenizer::new(dialect, sql).tokenize_with_location().ok()?;
+    let line_starts = std::iter::once(0)
+        .chain(sql.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<usize>>();
+    // The locations are in terms of lines and columns (in chars),
+    // both starting from 1
+    tokens
+        .iter()
+        .map(|t| {
+            let line = usize::try_from(t.span.start.line).ok()?.checked_sub(1)?;
+            let column = usize::try_from(t.span.start.column).ok()?.checked_sub(1)?;
+            let line_start = *line_starts.get(line)?;
+            let offset = sql[line_start..]
+                .char_indices()
+                .nth(column)
+                .map_or(sql.len(), |(i, _)| line_start + i);
+            Some(offset)
+        })
+        .collect()
+}
+
+/// Removes the part files of the split output file at `path`
+/// numbered after `num_parts` i.e. the ones left over from an
+/// earlier render that resulted in more parts, returning their paths
+pub fn remove_stale_parts(
+    path: &Path,
+    num_parts: usize,
+    out_format: OutputFormat,
+) -> io::Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    for n in num_parts + 1.. {
+        let part = out_format.path(&part_path(path, n)).into_owned();
+        if !part.try_exists()? {
+            break;
+        }
+        fs::remove_file(&part)?;
+        removed.push(part);
+    }
+    Ok(removed)
+}
+
+/// Returns the path of the `n`th part of a split output file
+/// i.e. with a zero-padded suffix added before the extension
+/// (`foo.sql` => `foo.001.sql`)
+pub fn part_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}.{n:03}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{n:03}"),
+    };
+    path.with_file_name(name)
+}
+
+// Returns the contents of an output file i.e. formatted `content` if
+// a formatter is configured, preceded by the `header` if any
+fn file_contents<'a>(
+    formatter: Option<&Formatter>,
+    header: Option<&str>,
+    content: &'a str,
+) -> Result<Cow<'a, [u8]>, Error> {
+    let contents = match formatter {
+        Some(f) => f.format(content).map(Cow::Owned)?,
+        None => Cow::Borrowed(content.as_bytes()),
+    };
+    match header {
+        Some(h) => {
+            let mut bytes = Vec::with_capacity(h.len() + contents.len());
+            bytes.extend_from_slice(h.as_bytes());
+            bytes.extend_from_slice(&contents);
+            Ok(Cow::Owned(bytes))
+        }
+        None => Ok(contents),
+    }
+}
+
+/// Returns whether the output file for `file` is to be written i.e.
+/// it doesn't exist or its contents would change
+///
+/// The timestamps in the header are ignored, so that the file is not
+/// considered changed only because it's rendered again.
+pub fn is_changed(
+    file: &SqlToWrite,
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
+) -> Result<bool, Error> {
+    let sql = file.tagged_sql(tagger);
+    let header = file.header.as_deref();
+    let contents = file_contents(file.formatter(formatter), header, &sql)?;
+    differs(&file.path, header, &contents, out_format)
+}
+
+/// Same as `is_changed`, but for the file combining the outputs of
+/// all the `files` (see `write_combined`), which must not be empty
+pub fn is_combined_changed(
+    files: &[SqlToWrite],
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
+) -> Result<bool, Error> {
+    let (path, sql) = combine(files, tagger);
+    let contents = file_contents(combined_formatter(files, formatter), None, &sql)?;
+    differs(path, None, &contents, out_format)
+}
+
+// Returns whether the existing output file for `path` differs from
+// `contents` that start with the `header`, except in the timestamps
+// in the header
+fn differs(
+    path: &Path,
+    header: Option<&str>,
+    contents: &[u8],
+    out_format: OutputFormat,
+) -> Result<bool, Error> {
+    let existing = match out_format.read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    let body = &contents[header.map_or(0, |h| h.len())..];
+    let header = header.map(fix_timestamps);
+    Ok(strip_header(header.as_deref(), &existing) != Some(body))
 }
 
 fn write<P: AsRef<Path>>(
     path: P,
     formatter: Option<&Formatter>,
+    header: Option<&str>,
+    out_format: OutputFormat,
     content: &str,
 ) -> Result<(), Error> {
-    match formatter {
-        Some(f) => {
-            let text = f.format(content);
-            fs::write(path, text).map_err(Error::Io)?;
+    let contents = file_contents(formatter, header, content)?;
+    out_format
+        .write(path.as_ref(), &contents)
+        .map_err(Error::Io)
+}
+
+// Concatenates the (tagged) sql of all files
+//
+// # Panics!
+// If the paths of all files are not equal (see `write_combined`)
+fn combine<'a>(files: &'a [SqlToWrite], tagger: Option<&NameTagger>) -> (&'a Path, String) {
+    let mut combined_output = String::new();
+    let mut paths = Vec::with_capacity(files.len());
+    for file in files {
+        let sql = file.tagged_sql(tagger);
+        combined_output.push_str(&sql);
+        combined_output.push('\n');
+        combined_output.push('\n');
+        paths.push(file.path.as_ref());
+    }
+    let mut path_set: HashSet<&Path> = HashSet::from_iter(paths);
+    if path_set.len() > 1 {
+        panic!("write_combined function called with disparate file paths. Please report this bug");
+    }
+    let filepath = path_set.drain().next().unwrap();
+    (filepath, combined_output)
+}
+
+// Returns the formatter to be applied to the combined file (see
+// `formatter_for_all`)
+fn combined_formatter<'f>(
+    files: &[SqlToWrite],
+    formatter: Option<&'f Formatter>,
+) -> Option<&'f Formatter> {
+    formatter_for_all(files.iter().map(|f| (f.format, f.dialect)), formatter)
+}
+
+/// Returns the formatter to be applied to a file that combines the
+/// outputs of multiple queries, given their `format` and `dialect`
+///
+/// The file is formatted only if none of the queries opt out of
+/// formatting. The formatter for a dialect is used only if all the
+/// queries are of that dialect. The same rule applies when writing
+/// the combined file and when checking its status.
+pub fn formatter_for_all<'f, 'd>(
+    queries: impl IntoIterator<Item = (bool, Option<&'d str>)>,
+    formatter: Option<&'f Formatter>,
+) -> Option<&'f Formatter> {
+    let mut queries = queries.into_iter();
+    let (format, mut dialect) = queries.next().unwrap_or((true, None));
+    if !format {
+        return None;
+    }
+    for (format, d) in queries {
+        if !format {
+            return None;
         }
-        None => {
-            fs::write(path, content).map_err(Error::Io)?;
+        if d != dialect {
+            dialect = None;
         }
     }
-    Ok(())
+    formatter.and_then(|f| f.for_dialect(dialect))
 }
 
 // Combines file contents and writes to a single file
@@ -248,6 +682,9 @@ fn write<P: AsRef<Path>>(
 // written to the same file (Layout = OneFileAllQueries). If this
 // condition is not satisfied, this functions panics.
 //
+// Nothing is written if there are no files e.g. with `--only-tests`,
+// or if all queries are disabled.
+//
 // @TODO: This function currently concatenates Strings in memory and
 // then writes in a single call. A more memory efficient approach
 // would be to keep the file open and write each query to it one by
@@ -256,22 +693,19 @@ pub fn write_combined(
     files: &Vec<SqlToWrite>,
     formatter: Option<&Formatter>,
     tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
 ) -> Result<(), Error> {
-    let mut combined_output = String::new();
-    let mut paths = Vec::with_capacity(files.len());
-    for file in files {
-        let sql = file.tagged_sql(tagger);
-        combined_output.push_str(&sql);
-        combined_output.push('\n');
-        combined_output.push('\n');
-        paths.push(file.path);
+    if files.is_empty() {
+        return Ok(());
     }
-    let mut path_set: HashSet<&Path> = HashSet::from_iter(paths);
-    if path_set.len() > 1 {
-        panic!("write_combined function called with disparate file paths. Please report this bug");
-    }
-    let filepath = path_set.drain().next().unwrap();
-    write(filepath, formatter, &combined_output)
+    let (filepath, combined_output) = combine(files, tagger);
+    write(
+        filepath,
+        combined_formatter(files, formatter),
+        None,
+        out_format,
+        &combined_output,
+    )
 }
 
 // Writes file contents to separate files in a loop
@@ -279,19 +713,211 @@ pub fn write_separately(
     files: &Vec<SqlToWrite>,
     formatter: Option<&Formatter>,
     tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
 ) -> Result<(), Error> {
-    for file in files {
+    write_separately_with(files, formatter, tagger, out_format, |_, _| Ok(()))
+}
+
+/// Same as `write_separately`, but calls `on_done` with the index of
+/// every file as soon as it's written, or has failed to be written
+///
+/// Writing stops at the first failure, in which case the error from
+/// writing the file is returned even if `on_done` also fails.
+pub fn write_separately_with<F>(
+    files: &[SqlToWrite],
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
+    mut on_done: F,
+) -> Result<(), Error>
+where
+    F: FnMut(usize, Result<(), &Error>) -> Result<(), Error>,
+{
+    for (i, file) in files.iter().enumerate() {
         let sql = file.tagged_sql(tagger);
-        write(file.path, formatter, &sql)?;
+        let res = write(
+            &file.path,
+            file.formatter(formatter),
+            file.header.as_deref(),
+            out_format,
+            &sql,
+        );
+        match res {
+            Ok(()) => on_done(i, Ok(()))?,
+            Err(e) => {
+                let _ = on_done(i, Err(&e));
+                return Err(e);
+            }
+        }
     }
     Ok(())
 }
 
+#[cfg(feature = "tokio")]
+mod concurrent {
+    use super::{ensure_parent_dir, file_contents, OutputFormat, SqlToWrite};
+    use crate::error::Error;
+    use crate::formatters::Formatter;
+    use crate::tagging::NameTagger;
+    use std::num::NonZeroUsize;
+    use tokio::task::JoinSet;
+
+    /// Writes the files concurrently using async I/O with at most
+    /// `limit` writes in flight at a time
+    ///
+    /// Tagging, formatting and compression happen upfront on the
+    /// calling thread, so only the actual writes are concurrent.
+    pub fn write_separately(
+        files: &[SqlToWrite],
+        formatter: Option<&Formatter>,
+        tagger: Option<&NameTagger>,
+        out_format: OutputFormat,
+        limit: NonZeroUsize,
+    ) -> Result<(), Error> {
+        let mut to_write = Vec::with_capacity(files.len());
+        for file in files {
+            let sql = file.tagged_sql(tagger);
+            let contents = file_contents(file.formatter(formatter), file.header.as_deref(), &sql)?;
+            let bytes = out_format.encode(&contents).map_err(Error::Io)?;
+            ensure_parent_dir(&file.path).map_err(Error::Io)?;
+            to_write.push((out_format.path(&file.path).into_owned(), bytes.into_owned()));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(Error::Io)?;
+        runtime.block_on(async move {
+            let mut tasks = JoinSet::new();
+            for (path, bytes) in to_write {
+                if tasks.len() >= limit.get() {
+                    join_next(&mut tasks).await?;
+                }
+                tasks.spawn(tokio::fs::write(path, bytes));
+            }
+            while !tasks.is_empty() {
+                join_next(&mut tasks).await?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn join_next(tasks: &mut JoinSet<std::io::Result<()>>) -> Result<(), Error> {
+        match tasks.join_next().await {
+            // @UNWRAP: The write tasks don't panic and are never
+            // cancelled
+            Some(res) => res.unwrap().map_err(Error::Io),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Same as `write_separately` but the files are written concurrently
+/// using async I/O, with at most `limit` writes in flight at a time
+#[cfg(feature = "tokio")]
+pub fn write_separately_async(
+    files: &[SqlToWrite],
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
+    limit: std::num::NonZeroUsize,
+) -> Result<(), Error> {
+    concurrent::write_separately(files, formatter, tagger, out_format, limit)
+}
+
+/// Returns the contents of all output files (after tagging and
+/// formatting, same as what the `render` command writes) mapped to
+/// their paths, without touching the file system
+pub fn to_map(
+    queries: &[SqlToWrite],
+    tests: &[SqlToWrite],
+    layout: &Layout,
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+) -> Result<HashMap<PathBuf, String>, Error> {
+    let contents = |formatter, header, sql: &str| {
+        file_contents(formatter, header, sql).map(|c| String::from_utf8_lossy(&c).into_owned())
+    };
+    let mut result = HashMap::with_capacity(queries.len() + tests.len());
+    match layout {
+        Layout::OneFileOneQuery => {
+            for file in queries {
+                let sql = file.tagged_sql(tagger);
+                result.insert(
+                    file.path.to_path_buf(),
+                    contents(file.formatter(formatter), file.header.as_deref(), &sql)?,
+                );
+            }
+        }
+        Layout::OneFileAllQueries(_) => {
+            if !queries.is_empty() {
+                let (path, sql) = combine(queries, tagger);
+                let formatter = combined_formatter(queries, formatter);
+                result.insert(path.to_path_buf(), contents(formatter, None, &sql)?);
+            }
+        }
+    }
+    for file in tests {
+        result.insert(
+            file.path.to_path_buf(),
+            contents(file.formatter(formatter), file.header.as_deref(), &file.sql)?,
+        );
+    }
+    Ok(result)
+}
+
+/// Writes all output files (same as what the `render` command writes)
+/// as entries of a tar archive to `writer` instead of the file system
+///
+/// The entries are named after the paths where the files would have
+/// been written and are sorted by them so that the archive is
+/// deterministic (except for the modification times, which are set
+/// to the current time). The names are relative to the current dir,
+/// or to the deepest dir containing all the files if some of them
+/// are outside of it, as absolute paths or `..` components can't be
+/// extracted safely.
+pub fn write_tar<W: Write>(
+    writer: W,
+    queries: &[SqlToWrite],
+    tests: &[SqlToWrite],
+    layout: &Layout,
+    formatter: Option<&Formatter>,
+    tagger: Option<&NameTagger>,
+    out_format: OutputFormat,
+) -> Result<(), Error> {
+    let files = to_map(queries, tests, layout, formatter, tagger)?;
+    let cwd = std::env::current_dir().map_err(Error::Io)?;
+    let paths = files.keys().map(|p| p.as_path()).collect::<Vec<&Path>>();
+    let names = util::relative_to_common_base(&paths, &cwd);
+    let mut files = names
+        .into_iter()
+        .zip(paths.iter().map(|p| &files[*p]))
+        .collect::<Vec<(PathBuf, &String)>>();
+    files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut builder = tar::Builder::new(writer);
+    for (path, contents) in files {
+        let bytes = out_format.encode(contents.as_bytes()).map_err(Error::Io)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder
+            .append_data(&mut header, out_format.path(&path), bytes.as_ref())
+            .map_err(Error::Io)?;
+    }
+    builder
+        .into_inner()
+        .and_then(|mut w| w.flush())
+        .map_err(Error::Io)
+}
+
 // @TODO: Add tests
 fn parse_combined_sql<'a>(
     filepath: &Path,
     tagger: &NameTagger,
     formatter: Option<&Formatter>,
+    out_format: OutputFormat,
     queries: &'a Queries,
 ) -> Result<HashMap<&'a str, String>, Error> {
     let tags_to_ids = queries
@@ -304,14 +930,14 @@ fn parse_combined_sql<'a>(
     let mut curr_id: Option<&str> = None;
     let mut result: HashMap<&str, String> = HashMap::with_capacity(tags_to_ids.len());
 
-    let file = File::open(filepath).map_err(Error::Io)?;
+    let contents = out_format.read(filepath).map_err(Error::Io)?;
+    let contents = String::from_utf8_lossy(&contents);
 
-    // @NOTE: `map_while(Result::ok)` is the equivalent of flatten
-    for line in io::BufReader::new(file).lines().map_while(Result::ok) {
-        match tags_to_ids.get(&line) {
+    for line in contents.lines() {
+        match tags_to_ids.get(line) {
             Some(id) => {
                 curr_id = Some(id);
-                result.insert(id, line);
+                result.insert(id, line.to_owned());
             }
             None => {
                 if curr_id.is_none() && line.is_empty() {
@@ -321,7 +947,7 @@ fn parse_combined_sql<'a>(
                     curr_id.ok_or(Error::QueryOutputParsing(filepath.display().to_string()))?;
                 if let Some(qlines) = result.get_mut(key) {
                     qlines.push('\n');
-                    qlines.push_str(line.as_str());
+                    qlines.push_str(line);
                 } else {
                     warn!("Query id not identified when parsing output file. Discarding line");
                 }
@@ -354,11 +980,26 @@ fn parse_combined_sql<'a>(
 /// output and cached
 pub struct QueryOutputReader<'a> {
     metadata: &'a Metadata,
+    out_format: OutputFormat,
     parsed_query_store: Option<HashMap<&'a str, String>>,
+    // Formatter of the combined output file (see `formatter_for_all`)
+    combined_formatter: Option<&'a Formatter>,
 }
 
 impl<'a> QueryOutputReader<'a> {
-    pub fn new(metadata: &'a Metadata) -> Result<Self, Error> {
+    /// Creates the reader, where `queries` are the ones whose outputs
+    /// are combined into a single file if layout = OneFileAllQueries
+    pub fn new<'q>(
+        metadata: &'a Metadata,
+        out_format: OutputFormat,
+        queries: impl IntoIterator<Item = &'q Query>,
+    ) -> Result<Self, Error> {
+        let combined_formatter = formatter_for_all(
+            queries
+                .into_iter()
+                .map(|q| (q.format, q.dialect.as_deref())),
+            metadata.formatter.as_ref(),
+        );
         // @TODO: Instead of eagerly loading data, we could have used
         // OnceCell here. But currently, the
         // `OnceCell.get_or_try_init` is a nightly only feature.
@@ -374,17 +1015,29 @@ impl<'a> QueryOutputReader<'a> {
                 Some(parse_combined_sql(
                     filepath,
                     tagger,
-                    metadata.formatter.as_ref(),
+                    combined_formatter,
+                    out_format,
                     &metadata.queries,
                 )?)
             }
         };
         Ok(Self {
             metadata,
+            out_format,
             parsed_query_store: pqs,
+            combined_formatter,
         })
     }
 
+    /// Returns the formatter to be applied to the output of `query`
+    /// for comparing it with the output file
+    pub fn formatter(&self, query: &Query) -> Option<&'a Formatter> {
+        match self.metadata.query_output_layout {
+            Layout::OneFileOneQuery => query.formatter(self.metadata.formatter.as_ref()),
+            Layout::OneFileAllQueries(_) => self.combined_formatter,
+        }
+    }
+
     pub fn read(&self, query_id: &str) -> Result<Vec<u8>, Error> {
         let query = self
             .metadata
@@ -392,7 +1045,7 @@ impl<'a> QueryOutputReader<'a> {
             .get(query_id)
             .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
         match self.metadata.query_output_layout {
-            Layout::OneFileOneQuery => fs::read(&query.output).map_err(Error::Io),
+            Layout::OneFileOneQuery => self.out_format.read(&query.output).map_err(Error::Io),
             Layout::OneFileAllQueries(_) => {
                 let query_store =
                     self.parsed_query_store
@@ -419,7 +1072,7 @@ impl<'a> QueryOutputReader<'a> {
             .get(query_id)
             .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
         match self.metadata.query_output_layout {
-            Layout::OneFileOneQuery => query.output.try_exists().map_err(Error::Io),
+            Layout::OneFileOneQuery => self.out_format.exists(&query.output).map_err(Error::Io),
             Layout::OneFileAllQueries(_) => {
                 let query_store =
                     self.parsed_query_store
@@ -437,8 +1090,170 @@ impl<'a> QueryOutputReader<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::header::FIXED_TIMESTAMP;
+    use crate::util::test_util::{test_manifest, TempDir};
+    use sqlparser::dialect::{MySqlDialect, PostgreSqlDialect};
     use toml::Table;
 
+    #[test]
+    fn test_ensure_output_dirs_preserve_tree() {
+        let dir = TempDir::new("tree");
+        let manifest = test_manifest(
+            "templates",
+            dir.join("out"),
+            r#"
+preserve_tree = true
+
+[[query_templates]]
+path = "reports/monthly/foo.sql.j2"
+
+[[queries]]
+id = "foo"
+template = "reports/monthly/foo.sql.j2"
+
+[[test_templates]]
+query = "foo"
+path = "reports/monthly/foo_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let query = metadata.queries.get("foo").unwrap();
+        assert_eq!(
+            dir.join("out/queries/reports/monthly/foo.sql"),
+            query.output
+        );
+        let tt = metadata.test_templates.iter().next().unwrap();
+        assert_eq!(
+            dir.join("out/tests/reports/monthly/foo_test.sql"),
+            tt.output
+        );
+
+        ensure_output_dirs(&metadata, true).unwrap();
+        assert!(dir.join("out/queries/reports/monthly").is_dir());
+        assert!(dir.join("out/tests/reports/monthly").is_dir());
+    }
+
+    #[test]
+    fn test_ensure_output_dirs_lazy() {
+        let dir = TempDir::new("lazy");
+        let manifest = test_manifest(
+            "templates",
+            dir.join("out"),
+            r#"
+preserve_tree = true
+
+[[query_templates]]
+path = "reports/monthly/foo.sql.j2"
+
+[[query_templates]]
+path = "reports/yearly/bar.sql.j2"
+
+[[queries]]
+id = "foo"
+template = "reports/monthly/foo.sql.j2"
+
+[[queries]]
+id = "bar"
+template = "reports/yearly/bar.sql.j2"
+skip = true
+
+[[test_templates]]
+query = "bar"
+path = "reports/yearly/bar_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        ensure_output_dirs(&metadata, false).unwrap();
+        assert!(dir.join("out/queries").is_dir());
+        assert!(dir.join("out/tests").is_dir());
+        assert!(!dir.join("out/queries/reports").exists());
+
+        // Only the query that's rendered gets its dir created
+        let files = metadata
+            .queries
+            .active(false)
+            .map(|q| SqlToWrite {
+                path: Cow::Borrowed(&q.output),
+                sql: "SELECT 1;\n".to_owned(),
+                name_tag: None,
+                format: true,
+                dialect: None,
+                header: None,
+            })
+            .collect::<Vec<SqlToWrite>>();
+        write_separately(&files, None, None, OutputFormat::Sql).unwrap();
+        assert!(dir.join("out/queries/reports/monthly/foo.sql").is_file());
+        assert!(!dir.join("out/queries/reports/yearly").exists());
+        assert!(!dir.join("out/tests/reports").exists());
+    }
+
+    #[test]
+    fn test_output_format_gzip_roundtrip() {
+        let dir = TempDir::new("gzip");
+        let path = dir.join("foo_test.sql");
+        let sql = "SELECT 1;\n";
+        let files = vec![SqlToWrite {
+            path: Cow::Borrowed(&path),
+            sql: sql.to_owned(),
+            name_tag: None,
+            format: true,
+            dialect: None,
+            header: None,
+        }];
+        write_separately(&files, None, None, OutputFormat::Gzip).unwrap();
+
+        // Only the compressed file is written
+        assert!(!path.exists());
+        let gz_path = dir.join("foo_test.sql.gz");
+        assert!(gz_path.exists());
+        assert_ne!(sql.as_bytes(), fs::read(&gz_path).unwrap());
+
+        assert_eq!(
+            Status::Unchanged,
+            testfile_status(&path, None, None, OutputFormat::Gzip, sql).unwrap()
+        );
+        assert_eq!(
+            Status::Modified,
+            testfile_status(&path, None, None, OutputFormat::Gzip, "SELECT 2;\n").unwrap()
+        );
+        // The uncompressed file is considered missing
+        assert_eq!(
+            Status::Added,
+            testfile_status(&path, None, None, OutputFormat::Sql, sql).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_output_format_is_fresh() {
+        let dir = TempDir::new("fresh");
+        let template = dir.join("foo.sql.j2");
+        let output = dir.join("foo.sql");
+        fs::write(&template, "SELECT 1;\n").unwrap();
+        let set_mtime = |path: &Path, secs: u64| {
+            let t = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(t)
+                .unwrap();
+        };
+
+        // Output doesn't exist yet
+        assert!(!OutputFormat::Sql.is_fresh(&output, &[&template]).unwrap());
+
+        fs::write(&output, "SELECT 1;\n").unwrap();
+        set_mtime(&template, 1_000_000);
+        set_mtime(&output, 2_000_000);
+        assert!(OutputFormat::Sql.is_fresh(&output, &[&template]).unwrap());
+        // The gzip output file doesn't exist
+        assert!(!OutputFormat::Gzip.is_fresh(&output, &[&template]).unwrap());
+
+        // Template modified after the output was written
+        set_mtime(&template, 3_000_000);
+        assert!(!OutputFormat::Sql.is_fresh(&output, &[&template]).unwrap());
+    }
+
     #[test]
     fn test_layout_decode() {
         // When layout = 'one-file-one-query' AND output file is not
@@ -451,8 +1266,8 @@ mod tests {
             "base",
         );
         match layout {
-            Ok(Layout::OneFileOneQuery) => assert!(true),
-            _ => assert!(false),
+            Ok(Layout::OneFileOneQuery) => {}
+            _ => panic!(),
         }
 
         // When layout = 'one-file-one-query' AND output file is
@@ -468,8 +1283,8 @@ query_output_file = 'queries.sql'
             "base",
         );
         match layout {
-            Ok(Layout::OneFileOneQuery) => assert!(true),
-            _ => assert!(false),
+            Ok(Layout::OneFileOneQuery) => {}
+            _ => panic!(),
         }
 
         // When layout = 'one-file-all-queries' AND output file is not
@@ -483,10 +1298,9 @@ query_output_file = 'queries.sql'
         );
         match layout {
             Ok(Layout::OneFileAllQueries(p)) => {
-                assert!(true);
                 assert_eq!(None, p);
             }
-            _ => assert!(false),
+            _ => panic!(),
         };
 
         // When layout = 'one-file-all-queries' AND output file is
@@ -503,10 +1317,486 @@ query_output_file = 'queries.sql'
         );
         match layout {
             Ok(Layout::OneFileAllQueries(p)) => {
-                assert!(true);
                 assert_eq!(Some(PathBuf::from("base/queries.sql")), p);
             }
-            _ => assert!(false),
+            _ => panic!(),
         };
     }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_write_separately_async() {
+        use crate::tagging::NameTagStyle;
+        use std::num::NonZeroUsize;
+
+        let dir = TempDir::new("async");
+        let (sync_dir, async_dir) = (dir.join("sync"), dir.join("async"));
+        fs::create_dir_all(&sync_dir).unwrap();
+        fs::create_dir_all(&async_dir).unwrap();
+
+        let ids = (0..20)
+            .map(|i| format!("query_{i}"))
+            .collect::<Vec<String>>();
+        let name_tags = ids
+            .iter()
+            .map(|id| NameTag::DeriveFromId(id.clone()))
+            .collect::<Vec<NameTag>>();
+        let sync_paths = ids
+            .iter()
+            .map(|id| sync_dir.join(format!("{id}.sql")))
+            .collect::<Vec<PathBuf>>();
+        let async_paths = ids
+            .iter()
+            .map(|id| async_dir.join(format!("{id}.sql")))
+            .collect::<Vec<PathBuf>>();
+        fn to_write<'a>(paths: &'a [PathBuf], name_tags: &'a [NameTag]) -> Vec<SqlToWrite<'a>> {
+            paths
+                .iter()
+                .zip(name_tags.iter())
+                .enumerate()
+                .map(|(i, (path, nt))| SqlToWrite {
+                    path: Cow::Borrowed(path.as_path()),
+                    sql: format!("SELECT {i};\n"),
+                    name_tag: Some(nt),
+                    format: true,
+                    dialect: None,
+                    header: None,
+                })
+                .collect()
+        }
+        let sync_files = to_write(&sync_paths, &name_tags);
+        let async_files = to_write(&async_paths, &name_tags);
+        let tagger = NameTagger {
+            style: NameTagStyle::KebabCase,
+        };
+        let limit = NonZeroUsize::new(4).unwrap();
+        for out_format in [OutputFormat::Sql, OutputFormat::Gzip] {
+            write_separately(&sync_files, None, Some(&tagger), out_format).unwrap();
+            write_separately_async(&async_files, None, Some(&tagger), out_format, limit).unwrap();
+            for (sp, ap) in sync_paths.iter().zip(async_paths.iter()) {
+                let expected = out_format.read(sp).unwrap();
+                assert!(expected.starts_with(b"-- name: query-"));
+                assert_eq!(expected, out_format.read(ap).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_tar() {
+        use crate::tagging::NameTagStyle;
+
+        let name_tags = [
+            NameTag::DeriveFromId("artists".to_owned()),
+            NameTag::DeriveFromId("albums".to_owned()),
+        ];
+        let queries = vec![
+            SqlToWrite {
+                path: Cow::Borrowed(Path::new("output/queries/artists.sql")),
+                sql: "SELECT * FROM artist;\n".to_owned(),
+                name_tag: Some(&name_tags[0]),
+                format: true,
+                dialect: None,
+                header: None,
+            },
+            SqlToWrite {
+                path: Cow::Borrowed(Path::new("output/queries/albums.sql")),
+                sql: "SELECT * FROM album;\n".to_owned(),
+                name_tag: Some(&name_tags[1]),
+                format: true,
+                dialect: None,
+                header: None,
+            },
+        ];
+        let tests = vec![SqlToWrite {
+            path: Cow::Borrowed(Path::new("output/tests/artists_test.sql")),
+            sql: "SELECT plan(1);\n".to_owned(),
+            name_tag: None,
+            format: true,
+            dialect: None,
+            header: None,
+        }];
+        let tagger = NameTagger {
+            style: NameTagStyle::KebabCase,
+        };
+
+        let mut buf = Vec::new();
+        let layout = Layout::OneFileOneQuery;
+        write_tar(
+            &mut buf,
+            &queries,
+            &tests,
+            &layout,
+            None,
+            Some(&tagger),
+            OutputFormat::Sql,
+        )
+        .unwrap();
+        let mut archive = tar::Archive::new(buf.as_slice());
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let path = entry.path().unwrap().into_owned();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (path, contents)
+            })
+            .collect::<Vec<(PathBuf, String)>>();
+        let expected = vec![
+            (
+                PathBuf::from("output/queries/albums.sql"),
+                "-- name: albums\nSELECT * FROM album;\n".to_owned(),
+            ),
+            (
+                PathBuf::from("output/queries/artists.sql"),
+                "-- name: artists\nSELECT * FROM artist;\n".to_owned(),
+            ),
+            (
+                PathBuf::from("output/tests/artists_test.sql"),
+                "SELECT plan(1);\n".to_owned(),
+            ),
+        ];
+        assert_eq!(expected, entries);
+
+        // In case of gzip, the entries are compressed and named the
+        // same as the files that would be written
+        let mut buf = Vec::new();
+        write_tar(
+            &mut buf,
+            &queries,
+            &[],
+            &layout,
+            None,
+            None,
+            OutputFormat::Gzip,
+        )
+        .unwrap();
+        let mut archive = tar::Archive::new(buf.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(
+            Path::new("output/queries/albums.sql.gz"),
+            entry.path().unwrap()
+        );
+        let mut contents = String::new();
+        GzDecoder::new(&mut entry)
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!("SELECT * FROM album;\n", contents);
+        assert!(entries.next().is_some());
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_is_same_output() {
+        assert!(is_same_output(b"SELECT 1;\n", b"SELECT 1;\n"));
+        // Without block markers, trailing whitespace is significant
+        assert!(!is_same_output(b"SELECT 1;\n", b"SELECT 1;"));
+        assert!(!is_same_output(b"SELECT 1;\n", b"SELECT 1;\n\n"));
+        // ..but not when the markers are to be ignored
+        assert!(is_same_output(
+            b"-- >>> one\nSELECT 1;\n-- <<< one\n",
+            b"SELECT 1;"
+        ));
+        assert!(!is_same_output(
+            b"-- >>> one\nSELECT 1;\n-- <<< one\n",
+            b"SELECT 2;\n"
+        ));
+    }
+
+    #[test]
+    fn test_write_tar_out_of_tree() {
+        let dir = TempDir::new("tar-out-of-tree");
+        let (query_path, test_path) = (
+            dir.join("out/queries/artists.sql"),
+            dir.join("out/queries/../tests/artists_test.sql"),
+        );
+        let to_write = |path| SqlToWrite {
+            path: Cow::Borrowed(path),
+            sql: "SELECT 1;\n".to_owned(),
+            name_tag: None,
+            format: true,
+            dialect: None,
+            header: None,
+        };
+        let mut buf = Vec::new();
+        write_tar(
+            &mut buf,
+            &[to_write(&query_path)],
+            &[to_write(&test_path)],
+            &Layout::OneFileOneQuery,
+            None,
+            None,
+            OutputFormat::Sql,
+        )
+        .unwrap();
+        // The entries are relative to the output dir outside of the
+        // current dir, so that the archive can be extracted
+        let mut archive = tar::Archive::new(buf.as_slice());
+        let paths = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(
+            vec![
+                PathBuf::from("queries/artists.sql"),
+                PathBuf::from("tests/artists_test.sql")
+            ],
+            paths
+        );
+        let mut archive = tar::Archive::new(buf.as_slice());
+        archive.unpack(dir.join("extracted")).unwrap();
+        assert!(dir.join("extracted/tests/artists_test.sql").exists());
+    }
+
+    #[test]
+    fn test_write_separately_raw() {
+        let dir = TempDir::new("raw");
+        let value: Value = toml::from_str("sqlformat-rs = {}").unwrap();
+        let formatter = Formatter::decode(&value).unwrap().unwrap();
+
+        let sql = "select id,name from artist;";
+        let json = "{\"id\":   1}";
+        let (sql_path, json_path) = (dir.join("artists.sql"), dir.join("config.json"));
+        let files = vec![
+            SqlToWrite {
+                path: Cow::Borrowed(&sql_path),
+                sql: sql.to_owned(),
+                name_tag: None,
+                format: true,
+                dialect: None,
+                header: None,
+            },
+            SqlToWrite {
+                path: Cow::Borrowed(&json_path),
+                sql: json.to_owned(),
+                name_tag: None,
+                format: false,
+                dialect: None,
+                header: None,
+            },
+        ];
+        write_separately(&files, Some(&formatter), None, OutputFormat::Sql).unwrap();
+        let formatted = fs::read_to_string(&sql_path).unwrap();
+        assert_ne!(sql, formatted);
+        assert_eq!(formatter.format(sql).unwrap(), formatted.into_bytes());
+        // Written verbatim even though a formatter is configured
+        assert_eq!(json, fs::read_to_string(&json_path).unwrap());
+
+        // The combined file is formatted only if all queries are to be
+        // formatted
+        assert!(combined_formatter(&files[..1], Some(&formatter)).is_some());
+        assert!(combined_formatter(&files, Some(&formatter)).is_none());
+    }
+
+    #[test]
+    fn test_formatter_by_dialect() {
+        let dir = TempDir::new("by-dialect");
+        let value: toml::Value = toml::from_str(
+            r#"
+[formatter.by_dialect.postgres]
+command = { exec_path = "sed", args = [ "-e", "s/^/-- pg\\n/" ] }
+
+[formatter.by_dialect.mysql]
+command = { exec_path = "sed", args = [ "-e", "s/^/-- mysql\\n/" ] }
+"#,
+        )
+        .unwrap();
+        let formatter = Formatter::decode(value.get("formatter").unwrap())
+            .unwrap()
+            .unwrap();
+        let paths = [
+            dir.join("pg.sql"),
+            dir.join("mysql.sql"),
+            dir.join("other.sql"),
+        ];
+        let files = paths
+            .iter()
+            .zip([Some("postgres"), Some("mysql"), None])
+            .map(|(path, dialect)| SqlToWrite {
+                path: Cow::Borrowed(path.as_path()),
+                sql: "SELECT 1;".to_owned(),
+                name_tag: None,
+                format: true,
+                dialect,
+                header: None,
+            })
+            .collect::<Vec<SqlToWrite>>();
+        write_separately(&files, Some(&formatter), None, OutputFormat::Sql).unwrap();
+        let contents = paths
+            .iter()
+            .map(|p| fs::read_to_string(p).unwrap())
+            .collect::<Vec<String>>();
+        assert!(contents[0].starts_with("-- pg\nSELECT 1;"));
+        assert!(contents[1].starts_with("-- mysql\nSELECT 1;"));
+        // No default formatter, so it's written as it is
+        assert_eq!("SELECT 1;", contents[2]);
+
+        // The combined file is formatted for a dialect only if all
+        // queries are of that dialect
+        assert!(combined_formatter(&files[..1], Some(&formatter)).is_some());
+        assert!(combined_formatter(&files[..2], Some(&formatter)).is_none());
+    }
+
+    #[test]
+    fn test_header_template() {
+        let dir = TempDir::new("header");
+        let manifest = test_manifest(
+            "templates",
+            dir.join("out"),
+            r#"
+header_template = """
+-- query: {{ id }}
+-- generated at {{ generated_at }}"""
+
+[name_tagger]
+style = "exact"
+
+[[query_templates]]
+path = "foo.sql.j2"
+
+[[queries]]
+id = "foo"
+template = "foo.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let query = metadata.queries.get("foo").unwrap();
+        let tagger = metadata.name_tagger.as_ref();
+        let sql = "SELECT 1;\n".to_owned();
+        let mut files = SqlToWrite::for_query(query, sql.clone());
+        files[0].header = metadata
+            .render_header("foo", "2024-05-01T09:30:00Z")
+            .unwrap();
+        write_separately(&files, None, tagger, OutputFormat::Sql).unwrap();
+        assert_eq!(
+            "-- query: foo\n-- generated at 2024-05-01T09:30:00Z\n-- name: foo\nSELECT 1;\n",
+            fs::read_to_string(&query.output).unwrap()
+        );
+
+        // A different `generated_at` doesn't cause drift
+        let header = metadata.render_header("foo", FIXED_TIMESTAMP).unwrap();
+        files[0].header = header.clone();
+        assert_eq!(
+            Status::Unchanged,
+            files[0].status(None, tagger, OutputFormat::Sql).unwrap()
+        );
+        let reader = QueryOutputReader::new(
+            &metadata,
+            OutputFormat::Sql,
+            metadata.queries.iter().map(|q| q.as_ref()),
+        )
+        .unwrap();
+        let tagged = tagger.unwrap().ensure_name_tag(&sql, &query.name_tag);
+        assert_eq!(
+            Status::Unchanged,
+            query_status(query, &reader, None, header.as_deref(), &tagged).unwrap()
+        );
+        assert_eq!(
+            Status::Modified,
+            query_status(query, &reader, None, None, &tagged).unwrap()
+        );
+        files[0].sql = "SELECT 2;\n".to_owned();
+        assert_eq!(
+            Status::Modified,
+            files[0].status(None, tagger, OutputFormat::Sql).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_split_on() {
+        let dir = TempDir::new("split");
+        let manifest = test_manifest(
+            "templates",
+            dir.join("out"),
+            r#"
+[[query_templates]]
+path = "foo.sql.j2"
+
+[[queries]]
+id = "foo"
+template = "foo.sql.j2"
+split_on = ";"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let query = metadata.queries.get("foo").unwrap();
+        let sql = "CREATE TABLE foo (id int);\n\nINSERT INTO foo VALUES (1);\n".to_owned();
+        let files = SqlToWrite::for_query(query, sql);
+        write_separately(&files, None, None, OutputFormat::Sql).unwrap();
+
+        let out_dir = dir.join("out/queries");
+        assert_eq!(
+            "CREATE TABLE foo (id int);\n",
+            fs::read_to_string(out_dir.join("foo.001.sql")).unwrap()
+        );
+        assert_eq!(
+            "INSERT INTO foo VALUES (1);\n",
+            fs::read_to_string(out_dir.join("foo.002.sql")).unwrap()
+        );
+        assert!(!out_dir.join("foo.sql").exists());
+        for file in &files {
+            assert_eq!(
+                Status::Unchanged,
+                file.status(None, None, OutputFormat::Sql).unwrap()
+            );
+        }
+
+        let pg = PostgreSqlDialect {};
+        // A marker comment is dropped from the parts
+        assert_eq!(
+            vec!["SELECT 1;\n", "SELECT 2;\n"],
+            split_sql("SELECT 1;\n-- split\nSELECT 2;\n", "-- split", &pg)
+        );
+        // The delimiter is not matched inside string literals, `$$`
+        // quoted bodies and comments
+        let sql = "INSERT INTO t VALUES ('a;b'); -- c;d
+CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;
+/* e; f */ SELECT 'é';";
+        assert_eq!(
+            vec![
+                "INSERT INTO t VALUES ('a;b');\n",
+                "-- c;d\nCREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;\n",
+                "/* e; f */ SELECT 'é';\n",
+            ],
+            split_sql(sql, ";", &pg)
+        );
+        assert_eq!(
+            vec!["SELECT '-- split';\n", "SELECT 2;\n"],
+            split_sql("SELECT '-- split';\n-- split\nSELECT 2;\n", "-- split", &pg)
+        );
+        // Falls back to splitting on every occurrence if the sql
+        // can't be tokenized
+        assert_eq!(
+            vec!["SELECT 'a;\n", "b;\n"],
+            split_sql("SELECT 'a;b", ";", &pg)
+        );
+        // The sql is tokenized as per the dialect e.g. `#` starts a
+        // comment in mysql
+        let sql = "SELECT 1; # a;b\nSELECT 2;";
+        assert_eq!(
+            vec!["SELECT 1;\n", "# a;b\nSELECT 2;\n"],
+            split_sql(sql, ";", &MySqlDialect {})
+        );
+        assert_eq!(3, split_sql(sql, ";", &pg).len());
+
+        // Parts left over from an earlier render with more parts are
+        // removed
+        fs::write(out_dir.join("foo.003.sql"), "SELECT 3;\n").unwrap();
+        fs::write(out_dir.join("foo.004.sql"), "SELECT 4;\n").unwrap();
+        let removed =
+            remove_stale_parts(&out_dir.join("foo.sql"), files.len(), OutputFormat::Sql).unwrap();
+        assert_eq!(
+            vec![out_dir.join("foo.003.sql"), out_dir.join("foo.004.sql")],
+            removed
+        );
+        assert!(out_dir.join("foo.002.sql").exists());
+        assert!(!out_dir.join("foo.003.sql").exists());
+        assert_eq!(
+            PathBuf::from("out/foo.012.sql"),
+            part_path(Path::new("out/foo.sql"), 12)
+        );
+    }
 }
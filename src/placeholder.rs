@@ -1,34 +1,50 @@
 use crate::error::{parse_error, Error};
 use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::str::FromStr;
 use toml::Value;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Placeholder {
     PosArgs,
     Variables,
 }
 
+/// Parses the value of the `placeholder` key in the manifest. The
+/// accepted values are the same as the ones returned by `label`.
+impl FromStr for Placeholder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|p| p.label() == s)
+            .ok_or(parse_error!("Invalid placeholder: '{}'", s))
+    }
+}
+
 impl TryFrom<&Value> for Placeholder {
     type Error = Error;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match value.as_str() {
-            Some(s) => {
-                if s == "posargs" {
-                    Ok(Self::PosArgs)
-                } else if s == "variables" {
-                    Ok(Self::Variables)
-                } else {
-                    Err(parse_error!("Invalid placeholder: '{}", s))
-                }
-            }
+            Some(s) => s.parse(),
             None => Err(parse_error!("Value of key 'placeholder' must be a string")),
         }
     }
 }
 
+impl Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
 impl Placeholder {
-    pub fn label(&self) -> &str {
+    /// All variants, in the order in which they're documented
+    pub const ALL: [Self; 2] = [Self::PosArgs, Self::Variables];
+
+    pub fn label(&self) -> &'static str {
         match self {
             Self::PosArgs => "posargs",
             Self::Variables => "variables",
@@ -36,6 +52,30 @@ impl Placeholder {
     }
 }
 
+/// Style of the bind parameters that the placeholders in the query
+/// templates are rendered as
+///
+/// `index` is the position (starting from 1) of the param in the
+/// order of its first occurrence in the query, and `name` is the
+/// name passed to the `placeholder` function in the template. The
+/// built-in styles are the `Placeholder` variants, but a custom one
+/// can be used for rendering via `Engine::with_placeholder_style`
+/// e.g. for drivers that expect `@p1`.
+pub trait PlaceholderStyle {
+    fn render_marker(&self, index: usize, name: Option<&str>) -> String;
+}
+
+impl PlaceholderStyle for Placeholder {
+    fn render_marker(&self, index: usize, name: Option<&str>) -> String {
+        match (self, name) {
+            (Self::PosArgs, _) => format!("${index}"),
+            (Self::Variables, Some(name)) => format!(":{name}"),
+            // Unnamed params are named after their position
+            (Self::Variables, None) => format!(":p{index}"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -48,20 +88,43 @@ mod tests {
         let p = Placeholder::try_from(&t["placeholder"]);
         assert!(p.is_ok());
         match p.unwrap() {
-            Placeholder::PosArgs => assert!(true),
-            _ => assert!(false),
+            Placeholder::PosArgs => {}
+            _ => panic!(),
         }
 
         let t = "placeholder = 'variables'".parse::<Table>().unwrap();
         let p = Placeholder::try_from(&t["placeholder"]);
         assert!(p.is_ok());
         match p.unwrap() {
-            Placeholder::Variables => assert!(true),
-            _ => assert!(false),
+            Placeholder::Variables => {}
+            _ => panic!(),
         }
 
         let t = "placeholder = 'question-marks'".parse::<Table>().unwrap();
         let p = Placeholder::try_from(&t["placeholder"]);
         assert!(p.is_err());
     }
+
+    #[test]
+    fn test_placeholder_parse_display() {
+        for p in Placeholder::ALL {
+            assert_eq!(p, p.to_string().parse::<Placeholder>().unwrap());
+        }
+        assert_eq!("posargs", Placeholder::PosArgs.to_string());
+        assert_eq!("variables", Placeholder::Variables.to_string());
+        match "Posargs".parse::<Placeholder>() {
+            Err(Error::Parsing(msg)) => assert_eq!("Invalid placeholder: 'Posargs'", msg),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_render_marker() {
+        assert_eq!("$2", Placeholder::PosArgs.render_marker(2, Some("genre")));
+        assert_eq!(
+            ":genre",
+            Placeholder::Variables.render_marker(2, Some("genre"))
+        );
+        assert_eq!(":p2", Placeholder::Variables.render_marker(2, None));
+    }
 }
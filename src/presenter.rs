@@ -0,0 +1,648 @@
+use crate::formatters::Formatter;
+use crate::output::Status;
+use crate::query::Query;
+use crate::render::CondOverrides;
+use crate::test_template::TestTemplate;
+use crate::util::{normalize_path, relative_to};
+use crate::validation::{ManifestMistake, Severity};
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Policy for coloring the output, set using the global `--color`
+/// option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color only if the output is a terminal and `NO_COLOR` is not
+    /// set
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR_CHOICE: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Sets the color policy for the rest of the invocation. Only the
+/// first call has any effect.
+pub fn set_color_choice(choice: ColorChoice) {
+    let _ = COLOR_CHOICE.set(choice);
+}
+
+/// Decides whether output should be colored
+///
+/// In case of `Auto`, as per the convention described at
+/// https://no-color.org, color is disabled if the `NO_COLOR` env var
+/// is set to a non-empty value. It's also disabled if the output is
+/// not a terminal.
+fn color_enabled(choice: ColorChoice, no_color: Option<&OsStr>, is_tty: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            let no_color = no_color.is_some_and(|v| !v.is_empty());
+            !no_color && is_tty
+        }
+    }
+}
+
+/// Returns whether output printed to stdout should be colored
+pub fn stdout_color_enabled() -> bool {
+    let choice = COLOR_CHOICE.get().copied().unwrap_or(ColorChoice::Auto);
+    let no_color = std::env::var_os("NO_COLOR");
+    color_enabled(choice, no_color.as_deref(), io::stdout().is_terminal())
+}
+
+/// Decides whether progress should be shown i.e. only if the output
+/// is a terminal and the user hasn't asked for silence
+fn progress_enabled(quiet: bool, is_tty: bool) -> bool {
+    !quiet && is_tty
+}
+
+/// Returns a progress bar for `len` queries
+///
+/// The progress bar is drawn to stderr but it's hidden if stdout is
+/// not a terminal (e.g. when the output is piped or in CI) or if
+/// `quiet` is true.
+pub fn progress_bar(len: usize, quiet: bool) -> ProgressBar {
+    if progress_enabled(quiet, io::stdout().is_terminal()) {
+        // @UNWRAP: The template is static and known to be valid
+        let style = ProgressStyle::with_template("{bar:40} {pos}/{len} queries rendered").unwrap();
+        ProgressBar::new(len as u64).with_style(style)
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{color}{text}{RESET}")
+    } else {
+        text.to_owned()
+    }
+}
+
+fn pluralize(count: usize, word: &str) -> String {
+    if count == 1 {
+        format!("{count} {word}")
+    } else {
+        format!("{count} {word}s")
+    }
+}
+
+/// Returns at most `max` of the `lines` followed by a line with the
+/// no. of lines left out, if any. All lines are returned if `max` is
+/// 0.
+pub fn truncate_lines(mut lines: Vec<String>, max: usize) -> Vec<String> {
+    if max > 0 && lines.len() > max {
+        let num_more = lines.len() - max;
+        lines.truncate(max);
+        lines.push(format!("... and {num_more} more"));
+    }
+    lines
+}
+
+/// Returns manifest mistakes formatted as lines prefixed with their
+/// severity, followed by a summary line with the counts of errors
+/// and warnings.
+///
+/// Only the first `max` mistakes are included (all if `max` is 0),
+/// but the counts in the summary line are of all mistakes.
+pub fn mistakes(mistakes: &[ManifestMistake], color: bool, max: usize) -> String {
+    let mut num_errors = 0;
+    let mut num_warnings = 0;
+    let mut lines = Vec::with_capacity(mistakes.len() + 1);
+    for mistake in mistakes {
+        let label = match mistake.severity() {
+            Severity::Error => {
+                num_errors += 1;
+                paint("error", RED, color)
+            }
+            Severity::Warning => {
+                num_warnings += 1;
+                paint("warning", YELLOW, color)
+            }
+        };
+        lines.push(format!("{label}: {}", mistake.err_msg()));
+    }
+    let mut lines = truncate_lines(lines, max);
+    lines.push(format!(
+        "{}, {}",
+        pluralize(num_errors, "error"),
+        pluralize(num_warnings, "warning")
+    ));
+    lines.join("\n")
+}
+
+/// Returns the cells of the row for `query` in the table printed by
+/// the `summary` command
+///
+/// The conds and tags are sorted so that the output is
+/// deterministic. In case of no conds or tags, `-` is shown.
+pub fn summary_row(
+    query: &Query,
+    tests: &[&Arc<TestTemplate>],
+    paths: &PathDisplay,
+) -> Vec<String> {
+    let mut conds = query
+        .conds
+        .iter()
+        .map(|c| c.as_str())
+        .collect::<Vec<&str>>();
+    conds.sort_unstable();
+    let conds = if conds.is_empty() {
+        "-".to_owned()
+    } else {
+        conds.join("\n")
+    };
+    let tags = if query.tags.is_empty() {
+        "-".to_owned()
+    } else {
+        sorted(query.tags.iter()).join("\n")
+    };
+    let tests = tests
+        .iter()
+        .map(|t| paths.show(&t.output))
+        .collect::<Vec<String>>()
+        .join("\n");
+    vec![
+        query.id.clone(),
+        paths.show(&query.output),
+        paths.show(&query.template),
+        conds,
+        tags,
+        tests,
+    ]
+}
+
+/// How the paths are shown in the output of the `summary` command
+pub enum PathDisplay {
+    /// Relative to the base dir i.e. the parent dir of the manifest
+    Relative(PathBuf),
+    /// Absolute, by joining relative paths to the base dir
+    Absolute(PathBuf),
+}
+
+impl PathDisplay {
+    pub fn show(&self, path: &Path) -> String {
+        match self {
+            Self::Relative(base) => relative_to(path, base),
+            Self::Absolute(base) => normalize_path(base.join(path)),
+        }
+        .display()
+        .to_string()
+    }
+}
+
+/// Returns the line printed to stderr by the `status` command with
+/// the `--summary-line` option i.e. the counts of output files by
+/// their status in a format that's easy to parse in scripts
+pub fn status_summary_line<'a, I: IntoIterator<Item = &'a Status>>(statuses: I) -> String {
+    let (mut changed, mut new, mut unchanged) = (0, 0, 0);
+    for status in statuses {
+        match status {
+            Status::Modified => changed += 1,
+            Status::Added => new += 1,
+            Status::Unchanged => unchanged += 1,
+        }
+    }
+    format!("tapestry: {changed} changed, {new} new, {unchanged} unchanged")
+}
+
+/// Returns the approx. human readable form of `bytes` e.g. `48 KB`
+fn approx_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.0} {}", UNITS[unit])
+}
+
+/// Returns the line printed by the `clean` command after deleting
+/// (or with `dry_run`, listing) the output files e.g.
+/// `Would delete 12 files (3 tests, 9 queries), freeing ~48 KB`
+pub fn clean_summary(num_queries: usize, num_tests: usize, bytes: u64, dry_run: bool) -> String {
+    let (verb, freeing) = if dry_run {
+        ("Would delete", "freeing")
+    } else {
+        ("Deleted", "freed")
+    };
+    let queries = if num_queries == 1 {
+        "1 query".to_owned()
+    } else {
+        format!("{num_queries} queries")
+    };
+    format!(
+        "{verb} {} ({}, {queries}), {freeing} ~{}",
+        pluralize(num_queries + num_tests, "file"),
+        pluralize(num_tests, "test"),
+        approx_size(bytes)
+    )
+}
+
+fn sorted<'a, I: Iterator<Item = &'a String>>(xs: I) -> Vec<&'a str> {
+    let mut xs = xs.map(|x| x.as_str()).collect::<Vec<&str>>();
+    xs.sort_unstable();
+    xs
+}
+
+/// Returns everything that's known about `query` i.e. the resolved
+/// paths, conds, vars (with their sources), formatter and tests
+/// formatted as lines for the `explain-query` command
+///
+/// `all_conds` are the conds of the query template and
+/// `overrides` the ones specified on the command line.
+pub fn explain_query(
+    query: &Query,
+    all_conds: &HashSet<String>,
+    overrides: &CondOverrides,
+    formatter: Option<&Formatter>,
+    tests: &[&Arc<TestTemplate>],
+) -> String {
+    let mut lines = vec![
+        format!("Query: {}", query.id),
+        format!("Template: {}", query.template.display()),
+        format!("Output: {}", query.output.display()),
+        format!("Skipped: {}", if query.skip { "yes" } else { "no" }),
+    ];
+
+    let active = sorted(
+        all_conds
+            .iter()
+            .filter(|c| overrides.resolve(&query.conds, c).0),
+    );
+    if active.is_empty() {
+        lines.push("Conds: -".to_owned());
+    } else {
+        lines.push(format!("Conds: {}", active.join(", ")));
+    }
+
+    lines.push("Vars:".to_owned());
+    for cond in sorted(all_conds.iter()) {
+        let (value, source) = overrides.resolve(&query.conds, cond);
+        lines.push(format!("  cond__{cond} = {value} ({source})"));
+    }
+    for (name, value) in query.args.iter() {
+        lines.push(format!("  {name} = {value} (queries[].args)"));
+    }
+
+    lines.push(format!(
+        "Formatter: {}",
+        formatter.map_or_else(|| "-".to_owned(), |f| f.describe())
+    ));
+
+    if tests.is_empty() {
+        lines.push("Tests: -".to_owned());
+    } else {
+        lines.push("Tests:".to_owned());
+        for t in tests {
+            lines.push(format!("  {} -> {}", t.path.display(), t.output.display()));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Returns the lines of template `source` around an error location
+/// along with a caret under the offending column, similar to rustc
+/// diagnostics. Used by the `--verbose-errors` option.
+///
+/// `line` is 1-based and `range` is the byte range of the failing
+/// expression in `source` (if known). The caret is omitted if the
+/// range doesn't start on `line`.
+pub fn template_snippet(
+    name: &str,
+    source: &str,
+    line: usize,
+    range: Option<Range<usize>>,
+) -> String {
+    let mut offset = 0;
+    let mut lines = Vec::new();
+    for l in source.split_inclusive('\n') {
+        lines.push((offset, l.trim_end_matches(['\n', '\r'])));
+        offset += l.len();
+    }
+    let Some(&(start, text)) = line.checked_sub(1).and_then(|i| lines.get(i)) else {
+        return format!("--> {name}:{line}");
+    };
+    let caret = range.filter(|r| r.start >= start && r.start <= start + text.len());
+    let col = caret
+        .as_ref()
+        .map(|r| source[start..r.start].chars().count() + 1);
+    let width = line.to_string().len();
+    let gutter = " ".repeat(width);
+    let mut res = vec![match col {
+        Some(col) => format!("{gutter}--> {name}:{line}:{col}"),
+        None => format!("{gutter}--> {name}:{line}"),
+    }];
+    res.push(format!("{gutter} |"));
+    if line > 1 {
+        res.push(format!("{:>width$} | {}", line - 1, lines[line - 2].1));
+    }
+    res.push(format!("{line:>width$} | {text}"));
+    if let (Some(r), Some(col)) = (caret, col) {
+        let end = r.end.min(start + text.len()).max(r.start);
+        let len = source[r.start..end].chars().count().max(1);
+        res.push(format!(
+            "{gutter} | {}{}",
+            " ".repeat(col - 1),
+            "^".repeat(len)
+        ));
+    }
+    res.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::query::test_util::setup_queries;
+    use crate::test_template::test_util::setup_test_templates;
+
+    #[test]
+    fn test_color_enabled() {
+        let auto = ColorChoice::Auto;
+        assert!(color_enabled(auto, None, true));
+        assert!(!color_enabled(auto, None, false));
+        assert!(!color_enabled(auto, Some(OsStr::new("1")), true));
+        // Empty value of `NO_COLOR` is to be ignored
+        assert!(color_enabled(auto, Some(OsStr::new("")), true));
+
+        // `Always` and `Never` override tty detection and `NO_COLOR`
+        assert!(color_enabled(ColorChoice::Always, None, false));
+        assert!(color_enabled(
+            ColorChoice::Always,
+            Some(OsStr::new("1")),
+            false
+        ));
+        assert!(!color_enabled(ColorChoice::Never, None, true));
+    }
+
+    #[test]
+    fn test_mistakes_color_choice() {
+        let ms = vec![ManifestMistake::NonUniqueDirs];
+        // Output is piped i.e. not a terminal
+        let output = mistakes(&ms, color_enabled(ColorChoice::Always, None, false), 0);
+        assert!(output.contains("\x1b[31merror\x1b[0m"));
+        let output = mistakes(&ms, color_enabled(ColorChoice::Never, None, true), 0);
+        assert!(!output.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_progress_enabled() {
+        assert!(progress_enabled(false, true));
+        assert!(!progress_enabled(false, false));
+        assert!(!progress_enabled(true, true));
+        assert!(!progress_enabled(true, false));
+        assert!(progress_bar(10, true).is_hidden());
+    }
+
+    #[test]
+    fn test_mistakes() {
+        let ms = vec![
+            ManifestMistake::NonUniqueDirs,
+            ManifestMistake::PathDoesnotExist {
+                path: Path::new("templates/queries"),
+                key: "query_templates_dir",
+            },
+            ManifestMistake::UndefinedTestTemplateFile(PathBuf::from("templates/tests/foo.sql.j2")),
+        ];
+
+        let expected = r#"error: Values for all '*_dir' keys in the manifest file must be unique
+error: Path 'templates/queries' does not exist; key: 'query_templates_dir'
+warning: Did you miss defining test template in manifest? templates/tests/foo.sql.j2
+2 errors, 1 warning"#;
+        assert_eq!(expected, mistakes(&ms, false, 0));
+        assert_eq!(expected, mistakes(&ms, false, 3));
+
+        let output = mistakes(&ms, true, 0);
+        assert!(output.contains("\x1b[31merror\x1b[0m: Values"));
+        assert!(output.contains("\x1b[33mwarning\x1b[0m: Did you"));
+        assert!(output.ends_with("2 errors, 1 warning"));
+    }
+
+    #[test]
+    fn test_mistakes_truncated() {
+        let paths = (0..60)
+            .map(|i| PathBuf::from(format!("templates/tests/t{i}.sql.j2")))
+            .collect::<Vec<PathBuf>>();
+        let ms = paths
+            .iter()
+            .map(|p| ManifestMistake::PathDoesnotExist {
+                path: p,
+                key: "test_templates[].template",
+            })
+            .collect::<Vec<ManifestMistake>>();
+        let output = mistakes(&ms, false, 50);
+        let lines = output.lines().collect::<Vec<&str>>();
+        assert_eq!(52, lines.len());
+        assert!(lines[49].contains("t49.sql.j2"));
+        assert_eq!("... and 10 more", lines[50]);
+        // The summary line counts all the mistakes
+        assert_eq!("60 errors, 0 warnings", lines[51]);
+    }
+
+    #[test]
+    fn test_summary_row() {
+        let template = "templates/queries/artists_long_songs.sql.j2";
+        let queries = setup_queries(vec![
+            (
+                "artists_long_songs@genre*limit",
+                template,
+                vec!["limit", "genre"],
+                "output/queries/artists_long_songs-genre-limit.sql",
+                None,
+            ),
+            (
+                "artists_long_songs",
+                template,
+                vec![],
+                "output/queries/artists_long_songs.sql",
+                None,
+            ),
+        ]);
+        let tts = setup_test_templates(vec![(
+            "artists_long_songs@genre*limit",
+            "templates/tests/artists_long_songs-genre-limit_test.sql.j2",
+            "output/tests/artists_long_songs-genre-limit_test.sql",
+        )]);
+
+        let paths = PathDisplay::Relative(PathBuf::from("/project"));
+        let query = queries.get("artists_long_songs@genre*limit").unwrap();
+        let row = summary_row(query, &tts.find_by_query(&query.id), &paths);
+        assert_eq!(
+            vec![
+                "artists_long_songs@genre*limit",
+                "output/queries/artists_long_songs-genre-limit.sql",
+                template,
+                "genre\nlimit",
+                "-",
+                "output/tests/artists_long_songs-genre-limit_test.sql",
+            ],
+            row
+        );
+
+        let query = queries.get("artists_long_songs").unwrap();
+        let row = summary_row(query, &tts.find_by_query(&query.id), &paths);
+        assert_eq!("-", row[3]);
+        assert_eq!("-", row[4]);
+        assert_eq!("", row[5]);
+    }
+
+    #[test]
+    fn test_path_display() {
+        // Paths in the manifest are either relative to its parent dir
+        // or absolute e.g. when overridden by env vars
+        let base = PathBuf::from("/home/user/project");
+        let relative = PathDisplay::Relative(base.clone());
+        assert_eq!(
+            "output/queries/artists.sql",
+            relative.show(Path::new("./output/queries/artists.sql"))
+        );
+        assert_eq!(
+            "templates/queries/artists.sql.j2",
+            relative.show(Path::new(
+                "/home/user/project/templates/../templates/queries/artists.sql.j2"
+            ))
+        );
+        assert_eq!(
+            "/srv/sql/artists.sql",
+            relative.show(Path::new("/srv/sql/artists.sql"))
+        );
+
+        let absolute = PathDisplay::Absolute(base);
+        assert_eq!(
+            "/home/user/project/output/queries/artists.sql",
+            absolute.show(Path::new("./output/queries/artists.sql"))
+        );
+        assert_eq!(
+            "/srv/sql/artists.sql",
+            absolute.show(Path::new("/srv/sql/artists.sql"))
+        );
+    }
+
+    #[test]
+    fn test_status_summary_line() {
+        let statuses = [
+            Status::Modified,
+            Status::Unchanged,
+            Status::Added,
+            Status::Modified,
+            Status::Unchanged,
+            Status::Modified,
+        ];
+        assert_eq!(
+            "tapestry: 3 changed, 1 new, 2 unchanged",
+            status_summary_line(&statuses)
+        );
+        assert_eq!(
+            "tapestry: 0 changed, 0 new, 0 unchanged",
+            status_summary_line(&[])
+        );
+    }
+
+    #[test]
+    fn test_clean_summary() {
+        assert_eq!("0 B", approx_size(0));
+        assert_eq!("1023 B", approx_size(1023));
+        assert_eq!("48 KB", approx_size(48 * 1024 + 100));
+        assert_eq!("2 MB", approx_size(2 * 1024 * 1024));
+        assert_eq!(
+            "Would delete 12 files (3 tests, 9 queries), freeing ~48 KB",
+            clean_summary(9, 3, 49_152, true)
+        );
+        assert_eq!(
+            "Deleted 1 file (0 tests, 1 query), freed ~10 B",
+            clean_summary(1, 0, 10, false)
+        );
+    }
+
+    #[test]
+    fn test_explain_query() {
+        let template = "templates/queries/artists_long_songs.sql.j2";
+        let queries = setup_queries(vec![(
+            "artists_long_songs@genre",
+            template,
+            vec!["genre"],
+            "output/queries/artists_long_songs-genre.sql",
+            None,
+        )]);
+        let tts = setup_test_templates(vec![(
+            "artists_long_songs@genre",
+            "templates/tests/artists_long_songs-genre_test.sql.j2",
+            "output/tests/artists_long_songs-genre_test.sql",
+        )]);
+        let all_conds = HashSet::from(["genre".to_owned(), "limit".to_owned()]);
+        let query = queries.get("artists_long_songs@genre").unwrap();
+        let tests = tts.find_active_by_query(query);
+
+        let output = explain_query(query, &all_conds, &CondOverrides::default(), None, &tests);
+        let expected = r#"Query: artists_long_songs@genre
+Template: templates/queries/artists_long_songs.sql.j2
+Output: output/queries/artists_long_songs-genre.sql
+Skipped: no
+Conds: genre
+Vars:
+  cond__genre = true (queries[].conds)
+  cond__limit = false (default)
+Formatter: -
+Tests:
+  templates/tests/artists_long_songs-genre_test.sql.j2 -> output/tests/artists_long_songs-genre_test.sql"#;
+        assert_eq!(expected, output);
+
+        // Overrides from the command line are reflected in the vars
+        let overrides = CondOverrides {
+            on: HashSet::from(["limit".to_owned()]),
+            off: HashSet::from(["genre".to_owned()]),
+        };
+        let output = explain_query(query, &all_conds, &overrides, None, &[]);
+        assert!(output.contains("Conds: limit\n"));
+        assert!(output.contains("  cond__genre = false (--no-cond)\n"));
+        assert!(output.contains("  cond__limit = true (--cond/--profile)\n"));
+        assert!(output.ends_with("Tests: -"));
+    }
+
+    #[test]
+    fn test_template_snippet() {
+        let source = "SELECT *\nFROM artist\nWHERE name = {{ name + 1 }}\n";
+        let start = source.find("name + 1").unwrap();
+        let snippet = template_snippet("artists.sql.j2", source, 3, Some(start..start + 8));
+        let expected = [
+            " --> artists.sql.j2:3:17",
+            "  |",
+            "2 | FROM artist",
+            "3 | WHERE name = {{ name + 1 }}",
+            "  |                 ^^^^^^^^",
+        ];
+        assert_eq!(expected.join("\n"), snippet);
+
+        // Location reported by minijinja for a render error
+        let env = minijinja::Environment::new();
+        let tmpl = env
+            .template_from_named_str("artists.sql.j2", source)
+            .unwrap();
+        let err = tmpl
+            .render(minijinja::context! { name => "AC/DC" })
+            .unwrap_err();
+        assert_eq!(Some(3), err.line());
+        let snippet = template_snippet("artists.sql.j2", source, 3, err.range());
+        assert_eq!(expected.join("\n"), snippet);
+
+        // Caret is omitted without a range
+        let snippet = template_snippet("artists.sql.j2", source, 1, None);
+        assert_eq!(" --> artists.sql.j2:1\n  |\n1 | SELECT *", snippet);
+    }
+}
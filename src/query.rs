@@ -1,15 +1,19 @@
+use crate::cond_expr::CondExpr;
 use crate::error::{parse_error, Error};
+use crate::formatters::Formatter;
 use crate::output::Layout;
 use crate::query_template::QueryTemplates;
 use crate::tagging::NameTag;
-use crate::toml::{decode_pathbuf, decode_string, decode_strset};
+use crate::toml::{decode_bool, decode_pathbuf, decode_string, decode_strset};
+use crate::util::{glob_match, mirrored_dir};
 use crate::validation::ManifestMistake;
 use regex::Regex;
+use sqlparser::dialect::{dialect_from_str, Dialect, PostgreSqlDialect};
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use toml::Value;
+use std::sync::{Arc, OnceLock};
+use toml::{Table, Value};
 
 fn slugify_id(id: &str) -> Cow<'_, str> {
     let re = Regex::new(r"@|\+|&|\*").unwrap();
@@ -22,6 +26,13 @@ fn id_to_output(id: &str, base_dir: &Path) -> PathBuf {
     base_dir.join(filepath)
 }
 
+/// Returns whether `id` can be used as a query id i.e. it's not
+/// empty (or whitespace only) and doesn't contain path separators,
+/// as the output file name may be derived from it
+pub fn is_valid_id(id: &str) -> bool {
+    !id.trim().is_empty() && !id.contains(['/', '\\'])
+}
+
 /// Computes the fallback value for 'output' field when one is not
 /// explicitly specified.
 ///
@@ -34,7 +45,7 @@ fn id_to_output(id: &str, base_dir: &Path) -> PathBuf {
 /// # Error:
 /// Returns `Error::Parsing` if output_layout = `OneFileAllQueries'
 /// and `query_output_file` key is not set in the manifest
-fn fallback_output<P: AsRef<Path>>(
+pub fn fallback_output<P: AsRef<Path>>(
     id: &str,
     base_dir: P,
     output_layout: &Layout,
@@ -50,20 +61,191 @@ fn fallback_output<P: AsRef<Path>>(
     }
 }
 
-#[derive(Debug)]
+/// Returns the strings that occur more than once in the array
+/// `value`, each only once and in the order of their second
+/// occurrence
+fn duplicate_strs(value: &Value) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut result: Vec<String> = vec![];
+    for s in value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        if !seen.insert(s) && !result.iter().any(|r| r == s) {
+            result.push(s.to_owned());
+        }
+    }
+    result
+}
+
+/// Returns `path` with the `suffix` of a variant appended to the
+/// file stem e.g. `foo.sql` -> `foo_tenant_a.sql`
+pub fn variant_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}_{suffix}.{}", ext.to_string_lossy()),
+        None => format!("{stem}_{suffix}"),
+    };
+    path.with_file_name(name)
+}
+
+#[derive(Debug, Clone)]
 pub struct Query {
     pub id: String,
     pub template: PathBuf,
     pub conds: HashSet<String>,
     pub output: PathBuf,
     pub name_tag: NameTag,
+    pub skip: bool,
+    /// Expression over the conds that must hold (after the cond
+    /// overrides, if any) for the query to be rendered
+    pub enabled_if: Option<CondExpr>,
+    /// Whether the output of the query and its tests is to be passed
+    /// through the formatter (if configured)
+    pub format: bool,
+    /// SQL dialect of the query, for selecting the formatter when
+    /// it's configured per dialect (see `formatter.by_dialect`) and
+    /// for parsing the rendered SQL (see `sql_dialect`)
+    pub dialect: Option<String>,
+    /// Delimiter on which the rendered output is split into multiple
+    /// numbered files (e.g. `foo.001.sql`, `foo.002.sql`)
+    pub split_on: Option<String>,
+    /// Glob pattern (relative to `test_templates_dir`) for test
+    /// templates of the query that are not declared individually
+    /// under `test_templates`
+    pub tests_glob: Option<String>,
+    /// Labels for selecting a subset of queries using the `--tag`
+    /// option of the commands
+    pub tags: HashSet<String>,
+    /// Name of the group that the query is defined under, if any
+    pub group: Option<String>,
+    /// Query specific vars that are added to the context when
+    /// rendering the template
+    pub args: Table,
+    /// Conds specified more than once in the manifest, which are
+    /// recorded only for validation as `conds` is a set
+    pub duplicate_conds: Vec<String>,
+    /// Id of the query that the variant was expanded from and the
+    /// suffix of the variant, if it's one of `queries[].variants`
+    pub variant: Option<(String, String)>,
+}
+
+/// The `default_conds` in the manifest, resolved for every query
+/// template to the ones that it supports (i.e. has in `all_conds`)
+#[derive(Debug, Default)]
+pub struct DefaultConds {
+    by_template: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl DefaultConds {
+    pub fn new(conds: &HashSet<String>, query_templates: &QueryTemplates) -> Self {
+        let by_template = query_templates
+            .iter()
+            .map(|qt| {
+                let xs = conds.intersection(&qt.all_conds).cloned().collect();
+                (qt.path.clone(), xs)
+            })
+            .collect();
+        Self { by_template }
+    }
+}
+
+/// Decodes the `queries[].args` table (or the table under `key`
+/// having the same structure)
+///
+/// The names of the args must not start with `cond__` so that they
+/// don't clash with the cond vars.
+fn decode_args(value: &Value, key: &str) -> Result<Table, Error> {
+    let table = value
+        .as_table()
+        .ok_or(parse_error!("Value of '{key}' must be a table"))?;
+    if let Some(k) = table.keys().find(|k| k.starts_with("cond__")) {
+        return Err(parse_error!(
+            "Invalid name in '{key}': '{k}' (must not start with 'cond__')"
+        ));
+    }
+    Ok(table.clone())
+}
+
+/// Decodes the `queries[].variants` array into pairs of suffix and
+/// vars
+fn decode_variants(value: &Value) -> Result<Vec<(String, Table)>, Error> {
+    let items = value.as_array().ok_or(parse_error!(
+        "Value of 'queries[].variants' must be an array"
+    ))?;
+    let mut variants = Vec::with_capacity(items.len());
+    for item in items {
+        let t = item
+            .as_table()
+            .ok_or(parse_error!("Invalid 'queries[].variants' entry"))?;
+        let suffix = t
+            .get("suffix")
+            .ok_or(parse_error!(
+                "Missing 'suffix' in 'queries[].variants' entry"
+            ))
+            .map(|v| decode_string(v, "queries[].variants[].suffix"))??;
+        if !is_valid_id(&suffix) {
+            return Err(parse_error!(
+                "Invalid 'queries[].variants[].suffix': '{suffix}' (must not be empty or contain a path separator)"
+            ));
+        }
+        if variants.iter().any(|(s, _)| s == &suffix) {
+            return Err(parse_error!(
+                "Duplicate 'queries[].variants[].suffix': '{suffix}'"
+            ));
+        }
+        let vars = match t.get("vars") {
+            Some(v) => decode_args(v, "queries[].variants[].vars")?,
+            None => Table::new(),
+        };
+        variants.push((suffix, vars));
+    }
+    if variants.is_empty() {
+        return Err(parse_error!(
+            "Value of 'queries[].variants' must not be empty"
+        ));
+    }
+    Ok(variants)
 }
 
 impl Query {
+    /// Returns a query with the defaults for all the optional keys,
+    /// same as a `queries[]` entry having only `id`, `template`,
+    /// `conds` and `output`
+    pub fn new(id: &str, template: PathBuf, conds: HashSet<String>, output: PathBuf) -> Self {
+        Self {
+            id: id.to_owned(),
+            template,
+            conds,
+            output,
+            name_tag: NameTag::DeriveFromId(id.to_owned()),
+            skip: false,
+            enabled_if: None,
+            format: true,
+            dialect: None,
+            split_on: None,
+            tests_glob: None,
+            tags: HashSet::new(),
+            group: None,
+            args: Table::new(),
+            duplicate_conds: vec![],
+            variant: None,
+        }
+    }
+
+    /// Decodes a `queries[]` entry
+    ///
+    /// The `default_conds` supported by its template are merged into
+    /// the conds of the query, unless it opts out with `inherit_conds
+    /// = false`.
     fn decode<P: AsRef<Path>>(
         templates_base_dir: P,
         output_base_dir: P,
         output_layout: &Layout,
+        preserve_tree: bool,
+        default_conds: &DefaultConds,
         value: &Value,
     ) -> Result<Self, Error> {
         match value.as_table() {
@@ -78,10 +260,19 @@ impl Query {
                     .map(|v| {
                         decode_pathbuf(v, Some(templates_base_dir.as_ref()), "queries[].template")
                     })??;
-                let conds = match t.get("conds") {
-                    Some(v) => decode_strset(v, "queries[].conds")?,
-                    None => HashSet::new(),
+                let (mut conds, duplicate_conds) = match t.get("conds") {
+                    Some(v) => (decode_strset(v, "queries[].conds")?, duplicate_strs(v)),
+                    None => (HashSet::new(), vec![]),
                 };
+                let inherit_conds = match t.get("inherit_conds") {
+                    Some(v) => decode_bool(v, "queries[].inherit_conds")?,
+                    None => true,
+                };
+                if inherit_conds {
+                    if let Some(xs) = default_conds.by_template.get(&template) {
+                        conds.extend(xs.iter().cloned());
+                    }
+                }
                 let output = match t.get("output") {
                     Some(v) => {
                         // @NOTE: When `output` is specified, it's
@@ -95,24 +286,181 @@ impl Query {
                         // discrepancy but validation will catch it.
                         decode_pathbuf(v, Some(output_base_dir.as_ref()), "queries[].output")?
                     }
+                    None if preserve_tree => {
+                        let output_dir = mirrored_dir(
+                            &template,
+                            templates_base_dir.as_ref(),
+                            output_base_dir.as_ref(),
+                        );
+                        fallback_output(&id, output_dir, output_layout)?
+                    }
                     None => fallback_output(&id, output_base_dir.as_ref(), output_layout)?,
                 };
                 let name_tag = match t.get("name_tag") {
                     Some(v) => NameTag::Custom(decode_string(v, "queries[].name_tag")?),
                     None => NameTag::DeriveFromId(id.clone()),
                 };
+                let skip = match t.get("skip") {
+                    Some(v) => decode_bool(v, "queries[].skip")?,
+                    None => false,
+                };
+                let enabled_if = match t.get("enabled_if") {
+                    Some(v) => {
+                        let expr = decode_string(v, "queries[].enabled_if")?;
+                        let expr = CondExpr::parse(&expr).map_err(|e| {
+                            parse_error!("Invalid 'queries[].enabled_if' of query '{id}': {e}")
+                        })?;
+                        Some(expr)
+                    }
+                    None => None,
+                };
+                let format = match t.get("format") {
+                    Some(v) => decode_bool(v, "queries[].format")?,
+                    None => true,
+                };
+                let dialect = match t.get("dialect") {
+                    Some(v) => {
+                        let name = decode_string(v, "queries[].dialect")?;
+                        if dialect_from_str(&name).is_none() {
+                            return Err(parse_error!(
+                                "Invalid 'queries[].dialect' of query '{id}': unknown dialect '{name}'"
+                            ));
+                        }
+                        Some(name)
+                    }
+                    None => None,
+                };
+                let split_on = match t.get("split_on") {
+                    Some(v) => {
+                        if let Layout::OneFileAllQueries(_) = output_layout {
+                            return Err(parse_error!(
+                                "'queries[].split_on' is not supported in case of 'one-file-all-queries' layout"
+                            ));
+                        }
+                        let delim = decode_string(v, "queries[].split_on")?;
+                        if delim.trim().is_empty() {
+                            return Err(parse_error!(
+                                "Value of 'queries[].split_on' must not be empty"
+                            ));
+                        }
+                        Some(delim)
+                    }
+                    None => None,
+                };
+                let tests_glob = match t.get("tests_glob") {
+                    Some(v) => {
+                        let pattern = decode_string(v, "queries[].tests_glob")?;
+                        if pattern.trim().is_empty() {
+                            return Err(parse_error!(
+                                "Value of 'queries[].tests_glob' must not be empty"
+                            ));
+                        }
+                        Some(pattern)
+                    }
+                    None => None,
+                };
+                let tags = match t.get("tags") {
+                    Some(v) => decode_strset(v, "queries[].tags")?,
+                    None => HashSet::new(),
+                };
+                let args = match t.get("args") {
+                    Some(v) => decode_args(v, "queries[].args")?,
+                    None => Table::new(),
+                };
                 Ok(Self {
                     id,
                     template,
                     conds,
                     output,
                     name_tag,
+                    skip,
+                    enabled_if,
+                    format,
+                    dialect,
+                    split_on,
+                    tests_glob,
+                    tags,
+                    group: None,
+                    args,
+                    duplicate_conds,
+                    variant: None,
                 })
             }
             None => Err(parse_error!("Invalid 'query' entry")),
         }
     }
 
+    /// Expands the query into one query per variant, if the
+    /// `queries[].variants` key is specified in its entry (`value`)
+    ///
+    /// The id and the output file name of every variant have its
+    /// suffix appended to them (separated by `_`), and its vars are
+    /// added to the args (overriding the ones with the same names).
+    /// In case of the `one-file-all-queries` layout, the output is
+    /// not changed.
+    fn expand_variants(self, value: &Value, output_layout: &Layout) -> Result<Vec<Self>, Error> {
+        let variants = match value.get("variants") {
+            Some(v) => decode_variants(v)?,
+            None => return Ok(vec![self]),
+        };
+        // All variants would match the same test templates, which
+        // would then be written to the same output files
+        if self.tests_glob.is_some() {
+            return Err(parse_error!(
+                "'queries[].tests_glob' is not supported along with 'queries[].variants'"
+            ));
+        }
+        let result = variants
+            .into_iter()
+            .map(|(suffix, vars)| {
+                let mut q = self.clone();
+                q.id = format!("{}_{suffix}", self.id);
+                q.name_tag = match &self.name_tag {
+                    NameTag::DeriveFromId(_) => NameTag::DeriveFromId(q.id.clone()),
+                    NameTag::Custom(tag) => NameTag::Custom(format!("{tag}_{suffix}")),
+                };
+                if let Layout::OneFileOneQuery = output_layout {
+                    q.output = variant_path(&self.output, &suffix);
+                }
+                q.args.extend(vars);
+                q.variant = Some((self.id.clone(), suffix));
+                q
+            })
+            .collect();
+        Ok(result)
+    }
+
+    /// Returns the formatter to be applied to the output of the
+    /// query and its tests i.e. none if `format = false`, otherwise
+    /// the one for the dialect of the query
+    pub fn formatter<'f>(&self, formatter: Option<&'f Formatter>) -> Option<&'f Formatter> {
+        formatter
+            .filter(|_| self.format)
+            .and_then(|f| f.for_dialect(self.dialect.as_deref()))
+    }
+
+    /// Returns the dialect for parsing (and tokenizing) the rendered
+    /// SQL of the query i.e. postgres if it's not specified
+    pub fn sql_dialect(&self) -> Box<dyn Dialect> {
+        self.dialect
+            .as_deref()
+            .and_then(dialect_from_str)
+            // Unknown dialects are rejected when decoding the query
+            .unwrap_or_else(|| Box::new(PostgreSqlDialect {}))
+    }
+
+    /// Returns whether the query has any of the `tags`. Always true
+    /// if `tags` is empty.
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        tags.is_empty() || tags.iter().any(|t| self.tags.contains(t))
+    }
+
+    /// Returns whether the query is defined under the `group`. Always
+    /// true if `group` is None.
+    pub fn in_group(&self, group: Option<&str>) -> bool {
+        group.map_or(true, |g| self.group.as_deref() == Some(g))
+    }
+
     fn validate<'a>(&'a self, query_templates: &'a QueryTemplates) -> Vec<ManifestMistake<'a>> {
         let mut mistakes = vec![];
         match query_templates.get(&self.template) {
@@ -128,12 +476,45 @@ impl Query {
                         conds: diff,
                     })
                 }
+                if let Some(expr) = &self.enabled_if {
+                    for cond in expr.conds() {
+                        if !qt.all_conds.contains(cond) {
+                            mistakes.push(ManifestMistake::UndeclaredEnabledIfCond {
+                                query_id: &self.id,
+                                cond,
+                            });
+                        }
+                    }
+                }
             }
             None => mistakes.push(ManifestMistake::QueryTemplateRefNotFound {
                 query_id: &self.id,
                 template: self.template.to_str().unwrap(),
             }),
         }
+        for cond in &self.duplicate_conds {
+            mistakes.push(ManifestMistake::DuplicateCond {
+                query_id: &self.id,
+                cond,
+            });
+        }
+        // The conds are turned into the `cond__<name>` vars, which
+        // must be valid identifiers in the templates. Thanks to the
+        // prefix, the names may start with a digit.
+        static COND_NAME_RE: OnceLock<Regex> = OnceLock::new();
+        let re = COND_NAME_RE.get_or_init(|| Regex::new(r"^[A-Za-z0-9_]+$").unwrap());
+        let mut invalid = self
+            .conds
+            .iter()
+            .filter(|c| !re.is_match(c))
+            .collect::<Vec<&String>>();
+        invalid.sort();
+        for cond in invalid {
+            mistakes.push(ManifestMistake::InvalidCondName {
+                query_id: &self.id,
+                cond,
+            });
+        }
 
         mistakes
     }
@@ -157,14 +538,14 @@ impl Query {
 
 #[derive(Debug)]
 pub struct Queries {
-    inner: Vec<Rc<Query>>,
-    index: HashMap<String, Rc<Query>>,
+    inner: Vec<Arc<Query>>,
+    index: HashMap<String, Arc<Query>>,
 }
 
 impl Queries {
     pub fn new() -> Self {
-        let inner: Vec<Rc<Query>> = vec![];
-        let index: HashMap<String, Rc<Query>> = HashMap::new();
+        let inner: Vec<Arc<Query>> = vec![];
+        let index: HashMap<String, Arc<Query>> = HashMap::new();
         Self { inner, index }
     }
 
@@ -172,6 +553,30 @@ impl Queries {
         templates_base_dir: P,
         output_base_dir: P,
         output_layout: &Layout,
+        preserve_tree: bool,
+        default_conds: &DefaultConds,
+        value: &Value,
+    ) -> Result<Self, Error> {
+        Self::decode_group(
+            None,
+            templates_base_dir,
+            output_base_dir,
+            output_layout,
+            preserve_tree,
+            default_conds,
+            value,
+        )
+    }
+
+    /// Same as `decode` but the queries are marked as belonging to
+    /// the `group` (see `groups` in the manifest)
+    pub fn decode_group<P: AsRef<Path>>(
+        group: Option<&str>,
+        templates_base_dir: P,
+        output_base_dir: P,
+        output_layout: &Layout,
+        preserve_tree: bool,
+        default_conds: &DefaultConds,
         value: &Value,
     ) -> Result<Self, Error> {
         // @NOTE: The index is populated at the time of initialization
@@ -179,21 +584,27 @@ impl Queries {
         // would be populating the index at the time of lookup (like a
         // read-through cache) but in that case we'd need to manage
         // multiple mutable references.
-        let mut index: HashMap<String, Rc<Query>> = HashMap::new();
+        let mut index: HashMap<String, Arc<Query>> = HashMap::new();
         let items = match value.as_array() {
             Some(xs) => {
                 let mut res = Vec::with_capacity(xs.len());
                 for x in xs {
-                    let q = Rc::new(Query::decode(
+                    let q = Query::decode(
                         &templates_base_dir,
                         &output_base_dir,
                         output_layout,
+                        preserve_tree,
+                        default_conds,
                         x,
-                    )?);
-                    let idx_key = q.id.clone();
-                    let idx_val = q.clone();
-                    res.push(q);
-                    index.insert(idx_key, idx_val);
+                    )?;
+                    for mut q in q.expand_variants(x, output_layout)? {
+                        q.group = group.map(str::to_owned);
+                        let q = Arc::new(q);
+                        let idx_key = q.id.clone();
+                        let idx_val = q.clone();
+                        res.push(q);
+                        index.insert(idx_key, idx_val);
+                    }
                 }
                 res
             }
@@ -205,6 +616,21 @@ impl Queries {
         })
     }
 
+    pub fn push(&mut self, query: Query) {
+        let query = Arc::new(query);
+        self.index.insert(query.id.clone(), query.clone());
+        self.inner.push(query);
+    }
+
+    /// Appends the `other` queries e.g. the ones of a group
+    ///
+    /// Ids that are not unique across both are not checked here, but
+    /// are reported by `validate`.
+    pub fn extend(&mut self, other: Self) {
+        self.inner.extend(other.inner);
+        self.index.extend(other.index);
+    }
+
     pub fn validate<'a>(
         &'a self,
         query_templates: &'a QueryTemplates,
@@ -214,7 +640,13 @@ impl Queries {
         let count = self.inner.len();
         let mut all_ids: HashMap<&str, usize> = HashMap::with_capacity(count);
         let mut all_outputs: HashMap<&Path, usize> = HashMap::with_capacity(count);
-        for query in &self.inner {
+        for (index, query) in self.inner.iter().enumerate() {
+            if !is_valid_id(&query.id) {
+                mistakes.push(ManifestMistake::InvalidQueryId {
+                    index,
+                    id: &query.id,
+                });
+            }
             mistakes.append(&mut query.validate(query_templates));
             all_ids
                 .entry(&query.id)
@@ -281,11 +713,53 @@ impl Queries {
         self.inner.iter().map(|q| q.output.as_path())
     }
 
-    pub fn get(&self, id: &str) -> Option<&Rc<Query>> {
+    /// Returns an iterator over queries to be processed by commands
+    /// such as `render`, `status` etc.
+    ///
+    /// Queries marked with `skip = true` are excluded unless
+    /// `include_skipped` is true.
+    pub fn active(&self, include_skipped: bool) -> impl Iterator<Item = &Arc<Query>> {
+        self.inner
+            .iter()
+            .filter(move |q| include_skipped || !q.skip)
+    }
+
+    /// Same as `active` but only the queries having any of the `tags`
+    /// are included. If `tags` is empty, no filtering is done.
+    pub fn tagged<'a: 'b, 'b>(
+        &'a self,
+        include_skipped: bool,
+        tags: &'b [String],
+    ) -> impl Iterator<Item = &'a Arc<Query>> + 'b {
+        self.active(include_skipped)
+            .filter(move |q| q.has_any_tag(tags))
+    }
+
+    /// Returns those of the `tags` that are not defined for any query
+    pub fn unknown_tags<'a>(&self, tags: &'a [String]) -> Vec<&'a str> {
+        tags.iter()
+            .filter(|t| !self.inner.iter().any(|q| q.tags.contains(*t)))
+            .map(|t| t.as_str())
+            .collect()
+    }
+
+    /// Returns an iterator over queries in the order in which they
+    /// are defined in the manifest, optionally filtered by a glob
+    /// `pattern` matched against the query ids
+    pub fn matching<'a>(
+        &'a self,
+        pattern: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Arc<Query>> {
+        self.inner
+            .iter()
+            .filter(move |q| pattern.map_or(true, |p| glob_match(p, &q.id)))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Arc<Query>> {
         self.index.get(id)
     }
 
-    pub fn iter(&self) -> std::slice::Iter<'_, Rc<Query>> {
+    pub fn iter(&self) -> std::slice::Iter<'_, Arc<Query>> {
         self.inner.iter()
     }
 
@@ -295,7 +769,7 @@ impl Queries {
 }
 
 #[cfg(test)]
-mod test_util {
+pub mod test_util {
 
     use super::*;
 
@@ -303,25 +777,40 @@ mod test_util {
         xs.iter().map(|s| String::from(*s)).collect()
     }
 
+    // Tuple of (id, template, conds, output, name_tag)
+    pub type QueryData<'a> = (&'a str, &'a str, Vec<&'a str>, &'a str, Option<&'a str>);
+
     // Setup queries from &'static str input to be used in tests
     //
     // Argument `data` is a vector of tuple with following type of
     // values (in order):
     //
-    //   id: &str, template: &str, conds: Vec<&str>, output: &str
-    pub fn setup_queries(data: Vec<(&str, &str, Vec<&str>, &str, Option<&str>)>) -> Queries {
+    //   id: &str, template: &str, conds: Vec<&str>, output: &str,
+    //   name_tag: Option<&str>
+    pub fn setup_queries(data: Vec<QueryData>) -> Queries {
         let mut qs = Queries::new();
         for (id, template, conds, output, name_tag) in data {
             let name_tag = name_tag.map_or_else(
                 || NameTag::DeriveFromId(String::from(id)),
                 |s| NameTag::Custom(String::from(s)),
             );
-            let q = Rc::new(Query {
+            let q = Arc::new(Query {
                 id: String::from(id),
                 template: PathBuf::from(template),
                 conds: strset(conds),
                 output: PathBuf::from(output),
                 name_tag,
+                skip: false,
+                enabled_if: None,
+                format: true,
+                dialect: None,
+                split_on: None,
+                tests_glob: None,
+                tags: HashSet::new(),
+                group: None,
+                args: Table::new(),
+                duplicate_conds: vec![],
+                variant: None,
             });
             let idx_key = q.id.clone();
             let idx_val = q.clone();
@@ -338,7 +827,6 @@ mod tests {
     use super::test_util::*;
     use super::*;
     use crate::query_template;
-    use toml;
 
     #[test]
     fn test_decode_query() {
@@ -352,14 +840,73 @@ output = 'my_query_explicit.sql'
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
             Ok(q) => {
                 assert_eq!("my_query", q.id);
                 assert_eq!(PathBuf::from("base/my_query_template.sql.j2"), q.template);
                 assert_eq!(PathBuf::from("output/my_query_explicit.sql"), q.output);
                 assert_eq!(strset(vec!["foo"]), q.conds);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
+        }
+
+        // When skip is specified
+        let table = r#"
+id = 'my_query'
+template = 'my_query_template.sql.j2'
+skip = true
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+        let value = toml::Value::Table(table);
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(q) => assert!(q.skip),
+            Err(_) => panic!(),
+        }
+
+        // When enabled_if is specified
+        let decode_enabled_if = |expr: &str| {
+            let table = format!(
+                "id = 'my_query'\ntemplate = 'my_query_template.sql.j2'\nenabled_if = '{expr}'"
+            )
+            .parse::<toml::Table>()
+            .unwrap();
+            Query::decode(
+                "base",
+                "output",
+                &Layout::OneFileOneQuery,
+                false,
+                &DefaultConds::default(),
+                &toml::Value::Table(table),
+            )
+        };
+        match decode_enabled_if("eu and not legacy") {
+            Ok(q) => assert_eq!(
+                Some("eu and not legacy".to_owned()),
+                q.enabled_if.map(|e| e.to_string())
+            ),
+            Err(_) => panic!(),
+        }
+        match decode_enabled_if("eu and") {
+            Err(Error::Parsing(msg)) => assert_eq!(
+                "Invalid 'queries[].enabled_if' of query 'my_query': unexpected end of expression",
+                msg
+            ),
+            _ => panic!(),
         }
 
         // When conds is not specified
@@ -371,14 +918,21 @@ output = 'my_query_explicit.sql'
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
             Ok(q) => {
                 assert_eq!("my_query", q.id);
                 assert_eq!(PathBuf::from("base/my_query_template.sql.j2"), q.template);
                 assert_eq!(PathBuf::from("output/my_query_explicit.sql"), q.output);
                 assert_eq!(HashSet::new(), q.conds);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When output is not specified
@@ -390,14 +944,72 @@ conds = [ 'foo' ]
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
             Ok(q) => {
                 assert_eq!("my_query", q.id);
                 assert_eq!(PathBuf::from("base/my_query_template.sql.j2"), q.template);
                 assert_eq!(PathBuf::from("output/my_query.sql"), q.output);
                 assert_eq!(strset(vec!["foo"]), q.conds);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
+        }
+
+        // When args are specified
+        let table = r#"
+id = 'my_query'
+template = 'my_query_template.sql.j2'
+args = { table = 'orders', limit = 10 }
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+        let value = toml::Value::Table(table);
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(q) => {
+                assert_eq!(Some("orders"), q.args["table"].as_str());
+                assert_eq!(Some(10), q.args["limit"].as_integer());
+            }
+            Err(_) => panic!(),
+        }
+
+        // When args clash with the cond vars
+        let table = r#"
+id = 'my_query'
+template = 'my_query_template.sql.j2'
+args = { cond__foo = true }
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+        let value = toml::Value::Table(table);
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(_) => panic!(),
+            Err(Error::Parsing(msg)) => {
+                assert_eq!(
+                    "Invalid name in 'queries[].args': 'cond__foo' (must not start with 'cond__')",
+                    msg
+                );
+            }
+            Err(_) => panic!(),
         }
 
         // When id is not specified
@@ -409,12 +1021,19 @@ output = 'my_query_explicit.sql'
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
-            Ok(_) => assert!(false),
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!("Missing 'id' in 'query' entry", msg);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When template is not specified
@@ -426,12 +1045,19 @@ output = 'my_query_explicit.sql'
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
-            Ok(_) => assert!(false),
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!("Missing 'template' in 'query' entry", msg);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When `all_conds` is not of correct type
@@ -444,16 +1070,375 @@ output = 'my_query_explicit.sql'
         .parse::<toml::Table>()
         .unwrap();
         let value = toml::Value::Table(table);
-        match Query::decode("base", "output", &Layout::OneFileOneQuery, &value) {
-            Ok(_) => assert!(false),
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!(
                     "Value of 'queries[].conds' is expected to be array of strings",
                     msg
                 );
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
+
+        // When `dialect` is not known
+        let table = r#"
+id = 'my_query'
+template = 'my_query_template.sql.j2'
+dialect = 'pgsql'
+"#
+        .parse::<toml::Table>()
+        .unwrap();
+        let value = toml::Value::Table(table);
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Ok(_) => panic!(),
+            Err(Error::Parsing(msg)) => {
+                assert_eq!(
+                    "Invalid 'queries[].dialect' of query 'my_query': unknown dialect 'pgsql'",
+                    msg
+                );
+            }
+            Err(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decode_queries_optional_conds() {
+        let value: toml::Value = toml::from_str(
+            r#"
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "artists_long_songs@genre"
+template = "artists_long_songs.sql.j2"
+conds = [ "genre" ]
+"#,
+        )
+        .unwrap();
+        let qs = Queries::decode(
+            "templates/queries",
+            "output/queries",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            value.get("queries").unwrap(),
+        )
+        .unwrap();
+        assert!(qs.get("artists_long_songs").unwrap().conds.is_empty());
+        assert_eq!(
+            strset(vec!["genre"]),
+            qs.get("artists_long_songs@genre").unwrap().conds
+        );
+
+        let qts = query_template::test_util::setup_query_templates(vec![(
+            "templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        assert!(qs.validate(&qts, &Layout::OneFileOneQuery).is_empty());
+    }
+
+    #[test]
+    fn test_decode_queries_variants() {
+        let value: toml::Value = toml::from_str(
+            r#"
+[[queries]]
+id = "orders"
+template = "orders.sql.j2"
+args = { schema = "public", limit = 10 }
+variants = [
+  { suffix = "tenant_a", vars = { schema = "a" } },
+  { suffix = "tenant_b", vars = { schema = "b" } },
+]
+"#,
+        )
+        .unwrap();
+        let qs = Queries::decode(
+            "templates/queries",
+            "output/queries",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            value.get("queries").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(2, qs.len());
+        assert!(qs.get("orders").is_none());
+        for (id, output, schema) in [
+            ("orders_tenant_a", "output/queries/orders_tenant_a.sql", "a"),
+            ("orders_tenant_b", "output/queries/orders_tenant_b.sql", "b"),
+        ] {
+            let q = qs.get(id).unwrap();
+            assert_eq!(PathBuf::from(output), q.output);
+            assert_eq!(Some(schema), q.args["schema"].as_str());
+            assert_eq!(Some(10), q.args["limit"].as_integer());
+            assert!(matches!(&q.name_tag, NameTag::DeriveFromId(x) if x == id));
+            assert_eq!("orders", q.variant.as_ref().unwrap().0);
+        }
+
+        // Suffixes must be unique
+        let value: toml::Value = toml::from_str(
+            r#"
+[[queries]]
+id = "orders"
+template = "orders.sql.j2"
+variants = [ { suffix = "a" }, { suffix = "a" } ]
+"#,
+        )
+        .unwrap();
+        let res = Queries::decode(
+            "templates/queries",
+            "output/queries",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            value.get("queries").unwrap(),
+        );
+        match res {
+            Err(Error::Parsing(msg)) => {
+                assert_eq!("Duplicate 'queries[].variants[].suffix': 'a'", msg)
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decode_queries_default_conds() {
+        let value: Value = toml::from_str(
+            r#"
+[[queries]]
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+
+[[queries]]
+id = "artists_long_songs@genre"
+template = "artists_long_songs.sql.j2"
+conds = [ "genre" ]
+
+[[queries]]
+id = "artists_long_songs@limit"
+template = "artists_long_songs.sql.j2"
+conds = [ "limit" ]
+inherit_conds = false
+
+[[queries]]
+id = "songs_by_genre"
+template = "songs_by_genre.sql.j2"
+"#,
+        )
+        .unwrap();
+        let qts = query_template::test_util::setup_query_templates(vec![
+            (
+                "templates/queries/artists_long_songs.sql.j2",
+                vec!["genre", "limit", "soft_delete"],
+            ),
+            ("templates/queries/songs_by_genre.sql.j2", vec!["genre"]),
+        ]);
+        let qs = Queries::decode(
+            "templates/queries",
+            "output/queries",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::new(&strset(vec!["soft_delete"]), &qts),
+            value.get("queries").unwrap(),
+        )
+        .unwrap();
+        // Default conds are inherited by queries with or without
+        // conds of their own
+        assert_eq!(
+            strset(vec!["soft_delete"]),
+            qs.get("artists_long_songs").unwrap().conds
+        );
+        assert_eq!(
+            strset(vec!["genre", "soft_delete"]),
+            qs.get("artists_long_songs@genre").unwrap().conds
+        );
+        // ..but not by the one that opts out
+        assert_eq!(
+            strset(vec!["limit"]),
+            qs.get("artists_long_songs@limit").unwrap().conds
+        );
+        // ..or the one whose template doesn't support them
+        assert!(qs.get("songs_by_genre").unwrap().conds.is_empty());
+
+        // When `inherit_conds` is not of correct type
+        let value: Value = toml::from_str(
+            r#"
+id = "artists_long_songs"
+template = "artists_long_songs.sql.j2"
+inherit_conds = "no"
+"#,
+        )
+        .unwrap();
+        match Query::decode(
+            "base",
+            "output",
+            &Layout::OneFileOneQuery,
+            false,
+            &DefaultConds::default(),
+            &value,
+        ) {
+            Err(Error::Parsing(msg)) => {
+                assert_eq!("Value of 'queries[].inherit_conds' must be a boolean", msg)
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_queries_active() {
+        let qts = query_template::test_util::setup_query_templates(vec![(
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        let mut qs = setup_queries(vec![
+            (
+                "artists_long_songs",
+                "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+                vec![],
+                "examples/chinook/output/queries/artists_long_songs.sql",
+                None,
+            ),
+            (
+                "artists_long_songs@genre",
+                "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+                vec!["genre", "undefined"],
+                "examples/chinook/output/queries/artists_long_songs-genre.sql",
+                None,
+            ),
+        ]);
+        // Mark the 2nd query as skipped. It needs to be removed from
+        // the index first so that the `Arc` can be mutably borrowed.
+        qs.index.remove("artists_long_songs@genre");
+        Arc::get_mut(&mut qs.inner[1]).unwrap().skip = true;
+        qs.index.insert(qs.inner[1].id.clone(), qs.inner[1].clone());
+
+        let ids = qs
+            .active(false)
+            .map(|q| q.id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["artists_long_songs"], ids);
+
+        let ids = qs
+            .active(true)
+            .map(|q| q.id.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["artists_long_songs", "artists_long_songs@genre"], ids);
+
+        // Skipped queries are still validated
+        let mistakes = qs.validate(&qts, &Layout::OneFileOneQuery);
+        assert_eq!(1, mistakes.len());
+        match &mistakes[0] {
+            ManifestMistake::InvalidConds { query_id, conds } => {
+                assert_eq!("artists_long_songs@genre", *query_id);
+                assert_eq!(&vec!["undefined"], conds);
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_queries_tagged() {
+        let template = "examples/chinook/templates/queries/artists_long_songs.sql.j2";
+        let mut qs = setup_queries(vec![
+            ("artists_long_songs", template, vec![], "a.sql", None),
+            (
+                "artists_long_songs*limit",
+                template,
+                vec!["limit"],
+                "b.sql",
+                None,
+            ),
+            ("songs_formats@artist", template, vec![], "c.sql", None),
+        ]);
+        qs.index.clear();
+        let tags = [vec!["reporting", "nightly"], vec!["reporting"], vec![]];
+        for (q, ts) in qs.inner.iter_mut().zip(tags) {
+            Arc::get_mut(q).unwrap().tags = strset(ts);
+        }
+        for q in &qs.inner {
+            qs.index.insert(q.id.clone(), q.clone());
+        }
+        let ids = |tags: &[String]| {
+            qs.tagged(false, tags)
+                .map(|q| q.id.clone())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(3, ids(&[]).len());
+        assert_eq!(vec!["artists_long_songs"], ids(&["nightly".to_owned()]));
+        assert_eq!(
+            vec!["artists_long_songs", "artists_long_songs*limit"],
+            ids(&["reporting".to_owned(), "nightly".to_owned()])
+        );
+
+        // Unknown tags match nothing
+        let unknown = vec!["weekly".to_owned()];
+        assert!(ids(&unknown).is_empty());
+        assert_eq!(vec!["weekly"], qs.unknown_tags(&unknown));
+        assert!(qs.unknown_tags(&["nightly".to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn test_queries_matching() {
+        let template = "examples/chinook/templates/queries/artists_long_songs.sql.j2";
+        let qs = setup_queries(vec![
+            ("artists_long_songs", template, vec![], "a.sql", None),
+            (
+                "artists_long_songs*limit",
+                template,
+                vec!["limit"],
+                "b.sql",
+                None,
+            ),
+            (
+                "artists_long_songs@genre*limit",
+                template,
+                vec!["genre", "limit"],
+                "c.sql",
+                None,
+            ),
+            (
+                "songs_formats@artist&album",
+                template,
+                vec![],
+                "d.sql",
+                None,
+            ),
+        ]);
+        let ids = |pattern| {
+            qs.matching(pattern)
+                .map(|q| q.id.as_str())
+                .collect::<Vec<&str>>()
+        };
+        assert_eq!(
+            vec![
+                "artists_long_songs",
+                "artists_long_songs*limit",
+                "artists_long_songs@genre*limit",
+                "songs_formats@artist&album",
+            ],
+            ids(None)
+        );
+        assert_eq!(
+            vec!["artists_long_songs*limit", "artists_long_songs@genre*limit"],
+            ids(Some("artists_*limit"))
+        );
+        assert!(ids(Some("unknown*")).is_empty());
     }
 
     #[test]
@@ -461,7 +1446,7 @@ output = 'my_query_explicit.sql'
         // When output_layout = OneFileOneQuery
         match fallback_output("foo", "base", &Layout::OneFileOneQuery) {
             Ok(p) => assert_eq!(PathBuf::from("base/foo.sql"), p),
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When output layout = OneFileAllQueries and output_file is
@@ -469,7 +1454,7 @@ output = 'my_query_explicit.sql'
         let layout = Layout::OneFileAllQueries(Some(PathBuf::from("base/queries.sql")));
         match fallback_output("foo", "base", &layout) {
             Ok(p) => assert_eq!(PathBuf::from("base/queries.sql"), p),
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When output layout = OneFileAllQueries and output_file is
@@ -479,10 +1464,35 @@ output = 'my_query_explicit.sql'
             Err(Error::Parsing(msg)) => {
                 assert_eq!("Either 'queries[].output' or 'query_output_file' must be specified in case of 'one-file-all-queries' layout", msg);
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
     }
 
+    #[test]
+    fn test_queries_validate_ids() {
+        let template = "examples/chinook/templates/queries/artists_long_songs.sql.j2";
+        let qts = query_template::test_util::setup_query_templates(vec![(template, vec![])]);
+        let qs = setup_queries(vec![
+            ("artists_long_songs", template, vec![], "a.sql", None),
+            ("", template, vec![], "b.sql", None),
+            (" \n", template, vec![], "c.sql", None),
+            ("reports/artists", template, vec![], "d.sql", None),
+        ]);
+        let mistakes = qs
+            .validate(&qts, &Layout::OneFileOneQuery)
+            .iter()
+            .map(|m| m.err_msg())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            vec![
+                "Invalid query id at 'queries[1]': \"\" (must not be empty or whitespace)",
+                "Invalid query id at 'queries[2]': \" \\n\" (must not be empty or whitespace)",
+                "Invalid query id at 'queries[3]': \"reports/artists\" (must not contain path separators)",
+            ],
+            mistakes
+        );
+    }
+
     #[test]
     fn test_queries_validate() {
         let qts = query_template::test_util::setup_query_templates(vec![
@@ -543,7 +1553,7 @@ output = 'my_query_explicit.sql'
                     template
                 );
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // When 'queries[].id' are not unique
@@ -570,7 +1580,7 @@ output = 'my_query_explicit.sql'
                 assert_eq!("queries[].id", key);
                 assert_eq!("artists_long_songs", value);
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // When layout = OneFileOneQuery and 'queries[].output' are
@@ -601,7 +1611,7 @@ output = 'my_query_explicit.sql'
                     value
                 );
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
 
         // When layout = OneFileAllQueries(None) and 'queries[].output' are
@@ -610,10 +1620,8 @@ output = 'my_query_explicit.sql'
         let mistakes = qs_good.validate(&qts, &layout);
         assert_eq!(1, mistakes.len());
         match mistakes[0] {
-            ManifestMistake::DisparateQueryOutputs => {
-                assert!(true);
-            }
-            _ => assert!(false),
+            ManifestMistake::DisparateQueryOutputs => {}
+            _ => panic!(),
         }
 
         // When layout = OneFileAllQueries(Some(qof)) and 'queries[].output' are
@@ -650,7 +1658,7 @@ output = 'my_query_explicit.sql'
                     output_path
                 );
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
     }
 }
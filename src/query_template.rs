@@ -1,9 +1,12 @@
 use crate::error::{parse_error, Error};
 use crate::toml::{decode_pathbuf, decode_strset};
 use crate::validation::{validate_path, ManifestMistake};
+use minijinja::Environment;
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
 use toml::Value;
 
 #[derive(Debug)]
@@ -41,9 +44,13 @@ impl QueryTemplate {
         self.path.to_str().unwrap()
     }
 
-    /// Returns file name of the template which can be used with
+    /// Returns name of the template which can be used with
     /// `minijinja::Environment` that's initialized using
-    /// `minijinja::path_loader`
+    /// `minijinja::path_loader` for `base_dir`
+    ///
+    /// It's the path of the template relative to `base_dir`, so that
+    /// templates inside nested dirs can be loaded. If the path is not
+    /// under `base_dir`, the file name is returned.
     ///
     /// # Panics
     ///
@@ -52,31 +59,74 @@ impl QueryTemplate {
     ///
     /// 2. If the path ends in `..`
     ///
-    pub fn file_name(&self) -> &str {
-        self.path
-            .file_name()
-            .map(|ostr| ostr.to_str().unwrap())
-            .unwrap()
+    pub fn loader_name(&self, base_dir: &Path) -> &str {
+        match self.path.strip_prefix(base_dir) {
+            Ok(p) => p.to_str().unwrap(),
+            Err(_) => self
+                .path
+                .file_name()
+                .map(|ostr| ostr.to_str().unwrap())
+                .unwrap(),
+        }
+    }
+
+    fn validate(&self) -> Option<ManifestMistake<'_>> {
+        validate_path(&self.path, "query_templates[].path").err()
+    }
+
+    /// Returns the conds that are referenced in the template (as
+    /// `cond__<name>` vars) but not declared in `all_conds`, in sorted
+    /// order
+    ///
+    /// Such conds are always false when rendering, which is likely
+    /// due to a typo. Only the template itself is scanned i.e. not
+    /// the ones included in it.
+    pub fn undeclared_conds(&self) -> Result<Vec<String>, Error> {
+        let source = fs::read_to_string(&self.path).map_err(Error::Io)?;
+        let env = Environment::new();
+        let tmpl = env.template_from_str(&source).map_err(Error::MiniJinja)?;
+        let mut conds = tmpl
+            .undeclared_variables(false)
+            .into_iter()
+            .filter_map(|var| var.strip_prefix("cond__").map(|c| c.to_owned()))
+            .filter(|c| !self.all_conds.contains(c))
+            .collect::<Vec<String>>();
+        conds.sort();
+        Ok(conds)
     }
 
-    fn validate(&self) -> Option<ManifestMistake> {
-        match validate_path(&self.path, "query_templates[].path") {
-            Ok(()) => None,
-            Err(m) => Some(m),
+    /// Returns the names of the params of the template i.e. the args
+    /// of the `placeholder` calls in it, or `None` if any of the
+    /// calls has a non-literal arg as the params can't be known
+    /// without rendering it
+    ///
+    /// Same as in case of `undeclared_conds`, included templates are
+    /// not scanned.
+    pub fn param_names(&self) -> Result<Option<HashSet<String>>, Error> {
+        let source = fs::read_to_string(&self.path).map_err(Error::Io)?;
+        let call_re = Regex::new(r"placeholder\(").unwrap();
+        let re = Regex::new(r#"placeholder\(\s*['"]([A-Za-z_]\w*)['"]\s*\)"#).unwrap();
+        if re.find_iter(&source).count() < call_re.find_iter(&source).count() {
+            return Ok(None);
         }
+        Ok(Some(
+            re.captures_iter(&source)
+                .map(|cap| cap[1].to_owned())
+                .collect(),
+        ))
     }
 }
 
 #[derive(Debug)]
 pub struct QueryTemplates {
-    inner: Vec<Rc<QueryTemplate>>,
-    index: HashMap<String, Rc<QueryTemplate>>,
+    inner: Vec<Arc<QueryTemplate>>,
+    index: HashMap<String, Arc<QueryTemplate>>,
 }
 
 impl QueryTemplates {
     pub fn new() -> Self {
-        let inner: Vec<Rc<QueryTemplate>> = vec![];
-        let index: HashMap<String, Rc<QueryTemplate>> = HashMap::new();
+        let inner: Vec<Arc<QueryTemplate>> = vec![];
+        let index: HashMap<String, Arc<QueryTemplate>> = HashMap::new();
         Self { inner, index }
     }
 
@@ -86,12 +136,12 @@ impl QueryTemplates {
         // would be populating the index at the time of lookup (like a
         // read-through cache) but in that case we'd need to manage
         // multiple mutable references.
-        let mut index: HashMap<String, Rc<QueryTemplate>> = HashMap::new();
+        let mut index: HashMap<String, Arc<QueryTemplate>> = HashMap::new();
         let items = match value.as_array() {
             Some(xs) => {
                 let mut res = Vec::with_capacity(xs.len());
                 for x in xs {
-                    let qt = Rc::new(QueryTemplate::decode(&base_dir, x)?);
+                    let qt = Arc::new(QueryTemplate::decode(&base_dir, x)?);
                     let idx_key = qt.id().to_owned();
                     let idx_val = qt.clone();
                     res.push(qt);
@@ -108,7 +158,13 @@ impl QueryTemplates {
         })
     }
 
-    pub fn validate(&self) -> Vec<ManifestMistake> {
+    pub fn push(&mut self, query_template: QueryTemplate) {
+        let qt = Arc::new(query_template);
+        self.index.insert(qt.id().to_owned(), qt.clone());
+        self.inner.push(qt);
+    }
+
+    pub fn validate(&self) -> Vec<ManifestMistake<'_>> {
         let mut mistakes = vec![];
         let count = self.inner.len();
         let mut all_paths: HashMap<&Path, usize> = HashMap::with_capacity(count);
@@ -133,12 +189,12 @@ impl QueryTemplates {
         mistakes
     }
 
-    pub fn get(&self, path: &Path) -> Option<&Rc<QueryTemplate>> {
+    pub fn get(&self, path: &Path) -> Option<&Arc<QueryTemplate>> {
         let key = path.to_str().unwrap().to_owned();
         self.index.get(&key)
     }
 
-    pub fn iter(&self) -> std::slice::Iter<'_, Rc<QueryTemplate>> {
+    pub fn iter(&self) -> std::slice::Iter<'_, Arc<QueryTemplate>> {
         self.inner.iter()
     }
 }
@@ -162,7 +218,7 @@ pub mod test_util {
     pub fn setup_query_templates(data: Vec<(&str, Vec<&str>)>) -> QueryTemplates {
         let mut qts = QueryTemplates::new();
         for (p, ac) in data {
-            let qt = Rc::new(QueryTemplate {
+            let qt = Arc::new(QueryTemplate {
                 path: PathBuf::from(p),
                 all_conds: strset(ac),
             });
@@ -180,7 +236,6 @@ mod tests {
 
     use super::test_util::*;
     use super::*;
-    use toml;
 
     #[test]
     fn test_decode_query_template() {
@@ -197,7 +252,7 @@ all_conds = [ 'foo', 'bar' ]
                 assert_eq!(PathBuf::from("base/my_query_template.sql.j2"), qt.path);
                 assert_eq!(strset(vec!["foo", "bar"]), qt.all_conds);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When `path` is missing
@@ -208,11 +263,11 @@ all_conds = [ 'foo', 'bar' ]
         .unwrap();
         let value = toml::Value::Table(table);
         match QueryTemplate::decode("base", &value) {
-            Ok(_) => assert!(false),
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!("Query template path missing", msg);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When `all_conds` is missing
@@ -227,17 +282,17 @@ path = 'my_query_template.sql.j2'
                 assert_eq!(PathBuf::from("base/my_query_template.sql.j2"), qt.path);
                 assert_eq!(HashSet::new(), qt.all_conds)
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When type of TOML value is not a table
         let value = toml::Value::String(String::from("hello"));
         match QueryTemplate::decode("base", &value) {
-            Ok(_) => assert!(false),
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!("Invalid 'query_template' entry", msg);
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
 
         // When `all_conds` is not of correct type
@@ -249,14 +304,14 @@ all_conds = [ 1, 2 ]
         .unwrap();
         let value = toml::Value::Table(table);
         match QueryTemplate::decode("base", &value) {
-            Ok(_) => assert!(false),
+            Ok(_) => panic!(),
             Err(Error::Parsing(msg)) => {
                 assert_eq!(
                     "Value of 'query_templates[].all_conds' is expected to be array of strings",
                     msg
                 );
             }
-            Err(_) => assert!(false),
+            Err(_) => panic!(),
         }
     }
 
@@ -287,7 +342,38 @@ all_conds = [ 1, 2 ]
                     value
                 );
             }
-            _ => assert!(false),
+            _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_undeclared_conds() {
+        let qts = setup_query_templates(vec![
+            (
+                "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+                vec!["genre", "limit"],
+            ),
+            (
+                "examples/chinook/templates/queries/songs_formats.sql.j2",
+                vec!["artist", "file_formt"],
+            ),
+        ]);
+        let conds = qts
+            .iter()
+            .map(|qt| qt.undeclared_conds().unwrap())
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(
+            vec![
+                vec![],
+                vec!["album_name".to_owned(), "file_format".to_owned()]
+            ],
+            conds
+        );
+
+        let qt = QueryTemplate {
+            path: PathBuf::from("examples/chinook/templates/queries/missing.sql.j2"),
+            all_conds: HashSet::new(),
+        };
+        assert!(qt.undeclared_conds().is_err());
+    }
 }
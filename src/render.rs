@@ -1,16 +1,121 @@
 use crate::error::Error;
+use crate::header;
 use crate::metadata::Metadata;
-use crate::placeholder::Placeholder;
-use minijinja::{context, path_loader, Environment};
+use crate::output::{self, SqlToWrite};
+use crate::placeholder::{Placeholder, PlaceholderStyle};
+use crate::query::Query;
+use minijinja::value::{Kwargs, Value, ValueKind};
+use minijinja::{context, path_loader, Environment, ErrorKind};
+use rayon::prelude::*;
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use sqlparser::dialect::Dialect;
+use sqlparser::parser::Parser;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::From;
-use std::path::Path;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 pub fn placeholder(name: String) -> Result<String, minijinja::Error> {
     Ok(format!("{{{{ {name} }}}}"))
 }
 
+/// Prefixes of the comments marking the start and end of a named
+/// block (see `sql_block`)
+const BLOCK_START: &str = "-- >>> ";
+const BLOCK_END: &str = "-- <<< ";
+
+/// Filter for tagging a region of the generated SQL as a named block,
+/// so that it can be extracted by other tools
+///
+/// Example: `{% filter sql_block("cte_active") %}...{% endfilter %}`
+///
+/// The region is surrounded by `-- >>> cte_active` and `-- <<<
+/// cte_active` comments.
+pub fn sql_block(value: String, name: String) -> Result<String, minijinja::Error> {
+    if name.trim().is_empty() || name.contains('\n') {
+        return Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            "name of the sql_block must be a non-empty single line string",
+        ));
+    }
+    let body = value.trim_matches('\n');
+    Ok(format!("{BLOCK_START}{name}\n{body}\n{BLOCK_END}{name}"))
+}
+
+// Checks whether the `line` is a comment marking the start or end of
+// a named block
+fn is_block_marker(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with(BLOCK_START) || line.starts_with(BLOCK_END)
+}
+
+/// Checks whether `sql` has any of the comments that mark the start
+/// and end of named blocks
+pub fn has_block_markers(sql: &str) -> bool {
+    sql.lines().any(is_block_marker)
+}
+
+/// Returns `sql` without the comments that mark the start and end of
+/// named blocks, so that outputs differing only in the markers can be
+/// considered equal
+pub fn strip_block_markers(sql: &str) -> String {
+    let result = sql
+        .split_inclusive('\n')
+        .filter(|line| !is_block_marker(line))
+        .collect::<String>();
+    result.trim_end().to_owned()
+}
+
+/// Returns `value` as a SQL literal
+fn sql_literal(value: &Value) -> Result<String, minijinja::Error> {
+    match value.kind() {
+        ValueKind::Undefined | ValueKind::None => Ok("NULL".to_owned()),
+        ValueKind::Bool | ValueKind::Number => Ok(value.to_string()),
+        // @UNWRAP: Value of kind `String` is always a str
+        ValueKind::String => Ok(format!("'{}'", value.as_str().unwrap().replace('\'', "''"))),
+        _ => Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            "expected value must be a string, number, boolean or none",
+        )),
+    }
+}
+
+/// Function available in test templates that expands into a
+/// postgres `DO` block which raises an exception if the SQL
+/// expression `actual` doesn't evaluate to `expected`
+///
+/// Example: `{{ assert_eq(actual="(SELECT count(*) FROM artist)", expected=3) }}`
+///
+/// An optional `msg` is included in the exception message, with any
+/// `%` escaped so that it's not taken for a `RAISE` parameter.
+pub fn assert_eq(kwargs: Kwargs) -> Result<String, minijinja::Error> {
+    let actual: String = kwargs.get("actual")?;
+    let expected = sql_literal(&kwargs.get::<Value>("expected")?)?;
+    let msg: Option<String> = kwargs.get("msg")?;
+    kwargs.assert_all_used()?;
+    let msg = msg.map_or_else(
+        || "assert_eq failed".to_owned(),
+        |m| {
+            format!(
+                "assert_eq failed: {}",
+                m.replace('\'', "''").replace('%', "%%")
+            )
+        },
+    );
+    Ok(format!(
+        r#"DO $tapestry$
+BEGIN
+    IF ({actual}) IS DISTINCT FROM {expected} THEN
+        RAISE EXCEPTION '{msg}: expected %, got %', {expected}, ({actual});
+    END IF;
+END
+$tapestry$;"#
+    ))
+}
+
 fn cond_vars(all_conds: &HashSet<String>, conds: &HashSet<String>) -> HashMap<String, bool> {
     let mut vars = HashMap::new();
     for c in all_conds {
@@ -19,6 +124,61 @@ fn cond_vars(all_conds: &HashSet<String>, conds: &HashSet<String>) -> HashMap<St
     vars
 }
 
+/// Conds to be turned on or off for all queries at render time, in
+/// addition to the ones configured in the manifest
+#[derive(Debug, Default)]
+pub struct CondOverrides {
+    pub on: HashSet<String>,
+    pub off: HashSet<String>,
+}
+
+/// Where the effective value of a cond for a query comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CondSource {
+    NoCond,
+    Cond,
+    Query,
+    Default,
+}
+
+impl Display for CondSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoCond => write!(f, "--no-cond"),
+            Self::Cond => write!(f, "--cond/--profile"),
+            Self::Query => write!(f, "queries[].conds"),
+            Self::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl CondOverrides {
+    /// Returns the effective value of `cond` for a query having
+    /// `conds` along with where it comes from. If a cond is both
+    /// turned on and off, off wins.
+    pub fn resolve(&self, conds: &HashSet<String>, cond: &str) -> (bool, CondSource) {
+        if self.off.contains(cond) {
+            (false, CondSource::NoCond)
+        } else if conds.contains(cond) {
+            (true, CondSource::Query)
+        } else if self.on.contains(cond) {
+            (true, CondSource::Cond)
+        } else {
+            (false, CondSource::Default)
+        }
+    }
+
+    /// Returns the effective conds i.e. `conds` with the overrides
+    /// applied
+    fn apply(&self, conds: &HashSet<String>) -> HashSet<String> {
+        conds
+            .union(&self.on)
+            .filter(|c| self.resolve(conds, c).0)
+            .cloned()
+            .collect()
+    }
+}
+
 fn capture_udvars<'a>(line: &'a str, re: &Regex, valid_udvars: &HashSet<String>) -> Vec<&'a str> {
     let mut result = vec![];
     for cap in re.captures_iter(line) {
@@ -32,69 +192,453 @@ fn capture_udvars<'a>(line: &'a str, re: &Regex, valid_udvars: &HashSet<String>)
     result
 }
 
-pub fn pos_args_mapping(template: &str, udvars: &HashSet<String>) -> HashMap<String, String> {
-    let mut result: HashMap<String, u8> = HashMap::with_capacity(udvars.len());
+/// Returns the mapping of the params in the intermediate output
+/// `template` to the markers rendered by the `style`
+///
+/// The params are indexed in the order of their first occurrence in
+/// the `template`, same as the positional args.
+pub fn placeholder_mapping(
+    template: &str,
+    udvars: &HashSet<String>,
+    style: &dyn PlaceholderStyle,
+) -> HashMap<String, String> {
+    let mut order: Vec<&str> = Vec::with_capacity(udvars.len());
     let re = Regex::new(r"\{\{\s?(\w+)\s?\}\}").unwrap();
-    let mut counter = 1;
     for line in template.lines() {
         if line.is_empty() {
             continue;
         }
         for var in capture_udvars(line, &re, udvars) {
-            if !result.contains_key(var) {
-                result.insert(var.to_owned(), counter);
-                counter += 1;
+            if !order.contains(&var) {
+                order.push(var);
             }
         }
     }
-    result
+    order
         .into_iter()
-        .map(|(k, v)| (k, format!("${v}")))
+        .enumerate()
+        .map(|(i, v)| (v.to_owned(), style.render_marker(i + 1, Some(v))))
         .collect::<HashMap<String, String>>()
 }
 
-pub fn variables_mapping(udvars: &HashSet<String>) -> HashMap<String, String> {
-    udvars
-        .iter()
-        .map(|v| (v.to_owned(), format!(":{v}")))
-        .collect::<HashMap<String, String>>()
+/// Returns the no. of distinct bind parameters in rendered `sql` as
+/// per the `placeholder` mode i.e. `$N` markers for `posargs` and
+/// `:name` markers for `variables`
+///
+/// Occurrences of the same parameter are counted only once. Casts
+/// such as `::text` are not mistaken for named parameters.
+pub fn count_placeholders(sql: &str, placeholder: &Placeholder) -> usize {
+    param_regex(placeholder)
+        .captures_iter(sql)
+        .filter_map(|cap| cap.get(1).map(|m| m.as_str()))
+        .collect::<HashSet<&str>>()
+        .len()
 }
 
+// Regex for the bind parameters as per the `placeholder` mode, with
+// the number or name of the param captured in the first group
+fn param_regex(placeholder: &Placeholder) -> Regex {
+    match placeholder {
+        Placeholder::PosArgs => Regex::new(r"\$(\d+)").unwrap(),
+        Placeholder::Variables => Regex::new(r"(?:^|[^:\w]):([A-Za-z_]\w*)").unwrap(),
+    }
+}
+
+/// Bind parameters of the query for which a test is rendered, made
+/// available to test templates as `query_params`
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct QueryParams {
+    /// No. of distinct params
+    pub count: usize,
+    /// Positions of the `$N` params in ascending order (empty for
+    /// `variables`)
+    pub positions: Vec<usize>,
+    /// Distinct params as they appear in the SQL i.e. `$N` in the
+    /// order of positions for `posargs` and `:name` in the order of
+    /// first occurrence for `variables`
+    pub markers: Vec<String>,
+}
+
+impl QueryParams {
+    pub fn from_sql(sql: &str, placeholder: &Placeholder) -> Self {
+        let re = param_regex(placeholder);
+        let names = re
+            .captures_iter(sql)
+            .filter_map(|cap| cap.get(1).map(|m| m.as_str()));
+        match placeholder {
+            Placeholder::PosArgs => {
+                let positions = names
+                    .filter_map(|n| n.parse::<usize>().ok())
+                    .collect::<BTreeSet<usize>>()
+                    .into_iter()
+                    .collect::<Vec<usize>>();
+                let markers = positions.iter().map(|p| format!("${p}")).collect();
+                Self {
+                    count: positions.len(),
+                    positions,
+                    markers,
+                }
+            }
+            Placeholder::Variables => {
+                let mut markers: Vec<String> = vec![];
+                for name in names {
+                    let marker = format!(":{name}");
+                    if !markers.contains(&marker) {
+                        markers.push(marker);
+                    }
+                }
+                Self {
+                    count: markers.len(),
+                    positions: vec![],
+                    markers,
+                }
+            }
+        }
+    }
+}
+
+// Trailing whitespace is ignored e.g. the final newline added by
+// `normalize`
 fn strip_trailing_semicolon(s: &str) -> &str {
+    let s = s.trim_end();
     s.strip_suffix(';').unwrap_or(s)
 }
 
+/// Checks whether rendered `sql` is effectively empty i.e. it
+/// contains nothing but whitespace, comments and semicolons
+pub fn is_empty_sql(sql: &str) -> bool {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ';');
+        if let Some(r) = rest.strip_prefix("--") {
+            rest = r.split_once('\n').map_or("", |(_, r)| r);
+        } else if let Some(r) = rest.strip_prefix("/*") {
+            rest = r.split_once("*/").map_or("", |(_, r)| r);
+        } else {
+            return rest.is_empty();
+        }
+    }
+}
+
+/// Returns the context for rendering the template of `query` i.e. the
+/// cond vars in `cond_ctx` as well as the args specific to the query
+/// (including the vars of its variant, if any)
+///
+/// The keys are sorted so that it's deterministic when printed.
+fn template_context(
+    query: &Query,
+    cond_ctx: HashMap<String, bool>,
+) -> BTreeMap<String, minijinja::Value> {
+    let mut ctx = cond_ctx
+        .into_iter()
+        .map(|(k, v)| (k, minijinja::Value::from(v)))
+        .collect::<BTreeMap<String, minijinja::Value>>();
+    for (k, v) in query.args.iter() {
+        ctx.insert(k.to_owned(), minijinja::Value::from_serializable(v));
+    }
+    ctx
+}
+
+/// Checks that rendered `sql` is syntactically valid by parsing it as
+/// per the `dialect` (see `Query::sql_dialect`)
+///
+/// Returns the error message of the parser, which includes the
+/// location of the error, if it's not valid.
+pub fn check_sql_syntax(sql: &str, dialect: &dyn Dialect) -> Result<(), String> {
+    Parser::parse_sql(dialect, sql)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// A variable referenced in a template
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ContextVar {
+    pub name: String,
+    /// Whether it's referenced in the condition of an `if` (or
+    /// `elif`) tag
+    pub conditional: bool,
+}
+
+/// Inputs of a template inferred by statically scanning it, printed
+/// by `validate --dump-context-schema`
+#[derive(Debug, Serialize)]
+pub struct ContextSchema {
+    pub template: String,
+    pub variables: Vec<ContextVar>,
+    pub filters: Vec<String>,
+}
+
+/// Returns the context schema of `tmpl` i.e. the variables that it
+/// expects in the context (excluding the `functions` registered with
+/// the env) and the filters used in it
+///
+/// Conditionals and filters are detected by scanning the tags and
+/// expressions in the source, so they are best effort.
+pub fn context_schema(tmpl: &minijinja::Template, functions: &[&str]) -> ContextSchema {
+    let source = tmpl.source();
+    let mut cond_names = HashSet::new();
+    let ident_re = Regex::new(r"[A-Za-z_]\w*").unwrap();
+    let if_re = Regex::new(r"(?s)\{%-?\s*(?:if|elif)\s(.*?)-?%\}").unwrap();
+    for cap in if_re.captures_iter(source) {
+        // @UNWRAP: The regex has a single group which always matches
+        let cond = cap.get(1).unwrap().as_str();
+        cond_names.extend(ident_re.find_iter(cond).map(|m| m.as_str()));
+    }
+    let mut variables = tmpl
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| !functions.contains(&name.as_str()))
+        .map(|name| ContextVar {
+            conditional: cond_names.contains(name.as_str()),
+            name,
+        })
+        .collect::<Vec<ContextVar>>();
+    variables.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let block_re = Regex::new(r"(?s)\{\{.*?\}\}|\{%.*?%\}").unwrap();
+    let filter_re = Regex::new(r"(?:\||\{%-?\s*filter\s)\s*([A-Za-z_]\w*)").unwrap();
+    let filters = block_re
+        .find_iter(source)
+        .flat_map(|block| {
+            filter_re
+                .captures_iter(block.as_str())
+                .map(|cap| cap[1].to_owned())
+                .collect::<Vec<String>>()
+        })
+        .collect::<BTreeSet<String>>();
+    ContextSchema {
+        template: tmpl.name().to_owned(),
+        variables,
+        filters: filters.into_iter().collect(),
+    }
+}
+
+/// Returns an environment with only those builtin filters, functions
+/// and tests of minijinja that are safe for rendering templates from
+/// untrusted sources
+///
+/// The allowlisted ones are pure i.e. they only transform the values
+/// passed to them. Notably, the `debug` function which dumps the
+/// state of the environment is not included.
+fn sandboxed_env() -> Environment<'static> {
+    use minijinja::{filters as f, functions as fns, tests as t};
+    let mut env = Environment::empty();
+    env.set_auto_escape_callback(minijinja::default_auto_escape_callback);
+    env.add_filter("abs", f::abs);
+    env.add_filter("capitalize", f::capitalize);
+    env.add_filter("default", f::default);
+    env.add_filter("d", f::default);
+    env.add_filter("first", f::first);
+    env.add_filter("float", f::float);
+    env.add_filter("int", f::int);
+    env.add_filter("join", f::join);
+    env.add_filter("last", f::last);
+    env.add_filter("length", f::length);
+    env.add_filter("list", f::list);
+    env.add_filter("lower", f::lower);
+    env.add_filter("replace", f::replace);
+    env.add_filter("reverse", f::reverse);
+    env.add_filter("round", f::round);
+    env.add_filter("sort", f::sort);
+    env.add_filter("title", f::title);
+    env.add_filter("trim", f::trim);
+    env.add_filter("unique", f::unique);
+    env.add_filter("upper", f::upper);
+    env.add_function("range", fns::range);
+    env.add_test("defined", t::is_defined);
+    env.add_test("undefined", t::is_undefined);
+    env.add_test("none", t::is_none);
+    env.add_test("number", t::is_number);
+    env.add_test("string", t::is_string);
+    env.add_test("in", t::is_in);
+    env
+}
+
+fn base_env(sandbox: bool) -> Environment<'static> {
+    if sandbox {
+        sandboxed_env()
+    } else {
+        Environment::new()
+    }
+}
+
 pub struct Engine<'a> {
     metadata: &'a Metadata,
     query_templates_env: Environment<'static>,
     test_templates_env: Environment<'static>,
+    cond_overrides: CondOverrides,
+    sandbox: bool,
+    /// Custom style of the placeholders that takes precedence over
+    /// the one in the manifest
+    placeholder_style: Option<Box<dyn PlaceholderStyle + Send + Sync>>,
 }
 
-impl<'a> From<&'a Metadata> for Engine<'a> {
-    fn from(metadata: &'a Metadata) -> Self {
+impl<'a> Engine<'a> {
+    fn new(metadata: &'a Metadata, sandbox: bool) -> Self {
         // Env for query_templates
-        let mut qt_env = Environment::new();
+        let mut qt_env = base_env(sandbox);
         qt_env.set_loader(path_loader(&metadata.query_templates_dir));
         qt_env.add_function("placeholder", placeholder);
+        qt_env.add_filter("sql_block", sql_block);
 
         // Env for test_templates
-        let mut tt_env = Environment::new();
+        let mut tt_env = base_env(sandbox);
         tt_env.set_loader(path_loader(&metadata.test_templates_dir));
+        tt_env.add_function("assert_eq", assert_eq);
+        tt_env.add_filter("sql_block", sql_block);
 
         Self {
             metadata,
             query_templates_env: qt_env,
             test_templates_env: tt_env,
+            cond_overrides: CondOverrides::default(),
+            sandbox,
+            placeholder_style: None,
         }
     }
 }
 
+impl<'a> From<&'a Metadata> for Engine<'a> {
+    fn from(metadata: &'a Metadata) -> Self {
+        Self::new(metadata, false)
+    }
+}
+
 impl<'a> Engine<'a> {
+    pub fn with_cond_overrides(mut self, cond_overrides: CondOverrides) -> Self {
+        self.cond_overrides = cond_overrides;
+        self
+    }
+
+    /// Returns the engine in sandbox mode i.e. with only the
+    /// allowlisted filters and functions available to the templates
+    /// (see `sandboxed_env`), for rendering untrusted manifests
+    pub fn sandboxed(self) -> Self {
+        let cond_overrides = self.cond_overrides;
+        let placeholder_style = self.placeholder_style;
+        Self {
+            cond_overrides,
+            placeholder_style,
+            ..Self::new(self.metadata, true)
+        }
+    }
+
+    /// Returns the engine with a custom `style` for rendering the
+    /// placeholders in queries, instead of the `placeholder` in the
+    /// manifest
+    ///
+    /// An explicit placeholder override (e.g. for test templates
+    /// with their own `placeholder`) still takes precedence.
+    // Not used by the commands, which only support the built-in
+    // styles
+    #[allow(dead_code)]
+    pub fn with_placeholder_style<S>(mut self, style: S) -> Self
+    where
+        S: PlaceholderStyle + Send + Sync + 'static,
+    {
+        self.placeholder_style = Some(Box::new(style));
+        self
+    }
+
+    /// Maps an error in rendering a template to `Error`, explaining
+    /// the failure in case of a filter or function that's not
+    /// allowed in sandbox mode
+    fn template_error(&self, e: minijinja::Error) -> Error {
+        match e.kind() {
+            ErrorKind::UnknownFilter | ErrorKind::UnknownFunction | ErrorKind::UnknownTest
+                if self.sandbox =>
+            {
+                Error::Sandbox(e.to_string())
+            }
+            _ => Error::MiniJinja(e),
+        }
+    }
+
+    /// Returns the context that the query template for `query_id` is
+    /// rendered with
+    pub fn query_context(&self, query_id: &str) -> Result<HashMap<String, bool>, Error> {
+        let query = self
+            .metadata
+            .queries
+            .get(query_id)
+            .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
+        let query_template = self.metadata.query_templates.get(&query.template).ok_or(
+            Error::UndefinedQueryTemplate(query.template_file_name().to_owned()),
+        )?;
+        let conds = self.cond_overrides.apply(&query.conds);
+        Ok(cond_vars(&query_template.all_conds, &conds))
+    }
+
+    /// Returns whether the query is to be rendered i.e. whether its
+    /// `enabled_if` expression (if any) holds for its conds, after
+    /// applying the cond overrides
+    pub fn is_enabled(&self, query: &Query) -> bool {
+        query.enabled_if.as_ref().map_or(true, |expr| {
+            expr.eval(&self.cond_overrides.apply(&query.conds))
+        })
+    }
+
+    /// Returns the context schemas of all the query templates
+    /// followed by all the test templates
+    pub fn context_schemas(&self) -> Result<Vec<ContextSchema>, Error> {
+        let metadata = self.metadata;
+        let mut result = Vec::new();
+        for qt in metadata.query_templates.iter() {
+            let tmpl = self
+                .query_templates_env
+                .get_template(qt.loader_name(&metadata.query_templates_dir))
+                .map_err(Error::MiniJinja)?;
+            result.push(context_schema(&tmpl, &["placeholder"]));
+        }
+        for tt in metadata.test_templates.iter() {
+            let tmpl = self
+                .test_templates_env
+                .get_template(tt.loader_name(&metadata.test_templates_dir))
+                .map_err(Error::MiniJinja)?;
+            result.push(context_schema(&tmpl, &["assert_eq"]));
+        }
+        Ok(result)
+    }
+
+    /// Returns the context for `query_id` as pretty printed json
+    ///
+    /// It's the same context that the query template is rendered
+    /// with i.e. including the args of the query (see
+    /// `template_context`).
+    pub fn explain_query(&self, query_id: &str) -> Result<String, Error> {
+        let query = self
+            .metadata
+            .queries
+            .get(query_id)
+            .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
+        let ctx = template_context(query, self.query_context(query_id)?);
+        // @UNWRAP: The context only has bools and the values of the
+        // args decoded from the manifest, which can be serialized
+        Ok(serde_json::to_string_pretty(&ctx).unwrap())
+    }
+
     pub fn render_query(
         &self,
         query_id: &str,
         placeholder_override: Option<&Placeholder>,
     ) -> Result<String, Error> {
+        let query = self
+            .metadata
+            .queries
+            .get(query_id)
+            .ok_or(Error::UndefinedQuery(query_id.to_owned()))?;
+        let cond_ctx = self.query_context(query_id)?;
+        self.render_query_with(query, cond_ctx, placeholder_override)
+    }
+
+    /// Renders the query once with all the conds of its template
+    /// turned off and then once for each cond turned on individually
+    /// (in sorted order), for previewing how each cond changes the
+    /// SQL
+    ///
+    /// Returns pairs of the cond that's turned on (`None` for the
+    /// first one) and the rendered SQL.
+    pub fn preview_query(&self, query_id: &str) -> Result<Vec<(Option<String>, String)>, Error> {
         let query = self
             .metadata
             .queries
@@ -103,25 +647,66 @@ impl<'a> Engine<'a> {
         let query_template = self.metadata.query_templates.get(&query.template).ok_or(
             Error::UndefinedQueryTemplate(query.template_file_name().to_owned()),
         )?;
+        let mut all_conds = query_template.all_conds.iter().collect::<Vec<&String>>();
+        all_conds.sort();
+        let mut result = Vec::with_capacity(all_conds.len() + 1);
+        let baseline = cond_vars(&query_template.all_conds, &HashSet::new());
+        result.push((None, self.render_query_with(query, baseline, None)?));
+        for cond in all_conds {
+            let conds = HashSet::from([cond.clone()]);
+            let cond_ctx = cond_vars(&query_template.all_conds, &conds);
+            let sql = self.render_query_with(query, cond_ctx, None)?;
+            result.push((Some(cond.clone()), sql));
+        }
+        Ok(result)
+    }
+
+    fn render_query_with(
+        &self,
+        query: &Query,
+        cond_ctx: HashMap<String, bool>,
+        placeholder_override: Option<&Placeholder>,
+    ) -> Result<String, Error> {
+        let query_template = self.metadata.query_templates.get(&query.template).ok_or(
+            Error::UndefinedQueryTemplate(query.template_file_name().to_owned()),
+        )?;
         let tmpl = self
             .query_templates_env
-            .get_template(query_template.file_name())
+            .get_template(query_template.loader_name(&self.metadata.query_templates_dir))
             .map_err(Error::MiniJinja)?;
-        let ctx = cond_vars(&query_template.all_conds, &query.conds);
-        let intermediate_output = tmpl.render(ctx).map_err(Error::MiniJinja)?;
+        let ctx = template_context(query, cond_ctx);
+        let intermediate_output = tmpl.render(ctx).map_err(|e| self.template_error(e))?;
         // Temporary environment to treat intermediate output as a
-        // jinja template and render it
-        let tmp_env = Environment::new();
+        // jinja template and render it. In sandbox mode, it has no
+        // filters or functions at all as only the placeholders are
+        // expected in the intermediate output.
+        let tmp_env = if self.sandbox {
+            Environment::empty()
+        } else {
+            Environment::new()
+        };
         let intermediate_tmpl = tmp_env
             .template_from_str(&intermediate_output)
             .map_err(Error::MiniJinja)?;
         let udvars = intermediate_tmpl.undeclared_variables(false);
-        let placeholder = placeholder_override.unwrap_or(&self.metadata.placeholder);
-        let vars = match placeholder {
-            Placeholder::PosArgs => pos_args_mapping(&intermediate_output, &udvars),
-            Placeholder::Variables => variables_mapping(&udvars),
+        let style: &dyn PlaceholderStyle = match (placeholder_override, &self.placeholder_style) {
+            (Some(p), _) => p,
+            (None, Some(s)) => s.as_ref(),
+            (None, None) => &self.metadata.placeholder,
         };
-        intermediate_tmpl.render(vars).map_err(Error::MiniJinja)
+        let vars = placeholder_mapping(&intermediate_output, &udvars, style);
+        let sql = intermediate_tmpl
+            .render(vars)
+            .map_err(|e| self.template_error(e))?;
+        Ok(self.normalized(sql))
+    }
+
+    // Returns `sql` after applying the `normalize` config (if any)
+    fn normalized(&self, sql: String) -> String {
+        match &self.metadata.normalize {
+            Some(n) => n.apply(&sql),
+            None => sql,
+        }
     }
 
     pub fn render_test(
@@ -138,15 +723,107 @@ impl<'a> Engine<'a> {
                 ))?;
         let tmpl = self
             .test_templates_env
-            .get_template(test_template.file_name())
+            .get_template(test_template.loader_name(&self.metadata.test_templates_dir))
             .map_err(Error::MiniJinja)?;
+        // The `prepared_statement` is rendered using the placeholder
+        // in the manifest, so it's used only if the test template
+        // doesn't override it
+        let prepared_statement = match test_template.placeholder {
+            Some(p) if p != self.metadata.placeholder => None,
+            _ => prepared_statement,
+        };
         // @TODO: Can we avoid allocation below by using `Cow`?
-        let ps = match prepared_statement {
-            Some(s) => s.to_owned(),
-            None => self.render_query(&test_template.query, Some(&Placeholder::PosArgs))?,
+        let (ps, placeholder) = match prepared_statement {
+            Some(s) => (s.to_owned(), self.metadata.placeholder),
+            None => {
+                let placeholder = test_template.placeholder.unwrap_or(Placeholder::PosArgs);
+                (
+                    self.render_query(&test_template.query, Some(&placeholder))?,
+                    placeholder,
+                )
+            }
         };
-        let ctx = context! { prepared_statement => strip_trailing_semicolon(&ps) };
-        tmpl.render(ctx).map_err(Error::MiniJinja)
+        let ps = strip_trailing_semicolon(&ps);
+        let query_params = QueryParams::from_sql(ps, &placeholder);
+        let ctx = context! {
+            prepared_statement => ps,
+            query_params => Value::from_serializable(&query_params),
+        };
+        let sql = tmpl.render(ctx).map_err(|e| self.template_error(e))?;
+        Ok(self.normalized(sql))
+    }
+
+    /// Renders all queries and their tests and returns the contents
+    /// of the output files mapped to their paths
+    ///
+    /// The contents are exactly the same as what the `render` command
+    /// writes (i.e. after name tagging and formatting) but nothing is
+    /// written to the file system. Skipped queries are excluded
+    /// unless `include_skipped` is true, whereas queries disabled by
+    /// `enabled_if` are always excluded.
+    pub fn render_to_map(&self, include_skipped: bool) -> Result<HashMap<PathBuf, String>, Error> {
+        let metadata = self.metadata;
+        let mut queries_to_write: Vec<SqlToWrite> = Vec::with_capacity(metadata.queries.len());
+        let mut tests_to_write: Vec<SqlToWrite> = Vec::new();
+        let generated_at = header::timestamp(SystemTime::now());
+        for query in metadata
+            .queries
+            .active(include_skipped)
+            .filter(|q| self.is_enabled(q))
+        {
+            let query_output = self.render_query(&query.id, None)?;
+            let header = metadata.render_header(&query.id, &generated_at)?;
+            let prep_stmt = match metadata.placeholder {
+                Placeholder::PosArgs => Some(query_output.as_str()),
+                Placeholder::Variables => None,
+            };
+            for tt in metadata.test_templates.find_active_by_query(query) {
+                tests_to_write.push(SqlToWrite {
+                    path: Cow::Borrowed(&tt.output),
+                    sql: self.render_test(&tt.path, prep_stmt)?,
+                    name_tag: None,
+                    format: query.format,
+                    dialect: query.dialect.as_deref(),
+                    header: header.clone(),
+                });
+            }
+            for mut qtw in SqlToWrite::for_query(query, query_output) {
+                qtw.header = header.clone();
+                queries_to_write.push(qtw);
+            }
+        }
+        output::to_map(
+            &queries_to_write,
+            &tests_to_write,
+            &metadata.query_output_layout,
+            metadata.formatter.as_ref(),
+            metadata.name_tagger.as_ref(),
+        )
+    }
+
+    /// Renders multiple test templates (typically all tests of a
+    /// query) with the same `prepared_statement`
+    ///
+    /// If `parallel` is true, the tests are rendered in parallel
+    /// using the rayon thread pool. In either case, the outputs are
+    /// returned in the same order as `paths`.
+    pub fn render_tests(
+        &self,
+        paths: &[&Path],
+        prepared_statement: Option<&str>,
+        parallel: bool,
+    ) -> Result<Vec<String>, Error> {
+        if parallel {
+            paths
+                .par_iter()
+                .map(|p| self.render_test(p, prepared_statement))
+                .collect()
+        } else {
+            paths
+                .iter()
+                .map(|p| self.render_test(p, prepared_statement))
+                .collect()
+        }
     }
 }
 
@@ -154,24 +831,663 @@ impl<'a> Engine<'a> {
 mod tests {
 
     use super::*;
+    use crate::cond_expr::CondExpr;
+    use crate::metadata::DirOverrides;
+    use crate::util::test_util::{test_manifest, TempDir};
+    use crate::validation::Severity;
+    use crate::{query, query_template, test_template};
+    use sqlparser::dialect::PostgreSqlDialect;
 
     fn strset(xs: Vec<&str>) -> HashSet<String> {
         xs.iter().map(|s| String::from(*s)).collect()
     }
 
+    #[test]
+    fn test_explain_query() {
+        let mut metadata = Metadata::default();
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            "templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![(
+            "artists_long_songs@genre",
+            "templates/queries/artists_long_songs.sql.j2",
+            vec!["genre"],
+            "output/queries/artists_long_songs-genre.sql",
+            None,
+        )]);
+        let engine = Engine::from(&metadata);
+        let expected = r#"{
+  "cond__genre": true,
+  "cond__limit": false
+}"#;
+        assert_eq!(
+            expected,
+            engine.explain_query("artists_long_songs@genre").unwrap()
+        );
+        assert!(engine.explain_query("unknown").is_err());
+
+        // The args of the query, including the vars of its variant,
+        // are part of the context
+        let dir = TempDir::new("explain-args");
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "orders.sql.j2"
+all_conds = [ "recent" ]
+
+[[queries]]
+id = "orders"
+template = "orders.sql.j2"
+args = { table = "orders", schema = "public" }
+variants = [ { suffix = "tenant_a", vars = { schema = "a" } } ]
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        let expected = r#"{
+  "cond__recent": false,
+  "schema": "a",
+  "table": "orders"
+}"#;
+        assert_eq!(expected, engine.explain_query("orders_tenant_a").unwrap());
+    }
+
+    #[test]
+    fn test_query_context_cond_overrides() {
+        let mut metadata = Metadata::default();
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            "templates/queries/artists_long_songs.sql.j2",
+            vec!["eu", "premium", "genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![(
+            "artists_long_songs@limit",
+            "templates/queries/artists_long_songs.sql.j2",
+            vec!["limit"],
+            "output/queries/artists_long_songs-limit.sql",
+            None,
+        )]);
+        metadata.profiles =
+            HashMap::from([("eu_premium".to_owned(), strset(vec!["eu", "premium"]))]);
+        // Profile + an extra `--cond` and a `--no-cond`
+        let mut on = metadata.profile_conds("eu_premium").unwrap().clone();
+        on.insert("genre".to_owned());
+        let overrides = CondOverrides {
+            on,
+            off: strset(vec!["limit"]),
+        };
+        let engine = Engine::from(&metadata).with_cond_overrides(overrides);
+        let ctx = engine.query_context("artists_long_songs@limit").unwrap();
+        assert!(ctx["cond__eu"]);
+        assert!(ctx["cond__premium"]);
+        assert!(ctx["cond__genre"]);
+        assert!(!ctx["cond__limit"]);
+
+        // Without overrides
+        let engine = Engine::from(&metadata);
+        let ctx = engine.query_context("artists_long_songs@limit").unwrap();
+        assert!(!ctx["cond__eu"]);
+        assert!(ctx["cond__limit"]);
+    }
+
+    #[test]
+    fn test_cond_overrides_resolve() {
+        let overrides = CondOverrides {
+            on: strset(vec!["eu", "limit"]),
+            off: strset(vec!["genre"]),
+        };
+        let conds = strset(vec!["limit", "genre"]);
+        assert_eq!(
+            (false, CondSource::NoCond),
+            overrides.resolve(&conds, "genre")
+        );
+        assert_eq!(
+            (true, CondSource::Query),
+            overrides.resolve(&conds, "limit")
+        );
+        assert_eq!((true, CondSource::Cond), overrides.resolve(&conds, "eu"));
+        assert_eq!(
+            (false, CondSource::Default),
+            overrides.resolve(&conds, "premium")
+        );
+        assert_eq!(strset(vec!["eu", "limit"]), overrides.apply(&conds));
+    }
+
+    #[test]
+    fn test_is_enabled() {
+        let metadata = Metadata::default();
+        let mut query = Query::new(
+            "artists@eu",
+            PathBuf::from("templates/queries/artists.sql.j2"),
+            strset(vec!["eu"]),
+            PathBuf::from("output/queries/artists-eu.sql"),
+        );
+        // Always enabled without an expression
+        assert!(Engine::from(&metadata).is_enabled(&query));
+
+        query.enabled_if = Some(CondExpr::parse("eu and not legacy").unwrap());
+        assert!(Engine::from(&metadata).is_enabled(&query));
+        // Disabled when `legacy` is turned on using `--cond`
+        let engine = Engine::from(&metadata).with_cond_overrides(CondOverrides {
+            on: strset(vec!["legacy"]),
+            off: HashSet::new(),
+        });
+        assert!(!engine.is_enabled(&query));
+        // ..or when `eu` is turned off using `--no-cond`
+        let engine = Engine::from(&metadata).with_cond_overrides(CondOverrides {
+            on: HashSet::new(),
+            off: strset(vec!["eu"]),
+        });
+        assert!(!engine.is_enabled(&query));
+    }
+
+    #[test]
+    fn test_count_placeholders() {
+        let sql =
+            "SELECT * FROM tracks WHERE artist = $1 AND album = $2 AND (genre = $3 OR $3 IS NULL);";
+        assert_eq!(3, count_placeholders(sql, &Placeholder::PosArgs));
+        assert_eq!(0, count_placeholders("SELECT 1;", &Placeholder::PosArgs));
+
+        let sql = "SELECT name::text FROM tracks WHERE artist = :artist AND album = :album OR :artist IS NULL;";
+        assert_eq!(2, count_placeholders(sql, &Placeholder::Variables));
+    }
+
+    #[test]
+    fn test_is_empty_sql() {
+        assert!(is_empty_sql(""));
+        assert!(is_empty_sql("  \n\t\n"));
+        assert!(is_empty_sql("\n;\n"));
+        assert!(is_empty_sql("-- name: foo\n  /* nothing\n here */\n"));
+        assert!(!is_empty_sql("-- name: foo\nSELECT 1;"));
+        assert!(!is_empty_sql("/* x */ SELECT 1 -- y"));
+
+        let mut metadata = Metadata::default();
+        metadata.query_templates_dir = PathBuf::from("examples/chinook/templates/queries");
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![(
+            "artists_long_songs",
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec![],
+            "output/queries/artists_long_songs.sql",
+            None,
+        )]);
+        let engine = Engine::from(&metadata);
+        let output = engine.render_query("artists_long_songs", None).unwrap();
+        assert!(!is_empty_sql(&output));
+    }
+
+    #[test]
+    fn test_check_sql_syntax() {
+        let mut metadata = Metadata::default();
+        metadata.query_templates_dir = PathBuf::from("examples/chinook/templates/queries");
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![(
+            "artists_long_songs@genre*limit",
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+            "output/queries/artists_long_songs-genre-limit.sql",
+            None,
+        )]);
+        let engine = Engine::from(&metadata);
+        for placeholder in [Placeholder::PosArgs, Placeholder::Variables] {
+            let output = engine
+                .render_query("artists_long_songs@genre*limit", Some(&placeholder))
+                .unwrap();
+            assert_eq!(Ok(()), check_sql_syntax(&output, &PostgreSqlDialect {}));
+        }
+
+        match check_sql_syntax(
+            "SELECT name\nFROM artist\nWHERE AND name = $1;",
+            &PostgreSqlDialect {},
+        ) {
+            Ok(()) => panic!(),
+            Err(msg) => assert!(msg.contains("Line: 3")),
+        }
+
+        // The sql is parsed as per the dialect of the query
+        let sql = "SELECT name FROM artist LIMIT 10, 20;";
+        let mut query = Query::clone(
+            metadata
+                .queries
+                .get("artists_long_songs@genre*limit")
+                .unwrap(),
+        );
+        assert!(check_sql_syntax(sql, query.sql_dialect().as_ref()).is_err());
+        query.dialect = Some("mysql".to_owned());
+        assert_eq!(Ok(()), check_sql_syntax(sql, query.sql_dialect().as_ref()));
+    }
+
+    #[test]
+    fn test_render_to_map() {
+        let mut metadata = Metadata::default();
+        metadata.query_templates_dir = PathBuf::from("examples/chinook/templates/queries");
+        metadata.test_templates_dir = PathBuf::from("examples/chinook/templates/tests");
+        let template = "examples/chinook/templates/queries/artists_long_songs.sql.j2";
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            template,
+            vec!["genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![
+            (
+                "artists_long_songs",
+                template,
+                vec![],
+                "output/queries/artists_long_songs.sql",
+                None,
+            ),
+            (
+                "artists_long_songs@genre*limit",
+                template,
+                vec!["genre", "limit"],
+                "output/queries/artists_long_songs-genre-limit.sql",
+                None,
+            ),
+        ]);
+        let tt_path = "examples/chinook/templates/tests/artists_long_songs-genre-limit_test.sql.j2";
+        metadata.test_templates = test_template::test_util::setup_test_templates(vec![(
+            "artists_long_songs@genre*limit",
+            tt_path,
+            "output/tests/artists_long_songs-genre-limit_test.sql",
+        )]);
+        let engine = Engine::from(&metadata);
+        let map = engine.render_to_map(false).unwrap();
+        assert_eq!(3, map.len());
+
+        let query_output = engine
+            .render_query("artists_long_songs@genre*limit", None)
+            .unwrap();
+        assert_eq!(
+            format!("-- name: artists-long-songs-genre-limit\n{query_output}"),
+            map[Path::new("output/queries/artists_long_songs-genre-limit.sql")]
+        );
+        assert!(map[Path::new("output/queries/artists_long_songs.sql")]
+            .starts_with("-- name: artists-long-songs\n"));
+        assert_eq!(
+            engine
+                .render_test(Path::new(tt_path), Some(&query_output))
+                .unwrap(),
+            map[Path::new("output/tests/artists_long_songs-genre-limit_test.sql")]
+        );
+    }
+
+    #[test]
+    fn test_render_query_args() {
+        let dir = TempDir::new("args");
+        std::fs::write(
+            dir.join("count_rows.sql.j2"),
+            "SELECT count(*) FROM {{ table }} WHERE created_at > {{ placeholder('since') }}{% if cond__active %} AND {{ status_col }} = 'active'{% endif %};\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "count_rows.sql.j2"
+all_conds = [ "active" ]
+
+[[queries]]
+id = "count_orders"
+template = "count_rows.sql.j2"
+args = { table = "orders" }
+
+[[queries]]
+id = "count_users@active"
+template = "count_rows.sql.j2"
+conds = [ "active" ]
+args = { table = "users", status_col = "state" }
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        assert_eq!(
+            "SELECT count(*) FROM orders WHERE created_at > $1;",
+            engine.render_query("count_orders", None).unwrap()
+        );
+        assert_eq!(
+            "SELECT count(*) FROM users WHERE created_at > $1 AND state = 'active';",
+            engine.render_query("count_users@active", None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_sandboxed() {
+        let dir = TempDir::new("sandbox");
+        let templates = [
+            (
+                "allowed.sql.j2",
+                "SELECT * FROM {{ table | upper }} WHERE id = {{ placeholder('id') }};",
+            ),
+            ("disallowed.sql.j2", "SELECT '{{ debug() }}';"),
+            ("disallowed_filter.sql.j2", "SELECT '{{ table | pprint }}';"),
+            // Functions can't be smuggled in via the intermediate
+            // output either
+            ("nested.sql.j2", "SELECT '{{ '{{ debug() }}' }}';"),
+        ];
+        let mut manifest = test_manifest(&dir, "output", "");
+        for (file, source) in templates {
+            std::fs::write(dir.join(file), source).unwrap();
+            let id = file.strip_suffix(".sql.j2").unwrap();
+            manifest.push_str(&format!(
+                "\n[[query_templates]]\npath = \"{file}\"\n\n[[queries]]\nid = \"{id}\"\ntemplate = \"{file}\"\nargs = {{ table = \"artists\" }}\n"
+            ));
+        }
+        let metadata: Metadata = manifest.parse().unwrap();
+
+        let engine = Engine::from(&metadata).sandboxed();
+        assert_eq!(
+            "SELECT * FROM ARTISTS WHERE id = $1;",
+            engine.render_query("allowed", None).unwrap()
+        );
+        for id in ["disallowed", "disallowed_filter", "nested"] {
+            match engine.render_query(id, None) {
+                Err(e @ Error::Sandbox(_)) => {
+                    assert!(e.to_string().starts_with("Not allowed in sandbox mode: "))
+                }
+                res => panic!("Unexpected result for '{id}': {res:?}"),
+            }
+        }
+
+        // Outside of the sandbox, all builtins are available
+        let engine = Engine::from(&metadata);
+        assert!(engine.render_query("disallowed", None).is_ok());
+        assert!(engine.render_query("disallowed_filter", None).is_ok());
+    }
+
+    #[test]
+    fn test_render_test_assert_eq() {
+        let dir = TempDir::new("assert-eq");
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        std::fs::write(dir.join("artists.sql.j2"), "SELECT * FROM artist;\n").unwrap();
+        std::fs::write(
+            dir.join("tests/artists_test.sql.j2"),
+            r#"{{ assert_eq(actual="(SELECT count(*) FROM (" ~ prepared_statement ~ ") t)", expected=275) }}
+{{ assert_eq(actual="(SELECT name FROM artist WHERE artist_id = 1)", expected="AC/DC", msg="artist's name is 100% AC/DC") }}
+{{ assert_eq(actual="(SELECT 1 WHERE false)", expected=none) }}
+"#,
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "artists_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        let expected = r#"DO $tapestry$
+BEGIN
+    IF ((SELECT count(*) FROM (SELECT * FROM artist) t)) IS DISTINCT FROM 275 THEN
+        RAISE EXCEPTION 'assert_eq failed: expected %, got %', 275, ((SELECT count(*) FROM (SELECT * FROM artist) t));
+    END IF;
+END
+$tapestry$;
+DO $tapestry$
+BEGIN
+    IF ((SELECT name FROM artist WHERE artist_id = 1)) IS DISTINCT FROM 'AC/DC' THEN
+        RAISE EXCEPTION 'assert_eq failed: artist''s name is 100%% AC/DC: expected %, got %', 'AC/DC', ((SELECT name FROM artist WHERE artist_id = 1));
+    END IF;
+END
+$tapestry$;
+DO $tapestry$
+BEGIN
+    IF ((SELECT 1 WHERE false)) IS DISTINCT FROM NULL THEN
+        RAISE EXCEPTION 'assert_eq failed: expected %, got %', NULL, ((SELECT 1 WHERE false));
+    END IF;
+END
+$tapestry$;"#;
+        let output = engine
+            .render_test(&dir.join("tests/artists_test.sql.j2"), None)
+            .unwrap();
+        assert_eq!(expected, output);
+
+        // Unknown kwargs are not allowed
+        std::fs::write(
+            dir.join("tests/artists_test.sql.j2"),
+            r#"{{ assert_eq(actual="1", expected=1, expcted=2) }}"#,
+        )
+        .unwrap();
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        assert!(engine
+            .render_test(&dir.join("tests/artists_test.sql.j2"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_render_test_placeholder_override() {
+        let dir = TempDir::new("tt-ph");
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        std::fs::write(
+            dir.join("artists.sql.j2"),
+            "SELECT * FROM artist WHERE name = {{ placeholder('name') }};\n",
+        )
+        .unwrap();
+        for name in ["posargs_test.sql.j2", "variables_test.sql.j2"] {
+            std::fs::write(
+                dir.join("tests").join(name),
+                "PREPARE q AS {{ prepared_statement }};\n",
+            )
+            .unwrap();
+        }
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "artists.sql.j2"
+
+[[queries]]
+id = "artists"
+template = "artists.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "posargs_test.sql.j2"
+
+[[test_templates]]
+query = "artists"
+path = "variables_test.sql.j2"
+placeholder = "variables"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        let query_output = engine.render_query("artists", None).unwrap();
+        let tests_dir = dir.join("tests");
+        // The sibling test uses the prepared statement rendered with
+        // the placeholder in the manifest..
+        assert_eq!(
+            "PREPARE q AS SELECT * FROM artist WHERE name = $1;",
+            engine
+                .render_test(&tests_dir.join("posargs_test.sql.j2"), Some(&query_output))
+                .unwrap()
+        );
+        // ..whereas the one that overrides it is rendered with named
+        // variables
+        assert_eq!(
+            "PREPARE q AS SELECT * FROM artist WHERE name = :name;",
+            engine
+                .render_test(
+                    &tests_dir.join("variables_test.sql.j2"),
+                    Some(&query_output)
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_test_query_params() {
+        let dir = TempDir::new("tt-params");
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        std::fs::write(
+            dir.join("tracks.sql.j2"),
+            "SELECT * FROM tracks WHERE artist = {{ placeholder('artist') }} AND (album = {{ placeholder('album') }} OR {{ placeholder('album') }} IS NULL);\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("tests/tracks_test.sql.j2"),
+            "PREPARE q ({% for _ in range(query_params.count) %}{% if not loop.first %}, {% endif %}text{% endfor %}) AS {{ prepared_statement }};\nEXECUTE q ({% for m in query_params.markers %}{% if not loop.first %}, {% endif %}'{{ m }}'{% endfor %});\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "tracks.sql.j2"
+
+[[queries]]
+id = "tracks"
+template = "tracks.sql.j2"
+
+[[test_templates]]
+query = "tracks"
+path = "tracks_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata);
+        let query_output = engine.render_query("tracks", None).unwrap();
+        assert_eq!(
+            "PREPARE q (text, text) AS SELECT * FROM tracks WHERE artist = $1 AND (album = $2 OR $2 IS NULL);\nEXECUTE q ('$1', '$2');",
+            engine
+                .render_test(&dir.join("tests/tracks_test.sql.j2"), Some(&query_output))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_query_params_from_sql() {
+        let sql = "SELECT * FROM tracks WHERE album = $2 AND (artist = $1 OR $1 IS NULL)";
+        assert_eq!(
+            QueryParams {
+                count: 2,
+                positions: vec![1, 2],
+                markers: vec!["$1".to_owned(), "$2".to_owned()],
+            },
+            QueryParams::from_sql(sql, &Placeholder::PosArgs)
+        );
+        let sql = "SELECT name::text FROM tracks WHERE album = :album AND (artist = :artist OR :album IS NULL)";
+        assert_eq!(
+            QueryParams {
+                count: 2,
+                positions: vec![],
+                markers: vec![":album".to_owned(), ":artist".to_owned()],
+            },
+            QueryParams::from_sql(sql, &Placeholder::Variables)
+        );
+    }
+
+    #[test]
+    fn test_render_discovered() {
+        let dir = TempDir::new("discover");
+        for name in ["artists", "albums", "songs"] {
+            std::fs::write(
+                dir.join(format!("{name}.sql.j2")),
+                format!("SELECT * FROM {name} WHERE id = {{{{ placeholder('id') }}}};\n"),
+            )
+            .unwrap();
+        }
+        // Files without the `.sql.j2` extension are not queries
+        std::fs::write(dir.join("README.md"), "# Queries\n").unwrap();
+        // Tests are discovered by the name of the query
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        for name in ["albums_test", "unknown_test"] {
+            std::fs::write(
+                dir.join(format!("tests/{name}.sql.j2")),
+                "PREPARE q AS {{ prepared_statement }}\n",
+            )
+            .unwrap();
+        }
+
+        let metadata = Metadata::discover(&dir, &DirOverrides::default()).unwrap();
+        assert!(metadata.formatter.is_some());
+        let tests = metadata
+            .test_templates
+            .iter()
+            .map(|tt| (tt.query.as_str(), tt.path.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![("albums", dir.join("tests/albums_test.sql.j2"))],
+            tests
+        );
+        assert!(metadata
+            .validate()
+            .iter()
+            .all(|m| m.severity() != Severity::Error));
+        let engine = Engine::from(&metadata);
+        let map = engine.render_to_map(false).unwrap();
+        assert_eq!(4, map.len());
+        assert_eq!(
+            "SELECT\n    *\nFROM\n    albums\nWHERE\n    id = $1;\n",
+            map[Path::new("output/queries/albums.sql")]
+        );
+        assert_eq!(
+            "PREPARE q AS\nSELECT\n    *\nFROM\n    albums\nWHERE\n    id = $1\n",
+            map[Path::new("output/tests/albums_test.sql")]
+        );
+        assert!(map.contains_key(Path::new("output/queries/artists.sql")));
+        assert!(map.contains_key(Path::new("output/queries/songs.sql")));
+    }
+
+    #[test]
+    fn test_render_tests_parallel() {
+        let mut metadata = Metadata::default();
+        metadata.test_templates_dir = PathBuf::from("examples/chinook/templates/tests");
+        let tt_paths = [
+            "examples/chinook/templates/tests/all_artists_long_songs_test.sql.j2",
+            "examples/chinook/templates/tests/artists_long_songs-genre-limit_test.sql.j2",
+            "examples/chinook/templates/tests/songs_formats-afa_test.sql.j2",
+        ];
+        metadata.test_templates = test_template::test_util::setup_test_templates(
+            tt_paths
+                .iter()
+                .enumerate()
+                .map(|(i, p)| ("my_query", *p, ["a.sql", "b.sql", "c.sql"][i]))
+                .collect(),
+        );
+        let engine = Engine::from(&metadata);
+        let paths = tt_paths.iter().map(Path::new).collect::<Vec<&Path>>();
+        let prep_stmt = Some("SELECT 1;");
+        let serial = engine.render_tests(&paths, prep_stmt, false).unwrap();
+        let parallel = engine.render_tests(&paths, prep_stmt, true).unwrap();
+        assert_eq!(3, parallel.len());
+        assert_eq!(serial, parallel);
+        assert!(parallel.iter().all(|o| o.contains("SELECT 1")));
+    }
+
     #[test]
     fn test_cond_vars() {
         let all_conds = strset(vec!["a", "b", "c"]);
         let conds = strset(vec!["b", "c"]);
         let res = cond_vars(&all_conds, &conds);
         assert_eq!(3, res.len());
-        assert_eq!(false, res["cond__a"]);
-        assert_eq!(true, res["cond__b"]);
-        assert_eq!(true, res["cond__c"]);
+        assert!(!res["cond__a"]);
+        assert!(res["cond__b"]);
+        assert!(res["cond__c"]);
     }
 
     #[test]
-    fn test_pos_args_mapping() {
+    fn test_placeholder_mapping() {
         let udvars = HashSet::from_iter(vec![
             "firstname".to_owned(),
             "lastname".to_owned(),
@@ -187,19 +1503,194 @@ WHERE department = {{ department }} AND lastname = {{ lastname }}
 AND tag = "{{ sometag }}"
 ;
 "#;
-        let result = pos_args_mapping(template, &udvars);
+        let result = placeholder_mapping(template, &udvars, &Placeholder::PosArgs);
         assert_eq!(3, result.len());
         assert_eq!("$1", result.get("firstname").unwrap());
         assert_eq!("$2", result.get("lastname").unwrap());
         assert_eq!("$3", result.get("department").unwrap());
 
         let template = "";
-        let result = pos_args_mapping(template, &udvars);
+        let result = placeholder_mapping(template, &udvars, &Placeholder::PosArgs);
         assert_eq!(0, result.len());
 
         let template = "SELECT * from employees WHERE firstname = {{ firstname }};";
-        let result = pos_args_mapping(template, &udvars);
+        let result = placeholder_mapping(template, &udvars, &Placeholder::PosArgs);
         assert_eq!(1, result.len());
         assert_eq!("$1", result.get("firstname").unwrap());
+
+        let template = "SELECT * from employees WHERE lastname = {{ lastname }} AND firstname = {{ firstname }};";
+        let result = placeholder_mapping(template, &udvars, &Placeholder::Variables);
+        assert_eq!(2, result.len());
+        assert_eq!(":lastname", result.get("lastname").unwrap());
+        assert_eq!(":firstname", result.get("firstname").unwrap());
+    }
+
+    /// ODBC style named params e.g. `@p1`
+    struct OdbcStyle;
+
+    impl PlaceholderStyle for OdbcStyle {
+        fn render_marker(&self, index: usize, _name: Option<&str>) -> String {
+            format!("@p{index}")
+        }
+    }
+
+    #[test]
+    fn test_render_custom_placeholder_style() {
+        let dir = TempDir::new("ph-style");
+        std::fs::create_dir_all(dir.join("tests")).unwrap();
+        std::fs::write(
+            dir.join("tracks.sql.j2"),
+            "SELECT * FROM tracks WHERE artist = {{ placeholder('artist') }} AND (album = {{ placeholder('album') }} OR {{ placeholder('album') }} IS NULL);\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("tests/tracks_test.sql.j2"),
+            "PREPARE q AS {{ prepared_statement }};\n",
+        )
+        .unwrap();
+        let manifest = test_manifest(
+            &dir,
+            "output",
+            r#"
+[[query_templates]]
+path = "tracks.sql.j2"
+
+[[queries]]
+id = "tracks"
+template = "tracks.sql.j2"
+
+[[test_templates]]
+query = "tracks"
+path = "tracks_test.sql.j2"
+"#,
+        );
+        let metadata: Metadata = manifest.parse().unwrap();
+        let engine = Engine::from(&metadata).with_placeholder_style(OdbcStyle);
+        assert_eq!(
+            "SELECT * FROM tracks WHERE artist = @p1 AND (album = @p2 OR @p2 IS NULL);",
+            engine.render_query("tracks", None).unwrap()
+        );
+        // An explicit placeholder takes precedence over the style
+        assert_eq!(
+            "SELECT * FROM tracks WHERE artist = $1 AND (album = $2 OR $2 IS NULL);",
+            engine
+                .render_query("tracks", Some(&Placeholder::PosArgs))
+                .unwrap()
+        );
+        // The style is retained in sandbox mode
+        let engine = engine.sandboxed();
+        let map = engine.render_to_map(false).unwrap();
+        assert_eq!(
+            "SELECT * FROM tracks WHERE artist = @p1 AND (album = @p2 OR @p2 IS NULL);",
+            map[Path::new("output/queries/tracks.sql")]
+                .lines()
+                .last()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sql_block() {
+        let mut env = Environment::new();
+        env.add_filter("sql_block", sql_block);
+        let template = r#"WITH
+{% filter sql_block("cte_active") %}
+active AS (SELECT * FROM customer WHERE active)
+{% endfilter %}
+SELECT * FROM active;"#;
+        let rendered = env.render_str(template, context! {}).unwrap();
+        assert_eq!(
+            "WITH\n-- >>> cte_active\nactive AS (SELECT * FROM customer WHERE active)\n-- <<< cte_active\nSELECT * FROM active;",
+            rendered
+        );
+        assert!(env
+            .render_str(r#"{% filter sql_block("") %}x{% endfilter %}"#, context! {})
+            .is_err());
+
+        // The block markers don't cause drift
+        let dir = TempDir::new("sql-block");
+        let path = dir.join("active.sql");
+        let out_format = output::OutputFormat::Sql;
+        std::fs::write(&path, strip_block_markers(&rendered)).unwrap();
+        assert_eq!(
+            output::Status::Unchanged,
+            output::testfile_status(&path, None, None, out_format, &rendered).unwrap()
+        );
+        let modified = rendered.replace("active)", "NOT active)");
+        assert_eq!(
+            output::Status::Modified,
+            output::testfile_status(&path, None, None, out_format, &modified).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_context_schema() {
+        let env = Environment::new();
+        let source = r#"SELECT * FROM customer
+WHERE region = '{{ region | upper }}'
+{% if premium %}
+  AND tier = {{ placeholder('tier') }}
+{% endif %}
+{% for c in countries %}
+  AND country != '{{ c }}'
+{% endfor %}"#;
+        let tmpl = env
+            .template_from_named_str("customers.sql.j2", source)
+            .unwrap();
+        let schema = context_schema(&tmpl, &["placeholder"]);
+        assert_eq!("customers.sql.j2", schema.template);
+        assert_eq!(
+            vec![
+                ContextVar {
+                    name: "countries".to_owned(),
+                    conditional: false
+                },
+                ContextVar {
+                    name: "premium".to_owned(),
+                    conditional: true
+                },
+                ContextVar {
+                    name: "region".to_owned(),
+                    conditional: false
+                },
+            ],
+            schema.variables
+        );
+        assert_eq!(vec!["upper"], schema.filters);
+    }
+
+    #[test]
+    fn test_preview_query() {
+        let mut metadata = Metadata {
+            query_templates_dir: PathBuf::from("examples/chinook/templates/queries"),
+            ..Metadata::default()
+        };
+        metadata.query_templates = query_template::test_util::setup_query_templates(vec![(
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre", "limit"],
+        )]);
+        metadata.queries = query::test_util::setup_queries(vec![(
+            "artists_long_songs@genre",
+            "examples/chinook/templates/queries/artists_long_songs.sql.j2",
+            vec!["genre"],
+            "output/queries/artists_long_songs-genre.sql",
+            None,
+        )]);
+        let engine = Engine::from(&metadata);
+        let previews = engine.preview_query("artists_long_songs@genre").unwrap();
+        assert_eq!(3, previews.len());
+        let conds = previews
+            .iter()
+            .map(|(c, _)| c.as_deref())
+            .collect::<Vec<Option<&str>>>();
+        assert_eq!(vec![None, Some("genre"), Some("limit")], conds);
+
+        // The conds of the query itself are not considered
+        let (_, baseline) = &previews[0];
+        assert!(!baseline.contains("genre g") && !baseline.contains("LIMIT"));
+        let (_, genre) = &previews[1];
+        assert!(genre.contains("g.name = $1") && !genre.contains("LIMIT"));
+        let (_, limit) = &previews[2];
+        assert!(limit.contains("LIMIT $1") && !limit.contains("genre g"));
     }
 }
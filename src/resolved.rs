@@ -0,0 +1,275 @@
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::output::Layout;
+use crate::test_template::TestKind;
+use crate::util::normalize_path;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use toml::Table;
+
+/// Format in which the resolved manifest is printed by the
+/// `--print-manifest` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedQueryTemplate {
+    pub path: PathBuf,
+    pub all_conds: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedQuery {
+    pub id: String,
+    pub template: PathBuf,
+    pub conds: BTreeSet<String>,
+    pub output: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_tag: Option<String>,
+    pub skip: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_if: Option<String>,
+    pub format: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dialect: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub split_on: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tests_glob: Option<String>,
+    pub tags: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Table::is_empty")]
+    pub args: Table,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedTestTemplate {
+    pub query: String,
+    pub path: PathBuf,
+    pub output: PathBuf,
+    pub requires_conds: BTreeSet<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<&'static str>,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<PathBuf>,
+}
+
+/// The manifest as seen by tapestry after parsing it i.e. with the
+/// paths made absolute, the defaults and dir overrides applied and
+/// the `tests_glob` of the queries expanded
+///
+/// Fields that are optional in the manifest are omitted if not
+/// specified. The formatter is included in the same form as in the
+/// output of `summary`.
+#[derive(Debug, Serialize)]
+pub struct ResolvedManifest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+    pub placeholder: &'static str,
+    pub query_templates_dir: PathBuf,
+    pub test_templates_dir: PathBuf,
+    pub queries_output_dir: PathBuf,
+    pub tests_output_dir: PathBuf,
+    pub query_output_layout: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_output_file: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_tagger_style: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_pattern: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_render: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, BTreeSet<String>>,
+    pub query_templates: Vec<ResolvedQueryTemplate>,
+    pub queries: Vec<ResolvedQuery>,
+    pub test_templates: Vec<ResolvedTestTemplate>,
+}
+
+fn sorted(set: &HashSet<String>) -> BTreeSet<String> {
+    set.iter().cloned().collect()
+}
+
+impl ResolvedManifest {
+    /// Resolves the `metadata`, with relative paths in it made
+    /// absolute by joining them to `base_dir`
+    pub fn new(metadata: &Metadata, base_dir: &Path) -> Self {
+        let abs = |p: &Path| normalize_path(base_dir.join(p));
+        let (query_output_layout, query_output_file) = match &metadata.query_output_layout {
+            Layout::OneFileOneQuery => ("one-file-one-query", None),
+            Layout::OneFileAllQueries(f) => ("one-file-all-queries", f.as_deref().map(abs)),
+        };
+        let query_templates = metadata
+            .query_templates
+            .iter()
+            .map(|qt| ResolvedQueryTemplate {
+                path: abs(&qt.path),
+                all_conds: sorted(&qt.all_conds),
+            })
+            .collect();
+        let queries = metadata
+            .queries
+            .iter()
+            .map(|q| ResolvedQuery {
+                id: q.id.clone(),
+                template: abs(&q.template),
+                conds: sorted(&q.conds),
+                output: abs(&q.output),
+                name_tag: metadata
+                    .name_tagger
+                    .as_ref()
+                    .map(|t| t.make_name_tag(&q.name_tag)),
+                skip: q.skip,
+                enabled_if: q.enabled_if.as_ref().map(|e| e.to_string()),
+                format: q.format,
+                dialect: q.dialect.clone(),
+                split_on: q.split_on.clone(),
+                tests_glob: q.tests_glob.clone(),
+                tags: sorted(&q.tags),
+                group: q.group.clone(),
+                args: q.args.clone(),
+            })
+            .collect();
+        let test_templates = metadata
+            .test_templates
+            .iter()
+            .map(|tt| ResolvedTestTemplate {
+                query: tt.query.clone(),
+                path: abs(&tt.path),
+                output: abs(&tt.output),
+                requires_conds: sorted(&tt.requires_conds),
+                placeholder: tt.placeholder.as_ref().map(|p| p.label()),
+                kind: tt.kind.label(),
+                expected: match &tt.kind {
+                    TestKind::Pgtap => None,
+                    TestKind::Golden { expected } => Some(abs(expected)),
+                },
+            })
+            .collect();
+        Self {
+            version: metadata.version,
+            placeholder: metadata.placeholder.label(),
+            query_templates_dir: abs(&metadata.query_templates_dir),
+            test_templates_dir: abs(&metadata.test_templates_dir),
+            queries_output_dir: abs(&metadata.queries_output_dir),
+            tests_output_dir: abs(&metadata.tests_output_dir),
+            query_output_layout,
+            query_output_file,
+            formatter: metadata.formatter.as_ref().map(|f| f.describe()),
+            name_tagger_style: metadata.name_tagger.as_ref().map(|t| t.style.to_string()),
+            header_template: metadata
+                .header_template
+                .as_ref()
+                .map(|h| h.source().to_owned()),
+            id_pattern: metadata.id_pattern.clone(),
+            post_render: metadata.post_render.as_ref().map(|h| h.args().to_vec()),
+            profiles: metadata
+                .profiles
+                .iter()
+                .map(|(name, conds)| (name.clone(), sorted(conds)))
+                .collect(),
+            query_templates,
+            queries,
+            test_templates,
+        }
+    }
+
+    pub fn to_string(&self, format: DumpFormat) -> Result<String, Error> {
+        match format {
+            DumpFormat::Toml => toml::to_string_pretty(self)
+                .map_err(|e| Error::Cli(format!("Couldn't serialize the manifest: {e}"))),
+            // @UNWRAP: Serializing to json can't fail as all keys are
+            // strings
+            DumpFormat::Json => Ok(serde_json::to_string_pretty(self).unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::metadata::DirOverrides;
+    use crate::util::test_util::{test_manifest, TempDir};
+    use std::fs;
+
+    #[test]
+    fn test_resolved_manifest() {
+        let dir = TempDir::new("resolved");
+        let tests_dir = dir.join("tests");
+        fs::create_dir_all(&tests_dir).unwrap();
+        fs::write(tests_dir.join("artists_count_test.sql.j2"), "").unwrap();
+
+        let manifest = test_manifest(
+            "templates",
+            "output",
+            r#"
+[name_tagger]
+style = "kebab-case"
+
+[[query_templates]]
+path = "artists.sql.j2"
+all_conds = [ "genre" ]
+
+[[queries]]
+id = "artists@genre"
+template = "artists.sql.j2"
+conds = [ "genre" ]
+tests_glob = "*_test.sql.j2"
+"#,
+        );
+        // The dir overrides would normally be read from env vars
+        // e.g. `TAPESTRY_TEST_TEMPLATES_DIR`
+        let overrides = DirOverrides {
+            test_templates_dir: Some(tests_dir.clone()),
+            ..DirOverrides::default()
+        };
+        let metadata = Metadata::parse(&manifest, &overrides).unwrap();
+        let resolved = ResolvedManifest::new(&metadata, Path::new("/project"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&resolved.to_string(DumpFormat::Json).unwrap()).unwrap();
+        assert_eq!(tests_dir.to_str().unwrap(), json["test_templates_dir"]);
+        assert_eq!("/project/templates", json["query_templates_dir"]);
+        assert_eq!(
+            "/project/output/queries/artists-genre.sql",
+            json["queries"][0]["output"]
+        );
+        assert_eq!("-- name: artists-genre", json["queries"][0]["name_tag"]);
+        assert_eq!("one-file-one-query", json["query_output_layout"]);
+        assert!(json.get("formatter").is_none());
+        // The test template matching the glob is included
+        let tts = json["test_templates"].as_array().unwrap();
+        assert_eq!(1, tts.len());
+        assert_eq!("artists@genre", tts[0]["query"]);
+        assert_eq!(
+            tests_dir
+                .join("artists_count_test.sql.j2")
+                .to_str()
+                .unwrap(),
+            tts[0]["path"]
+        );
+        assert_eq!(
+            "/project/output/tests/artists_count_test.sql",
+            tts[0]["output"]
+        );
+
+        let toml = resolved.to_string(DumpFormat::Toml).unwrap();
+        let value: toml::Table = toml.parse().unwrap();
+        assert_eq!(
+            Some("/project/output/tests"),
+            value["tests_output_dir"].as_str()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::error::Error;
+use crate::error::{Error, ScaffoldError};
 use crate::formatters::{discover_available_formatters, Formatter};
 use crate::metadata::Metadata;
 use crate::tagging::NameTagger;
@@ -10,15 +10,24 @@ use std::fmt::{self, Display};
 use std::fs;
 use std::path::Path;
 
+fn scaffold_io_error(e: std::io::Error) -> Error {
+    Error::Scaffolding(ScaffoldError::Io(e))
+}
+
 fn create_project_dir(path: &Path) -> Result<(), Error> {
-    match path.try_exists() {
-        Ok(true) => Err(Error::Scaffolding("Dir already exists".to_owned())),
-        Ok(false) => {
-            fs::create_dir(path).map_err(Error::Io)?;
-            Ok(())
-        }
-        Err(e) => Err(Error::Io(e)),
+    if !path.try_exists().map_err(scaffold_io_error)? {
+        return fs::create_dir(path).map_err(scaffold_io_error);
     }
+    let err = if path
+        .join("tapestry.toml")
+        .try_exists()
+        .map_err(scaffold_io_error)?
+    {
+        ScaffoldError::AlreadyInitialized(path.to_path_buf())
+    } else {
+        ScaffoldError::DirExists(path.to_path_buf())
+    };
+    Err(Error::Scaffolding(err))
 }
 
 #[derive(Serialize)]
@@ -68,7 +77,7 @@ fn write_manifest(path: &Path, metadata: &Metadata) -> Result<(), Error> {
     let template = env.get_template("manifest").map_err(Error::MiniJinja)?;
     let ctx = DefaultManifestContext::from(metadata);
     let content = template.render(ctx).unwrap();
-    fs::write(path, content).map_err(Error::Io)?;
+    fs::write(path, content).map_err(scaffold_io_error)?;
     Ok(())
 }
 
@@ -144,8 +153,8 @@ pub fn init_project(dir: &Path) -> Result<(), Error> {
     write_manifest(manifest_path.as_path(), &metadata)?;
 
     // Create subdirs
-    fs::create_dir_all(dir.join(&metadata.query_templates_dir)).map_err(Error::Io)?;
-    fs::create_dir_all(dir.join(&metadata.test_templates_dir)).map_err(Error::Io)?;
+    fs::create_dir_all(dir.join(&metadata.query_templates_dir)).map_err(scaffold_io_error)?;
+    fs::create_dir_all(dir.join(&metadata.test_templates_dir)).map_err(scaffold_io_error)?;
 
     // Create formatter config files if applicable
     if let Some(formatter) = metadata.formatter {
@@ -154,3 +163,39 @@ pub fn init_project(dir: &Path) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+
+    #[test]
+    fn test_create_project_dir() {
+        let base = TempDir::new("init");
+
+        // New dir is created
+        let dir = base.join("new");
+        create_project_dir(&dir).unwrap();
+        assert!(dir.is_dir());
+        // Existing dir is not used even if it's empty
+        match create_project_dir(&dir) {
+            Err(Error::Scaffolding(ScaffoldError::DirExists(p))) => assert_eq!(dir, p),
+            _ => panic!(),
+        }
+
+        fs::write(dir.join("tapestry.toml"), "").unwrap();
+        match create_project_dir(&dir) {
+            Err(Error::Scaffolding(ScaffoldError::AlreadyInitialized(p))) => assert_eq!(dir, p),
+            _ => panic!(),
+        }
+
+        // Parent dir doesn't exist
+        match create_project_dir(&base.join("missing/new")) {
+            Err(Error::Scaffolding(ScaffoldError::Io(e))) => {
+                assert_eq!(std::io::ErrorKind::NotFound, e.kind())
+            }
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use crate::error::Error;
+use crate::formatters::{self, InvocationCounts};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Time taken to render a query along with its tests
+#[derive(Debug, Serialize)]
+pub struct QueryDuration {
+    pub id: String,
+    pub duration_ms: f64,
+}
+
+/// Metrics of a `render` run, written as JSON when the `--stats-json`
+/// option is specified
+#[derive(Debug, Serialize)]
+pub struct RenderStats {
+    pub total_queries: usize,
+    pub total_tests: usize,
+    pub bytes_written: u64,
+    pub duration_ms: f64,
+    pub queries: Vec<QueryDuration>,
+    pub formatter: InvocationCounts,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl RenderStats {
+    /// Returns the stats for the queries rendered in `durations`
+    /// along with the total time taken, which includes formatting
+    /// and writing the output files
+    ///
+    /// `bytes_written` is the total size of the files at `paths`
+    /// (each file counted once), so it must be called only after
+    /// they are written.
+    pub fn new<'a, I: IntoIterator<Item = &'a Path>>(
+        durations: Vec<(String, Duration)>,
+        total_tests: usize,
+        paths: I,
+        total_duration: Duration,
+    ) -> Result<Self, Error> {
+        let mut bytes_written = 0;
+        let mut seen = HashSet::new();
+        for path in paths {
+            if seen.insert(path) {
+                bytes_written += fs::metadata(path).map_err(Error::Io)?.len();
+            }
+        }
+        let queries = durations
+            .into_iter()
+            .map(|(id, d)| QueryDuration {
+                id,
+                duration_ms: millis(d),
+            })
+            .collect::<Vec<QueryDuration>>();
+        Ok(Self {
+            total_queries: queries.len(),
+            total_tests,
+            bytes_written,
+            duration_ms: millis(total_duration),
+            queries,
+            formatter: formatters::invocation_counts(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        // @UNWRAP: Serializing the stats to json can't fail
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, json + "\n").map_err(Error::Io)
+    }
+}
+
+/// Kind of an output file reported by `render --json-lines`
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactKind {
+    Query,
+    Test,
+}
+
+/// Outcome of an output file reported by `render --json-lines`
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactStatus {
+    Written,
+    /// Not written as it's unchanged since it was last rendered
+    /// (`--since-lock`)
+    Skipped,
+    Failed,
+}
+
+/// A single output file, printed as one line of JSON by `render
+/// --json-lines` as soon as it's done with
+///
+/// `id` is the id of the query, also for its tests.
+#[derive(Debug, Serialize)]
+pub struct ArtifactLine<'a> {
+    pub id: &'a str,
+    pub kind: ArtifactKind,
+    pub output: Cow<'a, str>,
+    /// Size of the file on the disk, only if it's written
+    pub bytes: Option<u64>,
+    pub status: ArtifactStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> ArtifactLine<'a> {
+    /// Returns the line for the output file at `path` given the
+    /// result of writing (or rendering) it
+    pub fn new(
+        id: &'a str,
+        kind: ArtifactKind,
+        path: &'a Path,
+        result: Result<(), &Error>,
+    ) -> Self {
+        let (bytes, status, error) = match result {
+            Ok(()) => (
+                fs::metadata(path).ok().map(|m| m.len()),
+                ArtifactStatus::Written,
+                None,
+            ),
+            Err(e) => (None, ArtifactStatus::Failed, Some(e.to_string())),
+        };
+        Self {
+            id,
+            kind,
+            output: path.to_string_lossy(),
+            bytes,
+            status,
+            error,
+        }
+    }
+
+    pub fn skipped(id: &'a str, kind: ArtifactKind, path: &'a Path) -> Self {
+        Self {
+            id,
+            kind,
+            output: path.to_string_lossy(),
+            bytes: None,
+            status: ArtifactStatus::Skipped,
+            error: None,
+        }
+    }
+
+    /// Returns the line for an output file that's not written as
+    /// rendering or writing another file failed before it
+    pub fn unwritten(id: &'a str, kind: ArtifactKind, path: &'a Path) -> Self {
+        Self {
+            id,
+            kind,
+            output: path.to_string_lossy(),
+            bytes: None,
+            status: ArtifactStatus::Failed,
+            error: Some("Not written due to an earlier failure".to_owned()),
+        }
+    }
+}
+
+/// Writer of `ArtifactLine`s as newline delimited JSON
+///
+/// Every line is flushed right away so that the consumer can act
+/// upon an output file without waiting for the others.
+pub struct JsonLines<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn emit(&mut self, line: &ArtifactLine) -> Result<(), Error> {
+        // @UNWRAP: Serializing strings, integers and unit enums to
+        // json can't fail
+        let json = serde_json::to_string(line).unwrap();
+        writeln!(self.writer, "{json}")
+            .and_then(|_| self.writer.flush())
+            .map_err(Error::Io)
+    }
+}
+
+impl JsonLines<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+/// Breakdown of the time taken by `render` into its phases, printed
+/// when the `--profile-formatter` option is specified
+#[derive(Debug)]
+pub struct PhaseTimings {
+    pub rendering: Duration,
+    pub formatting: Duration,
+    pub io: Duration,
+}
+
+impl PhaseTimings {
+    /// Returns the timings given the time taken to render the
+    /// templates and to write the output files
+    ///
+    /// As the outputs are formatted while writing them, the time
+    /// spent in the formatter (`formatting`) is subtracted from
+    /// `writing` to arrive at the time spent in I/O.
+    pub fn new(rendering: Duration, writing: Duration, formatting: Duration) -> Self {
+        Self {
+            rendering,
+            formatting,
+            io: writing.saturating_sub(formatting),
+        }
+    }
+
+    fn total(&self) -> Duration {
+        self.rendering + self.formatting + self.io
+    }
+}
+
+impl fmt::Display for PhaseTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = millis(self.total());
+        let phases = [
+            ("rendering", self.rendering),
+            ("formatting", self.formatting),
+            ("io", self.io),
+        ];
+        writeln!(f, "Time spent ({total:.1}ms):")?;
+        for (i, (phase, d)) in phases.iter().enumerate() {
+            let ms = millis(*d);
+            let pct = if total > 0.0 { ms * 100.0 / total } else { 0.0 };
+            write!(f, "  {phase:<10} {ms:>10.1}ms {pct:>5.1}%")?;
+            if i + 1 < phases.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// No. of tests of every query, saved using `coverage --save` so
+/// that it can be used as the baseline for `coverage --baseline`
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageBaseline {
+    pub queries: BTreeMap<String, usize>,
+}
+
+impl CoverageBaseline {
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(Error::Io)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            Error::Cli(format!(
+                "Invalid coverage baseline '{}': {e}",
+                path.display()
+            ))
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        // @UNWRAP: Serializing a map of strings to integers can't fail
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(path, json + "\n").map_err(Error::Io)
+    }
+
+    /// Returns the ids of the queries that have tests in `self` (the
+    /// baseline) but no tests in `current`
+    ///
+    /// Queries that are not in `current` e.g. because they have been
+    /// removed are not considered.
+    pub fn regressions<'a>(&'a self, current: &Self) -> Vec<&'a str> {
+        self.queries
+            .iter()
+            .filter(|(id, n)| **n > 0 && current.queries.get(*id) == Some(&0))
+            .map(|(id, _)| id.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::formatters::Formatter;
+    use crate::output::{self, OutputFormat, SqlToWrite};
+    use crate::util::test_util::TempDir;
+    use std::time::Instant;
+
+    #[test]
+    fn test_render_stats_json() {
+        let dir = TempDir::new("stats");
+        let (q1, q2) = (dir.join("q1.sql"), dir.join("q2.sql"));
+        fs::write(&q1, "SELECT 1;\n").unwrap();
+        fs::write(&q2, "SELECT 22;\n").unwrap();
+
+        let durations = vec![
+            ("q1".to_owned(), Duration::from_millis(3)),
+            ("q2".to_owned(), Duration::from_millis(5)),
+        ];
+        // q1 is repeated as it would be in case of a combined output
+        // file, but it's counted only once
+        let paths = [q1.as_path(), q2.as_path(), q1.as_path()];
+        let stats = RenderStats::new(durations, 0, paths, Duration::from_millis(10)).unwrap();
+        let stats_path = dir.join("stats.json");
+        stats.write(&stats_path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&stats_path).unwrap()).unwrap();
+        let mut keys = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect::<Vec<&str>>();
+        keys.sort_unstable();
+        assert_eq!(
+            vec![
+                "bytes_written",
+                "duration_ms",
+                "formatter",
+                "queries",
+                "total_queries",
+                "total_tests"
+            ],
+            keys
+        );
+        assert_eq!(2, json["total_queries"]);
+        assert_eq!(21, json["bytes_written"]);
+        assert_eq!("q2", json["queries"][1]["id"]);
+        assert_eq!(5.0, json["queries"][1]["duration_ms"]);
+        assert!(json["formatter"]["runs"].is_u64());
+        assert!(json["formatter"]["cache_hits"].is_u64());
+    }
+
+    #[test]
+    fn test_phase_timings() {
+        let dir = TempDir::new("phases");
+        let value: toml::Value = toml::from_str("sqlformat-rs = {}").unwrap();
+        let formatter = Formatter::decode(&value).unwrap().unwrap();
+        let path = dir.join("artists.sql");
+        let files = vec![SqlToWrite {
+            path: Cow::Borrowed(&path),
+            sql: "select id, name from artist where id = 1;".to_owned(),
+            name_tag: None,
+            format: true,
+            dialect: None,
+            header: None,
+        }];
+
+        let fmt_before = formatters::formatting_duration();
+        let started = Instant::now();
+        output::write_separately(&files, Some(&formatter), None, OutputFormat::Sql).unwrap();
+        let writing = started.elapsed();
+        let formatting = formatters::formatting_duration() - fmt_before;
+
+        let timings = PhaseTimings::new(Duration::from_millis(2), writing, formatting);
+        assert!(timings.formatting > Duration::ZERO);
+        assert!(timings.formatting <= writing);
+        let breakdown = timings.to_string();
+        let phases = breakdown
+            .lines()
+            .skip(1)
+            .map(|l| l.split_whitespace().next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(vec!["rendering", "formatting", "io"], phases);
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let dir = TempDir::new("json-lines");
+        // A file in place of the parent dir, so that the last output
+        // fails to be written
+        fs::write(dir.join("blocked"), "").unwrap();
+        let paths = [
+            dir.join("queries/artists.sql"),
+            dir.join("tests/artists_test.sql"),
+            dir.join("blocked/albums.sql"),
+        ];
+        let files = paths
+            .iter()
+            .map(|p| SqlToWrite {
+                path: Cow::Borrowed(p),
+                sql: "SELECT 1;\n".to_owned(),
+                name_tag: None,
+                format: false,
+                dialect: None,
+                header: None,
+            })
+            .collect::<Vec<SqlToWrite>>();
+        let ids = ["artists", "artists", "albums"];
+        let kinds = [ArtifactKind::Query, ArtifactKind::Test, ArtifactKind::Query];
+
+        let mut buf = Vec::new();
+        let mut json_lines = JsonLines::new(&mut buf);
+        let res = output::write_separately_with(&files, None, None, OutputFormat::Sql, |i, res| {
+            json_lines.emit(&ArtifactLine::new(ids[i], kinds[i], &paths[i], res))
+        });
+        assert!(res.is_err());
+        json_lines
+            .emit(&ArtifactLine::skipped(
+                "songs",
+                ArtifactKind::Test,
+                Path::new("t.sql"),
+            ))
+            .unwrap();
+        json_lines
+            .emit(&ArtifactLine::unwritten(
+                "genres",
+                ArtifactKind::Query,
+                Path::new("q.sql"),
+            ))
+            .unwrap();
+
+        // Every line is valid json, one per output file, even though
+        // writing failed midway
+        let output = String::from_utf8(buf).unwrap();
+        let lines = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(5, lines.len());
+        assert_eq!("artists", lines[0]["id"]);
+        assert_eq!("query", lines[0]["kind"]);
+        assert_eq!(paths[0].to_str().unwrap(), lines[0]["output"]);
+        assert_eq!(10, lines[0]["bytes"]);
+        assert_eq!("written", lines[0]["status"]);
+        assert!(lines[0].get("error").is_none());
+        assert_eq!("test", lines[1]["kind"]);
+        assert_eq!("failed", lines[2]["status"]);
+        assert!(lines[2]["bytes"].is_null());
+        assert!(lines[2]["error"].is_string());
+        assert_eq!("skipped", lines[3]["status"]);
+        assert_eq!("failed", lines[4]["status"]);
+        assert_eq!("Not written due to an earlier failure", lines[4]["error"]);
+    }
+
+    #[test]
+    fn test_coverage_baseline() {
+        let dir = TempDir::new("cov-baseline");
+        let baseline = CoverageBaseline {
+            queries: BTreeMap::from([
+                ("artists".to_owned(), 2),
+                ("albums".to_owned(), 1),
+                ("songs".to_owned(), 0),
+                ("genres".to_owned(), 1),
+            ]),
+        };
+        let path = dir.join("coverage.json");
+        baseline.write(&path).unwrap();
+        let baseline = CoverageBaseline::read(&path).unwrap();
+
+        // Regression: a previously tested query has no tests, even
+        // though the overall coverage is the same. The removed query
+        // `genres` is not considered.
+        let current = CoverageBaseline {
+            queries: BTreeMap::from([
+                ("artists".to_owned(), 2),
+                ("albums".to_owned(), 0),
+                ("songs".to_owned(), 1),
+            ]),
+        };
+        assert_eq!(vec!["albums"], baseline.regressions(&current));
+
+        // Improvement
+        let current = CoverageBaseline {
+            queries: BTreeMap::from([
+                ("artists".to_owned(), 1),
+                ("albums".to_owned(), 1),
+                ("songs".to_owned(), 1),
+            ]),
+        };
+        assert!(baseline.regressions(&current).is_empty());
+
+        fs::write(&path, "[]").unwrap();
+        assert!(CoverageBaseline::read(&path).is_err());
+    }
+}
@@ -5,7 +5,7 @@ use toml::Value;
 
 use crate::error::{parse_error, Error};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum NameTag {
     DeriveFromId(String),
     Custom(String),
@@ -1,10 +1,15 @@
 use crate::error::{parse_error, Error};
-use crate::query::Queries;
-use crate::toml::{decode_pathbuf, decode_string};
+use crate::placeholder::Placeholder;
+use crate::query::{variant_path, Queries, Query};
+use crate::toml::{decode_pathbuf, decode_string, decode_strset};
+use crate::util::{glob_match, is_same_path, ls_files, mirrored_dir};
 use crate::validation::{validate_path, ManifestMistake};
-use std::collections::HashMap;
+use log::warn;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::Arc;
 use toml::Value;
 
 // path can be of the following format
@@ -37,17 +42,89 @@ fn path_to_output(path: &Path, base_dir: &Path) -> Result<PathBuf, Error> {
     }
 }
 
+/// Returns the output path for the test template at `path` when it's
+/// not explicitly specified
+pub fn default_output(
+    path: &Path,
+    templates_base_dir: &Path,
+    output_base_dir: &Path,
+    preserve_tree: bool,
+) -> Result<PathBuf, Error> {
+    if preserve_tree {
+        let output_dir = mirrored_dir(path, templates_base_dir, output_base_dir);
+        path_to_output(path, &output_dir)
+    } else {
+        path_to_output(path, output_base_dir)
+    }
+}
+
+/// Kind of a test template, which decides how its output is checked
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestKind {
+    /// pgTAP test that's run against a database by the `assert`
+    /// command
+    Pgtap,
+    /// Golden file check i.e. the rendered output is compared with
+    /// the committed `expected` file by the `test` command
+    Golden { expected: PathBuf },
+}
+
+impl TestKind {
+    fn decode<P: AsRef<Path>>(
+        kind: Option<&Value>,
+        expected: Option<&Value>,
+        templates_base_dir: P,
+    ) -> Result<Self, Error> {
+        let kind = match kind {
+            Some(v) => decode_string(v, "test_templates[].kind")?,
+            None => "pgtap".to_owned(),
+        };
+        match (kind.as_str(), expected) {
+            ("pgtap", None) => Ok(Self::Pgtap),
+            ("pgtap", Some(_)) => Err(parse_error!(
+                "Key 'expected' in 'test_templates' entry is only allowed with kind = \"golden\""
+            )),
+            ("golden", Some(v)) => Ok(Self::Golden {
+                expected: decode_pathbuf(
+                    v,
+                    Some(templates_base_dir.as_ref()),
+                    "test_templates[].expected",
+                )?,
+            }),
+            ("golden", None) => Err(parse_error!(
+                "Missing 'expected' in 'test_templates' entry with kind = \"golden\""
+            )),
+            (k, _) => Err(parse_error!(
+                "Invalid 'test_templates[].kind': '{k}' (must be one of 'pgtap', 'golden')"
+            )),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Pgtap => "pgtap",
+            Self::Golden { .. } => "golden",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TestTemplate {
     pub query: String,
     pub path: PathBuf,
     pub output: PathBuf,
+    pub requires_conds: HashSet<String>,
+    /// Placeholder to render the prepared statement of the query
+    /// with, if different from the one in the manifest
+    pub placeholder: Option<Placeholder>,
+    pub kind: TestKind,
 }
 
 impl TestTemplate {
     fn decode<P: AsRef<Path>>(
         templates_base_dir: P,
         output_base_dir: P,
+        preserve_tree: bool,
         value: &Value,
     ) -> Result<Self, Error> {
         match value.as_table() {
@@ -72,12 +149,29 @@ impl TestTemplate {
                         Some(output_base_dir.as_ref()),
                         "test_templates[].output",
                     )?,
-                    None => path_to_output(&path, output_base_dir.as_ref())?,
+                    None => default_output(
+                        &path,
+                        templates_base_dir.as_ref(),
+                        output_base_dir.as_ref(),
+                        preserve_tree,
+                    )?,
                 };
+                let requires_conds = match t.get("requires_conds") {
+                    Some(v) => decode_strset(v, "test_templates[].requires_conds")?,
+                    None => HashSet::new(),
+                };
+                let placeholder = match t.get("placeholder") {
+                    Some(v) => Some(Placeholder::try_from(v)?),
+                    None => None,
+                };
+                let kind = TestKind::decode(t.get("kind"), t.get("expected"), &templates_base_dir)?;
                 Ok(Self {
                     path,
                     query,
                     output,
+                    requires_conds,
+                    placeholder,
+                    kind,
                 })
             }
             None => Err(parse_error!("Invalid 'test_templates' entry")),
@@ -103,9 +197,39 @@ impl TestTemplate {
         mistakes
     }
 
-    /// Returns file name of the template which can be used with
+    /// Returns the params referenced in the template using psql's
+    /// variable syntax (i.e. `:name`, `:'name'` or `:"name"`) that
+    /// are neither in `declared` nor set in the template itself
+    /// using `\set`, in sorted order
+    ///
+    /// Casts such as `::text` are not mistaken for params, and string
+    /// literals, quoted identifiers and comments are not scanned.
+    pub fn undeclared_params(&self, declared: &HashSet<String>) -> Result<Vec<String>, Error> {
+        let source = fs::read_to_string(&self.path).map_err(Error::Io)?;
+        let source = blank_literals_and_comments(&source);
+        let set_re = Regex::new(r"(?m)^\s*\\set\s+([A-Za-z_]\w*)").unwrap();
+        let set_in_template = set_re
+            .captures_iter(&source)
+            .map(|cap| cap[1].to_owned())
+            .collect::<HashSet<String>>();
+        let param_re = Regex::new(r#"(?:^|[^:\w]):['"]?([A-Za-z_]\w*)"#).unwrap();
+        let mut params = param_re
+            .captures_iter(&source)
+            .map(|cap| cap[1].to_owned())
+            .filter(|p| !declared.contains(p) && !set_in_template.contains(p))
+            .collect::<Vec<String>>();
+        params.sort();
+        params.dedup();
+        Ok(params)
+    }
+
+    /// Returns name of the template which can be used with
     /// `minijinja::Environment` that's initialized using
-    /// `minijinja::path_loader`
+    /// `minijinja::path_loader` for `base_dir`
+    ///
+    /// It's the path of the template relative to `base_dir`, so that
+    /// templates inside nested dirs can be loaded. If the path is not
+    /// under `base_dir`, the file name is returned.
     ///
     /// # Panics
     ///
@@ -114,36 +238,94 @@ impl TestTemplate {
     ///
     /// 2. If the path ends in `..`
     ///
-    pub fn file_name(&self) -> &str {
-        self.path
-            .file_name()
-            .map(|ostr| ostr.to_str().unwrap())
-            .unwrap()
+    pub fn loader_name(&self, base_dir: &Path) -> &str {
+        match self.path.strip_prefix(base_dir) {
+            Ok(p) => p.to_str().unwrap(),
+            Err(_) => self
+                .path
+                .file_name()
+                .map(|ostr| ostr.to_str().unwrap())
+                .unwrap(),
+        }
+    }
+}
+
+/// Returns `source` with the string literals (including dollar quoted
+/// ones), quoted identifiers and comments replaced with a space, so
+/// that they are not scanned for params
+///
+/// psql's quoted variable references i.e. `:'name'` and `:"name"` are
+/// kept as they are.
+fn blank_literals_and_comments(source: &str) -> String {
+    let dollar_tag_re = Regex::new(r"^\$[A-Za-z_]*\$").unwrap();
+    let mut result = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        let skip_to = |start: usize, end: &str| {
+            rest[start..]
+                .find(end)
+                .map_or(rest.len(), |i| start + i + end.len())
+        };
+        let skipped = if rest.starts_with("--") {
+            skip_to(2, "\n")
+        } else if rest.starts_with("/*") {
+            skip_to(2, "*/")
+        } else if c == '\'' || c == '"' {
+            if result.ends_with(':') {
+                // Quoted variable reference
+                let end = skip_to(1, &c.to_string());
+                result.push_str(&rest[..end]);
+                rest = &rest[end..];
+                continue;
+            }
+            skip_to(1, &c.to_string())
+        } else if let Some(tag) = dollar_tag_re.find(rest) {
+            skip_to(tag.end(), tag.as_str())
+        } else {
+            result.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        // Newlines are kept so that the `\set` meta-commands are
+        // still at the start of a line
+        result.push(if rest[..skipped].ends_with('\n') {
+            '\n'
+        } else {
+            ' '
+        });
+        rest = &rest[skipped..];
     }
+    result
 }
 
 #[derive(Debug)]
 pub struct TestTemplates {
-    inner: Vec<Rc<TestTemplate>>,
+    inner: Vec<Arc<TestTemplate>>,
 }
 
 impl TestTemplates {
     pub fn new() -> Self {
-        let inner: Vec<Rc<TestTemplate>> = vec![];
+        let inner: Vec<Arc<TestTemplate>> = vec![];
         Self { inner }
     }
 
     pub fn decode<P: AsRef<Path>>(
         templates_base_dir: P,
         output_base_dir: P,
+        preserve_tree: bool,
         value: &Value,
     ) -> Result<Self, Error> {
         let items = match value.as_array() {
             Some(xs) => {
                 let mut res = Vec::with_capacity(xs.len());
                 for x in xs {
-                    let tt = TestTemplate::decode(&templates_base_dir, &output_base_dir, x)?;
-                    res.push(Rc::new(tt));
+                    let tt = TestTemplate::decode(
+                        &templates_base_dir,
+                        &output_base_dir,
+                        preserve_tree,
+                        x,
+                    )?;
+                    res.push(Arc::new(tt));
                 }
                 res
             }
@@ -152,33 +334,153 @@ impl TestTemplates {
         Ok(Self { inner: items })
     }
 
+    pub fn push(&mut self, test_template: TestTemplate) {
+        self.inner.push(Arc::new(test_template));
+    }
+
+    /// Adds a test template of the query `query_id` for every file
+    /// under `templates_base_dir` that matches the glob `pattern`
+    /// (`queries[].tests_glob`), unless it's already declared for
+    /// the query
+    ///
+    /// Wildcards are supported only in the file name i.e. the last
+    /// component of the pattern. The matching files are added in
+    /// sorted order and their outputs are derived from the file names
+    /// the same way as for the declared test templates.
+    pub fn expand_glob<P: AsRef<Path>>(
+        &mut self,
+        query_id: &str,
+        pattern: &str,
+        templates_base_dir: P,
+        output_base_dir: P,
+        preserve_tree: bool,
+    ) -> Result<(), Error> {
+        let (dir, file_pattern) = match pattern.rsplit_once('/') {
+            Some((dir, file_pattern)) => (dir, file_pattern),
+            None => ("", pattern),
+        };
+        if dir.contains(['*', '?']) || file_pattern.is_empty() {
+            return Err(parse_error!(
+                "Invalid 'queries[].tests_glob': '{pattern}' (wildcards are supported only in the file name)"
+            ));
+        }
+        let dir = templates_base_dir.as_ref().join(dir);
+        let mut paths = match ls_files(&dir, false) {
+            Ok(paths) => paths
+                .into_iter()
+                .filter(|p| {
+                    p.file_name()
+                        .and_then(|f| f.to_str())
+                        .is_some_and(|f| glob_match(file_pattern, f))
+                })
+                .collect::<Vec<PathBuf>>(),
+            Err(e) => {
+                warn!("Unable to read dir {}: {e}", dir.display());
+                vec![]
+            }
+        };
+        if paths.is_empty() {
+            warn!("No test templates found for query '{query_id}' matching '{pattern}'");
+        }
+        paths.sort();
+        for path in paths {
+            let declared = self
+                .inner
+                .iter()
+                .any(|tt| tt.query == query_id && is_same_path(&tt.path, &path));
+            if declared {
+                continue;
+            }
+            let output = default_output(
+                &path,
+                templates_base_dir.as_ref(),
+                output_base_dir.as_ref(),
+                preserve_tree,
+            )?;
+            self.inner.push(Arc::new(TestTemplate {
+                query: query_id.to_owned(),
+                path,
+                output,
+                requires_conds: HashSet::new(),
+                placeholder: None,
+                kind: TestKind::Pgtap,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Links the test templates of a query that's expanded into
+    /// variants (see `queries[].variants`) to every variant, with the
+    /// suffix of the variant appended to the output file name (and
+    /// to the `expected` file of a golden test)
+    ///
+    /// Test templates linked to a query that exists are kept as is,
+    /// so a test can also be linked to a single variant.
+    pub fn expand_variants(&mut self, queries: &Queries) {
+        let mut result = Vec::with_capacity(self.inner.len());
+        for tt in self.inner.drain(..) {
+            if queries.get(&tt.query).is_some() {
+                result.push(tt);
+                continue;
+            }
+            let variants = queries
+                .iter()
+                .filter_map(|q| match &q.variant {
+                    Some((base, suffix)) if *base == tt.query => Some((&q.id, suffix)),
+                    _ => None,
+                })
+                .collect::<Vec<(&String, &String)>>();
+            if variants.is_empty() {
+                result.push(tt);
+                continue;
+            }
+            for (id, suffix) in variants {
+                let kind = match &tt.kind {
+                    TestKind::Pgtap => TestKind::Pgtap,
+                    TestKind::Golden { expected } => TestKind::Golden {
+                        expected: variant_path(expected, suffix),
+                    },
+                };
+                result.push(Arc::new(TestTemplate {
+                    query: id.clone(),
+                    path: tt.path.clone(),
+                    output: variant_path(&tt.output, suffix),
+                    requires_conds: tt.requires_conds.clone(),
+                    placeholder: tt.placeholder,
+                    kind,
+                }));
+            }
+        }
+        self.inner = result;
+    }
+
     pub fn validate<'a, 'b>(&'a self, queries: &'b Queries) -> Vec<ManifestMistake<'a>>
     where
         'b: 'a,
     {
         let mut mistakes = vec![];
         let count = self.inner.len();
-        let mut all_outputs: HashMap<&Path, usize> = HashMap::with_capacity(count);
+        let mut all_outputs: HashMap<&Path, Vec<&TestTemplate>> = HashMap::with_capacity(count);
         for tt in &self.inner {
             mistakes.append(&mut tt.validate(queries));
-            all_outputs
-                .entry(&tt.output)
-                .and_modify(|c| *c += 1)
-                .or_insert(1);
+            all_outputs.entry(&tt.output).or_default().push(tt);
         }
-        for (key, val) in all_outputs.iter() {
-            if val > &1 {
-                let m = ManifestMistake::Duplicates {
-                    key: "test_templates[].path",
-                    value: key.to_str().unwrap(),
-                };
-                mistakes.push(m)
+        // Report every collision once, naming all the test templates
+        // (and their queries) that resolve to the same output path
+        for (output, tts) in all_outputs.into_iter() {
+            if tts.len() > 1 {
+                let mut sources = tts
+                    .iter()
+                    .map(|tt| (tt.query.as_str(), tt.path.as_path()))
+                    .collect::<Vec<(&str, &Path)>>();
+                sources.sort();
+                mistakes.push(ManifestMistake::DuplicateTestOutput { output, sources });
             }
         }
         mistakes
     }
 
-    pub fn get(&self, path: &Path) -> Option<&Rc<TestTemplate>> {
+    pub fn get(&self, path: &Path) -> Option<&Arc<TestTemplate>> {
         self.inner.iter().find(|tt| tt.path == path)
     }
 
@@ -190,14 +492,240 @@ impl TestTemplates {
     /// to either use an index or modify inner itself to use a
     /// suitable data structure such as a `HashMap` of Strings (query
     /// ids) mapping to `Vec<TestTemplate>`.
-    pub fn find_by_query(&self, query_id: &str) -> Vec<&Rc<TestTemplate>> {
+    pub fn find_by_query(&self, query_id: &str) -> Vec<&Arc<TestTemplate>> {
         self.inner
             .iter()
             .filter(|tt| tt.query.as_str() == query_id)
             .collect()
     }
 
-    pub fn iter(&self) -> std::slice::Iter<'_, Rc<TestTemplate>> {
+    /// Returns test templates for the `query` that are applicable
+    /// given its conds i.e. excluding the ones that require conds
+    /// which are off for the query
+    pub fn find_active_by_query(&self, query: &Query) -> Vec<&Arc<TestTemplate>> {
+        self.inner
+            .iter()
+            .filter(|tt| tt.query == query.id && tt.requires_conds.is_subset(&query.conds))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Arc<TestTemplate>> {
         self.inner.iter()
     }
 }
+
+#[cfg(test)]
+pub mod test_util {
+
+    use super::*;
+
+    // Setup test_templates from &'static str input to be used in
+    // tests
+    //
+    // Argument `data` is a vector of tuple with following type of
+    // values (in order):
+    //
+    //   query: &str, path: &str, output: &str
+    pub fn setup_test_templates(data: Vec<(&str, &str, &str)>) -> TestTemplates {
+        let mut tts = TestTemplates::new();
+        for (query, path, output) in data {
+            let tt = TestTemplate {
+                query: String::from(query),
+                path: PathBuf::from(path),
+                output: PathBuf::from(output),
+                requires_conds: HashSet::new(),
+                placeholder: None,
+                kind: TestKind::Pgtap,
+            };
+            tts.inner.push(Arc::new(tt));
+        }
+        tts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::query::test_util::setup_queries;
+
+    #[test]
+    fn test_validate_duplicate_outputs() {
+        let queries = setup_queries(vec![
+            (
+                "artists_long_songs",
+                "templates/queries/artists_long_songs.sql.j2",
+                vec![],
+                "output/queries/artists_long_songs.sql",
+                None,
+            ),
+            (
+                "songs_formats@artist",
+                "templates/queries/songs_formats.sql.j2",
+                vec!["artist"],
+                "output/queries/songs_formats-artist.sql",
+                None,
+            ),
+        ]);
+        let tts = test_util::setup_test_templates(vec![
+            (
+                "artists_long_songs",
+                "templates/tests/artists_long_songs_test.sql.j2",
+                "output/tests/common_test.sql",
+            ),
+            (
+                "songs_formats@artist",
+                "templates/tests/songs_formats_test.sql.j2",
+                "output/tests/common_test.sql",
+            ),
+            (
+                "songs_formats@artist",
+                "templates/tests/songs_formats_count_test.sql.j2",
+                "output/tests/songs_formats_count_test.sql",
+            ),
+        ]);
+        let mistakes = tts
+            .validate(&queries)
+            .into_iter()
+            .filter(|m| matches!(m, ManifestMistake::DuplicateTestOutput { .. }))
+            .collect::<Vec<ManifestMistake>>();
+        assert_eq!(1, mistakes.len());
+        match &mistakes[0] {
+            ManifestMistake::DuplicateTestOutput { output, sources } => {
+                assert_eq!(Path::new("output/tests/common_test.sql"), *output);
+                assert_eq!(
+                    &vec![
+                        (
+                            "artists_long_songs",
+                            Path::new("templates/tests/artists_long_songs_test.sql.j2")
+                        ),
+                        (
+                            "songs_formats@artist",
+                            Path::new("templates/tests/songs_formats_test.sql.j2")
+                        ),
+                    ],
+                    sources
+                );
+            }
+            _ => panic!(),
+        }
+        let msg = mistakes[0].err_msg();
+        assert!(msg.contains("artists_long_songs_test.sql.j2"));
+        assert!(msg.contains("songs_formats_test.sql.j2"));
+    }
+
+    #[test]
+    fn test_find_active_by_query() {
+        let value: Value = toml::from_str(
+            r#"
+[[test_templates]]
+query = "users_list"
+path = "users_list_test.sql.j2"
+
+[[test_templates]]
+query = "users_list"
+path = "users_list_soft_delete_test.sql.j2"
+requires_conds = ["soft_delete"]
+
+[[test_templates]]
+query = "users_list@soft_delete"
+path = "users_list-soft_delete_test.sql.j2"
+requires_conds = ["soft_delete"]
+"#,
+        )
+        .unwrap();
+        let tts = TestTemplates::decode(
+            "templates/tests",
+            "output/tests",
+            false,
+            value.get("test_templates").unwrap(),
+        )
+        .unwrap();
+        let template = "templates/queries/users_list.sql.j2";
+        let queries = setup_queries(vec![
+            ("users_list", template, vec![], "users_list.sql", None),
+            (
+                "users_list@soft_delete",
+                template,
+                vec!["soft_delete"],
+                "users_list-soft_delete.sql",
+                None,
+            ),
+        ]);
+        let paths = |query_id: &str| {
+            let query = queries.get(query_id).unwrap();
+            tts.find_active_by_query(query)
+                .iter()
+                .map(|tt| tt.path.to_str().unwrap().to_owned())
+                .collect::<Vec<String>>()
+        };
+
+        // Conditional test is skipped when the cond is off
+        assert_eq!(
+            vec!["templates/tests/users_list_test.sql.j2"],
+            paths("users_list")
+        );
+        assert_eq!(2, tts.find_by_query("users_list").len());
+
+        // Conditional test is included when the cond is on
+        assert_eq!(
+            vec!["templates/tests/users_list-soft_delete_test.sql.j2"],
+            paths("users_list@soft_delete")
+        );
+    }
+
+    #[test]
+    fn test_decode_kind() {
+        let decode = |entry: &str| {
+            let value: Value = toml::from_str(&format!(
+                "[[test_templates]]\nquery = \"artists\"\npath = \"artists_test.sql.j2\"\n{entry}"
+            ))
+            .unwrap();
+            TestTemplates::decode(
+                "templates/tests",
+                "output/tests",
+                false,
+                value.get("test_templates").unwrap(),
+            )
+        };
+        let tts = decode("").unwrap();
+        assert_eq!(TestKind::Pgtap, tts.iter().next().unwrap().kind);
+
+        let tts = decode("kind = \"golden\"\nexpected = \"artists.expected.sql\"").unwrap();
+        assert_eq!(
+            TestKind::Golden {
+                expected: PathBuf::from("templates/tests/artists.expected.sql")
+            },
+            tts.iter().next().unwrap().kind
+        );
+
+        assert!(decode("kind = \"golden\"").is_err());
+        assert!(decode("expected = \"artists.expected.sql\"").is_err());
+        assert!(decode("kind = \"snapshot\"").is_err());
+    }
+
+    #[test]
+    fn test_blank_literals_and_comments() {
+        assert_eq!(
+            "SELECT  ::jsonb, :'genre',  , :\"name\"  ",
+            blank_literals_and_comments(
+                "SELECT '{\"k\":\"v\"}'::jsonb, :'genre', \"a:b\", :\"name\" /* :c */"
+            )
+        );
+        assert_eq!(
+            "SELECT :year \n\\set limit 10\n ",
+            blank_literals_and_comments("SELECT :year -- or :month\n\\set limit 10\n$fn$ :d $fn$")
+        );
+        // `$1` is not a dollar quote
+        assert_eq!(
+            "WHERE id = $1",
+            blank_literals_and_comments("WHERE id = $1")
+        );
+        // Unterminated ones extend till the end
+        assert_eq!("SELECT  ", blank_literals_and_comments("SELECT ':a"));
+    }
+}
@@ -2,7 +2,7 @@ use crate::error::{parse_error, Error};
 use serde::{Serialize, Serializer};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use toml::Value;
+use toml::{Table, Value};
 
 /// Tries decoding a toml `Value` into a `String`
 ///
@@ -16,6 +16,17 @@ pub fn decode_string(value: &Value, key: &str) -> Result<String, Error> {
         .map(|s| s.to_owned())
 }
 
+/// Tries decoding a toml `Value` into a `bool`
+///
+/// The second arg `key` will be used in the error message in case
+/// decoding fails (i.e. in case the value in the toml file is not a
+/// boolean).
+pub fn decode_bool(value: &Value, key: &str) -> Result<bool, Error> {
+    value
+        .as_bool()
+        .ok_or(parse_error!("Value of '{}' must be a boolean", key))
+}
+
 /// Tries decoding a toml `Value` into a PathBuf
 ///
 /// The second arg `key` will be used in the error message in
@@ -62,6 +73,18 @@ pub fn decode_strset(value: &Value, key: &str) -> Result<HashSet<String>, Error>
     }
 }
 
+/// Returns keys in the toml `table` that are not in `known`
+///
+/// Each key is returned prefixed with `prefix` so that it can be
+/// identified in messages (e.g. `queries[].tempalte`).
+pub fn unknown_keys(table: &Table, known: &[&str], prefix: &str) -> Vec<String> {
+    table
+        .keys()
+        .filter(|k| !known.contains(&k.as_str()))
+        .map(|k| format!("{prefix}{k}"))
+        .collect()
+}
+
 // Abstractions for serializing simple toml tables
 
 enum SerializableTomlTableLine {
@@ -114,6 +137,12 @@ impl SerializableTomlTable {
         self.lines.push(entry)
     }
 
+    pub fn push_entry_strvec(&mut self, key: &str, values: &[String]) {
+        let v = Value::Array(values.iter().map(|s| Value::String(s.to_owned())).collect());
+        let entry = SerializableTomlTableLine::Entry(key.to_owned(), v);
+        self.lines.push(entry)
+    }
+
     pub fn push_comment(&mut self, msg: &str) {
         let comment = SerializableTomlTableLine::Comment(msg.to_owned());
         self.lines.push(comment)
@@ -1,4 +1,4 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::{fs, io};
 
 /// Checks whether a file is a backup file
@@ -9,7 +9,7 @@ use std::{fs, io};
 fn is_backup_file<P: AsRef<Path>>(path: P) -> bool {
     path.as_ref()
         .extension()
-        .map_or(false, |e| e.to_str().map_or(false, |s| s.ends_with('~')))
+        .is_some_and(|e| e.to_str().is_some_and(|s| s.ends_with('~')))
 }
 
 /// Returns list of files (and not dirs) inside a directory
@@ -25,14 +25,481 @@ pub fn ls_files<P: AsRef<Path>>(path: P, include_backups: bool) -> Result<Vec<Pa
     Ok(result)
 }
 
+/// Returns the path with all `.` components removed
+///
+/// This is a purely lexical operation i.e. it doesn't touch the file
+/// system. It's useful for comparing user provided paths such as
+/// `./output/queries/foo.sql` with the ones derived from the
+/// manifest.
+pub fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref()
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Returns the dir under `dest_base` that mirrors the location of
+/// `path` relative to `src_base`
+///
+/// E.g. for path `templates/reports/monthly/foo.sql.j2`, src_base
+/// `templates` and dest_base `output`, it returns
+/// `output/reports/monthly`. If `path` is not under `src_base`,
+/// `dest_base` is returned as it is.
+pub fn mirrored_dir(path: &Path, src_base: &Path, dest_base: &Path) -> PathBuf {
+    match path.strip_prefix(src_base).ok().and_then(|p| p.parent()) {
+        Some(subdir) => dest_base.join(subdir),
+        None => dest_base.to_path_buf(),
+    }
+}
+
+/// Checks whether `s` matches the glob `pattern`
+///
+/// Only the wildcards `*` (any sequence of chars, including none) and
+/// `?` (any single char) are supported. All other chars are matched
+/// literally.
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // Position in pattern of the last `*` seen and the position in
+    // `s` that it has been matched up to, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if let Some((spi, ssi)) = star {
+            pi = spi + 1;
+            si = ssi + 1;
+            star = Some((spi, ssi + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Returns the path with `.` components removed and `..` components
+/// resolved against the preceding ones
+///
+/// Like `normalize_path`, this is a purely lexical operation so
+/// symlinks are not taken into consideration.
+fn resolve_parent_dirs(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                // It's not possible to go above the root
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => result.push(".."),
+            },
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Returns `path` with the `.` and `..` components resolved and the
+/// longest prefix of it that exists canonicalized, so that any
+/// symlinks in the existing part are resolved too
+fn resolve_existing(path: &Path) -> PathBuf {
+    let path = resolve_parent_dirs(path);
+    let mut prefix = path.as_path();
+    loop {
+        let existing = if prefix.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            prefix
+        };
+        if let Ok(canonical) = existing.canonicalize() {
+            // @UNWRAP: `prefix` is always an ancestor of `path`
+            return canonical.join(path.strip_prefix(prefix).unwrap());
+        }
+        match prefix.parent() {
+            Some(parent) => prefix = parent,
+            None => return path,
+        }
+    }
+}
+
+/// Checks whether `path` is located inside `dir` after resolving any
+/// `.` and `..` components in both, as well as any symlinks in the
+/// parts of them that exist
+pub fn is_within<P: AsRef<Path>, Q: AsRef<Path>>(path: P, dir: Q) -> bool {
+    resolve_existing(path.as_ref()).starts_with(resolve_existing(dir.as_ref()))
+}
+
+/// Checks whether `a` and `b` are the same path after resolving any
+/// `.` and `..` components in both, or if both exist, whether they
+/// are the same file (e.g. an absolute and a relative path to it, or
+/// a path through a symlink)
+pub fn is_same_path<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    if resolve_parent_dirs(a) == resolve_parent_dirs(b) {
+        return true;
+    }
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(x), Ok(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// Returns `path` relative to `base` if it's an absolute path inside
+/// `base`, after resolving any `.` and `..` components in both
+///
+/// Relative paths are assumed to be relative to `base` already and
+/// are returned with only the `.` and `..` components resolved.
+/// Absolute paths outside `base` are returned as they are (resolved).
+pub fn relative_to<P: AsRef<Path>, Q: AsRef<Path>>(path: P, base: Q) -> PathBuf {
+    let path = resolve_parent_dirs(path.as_ref());
+    if path.is_absolute() {
+        if let Ok(rel) = path.strip_prefix(resolve_parent_dirs(base.as_ref())) {
+            return rel.to_path_buf();
+        }
+    }
+    path
+}
+
+/// Returns the `paths` relative to a common base dir e.g. for naming
+/// them as entries of an archive
+///
+/// The paths are resolved against `cwd`, which is the base if all of
+/// them are inside it. Otherwise the deepest dir containing all of
+/// them is the base.
+pub fn relative_to_common_base(paths: &[&Path], cwd: &Path) -> Vec<PathBuf> {
+    let cwd = resolve_parent_dirs(cwd);
+    let paths = paths
+        .iter()
+        .map(|p| resolve_parent_dirs(&cwd.join(p)))
+        .collect::<Vec<PathBuf>>();
+    let base = if paths.iter().all(|p| p.starts_with(&cwd)) {
+        cwd
+    } else {
+        let mut base = paths
+            .first()
+            .and_then(|p| p.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        while !paths.iter().all(|p| p.starts_with(&base)) && base.pop() {}
+        base
+    };
+    paths
+        .iter()
+        .map(|p| {
+            p.strip_prefix(&base)
+                .map_or_else(|_| p.clone(), Path::to_path_buf)
+        })
+        .collect()
+}
+
+/// Returns a line based diff between `old` and `new`
+///
+/// Only the changed lines are included, prefixed with `-` (removed)
+/// or `+` (added). The diff is computed using the longest common
+/// subsequence of lines which is good enough for small files such as
+/// the manifest.
+pub fn line_diff(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let (n, m) = (a.len(), b.len());
+    // lcs[i][j] = length of LCS of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            result.push(format!("+{}", b[j]));
+            j += 1;
+        } else {
+            result.push(format!("-{}", a[i]));
+            i += 1;
+        }
+    }
+    result.join("\n")
+}
+
+#[cfg(test)]
+pub mod test_util {
+
+    use super::*;
+    use std::ops::Deref;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static NUM_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Dir for the files of a test, which is removed along with its
+    /// contents when dropped i.e. also if the test panics
+    pub struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        /// Creates the dir under the system temp dir, with a name
+        /// having the `prefix` and unique across tests as well as
+        /// concurrently running test binaries
+        pub fn new(prefix: &str) -> Self {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let n = NUM_CREATED.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!(
+                "tapestry-{prefix}-{}-{n}-{nanos}",
+                std::process::id()
+            ));
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Returns a manifest with the `posargs` placeholder, the query
+    /// templates in `templates_dir` (and the test templates in its
+    /// `tests` subdir) and the outputs in the `queries` and `tests`
+    /// subdirs of `output_dir`, followed by the `rest` of the config
+    /// e.g. the templates and queries
+    pub fn test_manifest(
+        templates_dir: impl AsRef<Path>,
+        output_dir: impl AsRef<Path>,
+        rest: &str,
+    ) -> String {
+        let (templates_dir, output_dir) = (templates_dir.as_ref(), output_dir.as_ref());
+        format!(
+            r#"placeholder = "posargs"
+query_templates_dir = "{0}"
+test_templates_dir = "{0}/tests"
+queries_output_dir = "{1}/queries"
+tests_output_dir = "{1}/tests"
+{rest}"#,
+            templates_dir.display(),
+            output_dir.display(),
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_temp_dir() {
+        let (a, b) = (
+            test_util::TempDir::new("util"),
+            test_util::TempDir::new("util"),
+        );
+        assert_ne!(a.to_path_buf(), b.to_path_buf());
+        let path = a.to_path_buf();
+        fs::write(a.join("x.sql"), "SELECT 1;").unwrap();
+        drop(a);
+        assert!(!path.exists());
+        assert!(b.is_dir());
+    }
+
     #[test]
     fn test_is_backup_file() {
         assert!(is_backup_file("hello.sql~"));
         assert!(!is_backup_file("hello.sql"));
     }
+
+    #[test]
+    fn test_line_diff() {
+        let old = "a\nb\nc\n";
+        let new = "x\na\nc\nd\n";
+        assert_eq!("+x\n-b\n+d", line_diff(old, new));
+        assert_eq!("", line_diff(old, old));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            PathBuf::from("output/queries/foo.sql"),
+            normalize_path("./output/queries/foo.sql")
+        );
+        assert_eq!(
+            PathBuf::from("output/queries/foo.sql"),
+            normalize_path("output/./queries/foo.sql")
+        );
+        assert_eq!(
+            PathBuf::from("/output/foo.sql"),
+            normalize_path("/output/foo.sql")
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("artists_long_songs", "artists_long_songs"));
+        assert!(glob_match("artists_*", "artists_long_songs@genre*limit"));
+        assert!(glob_match("*@genre*", "artists_long_songs@genre*limit"));
+        assert!(glob_match(
+            "songs_formats@artist?album",
+            "songs_formats@artist&album"
+        ));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("artists_*", "songs_formats@artist&album"));
+        assert!(!glob_match(
+            "artists_long_songs",
+            "artists_long_songs*limit"
+        ));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn test_is_same_path() {
+        assert!(is_same_path(
+            "output/queries/foo.sql",
+            "./output/queries/foo.sql"
+        ));
+        assert!(is_same_path(
+            "output/tests/../queries/foo.sql",
+            "output/queries/foo.sql"
+        ));
+        assert!(!is_same_path(
+            "output/queries/foo.sql",
+            "output/tests/foo.sql"
+        ));
+        // Existing files are compared after resolving them
+        let abs = std::env::current_dir().unwrap().join("Cargo.toml");
+        assert!(is_same_path("Cargo.toml", &abs));
+        assert!(!is_same_path("Cargo.lock", &abs));
+    }
+
+    #[test]
+    fn test_is_within() {
+        assert!(is_within("output/queries/foo.sql", "output/queries"));
+        assert!(is_within("./output/queries/foo.sql", "output/queries/"));
+        assert!(is_within(
+            "output/queries/reports/../foo.sql",
+            "output/queries"
+        ));
+        assert!(is_within("../sql_queries/foo.sql", "../sql_queries"));
+        assert!(!is_within(
+            "output/queries/../../evil.sql",
+            "output/queries"
+        ));
+        assert!(!is_within(
+            "output/queries/../tests/foo.sql",
+            "output/queries"
+        ));
+        assert!(!is_within("../evil.sql", "output/queries"));
+        assert!(!is_within("/etc/passwd", "output/queries"));
+        assert!(!is_within(
+            "/output/queries/../../../evil.sql",
+            "/output/queries"
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_is_within_symlink() {
+        let dir = test_util::TempDir::new("util");
+        let queries_dir = dir.join("output/queries");
+        fs::create_dir_all(&queries_dir).unwrap();
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), queries_dir.join("link")).unwrap();
+        assert!(is_within(queries_dir.join("foo.sql"), &queries_dir));
+        assert!(is_within(queries_dir.join("new/foo.sql"), &queries_dir));
+        assert!(!is_within(queries_dir.join("link/foo.sql"), &queries_dir));
+    }
+
+    #[test]
+    fn test_relative_to_common_base() {
+        let rel = |paths: &[&str], cwd: &str| {
+            let paths = paths.iter().map(Path::new).collect::<Vec<&Path>>();
+            relative_to_common_base(&paths, Path::new(cwd))
+        };
+        // Paths inside the current dir are relative to it
+        assert_eq!(
+            vec![
+                PathBuf::from("output/queries/foo.sql"),
+                PathBuf::from("output/tests/foo_test.sql")
+            ],
+            rel(
+                &["output/queries/foo.sql", "/proj/output/tests/foo_test.sql"],
+                "/proj"
+            )
+        );
+        // Otherwise they are relative to the deepest dir containing
+        // all of them
+        assert_eq!(
+            vec![
+                PathBuf::from("queries/foo.sql"),
+                PathBuf::from("tests/foo_test.sql")
+            ],
+            rel(
+                &["../out/queries/foo.sql", "/out/tests/./foo_test.sql"],
+                "/proj"
+            )
+        );
+        assert_eq!(
+            vec![PathBuf::from("foo.sql")],
+            rel(&["/tmp/out/queries/foo.sql"], "/proj")
+        );
+    }
+
+    #[test]
+    fn test_mirrored_dir() {
+        assert_eq!(
+            PathBuf::from("output/reports/monthly"),
+            mirrored_dir(
+                Path::new("templates/reports/monthly/foo.sql.j2"),
+                Path::new("templates"),
+                Path::new("output")
+            )
+        );
+        assert_eq!(
+            PathBuf::from("output"),
+            mirrored_dir(
+                Path::new("templates/foo.sql.j2"),
+                Path::new("templates"),
+                Path::new("output")
+            )
+        );
+        assert_eq!(
+            PathBuf::from("output"),
+            mirrored_dir(
+                Path::new("elsewhere/foo.sql.j2"),
+                Path::new("templates"),
+                Path::new("output")
+            )
+        );
+    }
 }
@@ -1,4 +1,11 @@
-use std::path::Path;
+use crate::metadata::MANIFEST_VERSION;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
 
 #[derive(Debug)]
 pub enum ManifestMistake<'a> {
@@ -23,6 +30,26 @@ pub enum ManifestMistake<'a> {
         query_id: &'a str,
         conds: Vec<&'a str>,
     },
+    /// Conds in `default_conds` that are not in the `all_conds` of
+    /// any query template
+    InvalidDefaultConds(Vec<&'a str>),
+    /// Cond specified more than once in the `conds` of a query
+    DuplicateCond {
+        query_id: &'a str,
+        cond: &'a str,
+    },
+    /// Cond of a query for which `cond__<name>` is not a valid
+    /// identifier, hence can't be referenced in the template
+    InvalidCondName {
+        query_id: &'a str,
+        cond: &'a str,
+    },
+    /// Cond referenced in the `enabled_if` of a query that's not in
+    /// the `all_conds` of its template, hence can never be on
+    UndeclaredEnabledIfCond {
+        query_id: &'a str,
+        cond: &'a str,
+    },
     Duplicates {
         key: &'a str,
         value: &'a str,
@@ -33,6 +60,63 @@ pub enum ManifestMistake<'a> {
     },
     DisparateQueryOutputs,
     NameTaggingRequired(String),
+    UnsupportedVersion(i64),
+    PathEscapesDir {
+        path: &'a Path,
+        key: &'a str,
+        dir: &'a Path,
+    },
+    /// Multiple test templates resolve to the same output path. The
+    /// sources are pairs of query id and test template path.
+    DuplicateTestOutput {
+        output: &'a Path,
+        sources: Vec<(&'a str, &'a Path)>,
+    },
+    /// Query id that's empty (or only whitespace) or contains a path
+    /// separator. The index is of the entry in `queries`.
+    InvalidQueryId {
+        index: usize,
+        id: &'a str,
+    },
+    /// Output path of a query or a test that's the same as the path
+    /// of a template, which would be overwritten upon rendering
+    OutputOverwritesTemplate {
+        output: &'a Path,
+        key: &'a str,
+    },
+    /// Query id that doesn't match the naming convention specified
+    /// by `id_pattern` (or enforced by `validate --strict-ids`)
+    IdPatternMismatch {
+        id: &'a str,
+        pattern: &'a str,
+    },
+    /// Suffix of a variant (`queries[].variants[].suffix`) due to
+    /// which the id of the variant doesn't match `id_pattern`
+    VariantSuffixMismatch {
+        id: &'a str,
+        suffix: &'a str,
+        pattern: &'a str,
+    },
+    /// Param referenced in a test template that's rendered with the
+    /// `variables` placeholder, but which is not a param of the
+    /// linked query
+    UndeclaredTestParam {
+        test_template: &'a Path,
+        query_id: &'a str,
+        param: String,
+    },
+    // Warnings
+    UnknownKey(&'a str),
+    UnusedQueryTemplate(&'a Path),
+    UndefinedQueryTemplateFile(PathBuf),
+    UndefinedTestTemplateFile(PathBuf),
+    FormatterNotFound(&'a Path),
+    /// Cond that's referenced in a query template but not declared
+    /// in its `all_conds`, hence always false
+    UndeclaredCond {
+        template: &'a Path,
+        cond: String,
+    },
 }
 
 impl<'a> ManifestMistake<'a> {
@@ -63,6 +147,18 @@ impl<'a> ManifestMistake<'a> {
             Self::InvalidConds { query_id, conds } => {
                 format!("Invalid 'conds': {conds:?} defined for query: '{query_id}'")
             }
+            Self::InvalidDefaultConds(conds) => {
+                format!("Invalid 'default_conds': {conds:?} (not in 'all_conds' of any query template)")
+            }
+            Self::DuplicateCond { query_id, cond } => {
+                format!("Cond '{cond}' is specified more than once in 'conds' of query '{query_id}'")
+            }
+            Self::InvalidCondName { query_id, cond } => {
+                format!("Invalid cond {cond:?} in 'conds' of query '{query_id}' (must consist of only letters, digits and underscores, so that 'cond__<name>' is a valid identifier)")
+            }
+            Self::UndeclaredEnabledIfCond { query_id, cond } => {
+                format!("Cond '{cond}' in 'enabled_if' of query '{query_id}' is not in the 'all_conds' of its template")
+            }
             Self::Duplicates { key, value } => {
                 format!("Duplicates found; key: '{key}', value: '{value}'")
             }
@@ -78,8 +174,115 @@ impl<'a> ManifestMistake<'a> {
             Self::NameTaggingRequired(reason) => {
                 format!("Name tagging is required for reason: {reason}")
             }
+            Self::UnsupportedVersion(v) => {
+                format!("Unsupported manifest version: {v}; Supported versions: 1..={MANIFEST_VERSION}")
+            }
+            Self::PathEscapesDir { path, key, dir } => {
+                format!(
+                    "Path '{}' is outside of the dir '{}'; key: '{key}'",
+                    path.display(),
+                    dir.display()
+                )
+            }
+            Self::DuplicateTestOutput { output, sources } => {
+                let sources = sources
+                    .iter()
+                    .map(|(query_id, path)| format!("'{}' (query: '{query_id}')", path.display()))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                format!(
+                    "Test output '{}' is shared by multiple test templates: {sources}",
+                    output.display()
+                )
+            }
+            Self::InvalidQueryId { index, id } => {
+                let reason = if id.trim().is_empty() {
+                    "must not be empty or whitespace"
+                } else {
+                    "must not contain path separators"
+                };
+                format!("Invalid query id at 'queries[{index}]': {id:?} ({reason})")
+            }
+            Self::OutputOverwritesTemplate { output, key } => {
+                format!(
+                    "Output path '{}' is the same as that of a template, which would be overwritten; key: '{key}'",
+                    output.display()
+                )
+            }
+            Self::IdPatternMismatch { id, pattern } => {
+                format!("Query id '{id}' does not match the pattern '{pattern}'")
+            }
+            Self::VariantSuffixMismatch {
+                id,
+                suffix,
+                pattern,
+            } => {
+                format!("Invalid 'queries[].variants[].suffix': '{suffix}' (id '{id}' of the variant does not match the pattern '{pattern}')")
+            }
+            Self::UnknownKey(key) => {
+                format!("Unknown key found in manifest (typo?): '{key}'")
+            }
+            Self::UnusedQueryTemplate(path) => {
+                format!("Unused query template found in manifest: {}", path.display())
+            }
+            Self::UndefinedQueryTemplateFile(path) => {
+                format!("Did you miss defining query template in manifest? {}", path.display())
+            }
+            Self::UndefinedTestTemplateFile(path) => {
+                format!("Did you miss defining test template in manifest? {}", path.display())
+            }
+            Self::FormatterNotFound(exec_path) => {
+                format!("Executable for external formatter not found: {}", exec_path.display())
+            }
+            Self::UndeclaredTestParam {
+                test_template,
+                query_id,
+                param,
+            } => {
+                format!(
+                    "Test template '{}' refers to param ':{param}' which is not a param of query '{query_id}'",
+                    test_template.display()
+                )
+            }
+            Self::UndeclaredCond { template, cond } => {
+                format!(
+                    "Query template '{}' refers to 'cond__{cond}' but '{cond}' is not in its 'all_conds' (typo?)",
+                    template.display()
+                )
+            }
         }
     }
+
+    /// Returns the severity of the mistake
+    ///
+    /// Only errors invalidate the manifest. Warnings let the user
+    /// know that something may not be as per expectation.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::UnknownKey(_)
+            | Self::UnusedQueryTemplate(_)
+            | Self::UndefinedQueryTemplateFile(_)
+            | Self::UndefinedTestTemplateFile(_)
+            | Self::FormatterNotFound(_)
+            | Self::UndeclaredCond { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    /// Returns whether the mistake is found by looking up paths (or
+    /// executables) on the file system, as opposed to checking the
+    /// structure and consistency of the manifest itself
+    pub fn is_fs_check(&self) -> bool {
+        matches!(
+            self,
+            Self::PathDoesnotExist { .. }
+                | Self::UndefinedQueryTemplateFile(_)
+                | Self::UndefinedTestTemplateFile(_)
+                | Self::FormatterNotFound(_)
+                | Self::UndeclaredCond { .. }
+                | Self::UndeclaredTestParam { .. }
+        )
+    }
 }
 
 pub fn validate_path<'a>(path: &'a Path, key: &'a str) -> Result<(), ManifestMistake<'a>> {
@@ -0,0 +1,193 @@
+use crate::error::Error;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Modification times of the files being watched
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+// Adds the file at `path`, or all files under it (recursively) if
+// it's a dir, to the `snapshot`. Missing paths are ignored so that
+// e.g. a templates dir may be created after watching has started.
+fn add_to_snapshot(path: &Path, snapshot: &mut Snapshot) -> io::Result<()> {
+    let meta = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            add_to_snapshot(&entry?.path(), snapshot)?;
+        }
+    } else {
+        snapshot.insert(path.to_path_buf(), meta.modified()?);
+    }
+    Ok(())
+}
+
+/// Source of change events that polls the file system for changes to
+/// the watched paths (files or dirs) at a fixed interval
+///
+/// The first event is emitted right away, so that the outputs are
+/// rendered once on start. After that, an event is emitted whenever
+/// any file is added, removed or modified.
+pub struct Poller {
+    paths: Vec<PathBuf>,
+    interval: Duration,
+    last: Option<Snapshot>,
+}
+
+impl Poller {
+    pub fn new(paths: Vec<PathBuf>, interval: Duration) -> Self {
+        Self {
+            paths,
+            interval,
+            last: None,
+        }
+    }
+
+    fn snapshot(&self) -> io::Result<Snapshot> {
+        let mut snapshot = Snapshot::new();
+        for path in &self.paths {
+            add_to_snapshot(path, &mut snapshot)?;
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Iterator for Poller {
+    type Item = io::Result<()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let snapshot = match self.snapshot() {
+                Ok(s) => s,
+                Err(e) => return Some(Err(e)),
+            };
+            let changed = self.last.as_ref() != Some(&snapshot);
+            let first = self.last.is_none();
+            self.last = Some(snapshot);
+            if changed {
+                if !first {
+                    info!("Change detected");
+                }
+                return Some(Ok(()));
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+/// Executes the `--run` command using the shell, with its output
+/// streamed to the terminal
+pub fn run_shell(cmd: &str) -> Result<(), Error> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .status()
+        .map_err(|e| Error::Hook(format!("{cmd}: {e}")))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Hook(format!("{cmd}: exited with {status}")))
+    }
+}
+
+/// Re-renders the outputs using `render` for every event and then
+/// runs the command using `run` if any output files changed
+///
+/// `render` returns the no. of output files that it wrote. Errors
+/// while rendering or running the command are reported without
+/// stopping the loop, which ends only when the `events` run out or
+/// in case of an error in watching.
+pub fn watch_loop<E, R, C>(events: E, mut render: R, mut run: Option<C>) -> Result<(), Error>
+where
+    E: Iterator<Item = io::Result<()>>,
+    R: FnMut() -> Result<usize, Error>,
+    C: FnMut() -> Result<(), Error>,
+{
+    for event in events {
+        event.map_err(Error::Io)?;
+        match render() {
+            Ok(0) => info!("No changes in the output files"),
+            Ok(n) => {
+                eprintln!("Rendered {n} changed file(s)");
+                if let Some(run) = run.as_mut() {
+                    if let Err(e) = run() {
+                        warn!("{e}");
+                    }
+                }
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::util::test_util::TempDir;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_watch_loop() {
+        // Outcomes of rendering for the injected events: changes,
+        // no changes, error and then changes again
+        let outcomes = vec![Ok(2), Ok(0), Err(Error::Cli("oops".to_owned())), Ok(1)];
+        let events = (0..outcomes.len()).map(|_| Ok(()));
+        let mut outcomes = outcomes.into_iter();
+        let num_runs = Cell::new(0);
+        watch_loop(
+            events,
+            || outcomes.next().unwrap(),
+            Some(|| {
+                num_runs.set(num_runs.get() + 1);
+                Ok(())
+            }),
+        )
+        .unwrap();
+        assert_eq!(2, num_runs.get());
+
+        // A failing command doesn't stop the loop
+        let num_renders = Cell::new(0);
+        watch_loop(
+            (0..3).map(|_| Ok(())),
+            || {
+                num_renders.set(num_renders.get() + 1);
+                Ok(1)
+            },
+            Some(|| Err(Error::Hook("make reload-sql: exited with 2".to_owned()))),
+        )
+        .unwrap();
+        assert_eq!(3, num_renders.get());
+
+        // Error in watching ends the loop
+        let events = vec![Err(io::Error::new(io::ErrorKind::Other, "gone"))].into_iter();
+        let res = watch_loop(events, || Ok(1), None::<fn() -> Result<(), Error>>);
+        assert!(matches!(res, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_poller() {
+        let dir = TempDir::new("watch");
+        fs::create_dir_all(dir.join("queries")).unwrap();
+        fs::write(dir.join("queries/artists.sql.j2"), "SELECT 1;\n").unwrap();
+        let mut poller = Poller::new(
+            vec![dir.join("queries"), dir.join("missing")],
+            Duration::from_millis(10),
+        );
+        // The first event is emitted right away
+        assert!(poller.next().unwrap().is_ok());
+        // Adding a file emits an event
+        fs::write(dir.join("queries/albums.sql.j2"), "SELECT 2;\n").unwrap();
+        assert!(poller.next().unwrap().is_ok());
+        assert_eq!(2, poller.last.as_ref().unwrap().len());
+    }
+}